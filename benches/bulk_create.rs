@@ -0,0 +1,51 @@
+use common::{Item, TempRepo};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+mod common;
+
+const OBJECTS: usize = 200;
+
+fn items() -> Vec<Item> {
+  (0..OBJECTS)
+    .map(|i| Item {
+      name: format!("item {}", i),
+    })
+    .collect()
+}
+
+fn bulk_create(c: &mut Criterion) {
+  let mut group = c.benchmark_group("create");
+  group.sample_size(10);
+  // One commit per object, storage details written every time
+  group.bench_function("create_object_per_commit", |b| {
+    b.iter_batched(
+      TempRepo::init,
+      |temp| {
+        for item in items() {
+          let mut commit = temp.repo.commit_ctx("create");
+          temp.items.create_object(item, &mut commit);
+          assert!(commit.commit().unwrap().is_ok());
+        }
+        temp
+      },
+      BatchSize::PerIteration,
+    )
+  });
+  // Single commit, storage details written once
+  group.bench_function("create_objects", |b| {
+    b.iter_batched(
+      TempRepo::init,
+      |temp| {
+        let mut commit = temp.repo.commit_ctx("create");
+        temp.items.create_objects(items(), &mut commit);
+        assert!(commit.commit().unwrap().is_ok());
+        temp
+      },
+      BatchSize::PerIteration,
+    )
+  });
+  group.finish();
+}
+
+criterion_group!(benches, bulk_create);
+criterion_main!(benches);
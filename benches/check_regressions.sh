@@ -0,0 +1,29 @@
+#!/bin/sh
+# Compare bench results against a saved criterion baseline
+# and fail if any mean slowed down more than the threshold
+#
+#   cargo bench -- --save-baseline main     (on the reference commit)
+#   benches/check_regressions.sh main 0.10  (on the change)
+
+set -e
+
+BASELINE=${1:-main}
+THRESHOLD=${2:-0.10}
+CRITERION_DIR=${CARGO_TARGET_DIR:-target}/criterion
+
+cargo bench -- --baseline "$BASELINE"
+
+python3 - "$CRITERION_DIR" "$THRESHOLD" <<'PY'
+import json, pathlib, sys
+
+root, threshold = pathlib.Path(sys.argv[1]), float(sys.argv[2])
+regressed = []
+for path in sorted(root.glob("**/change/estimates.json")):
+    change = json.loads(path.read_text())["mean"]["point_estimate"]
+    name = path.parent.parent.relative_to(root)
+    print(f"{name}: {change:+.1%}")
+    if change > threshold:
+        regressed.append(str(name))
+if regressed:
+    sys.exit(f"Regressed over {threshold:.0%}: {', '.join(regressed)}")
+PY
@@ -0,0 +1,55 @@
+//! Repository fixtures shared by the benches
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use storage::sync::*;
+use storage::Action;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, schemars::JsonSchema, Clone, Debug)]
+pub struct Item {
+  pub name: String,
+}
+
+impl ObjectExt for Item {}
+
+#[derive(
+  Action, Serialize, Deserialize, schemars::JsonSchema, Clone, Debug,
+)]
+#[action(object = Item)]
+pub enum ItemAction {
+  #[action(field = name)]
+  SetName(String),
+}
+
+/// Load or init and register the items storage
+pub fn register_items(
+  repo: &Repository,
+) -> Result<Storage<Item, ItemAction>, String> {
+  Storage::load_or_init(repo, "items".into())?.register(repo)
+}
+
+// Temp repository removed on drop
+pub struct TempRepo {
+  dir: PathBuf,
+  pub repo: Repository,
+  pub items: Storage<Item, ItemAction>,
+}
+
+impl TempRepo {
+  pub fn init() -> Self {
+    let dir = std::env::temp_dir()
+      .join(format!("storage_bench_{}", Uuid::new_v4().simple()));
+    let repo =
+      Repository::init(Context::init(dir.clone(), "bench".into()), Mode::Local)
+        .unwrap();
+    let items = register_items(&repo).unwrap();
+    Self { dir, repo, items }
+  }
+}
+
+impl Drop for TempRepo {
+  fn drop(&mut self) {
+    let _ = std::fs::remove_dir_all(&self.dir);
+  }
+}
@@ -0,0 +1,162 @@
+use common::{register_items, Item, ItemAction, TempRepo};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use storage::codec::ActionCodec;
+use storage::sync::*;
+use storage::testing::TestServer;
+
+mod common;
+
+// Objects scanned by get_by_filter, override with BENCH_OBJECTS
+const FILTER_OBJECTS: usize = 100_000;
+const PATCH_OBJECTS: usize = 1_000;
+const PULL_COMMITS: usize = 200;
+const LOG_COMMITS: usize = 5_000;
+const CODEC_ACTIONS: usize = 5_000;
+
+fn items(count: usize) -> Vec<Item> {
+  (0..count)
+    .map(|i| Item {
+      name: format!("item {}", i),
+    })
+    .collect()
+}
+
+fn filter_objects() -> usize {
+  std::env::var("BENCH_OBJECTS")
+    .ok()
+    .and_then(|count| count.parse().ok())
+    .unwrap_or(FILTER_OBJECTS)
+}
+
+fn create_items(
+  repo: &Repository,
+  storage: &Storage<Item, ItemAction>,
+  count: usize,
+) {
+  let mut commit = repo.commit_ctx("create");
+  storage.create_objects(items(count), &mut commit);
+  assert!(commit.commit().unwrap().is_ok());
+}
+
+// Read, patch and commit a single object on disk
+fn patch(c: &mut Criterion) {
+  let temp = TempRepo::init();
+  create_items(&temp.repo, &temp.items, PATCH_OBJECTS);
+  let ids = temp
+    .items
+    .get_all(&temp.repo.ctx())
+    .unwrap()
+    .iter()
+    .map(|so| so.id())
+    .collect::<Vec<_>>();
+  let mut group = c.benchmark_group("patch");
+  let mut i = 0;
+  group.bench_function("patch_object", |b| {
+    b.iter(|| {
+      i += 1;
+      let id = ids[i % ids.len()];
+      let so = temp.items.get_object_by_id(&temp.repo.ctx(), id).unwrap();
+      let mut commit = temp.repo.commit_ctx("patch");
+      so.patch(ItemAction::SetName(format!("patched {}", i)), &mut commit)
+        .unwrap();
+      assert!(commit.commit().unwrap().is_ok());
+    })
+  });
+  group.finish();
+}
+
+// Full scan of an in memory storage
+fn get_by_filter(c: &mut Criterion) {
+  let repo =
+    Repository::init(Context::in_memory("bench".into()), Mode::Local).unwrap();
+  let storage = register_items(&repo).unwrap();
+  let count = filter_objects();
+  create_items(&repo, &storage, count);
+  let mut group = c.benchmark_group("query");
+  group.sample_size(10);
+  group.bench_function(format!("get_by_filter_{}", count), |b| {
+    b.iter(|| {
+      let res = storage
+        .get_by_filter(&repo.ctx(), |i| i.name.ends_with("42"))
+        .unwrap();
+      assert_eq!(res.len(), count / 100);
+    })
+  });
+  group.finish();
+}
+
+// Clone a client, applying every remote commit of the server
+fn pull(c: &mut Criterion) {
+  let server = TestServer::start(register_items).unwrap();
+  let alice = server.client("alice").unwrap();
+  for i in 0..PULL_COMMITS {
+    let mut commit = alice.repo.commit_ctx("create");
+    alice.storages.create_objects(items(10), &mut commit);
+    assert!(commit.commit().unwrap().is_ok(), "commit {}", i);
+  }
+  alice.repo.proceed_push().unwrap();
+  let mut group = c.benchmark_group("pull");
+  group.sample_size(10);
+  group.bench_function(format!("clone_{}_commits", PULL_COMMITS), |b| {
+    b.iter(|| server.client("bob").unwrap())
+  });
+  group.finish();
+}
+
+// Scan the commit log for rarely tagged commits
+fn commit_log(c: &mut Criterion) {
+  let temp = TempRepo::init();
+  for i in 0..LOG_COMMITS {
+    let meta = match i % 100 {
+      0 => CommitMeta::new().with_tag("release"),
+      _ => CommitMeta::new(),
+    };
+    let mut commit = temp.repo.commit_ctx_with_meta("create", meta);
+    temp.items.create_object(items(1).remove(0), &mut commit);
+    assert!(commit.commit().unwrap().is_ok());
+  }
+  let mut group = c.benchmark_group("commit_log");
+  group.sample_size(10);
+  group.bench_function(format!("scan_{}_commits", LOG_COMMITS), |b| {
+    b.iter(|| {
+      let res = temp
+        .repo
+        .commits(CommitFilter::new().with_tag("release"))
+        .unwrap();
+      assert_eq!(res.len(), LOG_COMMITS / 100);
+    })
+  });
+  group.finish();
+}
+
+// Encode and decode the action objects of a large commit
+// Throughput is the encoded size, smaller is better for the same time
+fn action_codec(c: &mut Criterion) {
+  let temp = TempRepo::init();
+  create_items(&temp.repo, &temp.items, CODEC_ACTIONS);
+  let commit = temp.repo.commits(CommitFilter::new()).unwrap().remove(0);
+  let actions = commit.serialized_actions();
+  let mut group = c.benchmark_group("action_codec");
+  group.sample_size(10);
+  for codec in [ActionCodec::Json, ActionCodec::Cbor] {
+    let encoded = codec.encode(actions).unwrap();
+    group.throughput(Throughput::Bytes(encoded.len() as u64));
+    group.bench_function(format!("encode_{}", codec.name()), |b| {
+      b.iter(|| codec.encode(actions).unwrap())
+    });
+    group.bench_function(format!("decode_{}", codec.name()), |b| {
+      b.iter(|| assert_eq!(codec.decode(&encoded).unwrap(), actions))
+    });
+  }
+  group.finish();
+}
+
+criterion_group!(
+  benches,
+  patch,
+  get_by_filter,
+  pull,
+  commit_log,
+  action_codec
+);
+criterion_main!(benches);
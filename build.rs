@@ -1,3 +1,4 @@
 fn main() {
   tonic_build::compile_protos("./sync_api.proto").unwrap();
+  tonic_build::compile_protos("./health.proto").unwrap();
 }
@@ -0,0 +1,214 @@
+//! Append-only audit log of administrative operations
+//! Operations changing the repository outside of regular commits, e.g.
+//! clean, snapshot and restore, compaction, purge and redaction, device
+//! revocation and forced resyncs, and requests rejected by the server
+//! for failed authentication, access policy or missing admin access,
+//! are appended to their own framed log file under the
+//! repository root, see Repository::audit_log.
+//! Failing to record never fails the audited operation.
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+  fs::{binary_continuous_append, binary_continuous_read, binary_init_empty},
+  prelude::path_helper,
+  sync::Context,
+};
+
+/// Audited operation
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AuditAction {
+  /// Local changes cleared by Repository::proceed_clean
+  Clean { discarded_commits: usize },
+  /// Repository snapshot written to path
+  Snapshot { path: String },
+  /// Repository restored from the snapshot at path
+  Restore { path: String },
+  /// Remote commits older than horizon compacted
+  Compact { horizon: DateTime<Utc> },
+  /// Removed objects purged by retention
+  Purge,
+  /// Remote actions redacted for reason
+  Redact {
+    action_ids: Vec<Uuid>,
+    reason: String,
+  },
+  /// Server request with missing or invalid credentials
+  AuthFailed,
+  /// Pushed commit rejected by the access policy
+  AccessDenied { commit_id: Uuid },
+  /// Later pushes of the device rejected, see device
+  RevokeDevice { device_id: String },
+  /// Device required to pull from scratch
+  ForceResync { device_id: String },
+  /// Admin request without admin access
+  AdminDenied { request: String },
+}
+
+impl AuditAction {
+  /// Name of the operation, e.g. to filter by
+  pub fn name(&self) -> &'static str {
+    match self {
+      AuditAction::Clean { .. } => "clean",
+      AuditAction::Snapshot { .. } => "snapshot",
+      AuditAction::Restore { .. } => "restore",
+      AuditAction::Compact { .. } => "compact",
+      AuditAction::Purge => "purge",
+      AuditAction::Redact { .. } => "redact",
+      AuditAction::AuthFailed => "auth_failed",
+      AuditAction::AccessDenied { .. } => "access_denied",
+      AuditAction::RevokeDevice { .. } => "revoke_device",
+      AuditAction::ForceResync { .. } => "force_resync",
+      AuditAction::AdminDenied { .. } => "admin_denied",
+    }
+  }
+}
+
+/// Audit log record
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEvent {
+  pub id: Uuid,
+  pub at: DateTime<Utc>,
+  // Uid of the repository for its own operations, the client
+  // (authenticated uid or address) for rejected requests
+  pub uid: String,
+  pub action: AuditAction,
+  // Error of a failed operation, or the reason of the rejection
+  pub error: Option<String>,
+}
+
+impl AuditEvent {
+  pub(crate) fn new(uid: &str, action: AuditAction) -> Self {
+    Self {
+      id: Uuid::new_v4(),
+      at: Utc::now(),
+      uid: uid.to_string(),
+      action,
+      error: None,
+    }
+  }
+  pub(crate) fn with_result<T>(mut self, res: &Result<T, String>) -> Self {
+    self.error = res.as_ref().err().cloned();
+    self
+  }
+  pub(crate) fn with_error(mut self, error: &str) -> Self {
+    self.error = Some(error.to_string());
+    self
+  }
+  pub fn is_failed(&self) -> bool {
+    self.error.is_some()
+  }
+}
+
+/// Audit log filter, see Repository::audit_log
+/// Every given criterion must match
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+  action: Option<String>,
+  uid: Option<String>,
+  from: Option<DateTime<Utc>>,
+  to: Option<DateTime<Utc>>,
+  failed_only: bool,
+}
+
+impl AuditFilter {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  /// Events of the operation, see AuditAction::name
+  pub fn with_action(mut self, name: &str) -> Self {
+    self.action = Some(name.to_string());
+    self
+  }
+  /// Events of the given user or client
+  pub fn with_uid(mut self, uid: &str) -> Self {
+    self.uid = Some(uid.to_string());
+    self
+  }
+  /// Events recorded in the [from, to) time range
+  pub fn with_time_range(
+    mut self,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+  ) -> Self {
+    self.from = from;
+    self.to = to;
+    self
+  }
+  /// Failed operations and rejected requests only
+  pub fn failed_only(mut self) -> Self {
+    self.failed_only = true;
+    self
+  }
+  pub(crate) fn matches(&self, event: &AuditEvent) -> bool {
+    self
+      .action
+      .as_ref()
+      .is_none_or(|name| event.action.name() == name)
+      && self.uid.as_ref().is_none_or(|uid| &event.uid == uid)
+      && self.from.is_none_or(|from| event.at >= from)
+      && self.to.is_none_or(|to| event.at < to)
+      && (!self.failed_only || event.is_failed())
+  }
+}
+
+/// Records audit events of the server auth interceptor
+pub(crate) type AuditSink = Arc<dyn Fn(AuditEvent) + Send + Sync>;
+
+// Append-only audit log file
+// Callers serialize appends, see Repository::record_audit_event
+pub(crate) struct AuditLog;
+
+impl AuditLog {
+  pub(crate) fn append(ctx: &Context, event: &AuditEvent) {
+    let path = path_helper::audit_log(ctx);
+    let res = match ctx.backend().exists(&path) {
+      true => Ok(()),
+      false => binary_init_empty(ctx, path.clone()),
+    }
+    .and_then(|_| binary_continuous_append(ctx, path, event));
+    if let Err(e) = res {
+      warn!(error = %e, "Audit event not recorded");
+    }
+  }
+  // Matching events in the order recorded
+  pub(crate) fn load(
+    ctx: &Context,
+    filter: &AuditFilter,
+  ) -> Result<Vec<AuditEvent>, String> {
+    let path = path_helper::audit_log(ctx);
+    if !ctx.backend().exists(&path) {
+      return Ok(vec![]);
+    }
+    Ok(
+      binary_continuous_read::<AuditEvent, AuditEvent>(ctx, path)?
+        .into_iter()
+        .filter(|event| filter.matches(event))
+        .collect(),
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_filter() {
+    let event = AuditEvent::new("admin", AuditAction::Purge);
+    assert!(AuditFilter::new().matches(&event));
+    assert!(AuditFilter::new().with_action("purge").matches(&event));
+    assert!(!AuditFilter::new().with_action("clean").matches(&event));
+    assert!(!AuditFilter::new().with_uid("other").matches(&event));
+    assert!(!AuditFilter::new().failed_only().matches(&event));
+    let failed = event.with_error("Denied");
+    assert!(AuditFilter::new().failed_only().matches(&failed));
+    assert!(!AuditFilter::new()
+      .with_time_range(None, Some(failed.at))
+      .matches(&failed));
+  }
+}
@@ -0,0 +1,218 @@
+use std::{
+  collections::{HashMap, HashSet},
+  sync::Arc,
+};
+
+use tonic::{metadata::MetadataValue, service::Interceptor, Request, Status};
+
+use crate::{
+  audit::{AuditAction, AuditEvent, AuditSink},
+  hub::REPO_METADATA,
+  limits::{client_key, Limiter},
+  sync::UniversalActionObject,
+};
+
+// Authorization metadata key
+const AUTHORIZATION: &str = "authorization";
+// Token scheme prefix
+pub(crate) const BEARER: &str = "Bearer ";
+
+/// Authentication provider trait
+/// Maps client credentials (token) to a uid
+pub trait AuthProvider: Send + Sync + 'static {
+  /// Returns the authenticated uid,
+  /// or error if the token is invalid
+  fn authenticate(&self, token: &str) -> Result<String, String>;
+}
+
+/// Commit metadata key of the pusher uid verified by the server
+/// Stamped on every commit merged by an authenticating server
+pub const VERIFIED_UID_META: &str = "verified_uid";
+
+/// Identity authenticated by an auth provider
+/// Commits of Repository::commit_ctx_as claim its uid
+#[derive(Debug, Clone, PartialEq)]
+pub struct Principal {
+  uid: String,
+}
+
+impl Principal {
+  /// Authenticate token with the given provider
+  pub fn authenticate(
+    provider: &dyn AuthProvider,
+    token: &str,
+  ) -> Result<Self, String> {
+    Ok(Self {
+      uid: provider.authenticate(token)?,
+    })
+  }
+  pub fn uid(&self) -> &str {
+    &self.uid
+  }
+}
+
+/// Prefix of errors rejecting a push by the access policy
+pub const POLICY_VIOLATION: &str = "Policy violation";
+
+/// Authorization policy of pushed commits
+/// Consulted by the server for every action object of a pushed commit,
+/// any error rejects the whole commit
+pub trait AccessPolicy: Send + Sync + 'static {
+  /// Returns error if uid may not apply the action object
+  /// uid is the authenticated uid, or the claimed commit uid
+  /// if the server has no auth provider
+  fn authorize(
+    &self,
+    uid: &str,
+    aob: &UniversalActionObject,
+  ) -> Result<(), String>;
+}
+
+/// Static token -> uid map provider
+#[derive(Default, Debug, Clone)]
+pub struct TokenAuth {
+  tokens: HashMap<String, String>,
+}
+
+impl TokenAuth {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  /// Register token for the given uid
+  pub fn with_token(mut self, token: &str, uid: &str) -> Self {
+    self.tokens.insert(token.to_string(), uid.to_string());
+    self
+  }
+}
+
+impl AuthProvider for TokenAuth {
+  fn authenticate(&self, token: &str) -> Result<String, String> {
+    self
+      .tokens
+      .get(token)
+      .cloned()
+      .ok_or("Unknown token".to_string())
+  }
+}
+
+// Authenticate authorization header value "Bearer <token>"
+pub(crate) fn authenticate_bearer(
+  provider: &dyn AuthProvider,
+  header: Option<&str>,
+) -> Result<String, String> {
+  let token = header
+    .and_then(|value| value.strip_prefix(BEARER))
+    .ok_or("Missing auth token".to_string())?;
+  provider.authenticate(token)
+}
+
+/// Authenticated identity
+/// inserted into request extensions by the server interceptor
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUid(pub String);
+
+/// Admin access of an authenticated admin uid, see
+/// ServeConfig::with_admin_uids
+/// inserted into request extensions by the server interceptor
+#[derive(Debug, Clone)]
+pub struct AdminAccess;
+
+/// Server side interceptor
+/// Without provider every request is accepted anonymously.
+/// With limiter every request takes from the rate limit of its client.
+/// With audit sink failed authentications are recorded, see audit
+/// Requests of admin uids get admin access.
+#[derive(Clone)]
+pub struct ServerAuth {
+  provider: Option<Arc<dyn AuthProvider>>,
+  limiter: Option<Arc<Limiter>>,
+  audit: Option<AuditSink>,
+  admin_uids: Arc<HashSet<String>>,
+}
+
+impl ServerAuth {
+  pub(crate) fn new(
+    provider: Option<Arc<dyn AuthProvider>>,
+    limiter: Option<Arc<Limiter>>,
+  ) -> Self {
+    Self {
+      provider,
+      limiter,
+      audit: None,
+      admin_uids: Arc::new(HashSet::new()),
+    }
+  }
+  pub(crate) fn with_audit(mut self, audit: AuditSink) -> Self {
+    self.audit = Some(audit);
+    self
+  }
+  pub(crate) fn with_admin_uids(mut self, admin_uids: HashSet<String>) -> Self {
+    self.admin_uids = Arc::new(admin_uids);
+    self
+  }
+}
+
+impl Interceptor for ServerAuth {
+  fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+    if let Some(provider) = &self.provider {
+      let header = request
+        .metadata()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+      let uid = match authenticate_bearer(provider.as_ref(), header) {
+        Ok(uid) => uid,
+        Err(e) => {
+          if let Some(audit) = &self.audit {
+            let event =
+              AuditEvent::new(&client_key(&request), AuditAction::AuthFailed);
+            audit(event.with_error(&e));
+          }
+          return Err(Status::unauthenticated(e));
+        }
+      };
+      if self.admin_uids.contains(&uid) {
+        request.extensions_mut().insert(AdminAccess);
+      }
+      request.extensions_mut().insert(AuthenticatedUid(uid));
+    }
+    if let Some(limiter) = &self.limiter {
+      limiter
+        .check_request(&client_key(&request))
+        .map_err(Status::from)?;
+      // Handlers check pushed commits against the limits
+      request.extensions_mut().insert(limiter.clone());
+    }
+    Ok(request)
+  }
+}
+
+/// Client side interceptor
+/// attaching the context auth token and remote repository name
+/// to every request
+#[derive(Clone)]
+pub(crate) struct ClientAuth {
+  token: Option<String>,
+  repo: Option<String>,
+}
+
+impl ClientAuth {
+  pub(crate) fn new(token: Option<String>, repo: Option<String>) -> Self {
+    Self { token, repo }
+  }
+}
+
+impl Interceptor for ClientAuth {
+  fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+    if let Some(token) = &self.token {
+      let value = MetadataValue::try_from(format!("{}{}", BEARER, token))
+        .map_err(|_| Status::invalid_argument("Invalid auth token"))?;
+      request.metadata_mut().insert(AUTHORIZATION, value);
+    }
+    if let Some(repo) = &self.repo {
+      let value = MetadataValue::try_from(repo.as_str())
+        .map_err(|_| Status::invalid_argument("Invalid repository name"))?;
+      request.metadata_mut().insert(REPO_METADATA, value);
+    }
+    Ok(request)
+  }
+}
@@ -0,0 +1,559 @@
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+/// Storage backend trait
+/// Every repository data is read and written via a Backend.
+/// Keys are the paths produced by path_helper, so backends can use
+/// them as file paths or as plain object keys.
+pub trait Backend: Send + Sync {
+  /// Read the whole value of key
+  fn get(&self, key: &Path) -> Result<Vec<u8>, String>;
+  /// Create or replace the value of key
+  fn put(&self, key: &Path, data: &[u8]) -> Result<(), String>;
+  /// Append data to the value of an existing key
+  fn append(&self, key: &Path, data: &[u8]) -> Result<(), String>;
+  /// List every key under the given prefix
+  fn scan(&self, prefix: &Path) -> Result<Vec<PathBuf>, String>;
+  /// Remove key
+  fn delete(&self, key: &Path) -> Result<(), String>;
+  /// Check whether key exists
+  fn exists(&self, key: &Path) -> bool;
+  /// Reader over the value of key
+  /// Backends able to stream values should override it
+  fn reader(&self, key: &Path) -> Result<Box<dyn Read + Send>, String> {
+    Ok(Box::new(Cursor::new(self.get(key)?)))
+  }
+  /// Size of the value of key in bytes
+  /// Backends able to tell it without reading the value should override it
+  fn size(&self, key: &Path) -> Result<u64, String> {
+    Ok(self.get(key)?.len() as u64)
+  }
+  /// Whether values outlive the process
+  /// Repositories of persistent backends are locked by a lock file
+  /// against other processes opening them
+  fn is_persistent(&self) -> bool {
+    true
+  }
+  /// Write buffered values, if the backend buffers any
+  fn flush(&self) -> Result<(), String> {
+    Ok(())
+  }
+}
+
+/// Default file system backend
+/// Keys are file paths under the repository root
+#[derive(Default, Debug, Clone, Copy)]
+pub struct FsBackend;
+
+impl Backend for FsBackend {
+  fn get(&self, key: &Path) -> Result<Vec<u8>, String> {
+    let mut file = OpenOptions::new()
+      .read(true)
+      .open(key)
+      .map_err(|_| format!("No binary file found: {:?}", key))?;
+    let mut contents = vec![];
+    file.read_to_end(&mut contents).map_err(|e| e.to_string())?;
+    Ok(contents)
+  }
+
+  fn put(&self, key: &Path, data: &[u8]) -> Result<(), String> {
+    // Get file parent folder
+    if let Some(parent) = key.parent() {
+      // Create parent dirs
+      std::fs::create_dir_all(parent)
+        .map_err(|_| format!("Error creating file parent folder: {:?}", key))?;
+    }
+    // Write into a temp file next to key, then rename it over key,
+    // so a crash mid-write never leaves a partially written value
+    let tmp = temp_path(key);
+    let res = write_synced(&tmp, data)
+      .and_then(|_| {
+        std::fs::rename(&tmp, key)
+          .map_err(|e| format!("Error renaming {:?} to {:?}: {}", tmp, key, e))
+      })
+      .and_then(|_| sync_parent(key));
+    if res.is_err() {
+      let _ = std::fs::remove_file(&tmp);
+    }
+    res
+  }
+
+  fn append(&self, key: &Path, data: &[u8]) -> Result<(), String> {
+    let mut file = OpenOptions::new()
+      .append(true)
+      .open(key)
+      .map_err(|_| format!("No continuous file found to append: {:?}", key))?;
+    file.write_all(data).map_err(|e| e.to_string())?;
+    file.sync_all().map_err(|e| e.to_string())?;
+    sync_parent(key)
+  }
+
+  fn scan(&self, prefix: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut res = vec![];
+    if !prefix.is_dir() {
+      return Ok(res);
+    }
+    let entries = std::fs::read_dir(prefix)
+      .map_err(|_| format!("Error reading folder: {:?}", prefix))?;
+    for entry in entries {
+      let path = entry.map_err(|e| e.to_string())?.path();
+      // Skip leftover temp files of interrupted writes
+      if is_temp_path(&path) {
+        continue;
+      }
+      match path.is_dir() {
+        true => res.extend(self.scan(&path)?),
+        false => res.push(path),
+      }
+    }
+    res.sort();
+    Ok(res)
+  }
+
+  fn delete(&self, key: &Path) -> Result<(), String> {
+    std::fs::remove_file(key)
+      .map_err(|_| format!("Error removing file with path: {:?}", key))
+  }
+
+  fn exists(&self, key: &Path) -> bool {
+    key.exists()
+  }
+
+  fn reader(&self, key: &Path) -> Result<Box<dyn Read + Send>, String> {
+    let file = std::fs::File::open(key)
+      .map_err(|_| format!("No binary file found: {:?}", key))?;
+    Ok(Box::new(std::io::BufReader::new(file)))
+  }
+
+  fn size(&self, key: &Path) -> Result<u64, String> {
+    std::fs::metadata(key)
+      .map(|metadata| metadata.len())
+      .map_err(|_| format!("No binary file found: {:?}", key))
+  }
+}
+
+/// In-memory backend
+/// Values are kept in a map and lost when the last clone is dropped.
+/// Clones share the same values, so a repository can be loaded again
+/// from a clone of the backend it was initiated with.
+#[derive(Default, Debug, Clone)]
+pub struct MemoryBackend {
+  values: Arc<Mutex<BTreeMap<PathBuf, Vec<u8>>>>,
+}
+
+impl MemoryBackend {
+  pub fn new() -> Self {
+    Self::default()
+  }
+}
+
+impl Backend for MemoryBackend {
+  fn get(&self, key: &Path) -> Result<Vec<u8>, String> {
+    self
+      .values
+      .lock()
+      .unwrap()
+      .get(key)
+      .cloned()
+      .ok_or_else(|| format!("No binary file found: {:?}", key))
+  }
+
+  fn put(&self, key: &Path, data: &[u8]) -> Result<(), String> {
+    self
+      .values
+      .lock()
+      .unwrap()
+      .insert(key.to_path_buf(), data.to_vec());
+    Ok(())
+  }
+
+  fn append(&self, key: &Path, data: &[u8]) -> Result<(), String> {
+    self
+      .values
+      .lock()
+      .unwrap()
+      .get_mut(key)
+      .ok_or_else(|| format!("No continuous file found to append: {:?}", key))?
+      .extend_from_slice(data);
+    Ok(())
+  }
+
+  fn scan(&self, prefix: &Path) -> Result<Vec<PathBuf>, String> {
+    // Keys are sorted, so the ones under prefix follow it
+    Ok(
+      self
+        .values
+        .lock()
+        .unwrap()
+        .range(prefix.to_path_buf()..)
+        .map(|(key, _)| key)
+        .take_while(|key| key.starts_with(prefix))
+        .filter(|key| *key != prefix)
+        .cloned()
+        .collect(),
+    )
+  }
+
+  fn delete(&self, key: &Path) -> Result<(), String> {
+    self
+      .values
+      .lock()
+      .unwrap()
+      .remove(key)
+      .map(|_| ())
+      .ok_or_else(|| format!("Error removing file with path: {:?}", key))
+  }
+
+  fn size(&self, key: &Path) -> Result<u64, String> {
+    self
+      .values
+      .lock()
+      .unwrap()
+      .get(key)
+      .map(|value| value.len() as u64)
+      .ok_or_else(|| format!("No binary file found: {:?}", key))
+  }
+
+  fn exists(&self, key: &Path) -> bool {
+    self.values.lock().unwrap().contains_key(key)
+  }
+
+  fn is_persistent(&self) -> bool {
+    false
+  }
+}
+
+/// Write-behind backend, see Context::with_write_behind
+/// Writes are buffered in memory and written to the inner backend in
+/// batches by flush, reads see the buffered values. Every batch is
+/// recorded in a journal first, so a batch interrupted by a crash is
+/// redone on the next open.
+pub struct WriteBehindBackend {
+  inner: Arc<dyn Backend>,
+  journal: PathBuf,
+  // Buffered writes by key, with their generation
+  pending: Mutex<BTreeMap<PathBuf, (u64, PendingWrite)>>,
+  generation: Mutex<u64>,
+  // Serializes flushes
+  flushing: Mutex<()>,
+}
+
+// Buffered write of a key
+#[derive(Clone)]
+enum PendingWrite {
+  Put(Vec<u8>),
+  // Data appended to the value in the inner backend
+  Append(Vec<u8>),
+  Delete,
+}
+
+// Journaled write of a flushed batch
+// Redoing it has the same result as doing it once
+#[derive(Serialize, Deserialize)]
+enum JournalWrite {
+  Put(Vec<u8>),
+  Append { offset: usize, data: Vec<u8> },
+  Delete,
+}
+
+impl WriteBehindBackend {
+  /// Buffer writes to inner, redoing the batch of the journal if any
+  pub fn open(
+    inner: Arc<dyn Backend>,
+    journal: PathBuf,
+  ) -> Result<Self, String> {
+    let res = Self {
+      inner,
+      journal,
+      pending: Mutex::new(BTreeMap::new()),
+      generation: Mutex::new(0),
+      flushing: Mutex::new(()),
+    };
+    if res.inner.exists(&res.journal) {
+      let batch: Vec<(PathBuf, JournalWrite)> =
+        bincode::deserialize(&res.inner.get(&res.journal)?)
+          .map_err(|e| format!("Invalid write journal: {}", e))?;
+      res.redo(&batch)?;
+    }
+    Ok(res)
+  }
+  // Buffer write of key
+  fn buffer(&self, key: &Path, write: PendingWrite) {
+    let mut generation = self.generation.lock().unwrap();
+    *generation += 1;
+    self
+      .pending
+      .lock()
+      .unwrap()
+      .insert(key.to_path_buf(), (*generation, write));
+  }
+  fn pending(&self, key: &Path) -> Option<PendingWrite> {
+    self
+      .pending
+      .lock()
+      .unwrap()
+      .get(key)
+      .map(|(_, w)| w.clone())
+  }
+  // Write the journaled batch to the inner backend, then drop the journal
+  fn redo(&self, batch: &[(PathBuf, JournalWrite)]) -> Result<(), String> {
+    for (key, write) in batch {
+      match write {
+        JournalWrite::Put(data) => self.inner.put(key, data)?,
+        JournalWrite::Append { offset, data } => {
+          let value = self.inner.get(key)?;
+          match value.len() {
+            len if len >= offset + data.len() => (),
+            len if len == *offset => self.inner.append(key, data)?,
+            // Partially appended before
+            _ => {
+              let mut value = value;
+              value.truncate(*offset);
+              value.extend_from_slice(data);
+              self.inner.put(key, &value)?;
+            }
+          }
+        }
+        JournalWrite::Delete if self.inner.exists(key) => {
+          self.inner.delete(key)?
+        }
+        JournalWrite::Delete => (),
+      }
+    }
+    self.inner.delete(&self.journal)
+  }
+}
+
+impl Backend for WriteBehindBackend {
+  fn get(&self, key: &Path) -> Result<Vec<u8>, String> {
+    match self.pending(key) {
+      Some(PendingWrite::Put(data)) => Ok(data),
+      Some(PendingWrite::Append(data)) => {
+        let mut value = self.inner.get(key)?;
+        value.extend(data);
+        Ok(value)
+      }
+      Some(PendingWrite::Delete) => {
+        Err(format!("No binary file found: {:?}", key))
+      }
+      None => self.inner.get(key),
+    }
+  }
+
+  fn put(&self, key: &Path, data: &[u8]) -> Result<(), String> {
+    self.buffer(key, PendingWrite::Put(data.to_vec()));
+    Ok(())
+  }
+
+  fn append(&self, key: &Path, data: &[u8]) -> Result<(), String> {
+    let write = match self.pending(key) {
+      Some(PendingWrite::Put(mut value)) => {
+        value.extend_from_slice(data);
+        PendingWrite::Put(value)
+      }
+      Some(PendingWrite::Append(mut appended)) => {
+        appended.extend_from_slice(data);
+        PendingWrite::Append(appended)
+      }
+      None if self.inner.exists(key) => PendingWrite::Append(data.to_vec()),
+      Some(PendingWrite::Delete) | None => {
+        return Err(format!("No continuous file found to append: {:?}", key))
+      }
+    };
+    self.buffer(key, write);
+    Ok(())
+  }
+
+  fn scan(&self, prefix: &Path) -> Result<Vec<PathBuf>, String> {
+    let mut res = self.inner.scan(prefix)?;
+    let pending = self.pending.lock().unwrap();
+    for (key, (_, write)) in pending.range(prefix.to_path_buf()..) {
+      if !key.starts_with(prefix) {
+        break;
+      }
+      match write {
+        _ if key == prefix => (),
+        PendingWrite::Delete => res.retain(|k| k != key),
+        _ => res.push(key.clone()),
+      }
+    }
+    res.sort();
+    res.dedup();
+    Ok(res)
+  }
+
+  fn delete(&self, key: &Path) -> Result<(), String> {
+    if !self.exists(key) {
+      return Err(format!("Error removing file with path: {:?}", key));
+    }
+    self.buffer(key, PendingWrite::Delete);
+    Ok(())
+  }
+
+  fn exists(&self, key: &Path) -> bool {
+    match self.pending(key) {
+      Some(PendingWrite::Delete) => false,
+      Some(_) => true,
+      None => self.inner.exists(key),
+    }
+  }
+
+  fn reader(&self, key: &Path) -> Result<Box<dyn Read + Send>, String> {
+    match self.pending(key) {
+      None => self.inner.reader(key),
+      Some(_) => Ok(Box::new(Cursor::new(self.get(key)?))),
+    }
+  }
+
+  fn is_persistent(&self) -> bool {
+    self.inner.is_persistent()
+  }
+
+  // Buffered values stay readable until written
+  // Values buffered meanwhile are kept for the next flush
+  fn flush(&self) -> Result<(), String> {
+    let _flushing = self.flushing.lock().unwrap();
+    let snapshot = self.pending.lock().unwrap().clone();
+    if snapshot.is_empty() {
+      return Ok(());
+    }
+    let mut batch = vec![];
+    for (key, (_, write)) in &snapshot {
+      let write = match write {
+        PendingWrite::Put(data) => JournalWrite::Put(data.clone()),
+        PendingWrite::Append(data) => JournalWrite::Append {
+          offset: self.inner.get(key)?.len(),
+          data: data.clone(),
+        },
+        PendingWrite::Delete => JournalWrite::Delete,
+      };
+      batch.push((key.clone(), write));
+    }
+    let journal = bincode::serialize(&batch).map_err(|e| e.to_string())?;
+    self.inner.put(&self.journal, &journal)?;
+    self.redo(&batch)?;
+    let mut pending = self.pending.lock().unwrap();
+    for (key, (generation, write)) in snapshot {
+      let (current, rest) = match pending.get_mut(&key) {
+        Some((current, rest)) => (*current, rest),
+        None => continue,
+      };
+      match (write, rest) {
+        _ if current == generation => {
+          pending.remove(&key);
+        }
+        // Appended again while flushing
+        (PendingWrite::Append(flushed), PendingWrite::Append(appended)) => {
+          appended.drain(..flushed.len());
+        }
+        _ => (),
+      }
+    }
+    Ok(())
+  }
+}
+
+// Temp file suffix used by atomic writes
+const TEMP_SUFFIX: &str = ".tmp";
+
+// Hidden temp file path in the same folder as key,
+// so the final rename never crosses file systems
+fn temp_path(key: &Path) -> PathBuf {
+  let file_name = key
+    .file_name()
+    .map(|name| name.to_string_lossy().to_string())
+    .unwrap_or_default();
+  key.with_file_name(format!(".{}{}", file_name, TEMP_SUFFIX))
+}
+
+fn is_temp_path(path: &Path) -> bool {
+  path
+    .file_name()
+    .map(|name| name.to_string_lossy())
+    .is_some_and(|name| name.starts_with('.') && name.ends_with(TEMP_SUFFIX))
+}
+
+// Create file with data and flush it to disk
+fn write_synced(path: &Path, data: &[u8]) -> Result<(), String> {
+  let mut file = std::fs::File::create(path)
+    .map_err(|_| format!("Error creating file with path: {:?}", path))?;
+  file.write_all(data).map_err(|e| e.to_string())?;
+  file.sync_all().map_err(|e| e.to_string())
+}
+
+// Flush parent folder entries to disk, making creates and renames durable
+#[cfg(unix)]
+fn sync_parent(key: &Path) -> Result<(), String> {
+  match key.parent() {
+    Some(parent) => std::fs::File::open(parent)
+      .and_then(|dir| dir.sync_all())
+      .map_err(|e| format!("Error syncing folder {:?}: {}", parent, e)),
+    None => Ok(()),
+  }
+}
+
+// Folders cannot be opened for syncing on non unix platforms
+#[cfg(not(unix))]
+fn sync_parent(_key: &Path) -> Result<(), String> {
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_memory_scan() {
+    let backend = MemoryBackend::new();
+    for key in ["/db/a/1", "/db/a/2/x", "/db/ab", "/db/b"] {
+      backend.put(Path::new(key), b"v").unwrap();
+    }
+    backend.append(Path::new("/db/a/1"), b"w").unwrap();
+    assert_eq!(backend.get(Path::new("/db/a/1")).unwrap(), b"vw");
+    assert!(backend.append(Path::new("/db/c"), b"w").is_err());
+    assert_eq!(
+      backend.scan(Path::new("/db/a")).unwrap(),
+      vec![PathBuf::from("/db/a/1"), PathBuf::from("/db/a/2/x")]
+    );
+    backend.delete(Path::new("/db/a/1")).unwrap();
+    assert!(!backend.clone().exists(Path::new("/db/a/1")));
+  }
+
+  #[test]
+  fn test_write_behind() {
+    let inner = MemoryBackend::new();
+    let journal = PathBuf::from("/db/journal");
+    let log = Path::new("/db/log");
+    inner.put(log, b"a").unwrap();
+    let backend =
+      WriteBehindBackend::open(Arc::new(inner.clone()), journal.clone())
+        .unwrap();
+    backend.append(log, b"b").unwrap();
+    backend.put(Path::new("/db/x"), b"x").unwrap();
+    backend.delete(Path::new("/db/x")).unwrap();
+    assert_eq!(backend.get(log).unwrap(), b"ab");
+    assert_eq!(inner.get(log).unwrap(), b"a");
+    assert_eq!(backend.scan(Path::new("/db")).unwrap(), vec![log]);
+    backend.flush().unwrap();
+    assert_eq!(inner.get(log).unwrap(), b"ab");
+    assert!(!inner.exists(Path::new("/db/x")) && !inner.exists(&journal));
+    // Batch interrupted after its first write is redone on open
+    let batch = vec![(
+      log.to_path_buf(),
+      JournalWrite::Append {
+        offset: 1,
+        data: b"bc".to_vec(),
+      },
+    )];
+    inner
+      .put(&journal, &bincode::serialize(&batch).unwrap())
+      .unwrap();
+    WriteBehindBackend::open(Arc::new(inner.clone()), journal).unwrap();
+    assert_eq!(inner.get(log).unwrap(), b"abc");
+  }
+}
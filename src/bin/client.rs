@@ -1,12 +1,14 @@
 use std::{ops::Deref, path::PathBuf};
 
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use storage::{
-  sync::{ActionExt, Context, ContextGuard, ObjectExt, Repository, Storage},
+  config::RepoConfig,
+  sync::{ActionExt, ContextGuard, ObjectExt, Repository, Storage},
   *,
 };
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 struct User {
   id: u32,
   name: String,
@@ -15,7 +17,7 @@ struct User {
 
 impl ObjectExt for User {}
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 enum UserAction {
   SetName(String),
   SetAge(i32),
@@ -108,7 +110,7 @@ impl AppData {
       },
       &mut ctx,
     );
-
+    ctx.commit()?.into_result()?;
     Ok(())
   }
 
@@ -120,28 +122,58 @@ impl AppData {
       |i| i.id == id,
       UserAction::SetAge(age),
     )?;
+    ctx.commit()?.into_result()?;
     Ok(())
   }
 }
 
+fn print_audit_log(repo: &Repository) -> Result<(), String> {
+  for event in repo.audit_log(audit::AuditFilter::new())? {
+    println!(
+      "{}",
+      serde_json::to_string(&event).map_err(|e| e.to_string())?
+    );
+  }
+  Ok(())
+}
+
+// Devices registered with the remote, admin request
+fn print_remote_devices(repo: &Repository) -> Result<(), String> {
+  for device in repo.remote_devices()? {
+    println!(
+      "{}",
+      serde_json::to_string(&device).map_err(|e| e.to_string())?
+    );
+  }
+  Ok(())
+}
+
 fn main() {
   pretty_env_logger::init();
 
-  // Init Demo Context
-  let ctx =
-    Context::init(PathBuf::from("./data/client"), "mezeipetister".into());
+  // Init Demo Config, overridable by STORAGE_* environment variables
+  let config =
+    RepoConfig::new(PathBuf::from("./data/client"), "mezeipetister".into())
+      .with_remote("http://localhost:50059")
+      .with_env_overrides()
+      .unwrap();
 
   // Init repo
-  // let repo: Repository = Repository::init(
-  //   ctx.clone(),
-  //   sync::Mode::Remote {
-  //     remote_url: "http://localhost:50059".to_string(),
-  //   },
-  // )
-  // .unwrap();
+  // let repo: Repository = Repository::init_with_config(&config).unwrap();
 
   // Load repo
-  let repo: Repository = Repository::load(ctx).unwrap();
+  let repo: Repository = Repository::load_with_config(&config).unwrap();
+
+  // `audit` prints the audit log as JSON lines instead
+  if std::env::args().nth(1).as_deref() == Some("audit") {
+    print_audit_log(&repo).unwrap();
+    return;
+  }
+  // `devices` prints the devices registered with the remote instead
+  if std::env::args().nth(1).as_deref() == Some("devices") {
+    print_remote_devices(&repo).unwrap();
+    return;
+  }
 
   // repo.proceed_pull().unwrap();
   // repo.proceed_push().unwrap();
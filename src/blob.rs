@@ -0,0 +1,276 @@
+//! Content addressed blob store
+//! Large binary values, e.g. images or PDF attachments, are stored
+//! next to the repository data instead of inside object payloads.
+//! Objects and actions refer to them by BlobRef, and blobs referred
+//! by pushed or pulled commits are transferred along with them.
+
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::{
+  prelude::path_helper,
+  server::sync_api::BlobChunk,
+  sync::{Commit, Context, Repository},
+  transport::Transport,
+};
+
+/// Field name of serialized blob references
+pub const BLOB_KEY: &str = "$blob";
+
+/// Size of blob chunks in transfers, below the default max message size
+pub const BLOB_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Reference to a stored blob, usable as a field of T or A
+/// Serialized as {"$blob": "<sha256 hex>", "size": <bytes>}
+#[derive(
+  Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq, Hash,
+)]
+pub struct BlobRef {
+  #[serde(rename = "$blob")]
+  id: String,
+  size: u64,
+}
+
+impl BlobRef {
+  /// Hex encoded sha256 digest of the blob content
+  pub fn id(&self) -> &str {
+    &self.id
+  }
+  /// Blob size in bytes
+  pub fn size(&self) -> u64 {
+    self.size
+  }
+}
+
+fn blob_id(data: &[u8]) -> String {
+  hex::encode(Sha256::digest(data))
+}
+
+// Blob ids are used as storage keys,
+// so anything but a sha256 hex digest is refused
+fn check_blob_id(id: &str) -> Result<(), String> {
+  let valid = id.len() == 64
+    && id
+      .bytes()
+      .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+  match valid {
+    true => Ok(()),
+    false => Err(format!("Invalid blob id {}", id)),
+  }
+}
+
+pub(crate) fn has_blob(ctx: &Context, id: &str) -> bool {
+  check_blob_id(id).is_ok()
+    && ctx.backend().exists(&path_helper::blob_path(ctx, id))
+}
+
+pub(crate) fn read_blob(ctx: &Context, id: &str) -> Result<Vec<u8>, String> {
+  check_blob_id(id)?;
+  let path = path_helper::blob_path(ctx, id);
+  if !ctx.backend().exists(&path) {
+    return Err(format!("Blob {} not found", id));
+  }
+  ctx.backend().get(&path)
+}
+
+// Store blob received from a peer
+// Its content must match its id
+pub(crate) fn store_blob(
+  ctx: &Context,
+  id: &str,
+  data: &[u8],
+) -> Result<(), String> {
+  check_blob_id(id)?;
+  if blob_id(data) != id {
+    return Err(format!("Blob {} content does not match its id", id));
+  }
+  let path = path_helper::blob_path(ctx, id);
+  match ctx.backend().exists(&path) {
+    true => Ok(()),
+    false => ctx.backend().put(&path, data),
+  }
+}
+
+/// Blobs referred by the given action object JSONs
+pub(crate) fn blob_refs(serialized_actions: &[String]) -> Vec<BlobRef> {
+  let mut refs = BTreeMap::new();
+  for aob in serialized_actions {
+    // Skip parsing action objects without any reference
+    if !aob.contains(BLOB_KEY) {
+      continue;
+    }
+    if let Ok(value) = serde_json::from_str::<Value>(aob) {
+      collect_refs(&value, &mut refs);
+    }
+  }
+  refs.into_values().collect()
+}
+
+/// Blobs referred by the given object
+pub(crate) fn object_blob_refs<T: Serialize>(object: &T) -> Vec<BlobRef> {
+  let mut refs = BTreeMap::new();
+  if let Ok(value) = serde_json::to_value(object) {
+    collect_refs(&value, &mut refs);
+  }
+  refs.into_values().collect()
+}
+
+fn collect_refs(value: &Value, refs: &mut BTreeMap<String, BlobRef>) {
+  match value {
+    Value::Object(map) if map.contains_key(BLOB_KEY) => {
+      if let Ok(blob) = serde_json::from_value::<BlobRef>(value.clone()) {
+        refs.insert(blob.id.clone(), blob);
+      }
+    }
+    Value::Object(map) => {
+      map.values().for_each(|value| collect_refs(value, refs))
+    }
+    Value::Array(values) => {
+      values.iter().for_each(|value| collect_refs(value, refs))
+    }
+    _ => (),
+  }
+}
+
+/// Split blob into transfer chunks
+/// Empty blobs are sent as a single empty chunk
+pub(crate) fn blob_chunks(id: &str, data: &[u8]) -> Vec<BlobChunk> {
+  let chunk = |data: &[u8]| BlobChunk {
+    blob_id: id.to_string(),
+    data: data.to_vec(),
+  };
+  match data.is_empty() {
+    true => vec![chunk(data)],
+    false => data.chunks(BLOB_CHUNK_SIZE).map(chunk).collect(),
+  }
+}
+
+/// Reassemble blobs from their chunks
+/// Chunks of a blob arrive in order, one blob after the other
+#[derive(Default)]
+pub(crate) struct BlobAssembler {
+  blobs: Vec<(String, Vec<u8>)>,
+}
+
+impl BlobAssembler {
+  pub(crate) fn push(&mut self, chunk: BlobChunk) {
+    match self.blobs.last_mut() {
+      Some((id, data)) if *id == chunk.blob_id => data.extend(chunk.data),
+      _ => self.blobs.push((chunk.blob_id, chunk.data)),
+    }
+  }
+  /// Blob ids and contents
+  pub(crate) fn finish(self) -> Vec<(String, Vec<u8>)> {
+    self.blobs
+  }
+}
+
+impl Repository {
+  /// Store blob content, returns its reference
+  /// Content addressed, so storing the same content again is a no-op
+  pub fn put_blob(&self, data: &[u8]) -> Result<BlobRef, String> {
+    let id = blob_id(data);
+    store_blob(&self.ctx(), &id, data)?;
+    Ok(BlobRef {
+      id,
+      size: data.len() as u64,
+    })
+  }
+  /// Content of the referred blob
+  /// Errors if it is not stored, e.g. not pulled yet
+  pub fn get_blob(&self, blob: &BlobRef) -> Result<Vec<u8>, String> {
+    let data = read_blob(&self.ctx(), &blob.id)?;
+    match data.len() as u64 == blob.size {
+      true => Ok(data),
+      false => Err(format!("Blob {} size mismatch", blob.id)),
+    }
+  }
+  /// Whether the referred blob is stored locally
+  pub fn has_blob(&self, blob: &BlobRef) -> bool {
+    has_blob(&self.ctx(), &blob.id)
+  }
+  // Ids of the given blobs not stored yet
+  pub(crate) fn missing_blobs(&self, blob_ids: Vec<String>) -> Vec<String> {
+    let ctx = self.ctx();
+    blob_ids
+      .into_iter()
+      .filter(|id| !has_blob(&ctx, id))
+      .collect()
+  }
+  // Upload blobs referred by commit the remote does not store yet
+  pub(crate) async fn upload_blobs(
+    &self,
+    transport: &mut Transport,
+    commit: &Commit,
+  ) -> Result<(), String> {
+    let blob_ids = commit.blobs().into_iter().map(|b| b.id).collect::<Vec<_>>();
+    if blob_ids.is_empty() {
+      return Ok(());
+    }
+    let missing = transport.missing_blobs(blob_ids).await?;
+    if missing.is_empty() {
+      return Ok(());
+    }
+    let mut chunks = vec![];
+    for id in &missing {
+      chunks.extend(blob_chunks(id, &read_blob(&self.ctx(), id)?));
+    }
+    debug!(blobs = missing.len(), "Uploading blobs");
+    transport.push_blobs(chunks).await
+  }
+  // Download blobs referred by commits not stored locally
+  pub(crate) async fn download_blobs(
+    &self,
+    transport: &mut Transport,
+    commits: &[Commit],
+  ) -> Result<(), String> {
+    let blob_ids = commits
+      .iter()
+      .flat_map(|commit| commit.blobs())
+      .map(|blob| blob.id)
+      .collect::<Vec<_>>();
+    let missing = self.missing_blobs(blob_ids);
+    if missing.is_empty() {
+      return Ok(());
+    }
+    debug!(blobs = missing.len(), "Downloading blobs");
+    let blobs = transport.pull_blobs(missing).await?;
+    let ctx = self.ctx();
+    for (id, data) in blobs {
+      store_blob(&ctx, &id, &data)?;
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::sync::Mode;
+
+  #[test]
+  fn test_blob_store() {
+    let repo =
+      Repository::init(Context::in_memory("test".into()), Mode::Local).unwrap();
+    let data = vec![7u8; BLOB_CHUNK_SIZE + 1];
+    let blob = repo.put_blob(&data).unwrap();
+    assert!(repo.has_blob(&blob));
+    assert_eq!(repo.get_blob(&blob).unwrap(), data);
+    // Found in nested action payloads
+    let aob = serde_json::json!({ "action": { "Patch": [blob.clone()] } });
+    assert_eq!(blob_refs(&[aob.to_string()]), vec![blob.clone()]);
+    // Reassembled from chunks
+    let mut assembler = BlobAssembler::default();
+    blob_chunks(blob.id(), &data)
+      .into_iter()
+      .for_each(|chunk| assembler.push(chunk));
+    assert_eq!(assembler.finish(), vec![(blob.id().to_string(), data)]);
+    // Content must match the id
+    assert!(store_blob(&repo.ctx(), blob.id(), b"other").is_err());
+    assert!(store_blob(&repo.ctx(), "../repo_details", b"").is_err());
+  }
+}
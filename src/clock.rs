@@ -0,0 +1,108 @@
+//! Hybrid logical clock of action objects
+//! Wall clocks of clients can be skewed, so concurrent actions are
+//! ordered by their clock instead of their dtime. A clock is never
+//! lower than any clock its repository created or received before,
+//! so an action is always ordered after the ones it was based on.
+
+use std::{fmt::Display, sync::Mutex};
+
+use chrono::{DateTime, Utc};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Hybrid logical timestamp
+/// Wall clock milliseconds, and a counter of the events within them
+#[derive(
+  Serialize,
+  Deserialize,
+  JsonSchema,
+  Default,
+  Debug,
+  Clone,
+  Copy,
+  PartialEq,
+  Eq,
+  PartialOrd,
+  Ord,
+  Hash,
+)]
+pub struct Hlc {
+  wall: i64,
+  counter: u32,
+}
+
+impl Hlc {
+  /// Wall clock part, the latest wall time seen when created
+  pub fn wall(&self) -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(self.wall).unwrap_or_default()
+  }
+  pub fn counter(&self) -> u32 {
+    self.counter
+  }
+}
+
+impl Display for Hlc {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{}.{}", self.wall, self.counter)
+  }
+}
+
+/// Clock of a repository, shared by every clone of its context
+#[derive(Default, Debug)]
+pub struct HybridClock {
+  latest: Mutex<Hlc>,
+}
+
+impl HybridClock {
+  /// Timestamp of a new local action
+  /// Greater than any timestamp created or observed before
+  pub fn tick(&self) -> Hlc {
+    let now = Utc::now().timestamp_millis();
+    let mut latest = self.latest.lock().unwrap();
+    *latest = match now > latest.wall {
+      true => Hlc {
+        wall: now,
+        counter: 0,
+      },
+      false => Hlc {
+        wall: latest.wall,
+        counter: latest.counter + 1,
+      },
+    };
+    *latest
+  }
+  /// Merge timestamp received from another repository
+  pub fn observe(&self, clock: Hlc) {
+    let mut latest = self.latest.lock().unwrap();
+    if clock > *latest {
+      *latest = clock;
+    }
+  }
+  /// Latest timestamp created or observed
+  pub fn latest(&self) -> Hlc {
+    *self.latest.lock().unwrap()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_hybrid_clock() {
+    let clock = HybridClock::default();
+    let first = clock.tick();
+    assert!(clock.tick() > first);
+    // Remote clock ahead of the wall clock, e.g. skewed by an hour
+    let ahead = Hlc {
+      wall: Utc::now().timestamp_millis() + 3_600_000,
+      counter: 7,
+    };
+    clock.observe(ahead);
+    let next = clock.tick();
+    assert_eq!((next.wall, next.counter), (ahead.wall, 8));
+    // Clocks behind are ignored
+    clock.observe(first);
+    assert!(clock.tick() > next);
+  }
+}
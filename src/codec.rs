@@ -0,0 +1,235 @@
+//! Pluggable serialization of action objects
+//! Action objects live as JSON strings, as their signatures and the
+//! remote signature of their commit cover the exact JSON. Codecs
+//! encode them in commit logs (see Context::with_action_codec) and on
+//! pull and watch streams (negotiated per request), decoding gives
+//! back the same strings.
+//!
+//! Cbor keeps the JSON tree with its key order, in binary form, and
+//! stores UUID strings in 16 bytes. Action objects not rendered back
+//! byte for byte, e.g. pushed by non-Rust clients with other number
+//! formatting, are stored as JSON text within the CBOR.
+//! MessagePack is not supported yet.
+
+use std::fmt;
+
+use serde::{
+  de::{self, MapAccess, SeqAccess, Visitor},
+  ser::{SerializeMap, SerializeSeq},
+  Deserialize, Deserializer, Serialize, Serializer,
+};
+use uuid::Uuid;
+
+/// Serialization of the action objects of commits
+#[derive(
+  Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq,
+)]
+pub enum ActionCodec {
+  /// JSON strings as they are
+  #[default]
+  Json,
+  /// Binary JSON tree, see module docs
+  Cbor,
+}
+
+impl ActionCodec {
+  /// Name of the codec in configs and on the wire
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::Json => "json",
+      Self::Cbor => "cbor",
+    }
+  }
+  /// Codec by name, json if empty
+  pub fn from_name(name: &str) -> Result<Self, String> {
+    match name {
+      "" | "json" => Ok(Self::Json),
+      "cbor" => Ok(Self::Cbor),
+      _ => Err(format!("Unknown action codec {}, use json or cbor", name)),
+    }
+  }
+  /// Encode serialized action objects
+  pub fn encode(&self, aob_strs: &[String]) -> Result<Vec<u8>, String> {
+    match self {
+      Self::Json => serde_json::to_vec(aob_strs).map_err(|e| e.to_string()),
+      Self::Cbor => {
+        let nodes =
+          aob_strs.iter().map(|s| Node::encode(s)).collect::<Vec<_>>();
+        let mut res = vec![];
+        ciborium::ser::into_writer(&nodes, &mut res)
+          .map_err(|e| format!("Error encoding action objects: {}", e))?;
+        Ok(res)
+      }
+    }
+  }
+  /// Decode serialized action objects encoded by the same codec
+  pub fn decode(&self, data: &[u8]) -> Result<Vec<String>, String> {
+    match self {
+      Self::Json => serde_json::from_slice(data)
+        .map_err(|e| format!("Error decoding action objects: {}", e)),
+      Self::Cbor => {
+        let nodes: Vec<Node> = ciborium::de::from_reader(data)
+          .map_err(|e| format!("Error decoding action objects: {}", e))?;
+        nodes.iter().map(Node::decode).collect()
+      }
+    }
+  }
+}
+
+// JSON tree keeping the order of object keys
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+  Null,
+  Bool(bool),
+  U64(u64),
+  I64(i64),
+  F64(f64),
+  Str(String),
+  // String of a hyphenated lowercase UUID
+  Uuid(Uuid),
+  Array(Vec<Node>),
+  Object(Vec<(String, Node)>),
+}
+
+impl Node {
+  // Tree of a serialized action object
+  // Kept as a JSON text string if not rendered back the same
+  fn encode(aob_str: &str) -> Node {
+    match serde_json::from_str::<Node>(aob_str) {
+      Ok(node @ Node::Object(_))
+        if node.render().ok().as_deref() == Some(aob_str) =>
+      {
+        node
+      }
+      _ => Node::Str(aob_str.to_string()),
+    }
+  }
+  fn decode(&self) -> Result<String, String> {
+    match self {
+      Node::Str(aob_str) => Ok(aob_str.clone()),
+      node => node.render(),
+    }
+  }
+  fn render(&self) -> Result<String, String> {
+    serde_json::to_string(self).map_err(|e| e.to_string())
+  }
+}
+
+impl Serialize for Node {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    match self {
+      Node::Null => serializer.serialize_unit(),
+      Node::Bool(b) => serializer.serialize_bool(*b),
+      Node::U64(n) => serializer.serialize_u64(*n),
+      Node::I64(n) => serializer.serialize_i64(*n),
+      Node::F64(n) => serializer.serialize_f64(*n),
+      Node::Str(s) => serializer.serialize_str(s),
+      Node::Uuid(id) => match serializer.is_human_readable() {
+        true => serializer.serialize_str(&id.hyphenated().to_string()),
+        false => serializer.serialize_bytes(id.as_bytes()),
+      },
+      Node::Array(items) => {
+        let mut seq = serializer.serialize_seq(Some(items.len()))?;
+        for item in items {
+          seq.serialize_element(item)?;
+        }
+        seq.end()
+      }
+      Node::Object(entries) => {
+        let mut map = serializer.serialize_map(Some(entries.len()))?;
+        for (key, value) in entries {
+          map.serialize_entry(key, value)?;
+        }
+        map.end()
+      }
+    }
+  }
+}
+
+impl<'de> Deserialize<'de> for Node {
+  fn deserialize<D: Deserializer<'de>>(
+    deserializer: D,
+  ) -> Result<Self, D::Error> {
+    deserializer.deserialize_any(NodeVisitor)
+  }
+}
+
+struct NodeVisitor;
+
+impl<'de> Visitor<'de> for NodeVisitor {
+  type Value = Node;
+  fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("JSON value")
+  }
+  fn visit_unit<E>(self) -> Result<Node, E> {
+    Ok(Node::Null)
+  }
+  fn visit_none<E>(self) -> Result<Node, E> {
+    Ok(Node::Null)
+  }
+  fn visit_bool<E>(self, b: bool) -> Result<Node, E> {
+    Ok(Node::Bool(b))
+  }
+  fn visit_u64<E>(self, n: u64) -> Result<Node, E> {
+    Ok(Node::U64(n))
+  }
+  fn visit_i64<E>(self, n: i64) -> Result<Node, E> {
+    Ok(Node::I64(n))
+  }
+  fn visit_f64<E>(self, n: f64) -> Result<Node, E> {
+    Ok(Node::F64(n))
+  }
+  fn visit_str<E>(self, s: &str) -> Result<Node, E> {
+    match Uuid::try_parse(s) {
+      Ok(id) if id.hyphenated().to_string() == s => Ok(Node::Uuid(id)),
+      _ => Ok(Node::Str(s.to_string())),
+    }
+  }
+  fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Node, E> {
+    Uuid::from_slice(bytes)
+      .map(Node::Uuid)
+      .map_err(|_| E::custom("bytes other than UUID"))
+  }
+  fn visit_seq<S: SeqAccess<'de>>(self, mut seq: S) -> Result<Node, S::Error> {
+    let mut items = vec![];
+    while let Some(item) = seq.next_element()? {
+      items.push(item);
+    }
+    Ok(Node::Array(items))
+  }
+  fn visit_map<M: MapAccess<'de>>(self, mut map: M) -> Result<Node, M::Error> {
+    let mut entries = vec![];
+    while let Some(entry) = map.next_entry()? {
+      entries.push(entry);
+    }
+    Ok(Node::Object(entries))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_cbor_round_trip() {
+    let aob_strs = vec![
+      format!(
+        "{{\"id\":\"{}\",\"b\":1,\"a\":[-2,1.5,null,true,\"x\"],\"c\":{{}}}}",
+        Uuid::new_v4()
+      ),
+      // Not rendered back the same, kept as text
+      "{\"a\": 1.50}".to_string(),
+      "\"str\"".to_string(),
+      format!(
+        "{{\"id\":\"{}\"}}",
+        Uuid::new_v4().to_string().to_uppercase()
+      ),
+    ];
+    let encoded = ActionCodec::Cbor.encode(&aob_strs).unwrap();
+    assert_eq!(ActionCodec::Cbor.decode(&encoded).unwrap(), aob_strs);
+    assert!(encoded.len() < aob_strs.iter().map(|s| s.len()).sum::<usize>());
+    let encoded = ActionCodec::Json.encode(&aob_strs).unwrap();
+    assert_eq!(ActionCodec::Json.decode(&encoded).unwrap(), aob_strs);
+    assert!(ActionCodec::from_name("msgpack").is_err());
+  }
+}
@@ -0,0 +1,540 @@
+//! Repository configuration
+//! Settings of the context and the repository, loadable from a TOML
+//! file and overridable by STORAGE_* environment variables, e.g.
+//!
+//!   db_root_path = "./data"
+//!   uid = "alice"
+//!   mode = "remote"
+//!   remote_url = "http://localhost:50059"
+//!   compression = "zstd"
+//!   compression_level = 3
+//!
+//!   [limits]
+//!   requests_per_sec = 50
+//!
+//! The config a repository is inited or loaded with is persisted next
+//! to its repo_details, so every process opening it, like the CLI,
+//! the server or an embedding app, agrees on the settings.
+//! TLS, auth, backends and id generators stay code only.
+
+use std::{
+  net::SocketAddr,
+  path::{Path, PathBuf},
+  time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  codec::ActionCodec,
+  limits::Limits,
+  prelude::path_helper,
+  server::{ServeConfig, DEFAULT_MAX_MESSAGE_SIZE},
+  sync::{
+    Compression, Context, Format, Mode, DEFAULT_PULL_BATCH_SIZE,
+    DEFAULT_READ_CONCURRENCY,
+  },
+};
+
+/// Prefix of the environment variables overriding config keys
+/// Key limits.commits_per_day is overridden by
+/// STORAGE_LIMITS_COMMITS_PER_DAY
+pub const ENV_PREFIX: &str = "STORAGE_";
+
+// Keys overridable by environment variables
+const KEYS: &[&str] = &[
+  "db_root_path",
+  "uid",
+  "mode",
+  "remote_url",
+  "server_addr",
+  "metrics_addr",
+  "format",
+  "compression",
+  "compression_level",
+  "max_message_size",
+  "read_concurrency",
+  "pull_batch_size",
+  "delta_sync",
+  "action_codec",
+  "flush_interval_ms",
+  "checkpoint_interval_ms",
+  "limits.requests_per_sec",
+  "limits.commits_per_day",
+  "limits.max_commit_size",
+  "limits.max_actions_per_commit",
+];
+
+/// Repository configuration
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepoConfig {
+  pub db_root_path: PathBuf,
+  pub uid: String,
+  // local, remote or server
+  pub mode: String,
+  // Remote mode only
+  pub remote_url: Option<String>,
+  // Server mode only
+  pub server_addr: Option<String>,
+  pub metrics_addr: Option<String>,
+  // Serialization format of a new repository, binary or json
+  pub format: String,
+  // none, zstd or lz4
+  pub compression: String,
+  // Zstd only, 3 if not set
+  pub compression_level: Option<i32>,
+  pub max_message_size: usize,
+  pub read_concurrency: usize,
+  pub pull_batch_size: usize,
+  // Pull large action objects delta encoded
+  pub delta_sync: bool,
+  // Action object codec of commit logs and pulls, json or cbor
+  pub action_codec: String,
+  // Write-behind flush interval, writes directly if not set
+  pub flush_interval_ms: Option<u64>,
+  // History checkpointing interval, not checkpointed if not set
+  pub checkpoint_interval_ms: Option<u64>,
+  // Server mode only
+  pub limits: LimitsConfig,
+}
+
+/// Server limits of a config, unlimited if not set
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+  pub requests_per_sec: Option<u32>,
+  pub commits_per_day: Option<u32>,
+  pub max_commit_size: Option<usize>,
+  pub max_actions_per_commit: Option<usize>,
+}
+
+impl Default for RepoConfig {
+  fn default() -> Self {
+    Self {
+      db_root_path: PathBuf::from("./data"),
+      uid: String::new(),
+      mode: "local".to_string(),
+      remote_url: None,
+      server_addr: None,
+      metrics_addr: None,
+      format: "binary".to_string(),
+      compression: "none".to_string(),
+      compression_level: None,
+      max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+      read_concurrency: DEFAULT_READ_CONCURRENCY,
+      pull_batch_size: DEFAULT_PULL_BATCH_SIZE,
+      delta_sync: false,
+      action_codec: "json".to_string(),
+      flush_interval_ms: None,
+      checkpoint_interval_ms: None,
+      limits: LimitsConfig::default(),
+    }
+  }
+}
+
+impl RepoConfig {
+  /// Local mode config with defaults
+  pub fn new(db_root_path: PathBuf, uid: String) -> Self {
+    Self {
+      db_root_path,
+      uid,
+      ..Self::default()
+    }
+  }
+  /// Remote mode, syncing with the given remote
+  pub fn with_remote(mut self, remote_url: &str) -> Self {
+    self.mode = "remote".to_string();
+    self.remote_url = Some(remote_url.to_string());
+    self
+  }
+  /// Server mode, listening on the given address
+  pub fn with_server(mut self, server_addr: &str) -> Self {
+    self.mode = "server".to_string();
+    self.server_addr = Some(server_addr.to_string());
+    self
+  }
+  /// Parse TOML config
+  /// Unset keys keep their defaults, unknown keys are errors
+  pub fn from_toml(s: &str) -> Result<Self, String> {
+    toml::from_str(s).map_err(|e| format!("Invalid repository config: {}", e))
+  }
+  /// TOML of the config
+  pub fn to_toml(&self) -> Result<String, String> {
+    toml::to_string(self)
+      .map_err(|e| format!("Error serializing repository config: {}", e))
+  }
+  /// Load TOML config file, apply environment overrides and validate
+  pub fn load(path: &Path) -> Result<Self, String> {
+    let s = std::fs::read_to_string(path).map_err(|e| {
+      format!("Error reading config file {}: {}", path.display(), e)
+    })?;
+    let config = Self::from_toml(&s)
+      .map_err(|e| format!("{} (in {})", e, path.display()))?
+      .with_env_overrides()?;
+    config.validate()?;
+    Ok(config)
+  }
+  /// Override keys by the set STORAGE_* environment variables
+  pub fn with_env_overrides(self) -> Result<Self, String> {
+    self.with_overrides(std::env::vars())
+  }
+  /// Override keys by the given variables, named like the
+  /// environment ones, other variables are ignored
+  pub fn with_overrides(
+    mut self,
+    vars: impl IntoIterator<Item = (String, String)>,
+  ) -> Result<Self, String> {
+    for (name, value) in vars {
+      let key = KEYS.iter().find(|key| env_name(key) == name);
+      if let Some(key) = key {
+        self
+          .set(key, &value)
+          .map_err(|e| format!("Invalid {}: {}", name, e))?;
+      }
+    }
+    Ok(self)
+  }
+  fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+    let value = value.trim();
+    match key {
+      "db_root_path" => self.db_root_path = PathBuf::from(value),
+      "uid" => self.uid = value.to_string(),
+      "mode" => self.mode = value.to_string(),
+      "remote_url" => self.remote_url = optional(value),
+      "server_addr" => self.server_addr = optional(value),
+      "metrics_addr" => self.metrics_addr = optional(value),
+      "format" => self.format = value.to_string(),
+      "compression" => self.compression = value.to_string(),
+      "compression_level" => self.compression_level = parse_opt(value)?,
+      "max_message_size" => self.max_message_size = parse(value)?,
+      "read_concurrency" => self.read_concurrency = parse(value)?,
+      "pull_batch_size" => self.pull_batch_size = parse(value)?,
+      "delta_sync" => self.delta_sync = parse(value)?,
+      "action_codec" => self.action_codec = value.to_string(),
+      "flush_interval_ms" => self.flush_interval_ms = parse_opt(value)?,
+      "checkpoint_interval_ms" => {
+        self.checkpoint_interval_ms = parse_opt(value)?
+      }
+      "limits.requests_per_sec" => {
+        self.limits.requests_per_sec = parse_opt(value)?
+      }
+      "limits.commits_per_day" => {
+        self.limits.commits_per_day = parse_opt(value)?
+      }
+      "limits.max_commit_size" => {
+        self.limits.max_commit_size = parse_opt(value)?
+      }
+      "limits.max_actions_per_commit" => {
+        self.limits.max_actions_per_commit = parse_opt(value)?
+      }
+      _ => return Err(format!("Unknown config key {}", key)),
+    }
+    Ok(())
+  }
+  /// Check the config, listing every problem found
+  pub fn validate(&self) -> Result<(), String> {
+    let mut errors = vec![];
+    if self.db_root_path.as_os_str().is_empty() {
+      errors.push("db_root_path must not be empty".to_string());
+    }
+    if self.uid.trim().is_empty() {
+      errors.push("uid must not be empty".to_string());
+    }
+    match self.mode.as_str() {
+      "local" => (),
+      "remote" => match &self.remote_url {
+        Some(url) if url.starts_with("http://") => (),
+        Some(url) if url.starts_with("https://") => (),
+        Some(url) => errors.push(format!(
+          "remote_url {} must start with http:// or https://",
+          url
+        )),
+        None => errors.push("remote mode requires remote_url".to_string()),
+      },
+      "server" => {
+        match &self.server_addr {
+          Some(addr) => {
+            if let Err(e) = check_addr(addr) {
+              errors.push(format!("server_addr {}", e));
+            }
+          }
+          None => errors.push("server mode requires server_addr".to_string()),
+        }
+        if let Some(Err(e)) = self.metrics_addr.as_deref().map(check_addr) {
+          errors.push(format!("metrics_addr {}", e));
+        }
+      }
+      mode => errors.push(format!(
+        "unknown mode {}, expected local, remote or server",
+        mode
+      )),
+    }
+    if self.mode != "remote" && self.remote_url.is_some() {
+      errors.push("remote_url is set, but mode is not remote".to_string());
+    }
+    if self.mode != "server" {
+      if self.server_addr.is_some() || self.metrics_addr.is_some() {
+        errors.push(
+          "server_addr or metrics_addr is set, but mode is not server"
+            .to_string(),
+        );
+      }
+      if self.limits != LimitsConfig::default() {
+        errors.push("limits are set, but mode is not server".to_string());
+      }
+    }
+    if let Err(e) = self.parse_format() {
+      errors.push(e);
+    }
+    if let Err(e) = self.parse_compression() {
+      errors.push(e);
+    }
+    if let Err(e) = self.parse_action_codec() {
+      errors.push(e);
+    }
+    if self.max_message_size < 1024 {
+      errors.push("max_message_size must be at least 1024 bytes".to_string());
+    }
+    if self.read_concurrency == 0 {
+      errors.push("read_concurrency must be at least 1".to_string());
+    }
+    if self.pull_batch_size == 0 {
+      errors.push("pull_batch_size must be at least 1".to_string());
+    }
+    if self.flush_interval_ms == Some(0) {
+      errors.push("flush_interval_ms must be at least 1".to_string());
+    }
+    if self.checkpoint_interval_ms == Some(0) {
+      errors.push("checkpoint_interval_ms must be at least 1".to_string());
+    }
+    let limits = &self.limits;
+    if limits.requests_per_sec == Some(0)
+      || limits.commits_per_day == Some(0)
+      || limits.max_commit_size == Some(0)
+      || limits.max_actions_per_commit == Some(0)
+    {
+      errors.push("limits must be at least 1, unset for unlimited".into());
+    }
+    match errors.is_empty() {
+      true => Ok(()),
+      false => Err(format!("Invalid repository config: {}", errors.join("; "))),
+    }
+  }
+  fn parse_format(&self) -> Result<Format, String> {
+    match self.format.as_str() {
+      "binary" => Ok(Format::Binary),
+      "json" => Ok(Format::Json),
+      format => Err(format!(
+        "unknown format {}, expected binary or json",
+        format
+      )),
+    }
+  }
+  fn parse_action_codec(&self) -> Result<ActionCodec, String> {
+    match self.action_codec.as_str() {
+      "json" => Ok(ActionCodec::Json),
+      "cbor" => Ok(ActionCodec::Cbor),
+      codec => Err(format!(
+        "unknown action_codec {}, expected json or cbor",
+        codec
+      )),
+    }
+  }
+  fn parse_compression(&self) -> Result<Compression, String> {
+    let compression = match self.compression.as_str() {
+      "none" => Compression::None,
+      "lz4" => Compression::Lz4,
+      "zstd" => {
+        let level = self.compression_level.unwrap_or(3);
+        let range = zstd::compression_level_range();
+        if !range.contains(&level) {
+          return Err(format!(
+            "compression_level must be between {} and {}",
+            range.start(),
+            range.end()
+          ));
+        }
+        Compression::Zstd(level)
+      }
+      compression => {
+        return Err(format!(
+          "unknown compression {}, expected none, zstd or lz4",
+          compression
+        ))
+      }
+    };
+    if self.compression_level.is_some() && self.compression != "zstd" {
+      return Err(
+        "compression_level is set, but compression is not zstd".into(),
+      );
+    }
+    Ok(compression)
+  }
+  /// Mode of a new repository
+  pub fn mode(&self) -> Result<Mode, String> {
+    self.validate()?;
+    let mode = match self.mode.as_str() {
+      "remote" => Mode::remote(self.remote_url.clone().unwrap_or_default()),
+      "server" => {
+        let mode = Mode::server(self.server_addr.clone().unwrap_or_default());
+        match &self.metrics_addr {
+          Some(addr) => mode.with_metrics_addr(addr.to_string()),
+          None => mode,
+        }
+      }
+      _ => Mode::local(),
+    };
+    Ok(mode)
+  }
+  /// Context of the config
+  pub fn context(&self) -> Result<Context, String> {
+    self.validate()?;
+    let ctx = Context::init(self.db_root_path.clone(), self.uid.clone())
+      .with_format(self.parse_format()?)
+      .with_compression(self.parse_compression()?)
+      .with_max_message_size(self.max_message_size)
+      .with_read_concurrency(self.read_concurrency)
+      .with_pull_batch_size(self.pull_batch_size)
+      .with_delta_sync(self.delta_sync)
+      .with_action_codec(self.parse_action_codec()?);
+    let ctx = match self.checkpoint_interval_ms {
+      Some(ms) => ctx.with_checkpoint_interval(Duration::from_millis(ms)),
+      None => ctx,
+    };
+    match self.flush_interval_ms {
+      Some(ms) => ctx.with_write_behind(Duration::from_millis(ms)),
+      None => Ok(ctx),
+    }
+  }
+  /// Server limits, None if unlimited
+  pub fn limits(&self) -> Option<Limits> {
+    let limits = &self.limits;
+    if *limits == LimitsConfig::default() {
+      return None;
+    }
+    let mut res = Limits::new();
+    if let Some(requests_per_sec) = limits.requests_per_sec {
+      res = res.with_requests_per_sec(requests_per_sec);
+    }
+    if let Some(commits_per_day) = limits.commits_per_day {
+      res = res.with_commits_per_day(commits_per_day);
+    }
+    if let Some(max_commit_size) = limits.max_commit_size {
+      res = res.with_max_commit_size(max_commit_size);
+    }
+    if let Some(max_actions) = limits.max_actions_per_commit {
+      res = res.with_max_actions_per_commit(max_actions);
+    }
+    Some(res)
+  }
+  /// Serve config of the config limits
+  pub fn serve_config(&self) -> ServeConfig {
+    match self.limits() {
+      Some(limits) => ServeConfig::default().with_limits(limits),
+      None => ServeConfig::default(),
+    }
+  }
+  /// Config persisted in the repository at the given path, if any
+  pub fn load_persisted(db_root_path: &Path) -> Result<Option<Self>, String> {
+    Self::read(&Context::init(db_root_path.to_path_buf(), String::new()))
+  }
+  // Config persisted in the repository of the context
+  pub(crate) fn read(ctx: &Context) -> Result<Option<Self>, String> {
+    let path = path_helper::repo_config(ctx);
+    if !ctx.backend().exists(&path) {
+      return Ok(None);
+    }
+    let bytes = ctx.backend().get(&path)?;
+    let s = String::from_utf8(bytes)
+      .map_err(|e| format!("Invalid persisted repository config: {}", e))?;
+    Self::from_toml(&s).map(Some)
+  }
+  // Persist config in the repository of the context
+  pub(crate) fn write(&self, ctx: &Context) -> Result<(), String> {
+    let path = path_helper::repo_config(ctx);
+    ctx.backend().put(&path, self.to_toml()?.as_bytes())
+  }
+}
+
+fn env_name(key: &str) -> String {
+  format!("{}{}", ENV_PREFIX, key.replace('.', "_").to_uppercase())
+}
+
+fn optional(value: &str) -> Option<String> {
+  match value.is_empty() {
+    true => None,
+    false => Some(value.to_string()),
+  }
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> Result<T, String>
+where
+  T::Err: std::fmt::Display,
+{
+  value
+    .parse()
+    .map_err(|e| format!("{} is not a valid value ({})", value, e))
+}
+
+// Empty value unsets the key
+fn parse_opt<T: std::str::FromStr>(value: &str) -> Result<Option<T>, String>
+where
+  T::Err: std::fmt::Display,
+{
+  match value.is_empty() {
+    true => Ok(None),
+    false => parse(value).map(Some),
+  }
+}
+
+fn check_addr(addr: &str) -> Result<(), String> {
+  addr.parse::<SocketAddr>().map(|_| ()).map_err(|_| {
+    format!("{} is not a valid socket address, e.g. [::1]:50059", addr)
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_repo_config() {
+    let config = RepoConfig::from_toml(
+      r#"
+      uid = "alice"
+      mode = "server"
+      server_addr = "[::1]:50059"
+      compression = "zstd"
+
+      [limits]
+      commits_per_day = 100
+      "#,
+    )
+    .unwrap();
+    assert!(config.validate().is_ok());
+    assert_eq!(config.parse_compression().unwrap(), Compression::Zstd(3));
+    assert!(config.limits().is_some());
+    // Persisted TOML round trips
+    let toml = config.to_toml().unwrap();
+    assert_eq!(RepoConfig::from_toml(&toml).unwrap(), config);
+    // Overrides
+    let vars = vec![
+      ("STORAGE_READ_CONCURRENCY".to_string(), "8".to_string()),
+      ("STORAGE_LIMITS_COMMITS_PER_DAY".to_string(), "".to_string()),
+      ("OTHER".to_string(), "x".to_string()),
+    ];
+    let config = config.with_overrides(vars).unwrap();
+    assert_eq!(config.read_concurrency, 8);
+    assert!(config.limits().is_none());
+    let vars = vec![("STORAGE_READ_CONCURRENCY".into(), "many".into())];
+    assert!(config.with_overrides(vars).is_err());
+    // Every problem is reported
+    let config = RepoConfig::from_toml("mode = \"remote\"\nformat = \"xml\"");
+    let err = config.unwrap().validate().unwrap_err();
+    assert!(err.contains("uid must not be empty"));
+    assert!(err.contains("remote mode requires remote_url"));
+    assert!(err.contains("unknown format xml"));
+    assert!(RepoConfig::from_toml("cache = 1").is_err());
+  }
+}
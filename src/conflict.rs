@@ -0,0 +1,176 @@
+use uuid::Uuid;
+
+use crate::{
+  clock::Hlc,
+  sync::{ActionExt, ObjectExt},
+};
+
+/// Conflict kinds
+/// detected while re-applying local actions
+/// on top of a remote update
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConflictKind {
+  /// Local patch cannot be applied anymore
+  ApplyError(String),
+  /// Local patch can be applied, but it collides
+  /// with the remote one (see ActionExt::conflicts_with)
+  /// Additive patches never collide with additive remote ones
+  Collision,
+}
+
+/// Pending local action conflicting with a remote update
+#[derive(Debug, Clone)]
+pub struct Conflict<T, A>
+where
+  T: ObjectExt,
+  A: ActionExt<ObjectType = T>,
+{
+  // Storage Object ID
+  pub object_id: Uuid,
+  // Local action object ID
+  pub action_id: Uuid,
+  // Object state the local action should be applied on
+  pub object: T,
+  // Pending local action
+  pub local_action: A,
+  // Remote action caused the conflict
+  // None if remote restored an object snapshot
+  pub remote_action: Option<A>,
+  // Clocks of the local and the remote action
+  // None if created by an older version
+  pub local_clock: Option<Hlc>,
+  pub remote_clock: Option<Hlc>,
+  pub kind: ConflictKind,
+}
+
+/// Conflict resolution
+#[derive(Debug, Clone)]
+pub enum Resolution<A> {
+  /// Drop local action
+  TakeRemote,
+  /// Re-apply local action on top of the remote state
+  TakeLocal,
+  /// Replace local action with the given one
+  Replace(A),
+}
+
+/// How the server merges commits pushed concurrently,
+/// i.e. not on top of the latest remote commit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MergeMode {
+  /// Concurrent commits are rejected,
+  /// clients pull and rebase them before pushing again
+  #[default]
+  Rebase,
+  /// Concurrent commits are interleaved after the remote ones, if
+  /// their actions commute with the concurrent ones on the same
+  /// objects (see ActionExt::commutes_with), or all of them are
+  /// additive (see ActionExt::is_additive). Replicas converge
+  /// without conflicts. Commits with other storage actions, or
+  /// removing or restoring objects, are rejected as with Rebase.
+  Crdt,
+}
+
+/// Conflict resolver trait
+/// Storage consults its resolver for every conflicting local action
+/// during remote updates
+pub trait ConflictResolver<T, A>: Send + Sync
+where
+  T: ObjectExt,
+  A: ActionExt<ObjectType = T>,
+{
+  fn resolve(&self, conflict: &Conflict<T, A>)
+    -> Result<Resolution<A>, String>;
+}
+
+/// Remote always wins
+/// Conflicting local actions are dropped
+pub struct TakeRemote;
+
+impl<T, A> ConflictResolver<T, A> for TakeRemote
+where
+  T: ObjectExt,
+  A: ActionExt<ObjectType = T>,
+{
+  fn resolve(&self, _: &Conflict<T, A>) -> Result<Resolution<A>, String> {
+    Ok(Resolution::TakeRemote)
+  }
+}
+
+/// Local always wins
+/// Conflicting local actions are re-applied,
+/// apply errors are returned back
+/// Default storage resolver
+pub struct TakeLocal;
+
+impl<T, A> ConflictResolver<T, A> for TakeLocal
+where
+  T: ObjectExt,
+  A: ActionExt<ObjectType = T>,
+{
+  fn resolve(&self, _: &Conflict<T, A>) -> Result<Resolution<A>, String> {
+    Ok(Resolution::TakeLocal)
+  }
+}
+
+/// Later action wins, by their hybrid logical clocks
+/// Wall clocks of clients can be skewed, so dtime is not compared.
+/// Actions without clock are older than any with one.
+pub struct LastWriterWins;
+
+impl<T, A> ConflictResolver<T, A> for LastWriterWins
+where
+  T: ObjectExt,
+  A: ActionExt<ObjectType = T>,
+{
+  fn resolve(
+    &self,
+    conflict: &Conflict<T, A>,
+  ) -> Result<Resolution<A>, String> {
+    match conflict.local_clock > conflict.remote_clock {
+      true => Ok(Resolution::TakeLocal),
+      false => Ok(Resolution::TakeRemote),
+    }
+  }
+}
+
+/// Merge local and remote actions into a new local action
+/// Merge fn gets the current object state, the local and the remote action
+/// (None if remote restored an object snapshot)
+pub struct Merge<F>(pub F);
+
+impl<T, A, F> ConflictResolver<T, A> for Merge<F>
+where
+  T: ObjectExt,
+  A: ActionExt<ObjectType = T>,
+  F: Fn(&T, &A, Option<&A>) -> Result<A, String> + Send + Sync,
+{
+  fn resolve(
+    &self,
+    conflict: &Conflict<T, A>,
+  ) -> Result<Resolution<A>, String> {
+    let merged = (self.0)(
+      &conflict.object,
+      &conflict.local_action,
+      conflict.remote_action.as_ref(),
+    )?;
+    Ok(Resolution::Replace(merged))
+  }
+}
+
+/// Ask the application (e.g. UI prompt) for every conflict
+pub struct Interactive<F>(pub F);
+
+impl<T, A, F> ConflictResolver<T, A> for Interactive<F>
+where
+  T: ObjectExt,
+  A: ActionExt<ObjectType = T>,
+  F: Fn(&Conflict<T, A>) -> Result<Resolution<A>, String> + Send + Sync,
+{
+  fn resolve(
+    &self,
+    conflict: &Conflict<T, A>,
+  ) -> Result<Resolution<A>, String> {
+    (self.0)(conflict)
+  }
+}
@@ -0,0 +1,272 @@
+//! Delta encoding of pulled action objects
+//! Clients asking for it on pull (see Context::with_delta_sync) get
+//! large action objects as a delta against an object state they
+//! already hold: the state after the parent action, or for creates
+//! the latest object sent in the same storage. The delta copies byte
+//! ranges of the base object JSON and inserts the rest, so decoding
+//! gives back the exact action object, and its signatures stay valid.
+//! Action objects referring to blobs are always sent whole, as their
+//! blobs are downloaded before applying them.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{blob, prelude::sha1_signature};
+
+// Action objects smaller than this are sent whole
+const DELTA_MIN_SIZE: usize = 1024;
+// Length of the base blocks matches are searched by
+const BLOCK_SIZE: usize = 32;
+// Version of the delta format
+const DELTA_VERSION: u32 = 1;
+
+// Object state JSON after the given remote action
+// by storage id, object id and action id, None if unknown
+pub(crate) type Baseline<'a> =
+  dyn Fn(&str, Uuid, Uuid) -> Result<Option<String>, String> + 'a;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+enum DeltaOp {
+  // Offset and length of a base range
+  Copy(usize, usize),
+  Insert(String),
+}
+
+// Delta encoded action object on the wire
+// Starts with its delta key, unlike any action object
+#[derive(Serialize, Deserialize)]
+struct DeltaActionObject {
+  delta: u32,
+  storage_id: String,
+  base_object_id: Uuid,
+  base_action_id: Uuid,
+  // Sha1 of the base JSON
+  base_hash: String,
+  ops: Vec<DeltaOp>,
+}
+
+// Fields of an action object the base is chosen by
+#[derive(Deserialize)]
+struct ActionObjectHeader {
+  id: Uuid,
+  storage_id: String,
+  object_id: Uuid,
+  parent_action_id: Option<Uuid>,
+}
+
+/// Encoder of the commits of a pull response, in the order sent
+#[derive(Default)]
+pub(crate) struct DeltaEncoder {
+  // Latest sent object and action id per storage
+  latest: HashMap<String, (Uuid, Uuid)>,
+}
+
+impl DeltaEncoder {
+  // Encode the action objects of the next commit
+  // Action objects without a smaller delta are kept whole
+  pub(crate) fn encode(
+    &mut self,
+    aob_strs: &[String],
+    baseline: &Baseline,
+  ) -> Vec<String> {
+    let headers: Vec<Option<ActionObjectHeader>> = aob_strs
+      .iter()
+      .map(|aob| serde_json::from_str(aob).ok())
+      .collect();
+    // Bases must be applied by the recipient before this commit
+    let ids: HashSet<Uuid> = headers.iter().flatten().map(|h| h.id).collect();
+    let res = aob_strs
+      .iter()
+      .zip(&headers)
+      .map(|(aob, header)| {
+        header
+          .as_ref()
+          .and_then(|header| self.encode_one(aob, header, &ids, baseline))
+          .unwrap_or_else(|| aob.clone())
+      })
+      .collect();
+    for header in headers.into_iter().flatten() {
+      self
+        .latest
+        .insert(header.storage_id, (header.object_id, header.id));
+    }
+    res
+  }
+  fn encode_one(
+    &self,
+    aob: &str,
+    header: &ActionObjectHeader,
+    ids: &HashSet<Uuid>,
+    baseline: &Baseline,
+  ) -> Option<String> {
+    if aob.len() < DELTA_MIN_SIZE
+      || !blob::blob_refs(&[aob.to_string()]).is_empty()
+    {
+      return None;
+    }
+    let (object_id, action_id) = match header.parent_action_id {
+      Some(parent_action_id) => (header.object_id, parent_action_id),
+      None => *self.latest.get(&header.storage_id)?,
+    };
+    if ids.contains(&action_id) {
+      return None;
+    }
+    let base = baseline(&header.storage_id, object_id, action_id).ok()??;
+    let delta = DeltaActionObject {
+      delta: DELTA_VERSION,
+      storage_id: header.storage_id.clone(),
+      base_object_id: object_id,
+      base_action_id: action_id,
+      base_hash: sha1_signature(&base).ok()?,
+      ops: diff(&base, aob),
+    };
+    let encoded = serde_json::to_string(&delta).ok()?;
+    (encoded.len() < aob.len()).then_some(encoded)
+  }
+}
+
+// Check if delta encoded
+pub(crate) fn is_delta(aob_str: &str) -> bool {
+  aob_str.starts_with("{\"delta\":")
+}
+
+// Decode the delta encoded action objects of a commit
+// Errors if a base is unknown or differs from the encoded one
+pub(crate) fn decode(
+  aob_strs: Vec<String>,
+  baseline: &Baseline,
+) -> Result<Vec<String>, String> {
+  aob_strs
+    .into_iter()
+    .map(|aob| {
+      if !is_delta(&aob) {
+        return Ok(aob);
+      }
+      let delta: DeltaActionObject = serde_json::from_str(&aob)
+        .map_err(|e| format!("Error deser delta action object: {}", e))?;
+      if delta.delta != DELTA_VERSION {
+        return Err(format!("Unsupported delta version {}", delta.delta));
+      }
+      let base = baseline(
+        &delta.storage_id,
+        delta.base_object_id,
+        delta.base_action_id,
+      )?
+      .ok_or(format!(
+        "Unknown delta base action {}",
+        delta.base_action_id
+      ))?;
+      if sha1_signature(&base)? != delta.base_hash {
+        return Err(format!("Delta base mismatch {}", delta.base_action_id));
+      }
+      patch(&base, &delta.ops)
+    })
+    .collect()
+}
+
+// Ops building target from base
+// Ranges of base blocks found in target are copied, extended as far
+// as they match, the rest is inserted
+fn diff(base: &str, target: &str) -> Vec<DeltaOp> {
+  let (b, t) = (base.as_bytes(), target.as_bytes());
+  let mut blocks: HashMap<&[u8], usize> = HashMap::new();
+  for offset in (0..b.len().saturating_sub(BLOCK_SIZE - 1)).step_by(BLOCK_SIZE)
+  {
+    blocks
+      .entry(&b[offset..offset + BLOCK_SIZE])
+      .or_insert(offset);
+  }
+  let mut ops = vec![];
+  let (mut inserted, mut i) = (0, 0);
+  while i + BLOCK_SIZE <= t.len() {
+    let offset = match blocks.get(&t[i..i + BLOCK_SIZE]) {
+      Some(offset) => *offset,
+      None => {
+        i += 1;
+        continue;
+      }
+    };
+    let (mut start, mut base_start) = (i, offset);
+    while start > inserted
+      && base_start > 0
+      && t[start - 1] == b[base_start - 1]
+    {
+      start -= 1;
+      base_start -= 1;
+    }
+    let (mut end, mut base_end) = (i + BLOCK_SIZE, offset + BLOCK_SIZE);
+    while end < t.len() && base_end < b.len() && t[end] == b[base_end] {
+      end += 1;
+      base_end += 1;
+    }
+    // Ranges must not split characters
+    while start < end
+      && !(target.is_char_boundary(start) && base.is_char_boundary(base_start))
+    {
+      start += 1;
+      base_start += 1;
+    }
+    while end > start
+      && !(target.is_char_boundary(end) && base.is_char_boundary(base_end))
+    {
+      end -= 1;
+      base_end -= 1;
+    }
+    if end == start {
+      i += 1;
+      continue;
+    }
+    if start > inserted {
+      ops.push(DeltaOp::Insert(target[inserted..start].to_string()));
+    }
+    ops.push(DeltaOp::Copy(base_start, end - start));
+    (inserted, i) = (end, end);
+  }
+  if inserted < t.len() {
+    ops.push(DeltaOp::Insert(target[inserted..].to_string()));
+  }
+  ops
+}
+
+fn patch(base: &str, ops: &[DeltaOp]) -> Result<String, String> {
+  let mut res = String::new();
+  for op in ops {
+    match op {
+      DeltaOp::Copy(offset, len) => {
+        let range = offset
+          .checked_add(*len)
+          .and_then(|end| base.get(*offset..end))
+          .ok_or("Delta copy out of base range")?;
+        res.push_str(range);
+      }
+      DeltaOp::Insert(s) => res.push_str(s),
+    }
+  }
+  Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_diff_patch() {
+    let base = format!(
+      "{{\"name\":\"árvíztűrő\",\"notes\":\"{}\"}}",
+      "x".repeat(500)
+    );
+    let target = base
+      .replace("árvíztűrő", "tükörfúrógép")
+      .replace("xxxx}", "yy}");
+    let ops = diff(&base, &target);
+    assert!(ops.len() <= 5);
+    assert!(serde_json::to_string(&ops).unwrap().len() < 200);
+    assert_eq!(patch(&base, &ops).unwrap(), target);
+    // Nothing in common, or no base at all
+    assert_eq!(patch(&base, &diff(&base, "abc")).unwrap(), "abc");
+    assert_eq!(patch("", &diff("", &target)).unwrap(), target);
+    assert!(patch("ab", &[DeltaOp::Copy(1, 5)]).is_err());
+  }
+}
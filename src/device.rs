@@ -0,0 +1,132 @@
+//! Device identity and registration
+//! Every repository holds a stable random device id, created on first
+//! open and kept across sessions, see Repository::device_id. Local
+//! commits carry it in their DEVICE_ID_META metadata, so pushed data
+//! can be traced back to the terminal that produced it.
+//!
+//! Clients register their device once per session with the remote
+//! before pulling. Servers keep a record of each device, see
+//! Repository::devices, updated on its pulls and merged pushes.
+//!
+//! Server admins may revoke a device, rejecting its later pushes, or
+//! require it to download the remote history again on its next pull,
+//! locally or over the admin requests, see ServeConfig::with_admin_uids.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+  fs::{binary_read, binary_write},
+  prelude::path_helper,
+  sync::Context,
+};
+
+/// Commit metadata key of the device the commit was created on
+pub const DEVICE_ID_META: &str = "device_id";
+
+/// Registration record of a device, kept by the server
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeviceRecord {
+  pub device_id: String,
+  // Authenticated uid of the latest registration, empty if the
+  // server does not authenticate
+  pub uid: String,
+  // Reported by the client, see Context::with_app_version
+  pub app_version: Option<String>,
+  pub first_seen: DateTime<Utc>,
+  // Latest pull or merged push of the device
+  pub last_sync: Option<DateTime<Utc>>,
+  // Latest remote commit sent to the device, None before its first
+  // pull or if the remote history was empty
+  pub cursor: Option<Uuid>,
+  // Pushes of the device are rejected
+  pub revoked: bool,
+  // Next pull of the device must start from scratch
+  pub resync_required: bool,
+}
+
+// Device id of the repository, created if there is none yet
+pub(crate) fn load_or_init_device_id(ctx: &Context) -> Result<String, String> {
+  let path = path_helper::device_id(ctx);
+  if ctx.backend().exists(&path) {
+    return binary_read(ctx, path);
+  }
+  let device_id = Uuid::new_v4().to_string();
+  binary_write(ctx, path, &device_id)?;
+  Ok(device_id)
+}
+
+// Server side device records
+// Callers serialize updates, see Repository::register_device
+pub(crate) struct DeviceRegistry;
+
+impl DeviceRegistry {
+  // Records in the order of their first registration
+  pub(crate) fn load(ctx: &Context) -> Result<Vec<DeviceRecord>, String> {
+    let path = path_helper::devices(ctx);
+    match ctx.backend().exists(&path) {
+      true => binary_read(ctx, path),
+      false => Ok(vec![]),
+    }
+  }
+  // Insert or update the record of device
+  pub(crate) fn register(
+    ctx: &Context,
+    device_id: &str,
+    uid: &str,
+    app_version: Option<String>,
+  ) -> Result<DeviceRecord, String> {
+    let mut records = Self::load(ctx)?;
+    let now = Utc::now();
+    let index = match records.iter().position(|r| r.device_id == device_id) {
+      Some(index) => index,
+      None => {
+        records.push(DeviceRecord {
+          device_id: device_id.to_string(),
+          uid: String::new(),
+          app_version: None,
+          first_seen: now,
+          last_sync: None,
+          cursor: None,
+          revoked: false,
+          resync_required: false,
+        });
+        records.len() - 1
+      }
+    };
+    let record = &mut records[index];
+    record.uid = uid.to_string();
+    record.app_version = app_version;
+    let res = record.clone();
+    binary_write(ctx, path_helper::devices(ctx), &records)?;
+    Ok(res)
+  }
+  // Update the record of a registered device
+  // None if the device is unknown
+  pub(crate) fn update(
+    ctx: &Context,
+    device_id: &str,
+    f: impl FnOnce(&mut DeviceRecord),
+  ) -> Result<Option<DeviceRecord>, String> {
+    let mut records = Self::load(ctx)?;
+    let record = match records.iter_mut().find(|r| r.device_id == device_id) {
+      Some(record) => record,
+      None => return Ok(None),
+    };
+    f(record);
+    let res = record.clone();
+    binary_write(ctx, path_helper::devices(ctx), &records)?;
+    Ok(Some(res))
+  }
+  pub(crate) fn get(
+    ctx: &Context,
+    device_id: &str,
+  ) -> Result<Option<DeviceRecord>, String> {
+    Ok(
+      Self::load(ctx)?
+        .into_iter()
+        .find(|r| r.device_id == device_id),
+    )
+  }
+}
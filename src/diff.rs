@@ -0,0 +1,104 @@
+//! Diff of the remote and local state of an object
+//! Shows the unsynced local changes of an object before pushing,
+//! see StorageObject::diff
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+use uuid::Uuid;
+
+/// Changed value between the remote and the local object state
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FieldChange {
+  // Dot separated field path, e.g. address.city
+  // Empty if the object is not serialized as a JSON object
+  pub path: String,
+  // None if the field is added locally
+  pub remote: Option<Value>,
+  // None if the field is removed locally
+  pub local: Option<Value>,
+}
+
+/// Unsynced local changes of an object
+#[derive(Serialize, Debug, Clone)]
+pub struct ObjectDiff {
+  pub object_id: Uuid,
+  // None if the object is not pushed yet
+  pub remote: Option<Value>,
+  pub local: Value,
+  // Changed fields, empty if the states are equal
+  pub changes: Vec<FieldChange>,
+  // Pending local actions in order (ActionExt::display)
+  pub pending_actions: Vec<String>,
+}
+
+impl ObjectDiff {
+  /// Whether the local state differs from the remote one
+  pub fn has_changes(&self) -> bool {
+    !self.changes.is_empty()
+  }
+}
+
+/// Field level changes from remote to local
+/// Nested objects are compared field by field, other values,
+/// arrays included, as a whole. Fields of objects not pushed yet
+/// are all added ones.
+pub(crate) fn diff_values(
+  remote: Option<&Value>,
+  local: &Value,
+) -> Vec<FieldChange> {
+  let empty = Value::Object(Map::new());
+  let remote = remote.or(local.is_object().then_some(&empty));
+  let mut res = vec![];
+  diff_at("", remote, Some(local), &mut res);
+  res
+}
+
+fn diff_at(
+  path: &str,
+  remote: Option<&Value>,
+  local: Option<&Value>,
+  res: &mut Vec<FieldChange>,
+) {
+  match (remote, local) {
+    (Some(Value::Object(remote)), Some(Value::Object(local))) => {
+      let mut keys = remote.keys().chain(local.keys()).collect::<Vec<_>>();
+      keys.sort();
+      keys.dedup();
+      for key in keys {
+        let path = match path.is_empty() {
+          true => key.to_string(),
+          false => format!("{}.{}", path, key),
+        };
+        diff_at(&path, remote.get(key), local.get(key), res);
+      }
+    }
+    (remote, local) if remote != local => res.push(FieldChange {
+      path: path.to_string(),
+      remote: remote.cloned(),
+      local: local.cloned(),
+    }),
+    _ => (),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_diff_values() {
+    let remote =
+      json!({ "name": "a", "tags": [1], "address": { "city": "x" } });
+    let local = json!({ "name": "b", "tags": [1], "address": { "zip": 1 } });
+    let changes = diff_values(Some(&remote), &local);
+    let paths = changes.iter().map(|c| c.path.as_str()).collect::<Vec<_>>();
+    assert_eq!(paths, vec!["address.city", "address.zip", "name"]);
+    assert_eq!(changes[0].local, None);
+    assert_eq!(changes[1].remote, None);
+    // Not pushed yet, or not an object
+    assert_eq!(diff_values(None, &json!({ "name": "a" }))[0].path, "name");
+    assert_eq!(diff_values(None, &json!(1))[0].path, "");
+    assert!(diff_values(Some(&local), &local).is_empty());
+  }
+}
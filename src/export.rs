@@ -0,0 +1,179 @@
+//! CSV export of storage objects for tabular consumers
+//! Objects are flattened through their json representation:
+//! nested fields become dot separated columns, e.g. address.city,
+//! arrays are written as json text and nulls as empty cells.
+
+use std::{fmt::Debug, io::Write};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+  query::field_at,
+  sync::{ActionExt, Context, ObjectExt, Storage},
+};
+
+/// Column of the storage object id
+pub const OBJECT_ID_COLUMN: &str = "object_id";
+
+/// CSV export settings
+/// Without selected columns every field of the first exported object
+/// becomes a column after object_id, in alphabetical order
+#[derive(Debug, Clone)]
+pub struct CsvExport {
+  // Field paths with their headers
+  columns: Vec<(String, String)>,
+  delimiter: u8,
+}
+
+impl Default for CsvExport {
+  fn default() -> Self {
+    Self {
+      columns: vec![],
+      delimiter: b',',
+    }
+  }
+}
+
+impl CsvExport {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  /// Select the field at the dot separated path as the next column
+  /// object_id selects the storage object id
+  pub fn with_column(self, path: &str) -> Self {
+    self.with_renamed_column(path, path)
+  }
+  /// Select the field at path as the next column under header
+  pub fn with_renamed_column(mut self, path: &str, header: &str) -> Self {
+    self.columns.push((path.to_string(), header.to_string()));
+    self
+  }
+  /// Field delimiter, comma by default
+  pub fn with_delimiter(mut self, delimiter: u8) -> Self {
+    self.delimiter = delimiter;
+    self
+  }
+  /// Write the objects of storage as CSV, with a header row
+  /// Objects are streamed one at a time, in the same order as
+  /// Storage::iter. Returns the number of exported objects
+  pub fn write<T, A>(
+    &self,
+    storage: &Storage<T, A>,
+    ctx: &Context,
+    writer: impl Write,
+  ) -> Result<usize, String>
+  where
+    T: ObjectExt + Serialize + for<'de> Deserialize<'de> + JsonSchema + 'static,
+    A: ActionExt<ObjectType = T>
+      + Serialize
+      + for<'de> Deserialize<'de>
+      + JsonSchema
+      + 'static
+      + Debug,
+  {
+    let mut csv = csv::WriterBuilder::new()
+      .delimiter(self.delimiter)
+      .from_writer(writer);
+    let mut columns = self.columns.clone();
+    if !columns.is_empty() {
+      write_header(&mut csv, &columns)?;
+    }
+    let mut count = 0;
+    for object in storage.iter(ctx) {
+      let object = object?;
+      let mut row = Map::new();
+      row.insert(OBJECT_ID_COLUMN.into(), object.id().to_string().into());
+      let value = serde_json::to_value(&*object).map_err(|e| e.to_string())?;
+      flatten(&mut row, "", value);
+      let row = Value::Object(row);
+      if columns.is_empty() {
+        columns = default_columns(&row);
+        write_header(&mut csv, &columns)?;
+      }
+      csv
+        .write_record(columns.iter().map(|(path, _)| cell(&row, path)))
+        .map_err(|e| format!("Export error: {}", e))?;
+      count += 1;
+    }
+    csv.flush().map_err(|e| format!("Export error: {}", e))?;
+    Ok(count)
+  }
+}
+
+fn write_header(
+  csv: &mut csv::Writer<impl Write>,
+  columns: &[(String, String)],
+) -> Result<(), String> {
+  csv
+    .write_record(columns.iter().map(|(_, header)| header))
+    .map_err(|e| format!("Export error: {}", e))
+}
+
+// Flatten nested objects into dot separated keys
+fn flatten(row: &mut Map<String, Value>, prefix: &str, value: Value) {
+  match value {
+    Value::Object(map) => {
+      for (key, value) in map {
+        let key = match prefix {
+          "" => key,
+          _ => format!("{}.{}", prefix, key),
+        };
+        flatten(row, &key, value);
+      }
+    }
+    value => {
+      row.insert(prefix.to_string(), value);
+    }
+  }
+}
+
+// Every column of the flattened row, object_id first
+fn default_columns(row: &Value) -> Vec<(String, String)> {
+  let mut columns = vec![OBJECT_ID_COLUMN.to_string()];
+  if let Value::Object(map) = row {
+    columns.extend(map.keys().filter(|k| *k != OBJECT_ID_COLUMN).cloned());
+  }
+  columns.into_iter().map(|c| (c.clone(), c)).collect()
+}
+
+// Cell of the flattened row at path
+fn cell(row: &Value, path: &str) -> String {
+  let value = match row {
+    Value::Object(map) if map.contains_key(path) => &map[path],
+    // Array elements, or fields of a flattened parent
+    _ => field_at(row, path),
+  };
+  match value {
+    Value::Null => String::new(),
+    Value::String(s) => s.clone(),
+    value => value.to_string(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn test_flatten_cells() {
+    let mut row = Map::new();
+    flatten(
+      &mut row,
+      "",
+      json!({"name": "a", "address": {"city": "b"}, "tags": [1, 2], "x": null}),
+    );
+    let row = Value::Object(row);
+    assert_eq!(cell(&row, "name"), "a");
+    assert_eq!(cell(&row, "address.city"), "b");
+    assert_eq!(cell(&row, "tags"), "[1,2]");
+    assert_eq!(cell(&row, "tags.1"), "2");
+    assert_eq!(cell(&row, "x"), "");
+    assert_eq!(cell(&row, "missing"), "");
+    let headers: Vec<String> =
+      default_columns(&row).into_iter().map(|(_, h)| h).collect();
+    assert_eq!(headers, ["object_id", "address.city", "name", "tags", "x"]);
+  }
+}
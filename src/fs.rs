@@ -1,169 +1,676 @@
 use serde::{Deserialize, Serialize};
-use std::fs::OpenOptions;
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::{
+  io::Read,
+  marker::PhantomData,
+  path::{Path, PathBuf},
+  time::Instant,
+};
+use tracing::instrument;
 
-enum mode {
+use crate::sync::Context;
+
+/// Serialization format of stored objects and commit log records
+/// Chosen at repository init and recorded on disk
+#[derive(
+  Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq,
+)]
+pub enum Format {
+  // Human readable, mainly for debugging
   Json,
+  #[default]
   Binary,
 }
 
-// Debug only
-const FS_MODE: mode = mode::Binary;
+impl Format {
+  fn as_str(&self) -> &'static str {
+    match self {
+      Format::Json => "json",
+      Format::Binary => "binary",
+    }
+  }
+}
 
-fn deserialize<T: for<'de> Deserialize<'de>>(c: &Vec<u8>) -> Result<T, String> {
-  match FS_MODE {
-    mode::Json => serde_json::from_slice(c).map_err(|e| e.to_string()),
-    mode::Binary => bincode::deserialize(&c).map_err(|e| e.to_string()),
+fn deserialize<T: for<'de> Deserialize<'de>>(
+  format: Format,
+  c: &[u8],
+) -> Result<T, String> {
+  match format {
+    Format::Json => serde_json::from_slice(c).map_err(|e| e.to_string()),
+    Format::Binary => bincode::deserialize(c).map_err(|e| e.to_string()),
   }
 }
 
-fn serialize(data: impl Serialize) -> Result<Vec<u8>, String> {
-  match FS_MODE {
-    mode::Json => serde_json::to_vec(&data).map_err(|e| e.to_string()),
-    mode::Binary => bincode::serialize(&data).map_err(|e| e.to_string()),
+fn serialize(format: Format, data: impl Serialize) -> Result<Vec<u8>, String> {
+  match format {
+    Format::Json => serde_json::to_vec(&data).map_err(|e| e.to_string()),
+    Format::Binary => bincode::serialize(&data).map_err(|e| e.to_string()),
+  }
+}
+
+// Recorded repository format
+// Repositories without format file are binary
+pub fn format_read(ctx: &Context, path: PathBuf) -> Result<Format, String> {
+  if !ctx.backend().exists(&path) {
+    return Ok(Format::Binary);
+  }
+  let data = ctx.backend().get(&path)?;
+  match String::from_utf8_lossy(&data).trim() {
+    "json" => Ok(Format::Json),
+    "binary" => Ok(Format::Binary),
+    format => Err(format!("Unknown repository format: {}", format)),
   }
 }
 
-fn serialize_into(
-  file: impl std::io::Write,
-  append_data: impl Serialize,
+pub fn format_write(
+  ctx: &Context,
+  path: PathBuf,
+  format: Format,
 ) -> Result<(), String> {
-  match FS_MODE {
-    mode::Json => {
-      serde_json::to_writer(file, &append_data).map_err(|e| e.to_string())
-    }
-    mode::Binary => {
-      bincode::serialize_into(file, &append_data).map_err(|e| e.to_string())
+  ctx.backend().put(&path, format.as_str().as_bytes())
+}
+
+/// Compression of stored objects and commit log records
+/// Reading detects compression by a magic header, so data written
+/// with any setting (or uncompressed data of older versions) stays readable.
+#[derive(
+  Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq,
+)]
+pub enum Compression {
+  #[default]
+  None,
+  /// Zstd with compression level
+  Zstd(i32),
+  Lz4,
+}
+
+// Compressed data header, followed by the algorithm byte
+const COMPRESSION_MAGIC: [u8; 3] = [0xc5, 0x7a, 0x43];
+const ZSTD: u8 = 1;
+const LZ4: u8 = 2;
+
+fn compress(
+  compression: Compression,
+  data: Vec<u8>,
+) -> Result<Vec<u8>, String> {
+  let (algorithm, compressed) = match compression {
+    // Plain data without header, as written by older versions
+    Compression::None => return Ok(data),
+    Compression::Zstd(level) => (
+      ZSTD,
+      zstd::bulk::compress(&data, level).map_err(|e| e.to_string())?,
+    ),
+    Compression::Lz4 => (LZ4, lz4_flex::compress_prepend_size(&data)),
+  };
+  let mut res =
+    Vec::with_capacity(COMPRESSION_MAGIC.len() + 1 + compressed.len());
+  res.extend_from_slice(&COMPRESSION_MAGIC);
+  res.push(algorithm);
+  res.extend(compressed);
+  Ok(res)
+}
+
+fn decompress(data: Vec<u8>) -> Result<Vec<u8>, String> {
+  let header_len = COMPRESSION_MAGIC.len() + 1;
+  if data.len() < header_len || !data.starts_with(&COMPRESSION_MAGIC) {
+    return Ok(data);
+  }
+  let compressed = &data[header_len..];
+  match data[COMPRESSION_MAGIC.len()] {
+    ZSTD => zstd::stream::decode_all(compressed).map_err(|e| e.to_string()),
+    LZ4 => {
+      lz4_flex::decompress_size_prepended(compressed).map_err(|e| e.to_string())
     }
+    algorithm => Err(format!("Unknown compression algorithm: {}", algorithm)),
   }
 }
 
-fn deserialize_from<T: for<'de> Deserialize<'de>>(
-  f: impl std::io::Read,
+// Serialize and compress data as set in context
+pub(crate) fn encode(
+  ctx: &Context,
+  data: impl Serialize,
+) -> Result<Vec<u8>, String> {
+  compress(ctx.compression, serialize(ctx.format(), data)?)
+}
+
+fn decode<T: for<'de> Deserialize<'de>>(
+  ctx: &Context,
+  data: Vec<u8>,
 ) -> Result<T, String> {
-  match FS_MODE {
-    mode::Json => serde_json::from_reader(f).map_err(|e| e.to_string()),
-    mode::Binary => bincode::deserialize_from(f).map_err(|e| e.to_string()),
+  deserialize(ctx.format(), &decompress(data)?)
+}
+
+// Continuous log header, followed by the log version byte
+// Logs of older versions have none, their records are plain bincode
+// back to back, see binary_legacy_read
+const LOG_MAGIC: [u8; 3] = [0xc5, 0x7a, 0x4c];
+const LOG_VERSION: u8 = 1;
+const LOG_HEADER_LEN: usize = LOG_MAGIC.len() + 1;
+
+// Continuous log frame header
+// u32 LE payload length followed by u32 LE CRC32 of the payload
+const FRAME_HEADER_LEN: usize = 8;
+
+// Continuous log kind, by its first bytes
+#[derive(Debug, PartialEq)]
+enum LogHeader {
+  // Empty, or a header cut short by a crash
+  Empty,
+  Framed,
+  // Unframed log of older versions
+  Legacy,
+}
+
+fn log_header(start: &[u8]) -> Result<LogHeader, String> {
+  let magic_len = start.len().min(LOG_MAGIC.len());
+  if start[..magic_len] != LOG_MAGIC[..magic_len] {
+    return Ok(LogHeader::Legacy);
+  }
+  if start.len() < LOG_HEADER_LEN {
+    return Ok(LogHeader::Empty);
+  }
+  match start[LOG_MAGIC.len()] {
+    LOG_VERSION => Ok(LogHeader::Framed),
+    version => Err(format!("Unknown log version: {}", version)),
+  }
+}
+
+// Read the log header from the start of reader
+fn read_log_header(f: &mut impl Read) -> Result<LogHeader, String> {
+  let mut start = vec![];
+  f.take(LOG_HEADER_LEN as u64)
+    .read_to_end(&mut start)
+    .map_err(|e| e.to_string())?;
+  log_header(&start)
+}
+
+fn legacy_log_error(path: &Path) -> String {
+  format!(
+    "Log {:?} is in the unframed format of older versions. \
+     Open the repository to upgrade it.",
+    path
+  )
+}
+
+// Continuous log frame read result
+enum Frame {
+  Record(Vec<u8>),
+  // Clean end of log at a frame boundary
+  End,
+  // Truncated or corrupted frame
+  Bad(String),
+}
+
+fn frame(payload: Vec<u8>) -> Vec<u8> {
+  let mut res = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+  res.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+  res.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+  res.extend(payload);
+  res
+}
+
+fn read_frame(f: &mut impl Read) -> Frame {
+  let mut header = [0u8; FRAME_HEADER_LEN];
+  let mut header_len = 0;
+  while header_len < FRAME_HEADER_LEN {
+    match f.read(&mut header[header_len..]) {
+      Ok(0) => break,
+      Ok(n) => header_len += n,
+      Err(e) => return Frame::Bad(e.to_string()),
+    }
+  }
+  match header_len {
+    0 => return Frame::End,
+    FRAME_HEADER_LEN => (),
+    _ => return Frame::Bad("Truncated frame header".into()),
+  }
+  let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as u64;
+  let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+  // Read via take, so a corrupted length cannot allocate a huge buffer
+  let mut payload = vec![];
+  if let Err(e) = f.take(len).read_to_end(&mut payload) {
+    return Frame::Bad(e.to_string());
+  }
+  if payload.len() as u64 != len {
+    return Frame::Bad("Truncated frame payload".into());
+  }
+  if crc32fast::hash(&payload) != crc {
+    return Frame::Bad("Frame checksum mismatch".into());
+  }
+  Frame::Record(payload)
+}
+
+// Decode record stored as versioned V,
+// or as unversioned T written before versioning
+// Conversion errors are returned as decode errors
+fn decode_versioned<V, T>(ctx: &Context, data: Vec<u8>) -> Result<T, String>
+where
+  V: for<'de> Deserialize<'de> + TryInto<T>,
+  V::Error: std::fmt::Display,
+  T: for<'de> Deserialize<'de>,
+{
+  match decode::<V>(ctx, data.clone()) {
+    Ok(versioned) => versioned.try_into().map_err(|e| e.to_string()),
+    Err(e) => decode::<T>(ctx, data).map_err(|_| e),
+  }
+}
+
+/// Lazy iterator over continuous log records
+/// Records are stored as V, and read as T.
+/// Yields an error at the first bad frame, then ends
+pub struct ContinuousIter<V, T> {
+  ctx: Context,
+  path: PathBuf,
+  reader: Box<dyn Read + Send>,
+  index: usize,
+  done: bool,
+  _record: PhantomData<fn() -> (V, T)>,
+}
+
+impl<V, T> Iterator for ContinuousIter<V, T>
+where
+  V: for<'de> Deserialize<'de> + TryInto<T>,
+  V::Error: std::fmt::Display,
+  T: for<'de> Deserialize<'de>,
+{
+  type Item = Result<T, String>;
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.done {
+      return None;
+    }
+    let res = match read_frame(&mut self.reader) {
+      Frame::Record(payload) => decode_versioned::<V, T>(&self.ctx, payload),
+      Frame::End => {
+        self.done = true;
+        return None;
+      }
+      Frame::Bad(reason) => Err(format!(
+        "Corrupted log {:?} at record {}: {}. Recovery required.",
+        self.path, self.index, reason
+      )),
+    };
+    self.done = res.is_err();
+    self.index += 1;
+    Some(res)
   }
 }
 
+// Errors if the log is in the unframed format of older versions
+pub fn binary_continuous_iter<V, T>(
+  ctx: &Context,
+  path: PathBuf,
+) -> Result<ContinuousIter<V, T>, String> {
+  let mut reader = ctx.backend().reader(&path)?;
+  let done = match read_log_header(&mut reader)? {
+    LogHeader::Empty => true,
+    LogHeader::Framed => false,
+    LogHeader::Legacy => return Err(legacy_log_error(&path)),
+  };
+  Ok(ContinuousIter {
+    ctx: ctx.clone(),
+    reader,
+    path,
+    index: 0,
+    done,
+    _record: PhantomData,
+  })
+}
+
+// Whether the log is in the unframed format of older versions
+pub fn binary_continuous_is_legacy(
+  ctx: &Context,
+  path: &Path,
+) -> Result<bool, String> {
+  let mut reader = ctx.backend().reader(path)?;
+  Ok(read_log_header(&mut reader)? == LogHeader::Legacy)
+}
+
+// Records of a log in the unframed format of older versions
+// Records were plain bincode back to back, whatever the format and
+// compression of the context. Errors at the first undecodable record,
+// so a log is never upgraded with records lost.
+pub fn binary_legacy_read<L: for<'de> Deserialize<'de>>(
+  ctx: &Context,
+  path: PathBuf,
+) -> Result<Vec<L>, String> {
+  let data = ctx.backend().get(&path)?;
+  let mut reader = data.as_slice();
+  let mut res = vec![];
+  while !reader.is_empty() {
+    let record = bincode::deserialize_from(&mut reader).map_err(|e| {
+      format!("Bad legacy log {:?} at record {}: {}", path, res.len(), e)
+    })?;
+    res.push(record);
+  }
+  Ok(res)
+}
+
+// Read continuous log records one by one
+// Records are stored as V, and read as T
+// Errors at the first bad frame
+fn continuous_read_each<V, T>(
+  ctx: &Context,
+  path: &Path,
+  mut f: impl FnMut(T),
+) -> Result<(), String>
+where
+  V: for<'de> Deserialize<'de> + TryInto<T>,
+  V::Error: std::fmt::Display,
+  T: for<'de> Deserialize<'de>,
+{
+  for record in binary_continuous_iter::<V, T>(ctx, path.to_path_buf())? {
+    f(record?);
+  }
+  Ok(())
+}
+
+/// Continuous log recovery result
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LogRecovery {
+  /// Valid records kept
+  pub salvaged: usize,
+  /// Bytes truncated from the first bad frame on
+  pub truncated_bytes: usize,
+}
+
+#[instrument(level = "trace", skip_all, fields(path = ?path))]
 pub fn binary_read<T: for<'de> Deserialize<'de>>(
+  ctx: &Context,
   path: PathBuf,
 ) -> Result<T, String> {
-  // Try open staging
-  let mut file = OpenOptions::new()
-    .read(true)
-    .open(&path)
-    .map_err(|_| format!("No binary file found: {:?}", &path))?;
-  let mut contents = vec![];
-  file.read_to_end(&mut contents).map_err(|e| e.to_string())?;
-  deserialize(&contents)
+  let started = Instant::now();
+  let res = decode(ctx, ctx.backend().get(&path)?);
+  ctx.metrics().observe_fs_read(started.elapsed());
+  res
 }
 
-pub fn binary_continuous_read<T: for<'de> Deserialize<'de>>(
+// Records stored as versioned V are converted into T
+pub fn binary_continuous_read<V, T>(
+  ctx: &Context,
   path: PathBuf,
-) -> Result<Vec<T>, String> {
-  // Try open staging
+) -> Result<Vec<T>, String>
+where
+  V: for<'de> Deserialize<'de> + TryInto<T>,
+  V::Error: std::fmt::Display,
+  T: for<'de> Deserialize<'de>,
+{
   let mut res: Vec<T> = Vec::new();
-  let mut f = std::fs::File::open(&path)
-    .map_err(|_| format!("No binary file found: {:?}", path))?;
-  f.seek(SeekFrom::Current(0)).unwrap();
-  loop {
-    match deserialize_from(&f) {
-      Ok(r) => res.push(r),
-      Err(_) => {
-        break;
-      }
-    }
-  }
+  continuous_read_each::<V, T>(ctx, &path, |r| res.push(r))?;
   Ok(res)
 }
 
-pub fn binary_continuous_read_after_filter<
-  T: for<'de> Deserialize<'de> + Clone,
->(
+// Items after the first one matching filter
+// None if no item matched
+pub fn binary_continuous_read_after_filter<V, T>(
+  ctx: &Context,
   path: PathBuf,
   filter: impl Fn(&T) -> bool,
-) -> Result<Vec<T>, String> {
-  // Try open staging
+) -> Result<Option<Vec<T>>, String>
+where
+  V: for<'de> Deserialize<'de> + TryInto<T>,
+  V::Error: std::fmt::Display,
+  T: for<'de> Deserialize<'de>,
+{
   let mut res: Vec<T> = Vec::new();
-  let mut f = std::fs::File::open(&path)
-    .map_err(|_| format!("No binary file found: {:?}", path))?;
-  f.seek(SeekFrom::Current(0)).unwrap();
   let mut append = false;
-  loop {
-    match deserialize_from(&f) {
-      Ok(r) => match append {
-        true => res.push((r as T).to_owned()),
-        false => {
-          if filter(&r) {
-            append = true;
-          }
-        }
-      },
-      Err(_) => {
-        break;
+  continuous_read_each::<V, T>(ctx, &path, |r: T| match append {
+    true => res.push(r),
+    false => {
+      if filter(&r) {
+        append = true;
       }
     }
-  }
-  Ok(res)
+  })?;
+  Ok(append.then_some(res))
 }
 
+#[instrument(level = "trace", skip_all, fields(path = ?path))]
 pub fn binary_update<T: Serialize + core::fmt::Debug>(
+  ctx: &Context,
   path: PathBuf,
   data: T,
 ) -> Result<(), String> {
-  let mut file = OpenOptions::new()
-    .write(true)
-    .open(&path)
-    .map_err(|_| format!("No bin file found to update: {:?}", &path))?;
-  file
-    .write_all(&serialize(data)?)
-    .map_err(|e| e.to_string())?;
-  file.flush().map_err(|e| e.to_string())?;
+  binary_update_encoded(ctx, path, &encode(ctx, data)?)
+}
+
+// Update with data already encoded, see encode
+#[instrument(level = "trace", skip_all, fields(path = ?path))]
+pub fn binary_update_encoded(
+  ctx: &Context,
+  path: PathBuf,
+  data: &[u8],
+) -> Result<(), String> {
+  if !ctx.backend().exists(&path) {
+    return Err(format!("No bin file found to update: {:?}", &path));
+  }
+  let started = Instant::now();
+  ctx.backend().put(&path, data)?;
+  ctx.metrics().observe_fs_write(started.elapsed());
   Ok(())
 }
 
+#[instrument(level = "trace", skip_all, fields(path = ?path))]
 pub fn binary_continuous_append<T: Serialize>(
+  ctx: &Context,
   path: PathBuf,
   append_data: T,
 ) -> Result<(), String> {
-  let mut file = std::fs::OpenOptions::new()
-    .write(true)
-    .append(true)
-    .open(&path)
-    .map_err(|_| format!("No continuous file found to append: {:?}", &path))?;
-  serialize_into(&file, &append_data)?;
-  file.flush().map_err(|e| e.to_string())?;
+  let started = Instant::now();
+  let mut data = vec![];
+  // New or emptied logs start with the header
+  if ctx.backend().size(&path)? == 0 {
+    data.extend_from_slice(&LOG_MAGIC);
+    data.push(LOG_VERSION);
+  }
+  data.extend(frame(encode(ctx, &append_data)?));
+  ctx.backend().append(&path, &data)?;
+  ctx.metrics().observe_fs_write(started.elapsed());
   Ok(())
 }
 
+// Create or replace continuous file with the given items
+#[instrument(level = "trace", skip_all, fields(path = ?path))]
+pub fn binary_continuous_write<T: Serialize>(
+  ctx: &Context,
+  path: PathBuf,
+  items: &[T],
+) -> Result<(), String> {
+  let started = Instant::now();
+  let mut data = LOG_MAGIC.to_vec();
+  data.push(LOG_VERSION);
+  for item in items {
+    data.extend(frame(encode(ctx, item)?));
+  }
+  ctx.backend().put(&path, &data)?;
+  ctx.metrics().observe_fs_write(started.elapsed());
+  Ok(())
+}
+
+// Truncate continuous log at its first bad frame
+// Frames are only checked, records are not deserialized
+// Logs in the unframed format of older versions are never truncated
+#[instrument(level = "trace", skip_all, fields(path = ?path))]
+pub fn binary_continuous_recover(
+  ctx: &Context,
+  path: PathBuf,
+) -> Result<LogRecovery, String> {
+  let data = ctx.backend().get(&path)?;
+  let mut reader = data.as_slice();
+  let mut res = LogRecovery::default();
+  let mut valid_len = match read_log_header(&mut reader)? {
+    // Header cut short, nothing to salvage
+    LogHeader::Empty => 0,
+    LogHeader::Framed => LOG_HEADER_LEN,
+    LogHeader::Legacy => return Err(legacy_log_error(&path)),
+  };
+  while let Frame::Record(payload) = read_frame(&mut reader) {
+    res.salvaged += 1;
+    valid_len += FRAME_HEADER_LEN + payload.len();
+  }
+  res.truncated_bytes = data.len() - valid_len;
+  if res.truncated_bytes > 0 {
+    ctx.backend().put(&path, &data[..valid_len])?;
+  }
+  Ok(res)
+}
+
 pub fn binary_init<
   T: Serialize + for<'de> Deserialize<'de> + core::fmt::Debug,
 >(
+  ctx: &Context,
   path: PathBuf,
   init_data: T,
 ) -> Result<T, String> {
-  // Get file parent folder
-  let parent = path.parent().unwrap();
-  // Create parent dirs
-  std::fs::create_dir_all(parent)
-    .map_err(|_| format!("Error creating file parent folder: {:?}", &path))?;
-  std::fs::File::create(&path)
-    .map_err(|_| format!("Error creating file with path: {:?}", &path))?;
-  binary_update(path.clone(), init_data)?;
-  let res = binary_read(path)?;
+  ctx.backend().put(&path, &encode(ctx, init_data)?)?;
+  let res = binary_read(ctx, path)?;
   Ok(res)
 }
 
-pub fn binary_init_empty(path: PathBuf) -> Result<(), String> {
-  // Get file parent folder
-  let parent = path.parent().unwrap();
-  // Create parent dirs
-  std::fs::create_dir_all(parent)
-    .map_err(|_| format!("Error creating file parent folder: {:?}", &path))?;
-  std::fs::File::create(&path)
-    .map_err(|_| format!("Error creating file with path: {:?}", &path))?;
+// Create or replace the value of path
+#[instrument(level = "trace", skip_all, fields(path = ?path))]
+pub fn binary_write<T: Serialize>(
+  ctx: &Context,
+  path: PathBuf,
+  data: T,
+) -> Result<(), String> {
+  let started = Instant::now();
+  ctx.backend().put(&path, &encode(ctx, data)?)?;
+  ctx.metrics().observe_fs_write(started.elapsed());
   Ok(())
 }
+
+// Rewrite the value of path, if any, in the format of to
+pub fn binary_migrate<T: Serialize + for<'de> Deserialize<'de>>(
+  from: &Context,
+  to: &Context,
+  path: PathBuf,
+) -> Result<(), String> {
+  if !from.backend().exists(&path) {
+    return Ok(());
+  }
+  let data: T = binary_read(from, path.clone())?;
+  binary_write(to, path, data)
+}
+
+// Rewrite the records of path, if any, in the format of to
+pub fn binary_continuous_migrate<T: Serialize + for<'de> Deserialize<'de>>(
+  from: &Context,
+  to: &Context,
+  path: PathBuf,
+) -> Result<(), String> {
+  if !from.backend().exists(&path) {
+    return Ok(());
+  }
+  let items = binary_continuous_read::<T, T>(from, path.clone())?;
+  binary_continuous_write(to, path, &items)
+}
+
+pub fn binary_init_empty(ctx: &Context, path: PathBuf) -> Result<(), String> {
+  ctx.backend().put(&path, &[])
+}
+
+#[instrument(level = "trace", skip_all, fields(path = ?path))]
+pub fn binary_remove(ctx: &Context, path: PathBuf) -> Result<(), String> {
+  ctx.backend().delete(&path)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_continuous_recover() {
+    let path = std::env::temp_dir()
+      .join(format!("storage_test_log_{}", std::process::id()));
+    let ctx = Context::init(std::env::temp_dir(), "test".into());
+    binary_init_empty(&ctx, path.clone()).unwrap();
+    for i in 0..3u32 {
+      binary_continuous_append(&ctx, path.clone(), i).unwrap();
+    }
+    // Simulate a crash in the middle of the last append
+    let mut data = ctx.backend().get(&path).unwrap();
+    data.truncate(data.len() - 2);
+    ctx.backend().put(&path, &data).unwrap();
+    assert!(binary_continuous_read::<u32, u32>(&ctx, path.clone()).is_err());
+    let recovery = binary_continuous_recover(&ctx, path.clone()).unwrap();
+    assert_eq!(recovery.salvaged, 2);
+    assert_eq!(recovery.truncated_bytes, FRAME_HEADER_LEN + 2);
+    assert_eq!(
+      binary_continuous_read::<u32, u32>(&ctx, path.clone()).unwrap(),
+      vec![0, 1]
+    );
+    ctx.backend().delete(&path).unwrap();
+  }
+
+  #[derive(Serialize, Deserialize)]
+  enum Versioned {
+    V1(String),
+  }
+
+  impl From<Versioned> for String {
+    fn from(v: Versioned) -> Self {
+      match v {
+        Versioned::V1(s) => s,
+      }
+    }
+  }
+
+  #[test]
+  fn test_versioned_read_legacy() {
+    let path = std::env::temp_dir()
+      .join(format!("storage_test_versioned_{}", std::process::id()));
+    let ctx = Context::init(std::env::temp_dir(), "test".into());
+    binary_init_empty(&ctx, path.clone()).unwrap();
+    // Legacy unversioned record followed by a versioned one
+    binary_continuous_append(&ctx, path.clone(), "old".to_string()).unwrap();
+    binary_continuous_append(&ctx, path.clone(), Versioned::V1("new".into()))
+      .unwrap();
+    assert_eq!(
+      binary_continuous_read::<Versioned, String>(&ctx, path.clone()).unwrap(),
+      vec!["old".to_string(), "new".to_string()]
+    );
+    ctx.backend().delete(&path).unwrap();
+  }
+
+  #[test]
+  fn test_legacy_log() {
+    let path = std::env::temp_dir()
+      .join(format!("storage_test_legacy_{}", std::process::id()));
+    let ctx = Context::init(std::env::temp_dir(), "test".into());
+    // Unframed records, as written by older versions
+    let mut data = vec![];
+    for record in ["a", "b"] {
+      bincode::serialize_into(&mut data, &record.to_string()).unwrap();
+    }
+    ctx.backend().put(&path, &data).unwrap();
+    assert!(binary_continuous_is_legacy(&ctx, &path).unwrap());
+    assert!(binary_continuous_read::<String, String>(&ctx, path.clone())
+      .unwrap_err()
+      .contains("older versions"));
+    // Never truncated by recovery
+    assert!(binary_continuous_recover(&ctx, path.clone()).is_err());
+    assert_eq!(ctx.backend().get(&path).unwrap(), data);
+    let records = binary_legacy_read::<String>(&ctx, path.clone()).unwrap();
+    assert_eq!(records, vec!["a".to_string(), "b".to_string()]);
+    // Upgraded logs start with the header
+    binary_continuous_write(&ctx, path.clone(), &records).unwrap();
+    assert!(!binary_continuous_is_legacy(&ctx, &path).unwrap());
+    assert_eq!(
+      binary_continuous_read::<String, String>(&ctx, path.clone()).unwrap(),
+      records
+    );
+    ctx.backend().delete(&path).unwrap();
+  }
+
+  #[test]
+  fn test_compression_roundtrip() {
+    let data = vec!["object".to_string(); 64];
+    for compression in
+      [Compression::None, Compression::Zstd(3), Compression::Lz4]
+    {
+      let ctx = Context::init(std::env::temp_dir(), "test".into())
+        .with_compression(compression);
+      let compressed = encode(&ctx, &data).unwrap();
+      assert_eq!(decode::<Vec<String>>(&ctx, compressed).unwrap(), data);
+    }
+  }
+}
@@ -0,0 +1,539 @@
+//! HTTP/JSON gateway of the sync protocol
+//! For clients not speaking gRPC, e.g. browser dashboards
+//!
+//! Endpoints map onto the same repository api as the gRPC server:
+//! - GET /pull?after_commit_id=&storage_ids=a,b&limit=&device_id=
+//! - POST /push {"commit": {..}, "client_id": ".."}
+//! - GET /watch?after_commit_id=&storage_ids=a,b&client_id= (SSE)
+//! - GET /status
+//! - GET /public_key
+//! - GET /info
+//! - POST /query {"storage_id": "..", "query": {..}}
+//! - POST /register {"device_id": "..", "app_version": ".."}
+//!
+//! Requests must carry an "Authorization: Bearer <token>" header
+//! if the router has an auth provider.
+
+use std::{convert::Infallible, sync::Arc};
+
+use axum::{
+  extract::{Query, State},
+  http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+  response::{
+    sse::{Event, KeepAlive, Sse},
+    IntoResponse, Response,
+  },
+  routing::{get, post},
+  Json, Router,
+};
+use chrono::{DateTime, Utc};
+use futures_util::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tonic::{Code, Request, Status};
+use uuid::Uuid;
+
+use crate::{
+  auth::{authenticate_bearer, AuthProvider, AuthenticatedUid},
+  query::{self, QueryResult},
+  server::{
+    sync_api::{
+      api_server::Api, CommitObj, InfoRequest, InfoResponse, PublicKeyRequest,
+      PullRequest, QueryRequest, RegisterRequest, WatchRequest,
+    },
+    PROTOCOL_VERSION,
+  },
+  sync::{Repository, StorageStatus},
+};
+
+#[derive(Clone)]
+struct Gateway {
+  repo: Arc<Repository>,
+  auth_provider: Option<Arc<dyn AuthProvider>>,
+}
+
+/// Gateway router without authentication
+/// Serve it with axum, or merge it into an existing router
+pub fn router(repo: Repository) -> Router {
+  new_router(repo, None)
+}
+
+/// Gateway router authenticating every request by the given provider
+/// Pushed commits must belong to the authenticated uid
+pub fn router_with_auth(
+  repo: Repository,
+  auth_provider: impl AuthProvider,
+) -> Router {
+  new_router(repo, Some(Arc::new(auth_provider)))
+}
+
+fn new_router(
+  repo: Repository,
+  auth_provider: Option<Arc<dyn AuthProvider>>,
+) -> Router {
+  Router::new()
+    .route("/pull", get(pull))
+    .route("/push", post(push))
+    .route("/watch", get(watch))
+    .route("/status", get(status))
+    .route("/public_key", get(public_key))
+    .route("/info", get(info))
+    .route("/query", post(query))
+    .route("/register", post(register))
+    .with_state(Gateway {
+      repo: Arc::new(repo),
+      auth_provider,
+    })
+}
+
+// Gateway error as HTTP status with a JSON body
+struct GatewayError(StatusCode, String);
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ErrorBody {
+  pub(crate) error: String,
+}
+
+impl From<Status> for GatewayError {
+  fn from(status: Status) -> Self {
+    let code = match status.code() {
+      Code::InvalidArgument => StatusCode::BAD_REQUEST,
+      Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+      Code::PermissionDenied => StatusCode::FORBIDDEN,
+      Code::NotFound => StatusCode::NOT_FOUND,
+      // Unknown cursor, client must resync
+      Code::OutOfRange => StatusCode::GONE,
+      Code::FailedPrecondition => StatusCode::CONFLICT,
+      Code::ResourceExhausted => StatusCode::PAYLOAD_TOO_LARGE,
+      _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    Self(code, status.message().to_string())
+  }
+}
+
+impl IntoResponse for GatewayError {
+  fn into_response(self) -> Response {
+    (self.0, Json(ErrorBody { error: self.1 })).into_response()
+  }
+}
+
+impl Gateway {
+  // Wrap message into a request of the authenticated uid
+  fn request<T>(
+    &self,
+    headers: &HeaderMap,
+    message: T,
+  ) -> Result<Request<T>, GatewayError> {
+    let mut request = Request::new(message);
+    if let Some(provider) = &self.auth_provider {
+      let header = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+      let uid = authenticate_bearer(provider.as_ref(), header)
+        .map_err(|e| GatewayError(StatusCode::UNAUTHORIZED, e))?;
+      request.extensions_mut().insert(AuthenticatedUid(uid));
+    }
+    Ok(request)
+  }
+}
+
+// Commit JSON sent over the sync api
+fn commit_value(commit_obj: &CommitObj) -> Result<Value, GatewayError> {
+  serde_json::from_str(&commit_obj.obj_json_string)
+    .map_err(|e| GatewayError(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))
+}
+
+// Comma separated storage ids, empty means all
+fn split_storage_ids(storage_ids: &str) -> Vec<String> {
+  storage_ids
+    .split(',')
+    .filter(|id| !id.is_empty())
+    .map(|id| id.to_string())
+    .collect()
+}
+
+#[derive(Deserialize)]
+struct CursorQuery {
+  #[serde(default)]
+  after_commit_id: String,
+  #[serde(default)]
+  storage_ids: String,
+  // Commits pushed by this client are not streamed back by watch
+  #[serde(default)]
+  client_id: String,
+  // Most commits pulled, all if 0
+  #[serde(default)]
+  limit: u32,
+  // Pulling device, see device
+  #[serde(default)]
+  device_id: String,
+}
+
+async fn pull(
+  State(gateway): State<Gateway>,
+  headers: HeaderMap,
+  Query(query): Query<CursorQuery>,
+) -> Result<Json<Vec<Value>>, GatewayError> {
+  let request = gateway.request(
+    &headers,
+    PullRequest {
+      after_commit_id: query.after_commit_id,
+      storage_ids: split_storage_ids(&query.storage_ids),
+      protocol_version: PROTOCOL_VERSION,
+      limit: query.limit,
+      accept_delta: false,
+      action_codec: String::new(),
+      device_id: query.device_id,
+    },
+  )?;
+  let mut stream = Api::pull(gateway.repo.as_ref(), request)
+    .await?
+    .into_inner();
+  let mut commits = vec![];
+  while let Some(commit_obj) = stream.next().await {
+    commits.push(commit_value(&commit_obj?)?);
+  }
+  Ok(Json(commits))
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct PushBody {
+  pub(crate) commit: Value,
+  // Watchers with the same client id are not notified
+  #[serde(default)]
+  pub(crate) client_id: String,
+}
+
+async fn push(
+  State(gateway): State<Gateway>,
+  headers: HeaderMap,
+  Json(body): Json<PushBody>,
+) -> Result<Json<Value>, GatewayError> {
+  let request = gateway.request(
+    &headers,
+    CommitObj {
+      obj_json_string: body.commit.to_string(),
+      client_id: body.client_id,
+      protocol_version: PROTOCOL_VERSION,
+      action_codec: String::new(),
+      encoded_actions: vec![],
+      presence: vec![],
+    },
+  )?;
+  let commit_obj = Api::push(gateway.repo.as_ref(), request)
+    .await?
+    .into_inner();
+  Ok(Json(commit_value(&commit_obj)?))
+}
+
+// Server sent events, one "commit" event per remote commit
+// Stream ends with an "error" event if the watch fails
+async fn watch(
+  State(gateway): State<Gateway>,
+  headers: HeaderMap,
+  Query(query): Query<CursorQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, GatewayError> {
+  let request = gateway.request(
+    &headers,
+    WatchRequest {
+      after_commit_id: query.after_commit_id,
+      storage_ids: split_storage_ids(&query.storage_ids),
+      client_id: query.client_id,
+      protocol_version: PROTOCOL_VERSION,
+      action_codec: String::new(),
+      presence: false,
+    },
+  )?;
+  let stream = Api::watch(gateway.repo.as_ref(), request)
+    .await?
+    .into_inner();
+  let events = stream.map(|commit_obj| {
+    Ok(match commit_obj {
+      Ok(commit_obj) => Event::default()
+        .event("commit")
+        .data(commit_obj.obj_json_string),
+      Err(status) => Event::default().event("error").data(status.message()),
+    })
+  });
+  Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Repository status served by the gateway
+#[derive(Serialize)]
+struct StatusResponse {
+  repository_id: Uuid,
+  local_commits: usize,
+  storages: Vec<StorageStatus>,
+  last_pull: Option<DateTime<Utc>>,
+  last_push: Option<DateTime<Utc>>,
+}
+
+async fn status(
+  State(gateway): State<Gateway>,
+  headers: HeaderMap,
+) -> Result<Json<StatusResponse>, GatewayError> {
+  gateway.request(&headers, ())?;
+  let status = gateway
+    .repo
+    .status()
+    .map_err(|e| GatewayError(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+  Ok(Json(StatusResponse {
+    repository_id: gateway.repo.id(),
+    local_commits: status.local_commits,
+    storages: status.storages,
+    last_pull: status.last_pull,
+    last_push: status.last_push,
+  }))
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct PublicKeyBody {
+  pub(crate) public_key: String,
+}
+
+async fn public_key(
+  State(gateway): State<Gateway>,
+  headers: HeaderMap,
+) -> Result<Json<PublicKeyBody>, GatewayError> {
+  let request = gateway.request(&headers, PublicKeyRequest {})?;
+  let res = Api::public_key(gateway.repo.as_ref(), request).await?;
+  Ok(Json(PublicKeyBody {
+    public_key: res.into_inner().public_key,
+  }))
+}
+
+/// Server info served by the gateway
+#[derive(Serialize, Deserialize)]
+pub(crate) struct InfoBody {
+  protocol_version: u32,
+  repository_id: String,
+  latest_remote_commit_id: String,
+  storage_count: u32,
+  features: Vec<String>,
+}
+
+impl From<InfoResponse> for InfoBody {
+  fn from(info: InfoResponse) -> Self {
+    Self {
+      protocol_version: info.protocol_version,
+      repository_id: info.repository_id,
+      latest_remote_commit_id: info.latest_remote_commit_id,
+      storage_count: info.storage_count,
+      features: info.features,
+    }
+  }
+}
+
+impl From<InfoBody> for InfoResponse {
+  fn from(info: InfoBody) -> Self {
+    Self {
+      protocol_version: info.protocol_version,
+      repository_id: info.repository_id,
+      latest_remote_commit_id: info.latest_remote_commit_id,
+      storage_count: info.storage_count,
+      features: info.features,
+    }
+  }
+}
+
+async fn info(
+  State(gateway): State<Gateway>,
+  headers: HeaderMap,
+) -> Result<Json<InfoBody>, GatewayError> {
+  let request = gateway.request(&headers, InfoRequest {})?;
+  let res = Api::info(gateway.repo.as_ref(), request).await?;
+  Ok(Json(res.into_inner().into()))
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct QueryBody {
+  pub(crate) storage_id: String,
+  pub(crate) query: query::Query,
+}
+
+async fn query(
+  State(gateway): State<Gateway>,
+  headers: HeaderMap,
+  Json(body): Json<QueryBody>,
+) -> Result<Json<Vec<QueryResult>>, GatewayError> {
+  let request = gateway.request(
+    &headers,
+    QueryRequest {
+      storage_id: body.storage_id,
+      query_json: serde_json::to_string(&body.query)
+        .map_err(|e| GatewayError(StatusCode::BAD_REQUEST, e.to_string()))?,
+    },
+  )?;
+  let res = Api::query(gateway.repo.as_ref(), request).await?;
+  let results = res
+    .into_inner()
+    .objects
+    .into_iter()
+    .map(QueryResult::try_from)
+    .collect::<Result<_, _>>()
+    .map_err(|e| GatewayError(StatusCode::INTERNAL_SERVER_ERROR, e))?;
+  Ok(Json(results))
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct RegisterBody {
+  pub(crate) device_id: String,
+  #[serde(default)]
+  pub(crate) app_version: Option<String>,
+}
+
+/// Device registration served by the gateway
+#[derive(Serialize, Deserialize)]
+pub(crate) struct RegisteredBody {
+  pub(crate) first_seen: String,
+}
+
+async fn register(
+  State(gateway): State<Gateway>,
+  headers: HeaderMap,
+  Json(body): Json<RegisterBody>,
+) -> Result<Json<RegisteredBody>, GatewayError> {
+  let request = gateway.request(
+    &headers,
+    RegisterRequest {
+      device_id: body.device_id,
+      app_version: body.app_version.unwrap_or_default(),
+      protocol_version: PROTOCOL_VERSION,
+    },
+  )?;
+  let res = Api::register(gateway.repo.as_ref(), request).await?;
+  Ok(Json(RegisteredBody {
+    first_seen: res.into_inner().first_seen,
+  }))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::net::TcpListener;
+
+  use schemars::JsonSchema;
+  use tokio::sync::oneshot;
+
+  use super::*;
+  use crate::{
+    auth::TokenAuth,
+    sync::{ActionExt, Context, Mode, ObjectExt, Storage},
+    transport::GATEWAY_SCHEME,
+  };
+
+  #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+  struct Note {
+    text: String,
+  }
+
+  impl ObjectExt for Note {}
+
+  #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+  enum NoteAction {
+    SetText(String),
+  }
+
+  impl ActionExt for NoteAction {
+    type ObjectType = Note;
+    fn apply_patch(
+      &self,
+      _object: &Note,
+      _dtime: DateTime<Utc>,
+      _uid: &str,
+    ) -> Result<Note, String> {
+      match self {
+        NoteAction::SetText(text) => Ok(Note { text: text.clone() }),
+      }
+    }
+    fn display(&self) -> String {
+      format!("{:?}", self)
+    }
+  }
+
+  fn notes(repo: &Repository) -> Result<Storage<Note, NoteAction>, String> {
+    Storage::load_or_init(repo, "notes".into())?.register(repo)
+  }
+
+  // Serve the gateway of a server repository on a local port
+  // Stopped when the returned sender is dropped
+  fn serve(
+    auth: TokenAuth,
+  ) -> (
+    String,
+    Repository,
+    Storage<Note, NoteAction>,
+    oneshot::Sender<()>,
+  ) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.set_nonblocking(true).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let repo = Repository::init(
+      Context::in_memory("server".into()),
+      Mode::server(addr.to_string()),
+    )
+    .unwrap();
+    let storage = notes(&repo).unwrap();
+    let router = router_with_auth(repo.handle(), auth);
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    std::thread::spawn(move || {
+      let rt = tokio::runtime::Runtime::new().unwrap();
+      rt.block_on(async {
+        axum::Server::from_tcp(listener)
+          .unwrap()
+          .serve(router.into_make_service())
+          .with_graceful_shutdown(async {
+            let _ = shutdown_rx.await;
+          })
+          .await
+          .unwrap();
+      });
+    });
+    let url = format!("{}{}", GATEWAY_SCHEME, addr);
+    (url, repo, storage, shutdown_tx)
+  }
+
+  fn texts(
+    repo: &Repository,
+    storage: &Storage<Note, NoteAction>,
+  ) -> Vec<String> {
+    storage
+      .get_all(&repo.ctx().clone())
+      .unwrap()
+      .into_iter()
+      .map(|so| so.text.clone())
+      .collect()
+  }
+
+  #[test]
+  fn test_push_and_pull_over_gateway() {
+    let auth = TokenAuth::new()
+      .with_token("anna-token", "anna")
+      .with_token("bob-token", "bob");
+    let (url, server, server_notes, _shutdown) = serve(auth);
+    let ctx = Context::in_memory("anna".into()).with_auth_token("anna-token");
+    let (anna, anna_notes) = Repository::clone(ctx, &url, notes).unwrap();
+    let ctx = Context::in_memory("bob".into()).with_auth_token("bob-token");
+    let (bob, bob_notes) = Repository::clone(ctx, &url, notes).unwrap();
+    let mut ctx = anna.commit_ctx("Create note");
+    anna_notes.create_object(
+      Note {
+        text: "Szépség".into(),
+      },
+      &mut ctx,
+    );
+    ctx.commit().unwrap().into_result().unwrap();
+    anna.proceed_push().unwrap();
+    bob.proceed_pull().unwrap();
+    assert_eq!(texts(&server, &server_notes), vec!["Szépség".to_string()]);
+    assert_eq!(texts(&bob, &bob_notes), vec!["Szépség".to_string()]);
+  }
+
+  #[test]
+  fn test_gateway_rejects_unknown_token() {
+    let auth = TokenAuth::new().with_token("anna-token", "anna");
+    let (url, _, _, _shutdown) = serve(auth);
+    let ctx = Context::in_memory("anna".into()).with_auth_token("bob-token");
+    let err = Repository::clone(ctx, &url, notes).err().unwrap();
+    assert!(err.contains("Unknown token"), "{}", err);
+  }
+}
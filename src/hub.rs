@@ -0,0 +1,336 @@
+//! Server hosting many named repositories under one sync api
+//! Clients select the repository by the "repo-name" request metadata,
+//! see Context::with_remote_repo. Every hosted repository must be in
+//! server mode; its own server address is not used by the hub.
+
+use std::{
+  collections::{HashMap, HashSet},
+  future::Future,
+  sync::{Arc, RwLock},
+};
+
+use tonic::{
+  service::interceptor::InterceptedService, transport::Server, Code, Request,
+  Response, Status, Streaming,
+};
+
+use crate::{
+  auth::{AuthenticatedUid, ServerAuth},
+  limits::{Limiter, Rejection},
+  server::{
+    health_api::health_server::HealthServer,
+    sync_api::{
+      api_server::{Api, ApiServer},
+      BlobChunk, BlobList, CommitChunk, CommitObj, DeviceInfo, DeviceRequest,
+      InfoRequest, InfoResponse, ListDevicesRequest, ListDevicesResponse,
+      PresenceList, PresenceRequest, PublicKeyRequest, PublicKeyResponse,
+      PullRequest, QueryRequest, QueryResponse, RegisterRequest,
+      RegisterResponse, WatchRequest,
+    },
+    HealthService, ServeConfig,
+  },
+  sync::Repository,
+  tls::ServerTls,
+};
+
+/// Request metadata key of the repository name
+pub const REPO_METADATA: &str = "repo-name";
+
+// Hosted repository
+struct HubRepo {
+  repo: Arc<Repository>,
+  // Uids allowed to access it, everyone if None
+  allowed_uids: Option<HashSet<String>>,
+}
+
+/// Hub of named repositories
+/// Clones share the hosted repositories, so repositories can be added
+/// and removed while the hub is served
+#[derive(Clone, Default)]
+pub struct RepoHub {
+  repos: Arc<RwLock<HashMap<String, HubRepo>>>,
+}
+
+impl RepoHub {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  /// Host repository under name, accessible by everyone
+  pub fn add(&self, name: &str, repo: Repository) -> Result<(), String> {
+    self.insert(name, repo, None)
+  }
+  /// Host repository under name, accessible by the given uids only
+  /// Requires the hub to be served with an auth provider
+  pub fn add_restricted<'a>(
+    &self,
+    name: &str,
+    repo: Repository,
+    allowed_uids: impl IntoIterator<Item = &'a str>,
+  ) -> Result<(), String> {
+    let allowed_uids = allowed_uids.into_iter().map(String::from).collect();
+    self.insert(name, repo, Some(allowed_uids))
+  }
+  fn insert(
+    &self,
+    name: &str,
+    repo: Repository,
+    allowed_uids: Option<HashSet<String>>,
+  ) -> Result<(), String> {
+    if !repo.is_server() {
+      return Err(format!("Repository {} is not in server mode", name));
+    }
+    let mut repos = self.repos.write().unwrap();
+    if repos.contains_key(name) {
+      return Err(format!("Repository {} is already hosted", name));
+    }
+    repos.insert(
+      name.to_string(),
+      HubRepo {
+        repo: Arc::new(repo),
+        allowed_uids,
+      },
+    );
+    Ok(())
+  }
+  /// Stop hosting repository
+  /// Its open watch streams are closed
+  pub fn remove(&self, name: &str) -> Result<(), String> {
+    let hub_repo = self
+      .repos
+      .write()
+      .unwrap()
+      .remove(name)
+      .ok_or(format!("Unknown repository {}", name))?;
+    hub_repo.repo.set_server_shutdown(true);
+    Ok(())
+  }
+  /// Names of the hosted repositories
+  pub fn names(&self) -> Vec<String> {
+    let mut names: Vec<String> =
+      self.repos.read().unwrap().keys().cloned().collect();
+    names.sort();
+    names
+  }
+  /// Sync api service to mount in an existing tonic server
+  pub fn into_service(self) -> ApiServer<RepoHub> {
+    ApiServer::new(self)
+  }
+  /// Sync api service authenticating every request by the config
+  /// auth provider, and enforcing its limits
+  pub fn into_service_with_config(
+    self,
+    config: ServeConfig,
+  ) -> InterceptedService<ApiServer<RepoHub>, ServerAuth> {
+    ApiServer::with_interceptor(
+      self,
+      ServerAuth::new(
+        config.auth_provider,
+        config.limits.map(|limits| Arc::new(Limiter::new(limits))),
+      )
+      .with_admin_uids(config.admin_uids),
+    )
+  }
+  /// Serve hosted repositories on server_addr
+  pub fn serve(&self, server_addr: &str) -> Result<(), String> {
+    self.serve_with_config(
+      server_addr,
+      None,
+      ServeConfig::default(),
+      std::future::pending(),
+    )
+  }
+  /// Serve hosted repositories with the given config,
+  /// stopping once signal completes
+  pub fn serve_with_config(
+    &self,
+    server_addr: &str,
+    tls: Option<ServerTls>,
+    config: ServeConfig,
+    signal: impl Future<Output = ()>,
+  ) -> Result<(), String> {
+    let server_addr = server_addr
+      .parse()
+      .map_err(|e| format!("Invalid server address: {}", e))?;
+    let mut server = Server::builder();
+    if let Some(tls) = tls {
+      server = server
+        .tls_config(tls.config()?)
+        .map_err(|e| format!("TLS config error: {}", e))?;
+    }
+    for hub_repo in self.repos.read().unwrap().values() {
+      hub_repo.repo.set_server_shutdown(false);
+    }
+    let ServeConfig {
+      auth_provider,
+      runtime,
+      limits,
+      admin_uids,
+    } = config;
+    let auth = ServerAuth::new(
+      auth_provider,
+      limits.map(|limits| Arc::new(Limiter::new(limits))),
+    )
+    .with_admin_uids(admin_uids);
+    let serve = async {
+      server
+        .add_service(HealthServer::new(HealthService))
+        .add_service(ApiServer::with_interceptor(self.clone(), auth))
+        .serve_with_shutdown(server_addr, async {
+          signal.await;
+          info!("Shutting down repository hub");
+          // Close watch streams, so their connections can end
+          for hub_repo in self.repos.read().unwrap().values() {
+            hub_repo.repo.set_server_shutdown(true);
+          }
+        })
+        .await
+        .map_err(|e| format!("Server error: {}", e))
+    };
+    runtime.block_on(serve)?
+  }
+  // Repository the request is addressed to
+  // Errors if it is unknown, or the requester has no access to it
+  fn resolve<T>(
+    &self,
+    request: &Request<T>,
+  ) -> Result<Arc<Repository>, Rejection> {
+    let name = request
+      .metadata()
+      .get(REPO_METADATA)
+      .and_then(|value| value.to_str().ok())
+      .ok_or_else(|| {
+        Rejection::invalid_argument("Missing repository name".into())
+      })?;
+    let repos = self.repos.read().unwrap();
+    let hub_repo = repos.get(name).ok_or_else(|| Rejection {
+      code: Code::NotFound,
+      message: format!("Unknown repository {}", name),
+    })?;
+    if let Some(allowed_uids) = &hub_repo.allowed_uids {
+      let uid = request.extensions().get::<AuthenticatedUid>();
+      if !uid.is_some_and(|uid| allowed_uids.contains(&uid.0)) {
+        return Err(Rejection {
+          code: Code::PermissionDenied,
+          message: format!("No access to repository {}", name),
+        });
+      }
+    }
+    Ok(hub_repo.repo.clone())
+  }
+}
+
+#[tonic::async_trait]
+impl Api for RepoHub {
+  type PullStream = <Repository as Api>::PullStream;
+
+  async fn pull(
+    &self,
+    request: Request<PullRequest>,
+  ) -> Result<Response<Self::PullStream>, Status> {
+    Api::pull(self.resolve(&request)?.as_ref(), request).await
+  }
+
+  async fn push(
+    &self,
+    request: Request<CommitObj>,
+  ) -> Result<Response<CommitObj>, Status> {
+    Api::push(self.resolve(&request)?.as_ref(), request).await
+  }
+
+  type PushChunkedStream = <Repository as Api>::PushChunkedStream;
+
+  async fn push_chunked(
+    &self,
+    request: Request<Streaming<CommitChunk>>,
+  ) -> Result<Response<Self::PushChunkedStream>, Status> {
+    Api::push_chunked(self.resolve(&request)?.as_ref(), request).await
+  }
+
+  type WatchStream = <Repository as Api>::WatchStream;
+
+  async fn watch(
+    &self,
+    request: Request<WatchRequest>,
+  ) -> Result<Response<Self::WatchStream>, Status> {
+    Api::watch(self.resolve(&request)?.as_ref(), request).await
+  }
+
+  async fn public_key(
+    &self,
+    request: Request<PublicKeyRequest>,
+  ) -> Result<Response<PublicKeyResponse>, Status> {
+    Api::public_key(self.resolve(&request)?.as_ref(), request).await
+  }
+
+  async fn info(
+    &self,
+    request: Request<InfoRequest>,
+  ) -> Result<Response<InfoResponse>, Status> {
+    Api::info(self.resolve(&request)?.as_ref(), request).await
+  }
+
+  async fn query(
+    &self,
+    request: Request<QueryRequest>,
+  ) -> Result<Response<QueryResponse>, Status> {
+    Api::query(self.resolve(&request)?.as_ref(), request).await
+  }
+
+  async fn missing_blobs(
+    &self,
+    request: Request<BlobList>,
+  ) -> Result<Response<BlobList>, Status> {
+    Api::missing_blobs(self.resolve(&request)?.as_ref(), request).await
+  }
+
+  async fn push_blobs(
+    &self,
+    request: Request<Streaming<BlobChunk>>,
+  ) -> Result<Response<BlobList>, Status> {
+    Api::push_blobs(self.resolve(&request)?.as_ref(), request).await
+  }
+
+  type PullBlobsStream = <Repository as Api>::PullBlobsStream;
+
+  async fn pull_blobs(
+    &self,
+    request: Request<BlobList>,
+  ) -> Result<Response<Self::PullBlobsStream>, Status> {
+    Api::pull_blobs(self.resolve(&request)?.as_ref(), request).await
+  }
+
+  async fn register(
+    &self,
+    request: Request<RegisterRequest>,
+  ) -> Result<Response<RegisterResponse>, Status> {
+    Api::register(self.resolve(&request)?.as_ref(), request).await
+  }
+
+  async fn list_devices(
+    &self,
+    request: Request<ListDevicesRequest>,
+  ) -> Result<Response<ListDevicesResponse>, Status> {
+    Api::list_devices(self.resolve(&request)?.as_ref(), request).await
+  }
+
+  async fn revoke_device(
+    &self,
+    request: Request<DeviceRequest>,
+  ) -> Result<Response<DeviceInfo>, Status> {
+    Api::revoke_device(self.resolve(&request)?.as_ref(), request).await
+  }
+
+  async fn force_resync(
+    &self,
+    request: Request<DeviceRequest>,
+  ) -> Result<Response<DeviceInfo>, Status> {
+    Api::force_resync(self.resolve(&request)?.as_ref(), request).await
+  }
+
+  async fn announce(
+    &self,
+    request: Request<PresenceRequest>,
+  ) -> Result<Response<PresenceList>, Status> {
+    Api::announce(self.resolve(&request)?.as_ref(), request).await
+  }
+}
@@ -0,0 +1,33 @@
+//! Id generation of commits, action objects and objects
+//! Random UUIDv4 ids by default. Time ordered UUIDv7 ids sort
+//! chronologically, e.g. for range scans or external indexes.
+//! Other schemes, e.g. ULID, can be plugged in via IdGenerator.
+
+use uuid::Uuid;
+
+/// Generator of new ids, see Context::with_id_generator
+pub trait IdGenerator: Send + Sync {
+  fn new_id(&self) -> Uuid;
+}
+
+/// Random UUIDv4 ids
+#[derive(Default, Debug, Clone, Copy)]
+pub struct RandomIds;
+
+impl IdGenerator for RandomIds {
+  fn new_id(&self) -> Uuid {
+    Uuid::new_v4()
+  }
+}
+
+/// Time ordered UUIDv7 ids
+/// Monotonic within the process, even if generated in the same
+/// millisecond.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct TimeOrderedIds;
+
+impl IdGenerator for TimeOrderedIds {
+  fn new_id(&self) -> Uuid {
+    Uuid::now_v7()
+  }
+}
@@ -1,8 +1,52 @@
 extern crate pretty_env_logger;
 #[macro_use]
-extern crate log;
+extern crate tracing;
 
+pub mod audit;
+pub mod auth;
+pub mod backend;
+pub mod blob;
+pub mod clock;
+pub mod codec;
+pub mod config;
+pub mod conflict;
+mod delta;
+pub mod device;
+pub mod diff;
+pub mod export;
 mod fs;
+#[cfg(feature = "http-gateway")]
+pub mod gateway;
+pub mod hub;
+pub mod id;
+pub mod limits;
+pub mod lock;
+pub mod metrics;
+pub mod migration;
+pub mod mirror;
 mod prelude;
+pub mod presence;
+pub mod projection;
+pub mod query;
+pub mod redaction;
+pub mod retention;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod schema_export;
 pub mod server;
 pub mod sync;
+pub mod testing;
+pub mod tls;
+pub mod trace;
+pub mod transport;
+pub mod usage;
+pub mod verify;
+pub mod worker;
+
+pub use storage_derive::Action;
+
+// Used by derive macros
+#[doc(hidden)]
+pub mod __private {
+  pub use chrono::{DateTime, Utc};
+}
@@ -0,0 +1,220 @@
+//! Server side rate limits and quotas
+//! Clients are told apart by their authenticated uid,
+//! or by their address if the server has no auth provider.
+//!
+//! Exceeding the request rate or the daily commit quota is answered
+//! with RESOURCE_EXHAUSTED, commits over the size or action limits
+//! with INVALID_ARGUMENT.
+
+use std::{collections::HashMap, sync::Mutex, time::Instant};
+
+use chrono::{NaiveDate, Utc};
+use tonic::{Code, Request, Status};
+
+use crate::{auth::AuthenticatedUid, sync::Commit};
+
+/// Server limits, unlimited by default
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+  requests_per_sec: Option<u32>,
+  commits_per_day: Option<u32>,
+  max_commit_size: Option<usize>,
+  max_actions_per_commit: Option<usize>,
+}
+
+impl Limits {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  /// Requests per second of a client, bursts up to the same amount
+  pub fn with_requests_per_sec(mut self, requests_per_sec: u32) -> Self {
+    self.requests_per_sec = Some(requests_per_sec);
+    self
+  }
+  /// Pushed commits per UTC day of a client
+  pub fn with_commits_per_day(mut self, commits_per_day: u32) -> Self {
+    self.commits_per_day = Some(commits_per_day);
+    self
+  }
+  /// Largest pushed commit in serialized bytes
+  pub fn with_max_commit_size(mut self, max_commit_size: usize) -> Self {
+    self.max_commit_size = Some(max_commit_size);
+    self
+  }
+  /// Most action objects in a pushed commit
+  pub fn with_max_actions_per_commit(mut self, max_actions: usize) -> Self {
+    self.max_actions_per_commit = Some(max_actions);
+    self
+  }
+}
+
+/// Rejected request, converts into its gRPC status
+#[derive(Debug, Clone)]
+pub struct Rejection {
+  pub code: Code,
+  pub message: String,
+}
+
+impl Rejection {
+  pub(crate) fn resource_exhausted(message: String) -> Self {
+    Self {
+      code: Code::ResourceExhausted,
+      message,
+    }
+  }
+  pub(crate) fn invalid_argument(message: String) -> Self {
+    Self {
+      code: Code::InvalidArgument,
+      message,
+    }
+  }
+}
+
+impl From<Rejection> for Status {
+  fn from(rejection: Rejection) -> Self {
+    Status::new(rejection.code, rejection.message)
+  }
+}
+
+// Usage of a client
+struct Usage {
+  // Request token bucket
+  tokens: f64,
+  refilled: Instant,
+  // Commits pushed on day
+  day: NaiveDate,
+  commits: u32,
+}
+
+impl Usage {
+  fn new(tokens: f64) -> Self {
+    Self {
+      tokens,
+      refilled: Instant::now(),
+      day: Utc::now().date_naive(),
+      commits: 0,
+    }
+  }
+  // Commits of today
+  fn commits_today(&mut self) -> &mut u32 {
+    let today = Utc::now().date_naive();
+    if self.day != today {
+      self.day = today;
+      self.commits = 0;
+    }
+    &mut self.commits
+  }
+}
+
+/// Limits enforced by the server with the usage of its clients
+/// Inserted into request extensions by the server interceptor
+pub(crate) struct Limiter {
+  limits: Limits,
+  usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl Limiter {
+  pub(crate) fn new(limits: Limits) -> Self {
+    Self {
+      limits,
+      usage: Mutex::new(HashMap::new()),
+    }
+  }
+  // Largest pushed commit in serialized bytes, if limited
+  pub(crate) fn max_commit_size(&self) -> Option<usize> {
+    self.limits.max_commit_size
+  }
+  // Run f on the usage of client
+  fn with_usage<R>(&self, client: &str, f: impl FnOnce(&mut Usage) -> R) -> R {
+    let burst = self.limits.requests_per_sec.unwrap_or_default() as f64;
+    let mut usage = self.usage.lock().unwrap();
+    f(usage
+      .entry(client.to_string())
+      .or_insert_with(|| Usage::new(burst)))
+  }
+  // Take a request token of client
+  pub(crate) fn check_request(&self, client: &str) -> Result<(), Rejection> {
+    let rate = match self.limits.requests_per_sec {
+      Some(rate) => rate as f64,
+      None => return Ok(()),
+    };
+    self.with_usage(client, |usage| {
+      let now = Instant::now();
+      let elapsed = now.duration_since(usage.refilled).as_secs_f64();
+      usage.tokens = (usage.tokens + elapsed * rate).min(rate);
+      usage.refilled = now;
+      if usage.tokens < 1.0 {
+        return Err(Rejection::resource_exhausted(format!(
+          "Rate limit exceeded: {} requests per second",
+          rate
+        )));
+      }
+      usage.tokens -= 1.0;
+      Ok(())
+    })
+  }
+  // Check pushed commit against the limits, and reserve it
+  // from the daily quota of client. Release it if the merge fails.
+  pub(crate) fn reserve_commit(
+    &self,
+    client: &str,
+    commit_json: &str,
+  ) -> Result<(), Rejection> {
+    if let Some(max) = self.limits.max_commit_size {
+      if commit_json.len() > max {
+        return Err(Rejection::invalid_argument(format!(
+          "Commit too large: {} bytes, limit is {}",
+          commit_json.len(),
+          max
+        )));
+      }
+    }
+    if let Some(max) = self.limits.max_actions_per_commit {
+      let commit: Commit = serde_json::from_str(commit_json)
+        .map_err(|_| Rejection::invalid_argument("Invalid commit".into()))?;
+      let actions = commit.serialized_actions().len();
+      if actions > max {
+        return Err(Rejection::invalid_argument(format!(
+          "Too many action objects in commit: {}, limit is {}",
+          actions, max
+        )));
+      }
+    }
+    let quota = match self.limits.commits_per_day {
+      Some(quota) => quota,
+      None => return Ok(()),
+    };
+    self.with_usage(client, |usage| {
+      let commits = usage.commits_today();
+      if *commits >= quota {
+        return Err(Rejection::resource_exhausted(format!(
+          "Daily commit quota exceeded: {} commits per day",
+          quota
+        )));
+      }
+      *commits += 1;
+      Ok(())
+    })
+  }
+  // Give back a reserved commit
+  pub(crate) fn release_commit(&self, client: &str) {
+    if self.limits.commits_per_day.is_some() {
+      self.with_usage(client, |usage| {
+        let commits = usage.commits_today();
+        *commits = commits.saturating_sub(1);
+      })
+    }
+  }
+}
+
+// Key of the requesting client
+// Authenticated uid, or the remote address without auth
+pub(crate) fn client_key<T>(request: &Request<T>) -> String {
+  match request.extensions().get::<AuthenticatedUid>() {
+    Some(uid) => uid.0.clone(),
+    None => request
+      .remote_addr()
+      .map(|addr| addr.ip().to_string())
+      .unwrap_or_default(),
+  }
+}
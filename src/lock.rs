@@ -0,0 +1,144 @@
+use std::{
+  fs::{File, OpenOptions},
+  io::{Read, Seek, SeekFrom, Write},
+  path::PathBuf,
+  time::{Duration, Instant},
+};
+
+use fs2::FileExt;
+
+// Delay between two lock attempts while waiting
+const LOCK_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Advisory repository lock
+/// Held by a loaded repository, so no other process can open the same
+/// db root path. The lock is released when the repository is dropped,
+/// or by the OS when the owner process dies. A lock file left behind
+/// by a dead process is therefore stale and is taken over.
+#[derive(Debug)]
+pub struct RepoLock {
+  path: PathBuf,
+  file: File,
+}
+
+impl RepoLock {
+  /// Acquire lock file
+  /// Waits up to wait duration if locked, errors immediately if None
+  pub fn acquire(
+    path: PathBuf,
+    wait: Option<Duration>,
+  ) -> Result<Self, String> {
+    if let Some(parent) = path.parent() {
+      std::fs::create_dir_all(parent)
+        .map_err(|_| format!("Error creating lock file folder: {:?}", path))?;
+    }
+    let mut file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(&path)
+      .map_err(|e| format!("Error opening lock file {:?}: {}", path, e))?;
+    let deadline = wait.map(|wait| Instant::now() + wait);
+    while file.try_lock_exclusive().is_err() {
+      match deadline {
+        Some(deadline) if Instant::now() < deadline => {
+          std::thread::sleep(LOCK_RETRY_DELAY)
+        }
+        _ => {
+          return Err(format!(
+            "Repository is locked by process {}",
+            owner(&mut file).unwrap_or_else(|| "unknown".to_string())
+          ))
+        }
+      }
+    }
+    // Record owner, replacing any stale owner info
+    file.set_len(0).map_err(|e| e.to_string())?;
+    file.seek(SeekFrom::Start(0)).map_err(|e| e.to_string())?;
+    write!(file, "{}", std::process::id()).map_err(|e| e.to_string())?;
+    file.flush().map_err(|e| e.to_string())?;
+    Ok(Self { path, file })
+  }
+  pub fn path(&self) -> &PathBuf {
+    &self.path
+  }
+}
+
+impl Drop for RepoLock {
+  fn drop(&mut self) {
+    let _ = self.file.unlock();
+  }
+}
+
+// Owner process id recorded in the lock file
+fn owner(file: &mut File) -> Option<String> {
+  let mut owner = String::new();
+  file.seek(SeekFrom::Start(0)).ok()?;
+  file.read_to_string(&mut owner).ok()?;
+  match owner.is_empty() {
+    true => None,
+    false => Some(owner),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    prelude::path_helper,
+    sync::{Context, Mode, Repository},
+  };
+
+  // Context of a new repository root in the temp dir
+  fn temp_ctx(name: &str) -> Context {
+    let path = std::env::temp_dir().join(format!(
+      "storage_test_lock_{}_{}",
+      name,
+      std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&path);
+    Context::init(path, "test".into())
+  }
+
+  #[test]
+  fn test_second_load_errors() {
+    let ctx = temp_ctx("second");
+    let repo = Repository::init(ctx.clone(), Mode::Local).unwrap();
+    let err = Repository::load(ctx.clone()).err().unwrap();
+    assert!(err.contains("locked"));
+    drop(repo);
+    assert!(Repository::load(ctx.clone()).is_ok());
+    std::fs::remove_dir_all(&ctx.db_root_path).unwrap();
+  }
+
+  #[test]
+  fn test_try_load_waits_for_release() {
+    let ctx = temp_ctx("wait");
+    let repo = Repository::init(ctx.clone(), Mode::Local).unwrap();
+    let holder = std::thread::spawn(move || {
+      std::thread::sleep(Duration::from_millis(200));
+      drop(repo);
+    });
+    let loaded =
+      Repository::try_load(ctx.clone(), Some(Duration::from_secs(10)));
+    assert!(loaded.is_ok());
+    holder.join().unwrap();
+    drop(loaded);
+    std::fs::remove_dir_all(&ctx.db_root_path).unwrap();
+  }
+
+  #[test]
+  fn test_stale_lock_taken_over() {
+    let ctx = temp_ctx("stale");
+    drop(Repository::init(ctx.clone(), Mode::Local).unwrap());
+    // Lock file left behind by a dead process
+    let path = path_helper::repo_lock(&ctx);
+    std::fs::write(&path, "999999").unwrap();
+    let repo = Repository::load(ctx.clone()).unwrap();
+    let owner = std::fs::read_to_string(&path).unwrap();
+    assert_eq!(owner, std::process::id().to_string());
+    drop(repo);
+    std::fs::remove_dir_all(&ctx.db_root_path).unwrap();
+  }
+}
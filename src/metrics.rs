@@ -0,0 +1,202 @@
+//! Sync server metrics in the Prometheus text format
+//! Collected by every repository, exported by a server whose mode has
+//! a metrics address (see Mode::with_metrics_addr) at GET /metrics.
+//! The listener requires the "metrics" feature.
+
+use std::{
+  fmt::Write,
+  sync::atomic::{AtomicI64, AtomicU64, Ordering},
+  time::Duration,
+};
+
+// Latency histogram bucket upper bounds in seconds
+const LATENCY_BUCKETS: [f64; 10] =
+  [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Latency histogram with fixed buckets
+#[derive(Debug, Default)]
+pub struct Histogram {
+  // Observations per bucket, not cumulative
+  buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+  count: AtomicU64,
+  // Sum of observations in microseconds
+  sum_micros: AtomicU64,
+}
+
+impl Histogram {
+  pub fn observe(&self, duration: Duration) {
+    let secs = duration.as_secs_f64();
+    if let Some(i) = LATENCY_BUCKETS.iter().position(|le| secs <= *le) {
+      self.buckets[i].fetch_add(1, Ordering::Relaxed);
+    }
+    self.count.fetch_add(1, Ordering::Relaxed);
+    self
+      .sum_micros
+      .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+  }
+  fn render(&self, out: &mut String, name: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    let mut cumulative = 0;
+    for (le, bucket) in LATENCY_BUCKETS.iter().zip(&self.buckets) {
+      cumulative += bucket.load(Ordering::Relaxed);
+      let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, le, cumulative);
+    }
+    let count = self.count.load(Ordering::Relaxed);
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+    let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+    let _ = writeln!(out, "{}_sum {}", name, sum);
+    let _ = writeln!(out, "{}_count {}", name, count);
+  }
+}
+
+/// Repository metrics
+/// Shared by every clone of the repository context
+#[derive(Debug, Default)]
+pub struct Metrics {
+  pushes_accepted: AtomicU64,
+  pushes_rejected: AtomicU64,
+  pull_bytes: AtomicU64,
+  watch_subscribers: AtomicI64,
+  merge_latency: Histogram,
+  fs_read_latency: Histogram,
+  fs_write_latency: Histogram,
+}
+
+impl Metrics {
+  pub fn push_accepted(&self) {
+    self.pushes_accepted.fetch_add(1, Ordering::Relaxed);
+  }
+  pub fn push_rejected(&self) {
+    self.pushes_rejected.fetch_add(1, Ordering::Relaxed);
+  }
+  pub fn add_pull_bytes(&self, bytes: usize) {
+    self.pull_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+  }
+  /// Count a watch subscriber until the returned guard is dropped
+  pub fn watch_subscriber(self: &std::sync::Arc<Self>) -> WatchSubscriber {
+    self.watch_subscribers.fetch_add(1, Ordering::Relaxed);
+    WatchSubscriber {
+      metrics: self.clone(),
+    }
+  }
+  pub fn observe_merge(&self, duration: Duration) {
+    self.merge_latency.observe(duration);
+  }
+  pub fn observe_fs_read(&self, duration: Duration) {
+    self.fs_read_latency.observe(duration);
+  }
+  pub fn observe_fs_write(&self, duration: Duration) {
+    self.fs_write_latency.observe(duration);
+  }
+  /// Render metrics in the Prometheus text format
+  /// Object counts are given per storage id
+  pub fn render(&self, object_counts: &[(String, usize)]) -> String {
+    let mut out = String::new();
+    let counters = [
+      (
+        "sync_pushes_accepted_total",
+        "Pushed commits merged",
+        self.pushes_accepted.load(Ordering::Relaxed),
+      ),
+      (
+        "sync_pushes_rejected_total",
+        "Pushed commits rejected",
+        self.pushes_rejected.load(Ordering::Relaxed),
+      ),
+      (
+        "sync_pull_bytes_total",
+        "Commit bytes served to pulls",
+        self.pull_bytes.load(Ordering::Relaxed),
+      ),
+    ];
+    for (name, help, value) in counters {
+      let _ = writeln!(out, "# HELP {} {}", name, help);
+      let _ = writeln!(out, "# TYPE {} counter", name);
+      let _ = writeln!(out, "{} {}", name, value);
+    }
+    let _ = writeln!(out, "# HELP sync_watch_subscribers Open watch streams");
+    let _ = writeln!(out, "# TYPE sync_watch_subscribers gauge");
+    let _ = writeln!(
+      out,
+      "sync_watch_subscribers {}",
+      self.watch_subscribers.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(out, "# HELP storage_objects Objects per storage");
+    let _ = writeln!(out, "# TYPE storage_objects gauge");
+    for (storage_id, count) in object_counts {
+      let _ = writeln!(
+        out,
+        "storage_objects{{storage_id=\"{}\"}} {}",
+        storage_id, count
+      );
+    }
+    self.merge_latency.render(
+      &mut out,
+      "sync_merge_seconds",
+      "Pushed commit merge latency",
+    );
+    self.fs_read_latency.render(
+      &mut out,
+      "fs_read_seconds",
+      "Repository data read latency",
+    );
+    self.fs_write_latency.render(
+      &mut out,
+      "fs_write_seconds",
+      "Repository data write latency",
+    );
+    out
+  }
+}
+
+/// Open watch stream, counted until dropped
+pub struct WatchSubscriber {
+  metrics: std::sync::Arc<Metrics>,
+}
+
+impl Drop for WatchSubscriber {
+  fn drop(&mut self) {
+    self
+      .metrics
+      .watch_subscribers
+      .fetch_sub(1, Ordering::Relaxed);
+  }
+}
+
+// Serve GET /metrics on addr until shutdown resolves
+#[cfg(feature = "metrics")]
+pub(crate) async fn serve(
+  addr: std::net::SocketAddr,
+  render: impl Fn() -> String + Clone + Send + Sync + 'static,
+  shutdown: impl std::future::Future<Output = ()>,
+) -> Result<(), String> {
+  use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Response, Server, StatusCode,
+  };
+  use std::convert::Infallible;
+
+  let make_service = make_service_fn(move |_| {
+    let render = render.clone();
+    async move {
+      Ok::<_, Infallible>(service_fn(move |req| {
+        let res = match (req.method(), req.uri().path()) {
+          (&hyper::Method::GET, "/metrics") => Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Body::from(render())),
+          _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+        };
+        async move { res }
+      }))
+    }
+  });
+  Server::try_bind(&addr)
+    .map_err(|e| format!("Metrics listener error: {}", e))?
+    .serve(make_service)
+    .with_graceful_shutdown(shutdown)
+    .await
+    .map_err(|e| format!("Metrics listener error: {}", e))
+}
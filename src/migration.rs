@@ -0,0 +1,71 @@
+use serde_json::Value;
+
+/// Storage schema migrator
+/// Converts serialized object payloads (T) of older schema versions
+/// into the current one, before they are deserialized
+pub trait Migrator: Send + Sync {
+  /// Current schema version of T
+  fn schema_version(&self) -> u32;
+  /// Convert payload of from_version into from_version + 1
+  fn migrate(&self, from_version: u32, payload: Value)
+    -> Result<Value, String>;
+}
+
+// Migrate payload step by step from the given version
+// to the current migrator schema version
+pub(crate) fn migrate_payload(
+  migrator: &dyn Migrator,
+  from_version: u32,
+  mut payload: Value,
+) -> Result<Value, String> {
+  for version in from_version..migrator.schema_version() {
+    payload = migrator.migrate(version, payload).map_err(|e| {
+      format!("Schema migration from version {} failed: {}", version, e)
+    })?;
+  }
+  Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+
+  // Each version appends its number to the steps
+  struct Steps;
+
+  impl Migrator for Steps {
+    fn schema_version(&self) -> u32 {
+      3
+    }
+    fn migrate(
+      &self,
+      from_version: u32,
+      mut payload: Value,
+    ) -> Result<Value, String> {
+      match from_version {
+        2 if payload["fail"] == json!(true) => Err("Failing step".into()),
+        _ => {
+          payload["steps"]
+            .as_array_mut()
+            .unwrap()
+            .push(json!(from_version));
+          Ok(payload)
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn test_migrate_step_by_step() {
+    let payload = migrate_payload(&Steps, 1, json!({"steps": []})).unwrap();
+    assert_eq!(payload["steps"], json!([1, 2]));
+    // Current version is left as it is
+    let payload = migrate_payload(&Steps, 3, json!({"steps": []})).unwrap();
+    assert_eq!(payload["steps"], json!([]));
+    let err = migrate_payload(&Steps, 0, json!({"steps": [], "fail": true}))
+      .unwrap_err();
+    assert_eq!(err, "Schema migration from version 2 failed: Failing step");
+  }
+}
@@ -0,0 +1,141 @@
+//! Mirrors of the current object states, e.g. for ad-hoc SQL queries
+//! A mirror set on the repository (see Repository::set_object_mirror)
+//! is updated with every object the storage hooks apply.
+//! It is never read back, the repository stays the source of truth.
+
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+use uuid::Uuid;
+
+/// Current state of a mirrored object
+#[derive(Debug, Clone)]
+pub struct MirrorRow {
+  pub object_id: Uuid,
+  /// Time and uid of the latest action
+  pub dtime: DateTime<Utc>,
+  pub uid: String,
+  /// Object as json
+  pub data: Value,
+}
+
+/// Object state mirror
+/// Failing mirror updates are logged, they never fail a commit
+pub trait ObjectMirror: Send + Sync + 'static {
+  /// Replace every row of the storage
+  fn reset(
+    &self,
+    storage_id: &str,
+    rows: &mut dyn Iterator<Item = Result<MirrorRow, String>>,
+  ) -> Result<(), String>;
+  /// Insert or replace the row of an object
+  fn upsert(&self, storage_id: &str, row: MirrorRow) -> Result<(), String>;
+  /// Remove the row of a removed object
+  fn remove(&self, storage_id: &str, object_id: Uuid) -> Result<(), String>;
+}
+
+#[cfg(feature = "sqlite-mirror")]
+pub use sqlite::SqliteMirror;
+
+#[cfg(feature = "sqlite-mirror")]
+mod sqlite {
+  use std::{path::Path, sync::Mutex};
+
+  use rusqlite::{params, Connection};
+  use uuid::Uuid;
+
+  use super::{MirrorRow, ObjectMirror};
+
+  /// SQLite database with one table per storage id
+  /// Tables have id, dtime, uid and data (object json) text columns,
+  /// so standard SQL tools can query them, e.g. with json_extract.
+  /// The database is rebuilt from the repository, do not write it.
+  pub struct SqliteMirror {
+    conn: Mutex<Connection>,
+  }
+
+  impl SqliteMirror {
+    /// Open or create the database at path
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, String> {
+      let conn = Connection::open(path).map_err(|e| e.to_string())?;
+      Ok(Self {
+        conn: Mutex::new(conn),
+      })
+    }
+  }
+
+  // Quoted table name of a storage
+  fn table(storage_id: &str) -> String {
+    format!("\"{}\"", storage_id.replace('"', "\"\""))
+  }
+
+  fn create_table(conn: &Connection, storage_id: &str) -> Result<(), String> {
+    conn
+      .execute(
+        &format!(
+          "CREATE TABLE IF NOT EXISTS {} \
+           (id TEXT PRIMARY KEY, dtime TEXT, uid TEXT, data TEXT)",
+          table(storage_id)
+        ),
+        [],
+      )
+      .map(|_| ())
+      .map_err(|e| e.to_string())
+  }
+
+  fn insert(
+    conn: &Connection,
+    storage_id: &str,
+    row: MirrorRow,
+  ) -> Result<(), String> {
+    conn
+      .execute(
+        &format!(
+          "INSERT OR REPLACE INTO {} (id, dtime, uid, data) \
+           VALUES (?1, ?2, ?3, ?4)",
+          table(storage_id)
+        ),
+        params![
+          row.object_id.to_string(),
+          row.dtime.to_rfc3339(),
+          row.uid,
+          row.data.to_string()
+        ],
+      )
+      .map(|_| ())
+      .map_err(|e| e.to_string())
+  }
+
+  impl ObjectMirror for SqliteMirror {
+    fn reset(
+      &self,
+      storage_id: &str,
+      rows: &mut dyn Iterator<Item = Result<MirrorRow, String>>,
+    ) -> Result<(), String> {
+      let mut conn = self.conn.lock().unwrap();
+      let tx = conn.transaction().map_err(|e| e.to_string())?;
+      tx.execute(&format!("DROP TABLE IF EXISTS {}", table(storage_id)), [])
+        .map_err(|e| e.to_string())?;
+      create_table(&tx, storage_id)?;
+      for row in rows {
+        insert(&tx, storage_id, row?)?;
+      }
+      tx.commit().map_err(|e| e.to_string())
+    }
+    fn upsert(&self, storage_id: &str, row: MirrorRow) -> Result<(), String> {
+      let conn = self.conn.lock().unwrap();
+      create_table(&conn, storage_id)?;
+      insert(&conn, storage_id, row)
+    }
+    fn remove(&self, storage_id: &str, object_id: Uuid) -> Result<(), String> {
+      let conn = self.conn.lock().unwrap();
+      create_table(&conn, storage_id)?;
+      conn
+        .execute(
+          &format!("DELETE FROM {} WHERE id = ?1", table(storage_id)),
+          params![object_id.to_string()],
+        )
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+    }
+  }
+}
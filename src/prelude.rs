@@ -1,18 +1,202 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
 use serde::Serialize;
-use sha1::{Digest, Sha1};
+use serde_json::{Number, Value};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
 
+/// Object signature algorithms
+/// Signatures are prefixed by their algorithm name, e.g. sha256:<hex>.
+/// Unprefixed signatures are legacy sha1 ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureAlgorithm {
+  Sha1,
+  Sha256,
+}
+
+impl SignatureAlgorithm {
+  /// Algorithm of new signatures
+  pub const DEFAULT: Self = Self::Sha256;
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::Sha1 => "sha1",
+      Self::Sha256 => "sha256",
+    }
+  }
+  /// Algorithm and hex digest of signature
+  pub fn parse(signature: &str) -> Result<(Self, &str), String> {
+    match signature.split_once(':') {
+      Some(("sha1", digest)) => Ok((Self::Sha1, digest)),
+      Some(("sha256", digest)) => Ok((Self::Sha256, digest)),
+      Some((name, _)) => Err(format!("Unknown signature algorithm {}", name)),
+      None => Ok((Self::Sha1, signature)),
+    }
+  }
+  fn digest(&self, message: &[u8]) -> String {
+    match self {
+      Self::Sha1 => hex::encode(Sha1::digest(message)),
+      Self::Sha256 => hex::encode(Sha256::digest(message)),
+    }
+  }
+}
+
+/// Signature of object with the default algorithm, e.g. sha256:<hex>
+/// Computed over its canonical json, see canonical_json
+pub fn object_signature<T: Serialize>(object: &T) -> Result<String, String> {
+  let algorithm = SignatureAlgorithm::DEFAULT;
+  Ok(format!(
+    "{}:{}",
+    algorithm.name(),
+    algorithm.digest(&canonical_json(object)?)
+  ))
+}
+
+/// Check object signature of any supported algorithm
+/// Legacy sha1 signatures computed over plain serde_json output,
+/// before canonical serialization, are accepted as well
+pub fn verify_object_signature<T: Serialize>(
+  object: &T,
+  signature: &str,
+) -> Result<bool, String> {
+  let (algorithm, digest) = SignatureAlgorithm::parse(signature)?;
+  if algorithm.digest(&canonical_json(object)?) == digest {
+    return Ok(true);
+  }
+  if algorithm != SignatureAlgorithm::Sha1 {
+    return Ok(false);
+  }
+  let legacy = serde_json::to_vec(object).map_err(|e| e.to_string())?;
+  Ok(algorithm.digest(&legacy) == digest)
+}
+
+/// Unprefixed sha1 digest of object's canonical json
+/// Not used for integrity checks, only to identify content (e.g. schemas)
 pub fn sha1_signature<T: Serialize>(object: &T) -> Result<String, String> {
-  // create a Sha1 object
-  let mut hasher = Sha1::new();
-  // process input message
-  hasher.update(serde_json::to_string(object).unwrap());
-  // acquire hash digest in the form of GenericArray,
-  // which in this case is equivalent to [u8; 20]
-  let result = hasher.finalize();
-  let res = format!("{:x}", result);
+  Ok(SignatureAlgorithm::Sha1.digest(&canonical_json(object)?))
+}
+
+// Prefix of Ed25519 signatures
+const ED25519_PREFIX: &str = "ed25519:";
+
+/// Sign object (serialized as canonical json) with the given Ed25519 key
+/// Returns hex encoded signature prefixed by ed25519:
+pub fn ed25519_signature<T: Serialize>(
+  key: &SigningKey,
+  object: &T,
+) -> Result<String, String> {
+  let message = canonical_json(object)?;
+  Ok(format!(
+    "{}{}",
+    ED25519_PREFIX,
+    hex::encode(key.sign(&message).to_bytes())
+  ))
+}
+
+/// Verify hex encoded Ed25519 signature of object
+/// Unprefixed signatures and signatures of plain serde_json output,
+/// before canonical serialization, are accepted as well
+pub fn ed25519_verify<T: Serialize>(
+  key: &VerifyingKey,
+  object: &T,
+  signature: &str,
+) -> Result<bool, String> {
+  let signature = match signature.split_once(':') {
+    Some(_) => match signature.strip_prefix(ED25519_PREFIX) {
+      Some(signature) => signature,
+      None => return Ok(false),
+    },
+    None => signature,
+  };
+  let bytes = match hex::decode(signature) {
+    Ok(bytes) => bytes,
+    Err(_) => return Ok(false),
+  };
+  let signature = match Signature::from_slice(&bytes) {
+    Ok(signature) => signature,
+    Err(_) => return Ok(false),
+  };
+  if key.verify(&canonical_json(object)?, &signature).is_ok() {
+    return Ok(true);
+  }
+  let legacy = serde_json::to_vec(object).map_err(|e| e.to_string())?;
+  Ok(key.verify(&legacy, &signature).is_ok())
+}
+
+/// Canonical json of object, used for signatures
+/// No whitespace, object keys sorted by their bytes, integers in decimal,
+/// other numbers in shortest round-trip exponent form (e.g. 1.5e0).
+/// Integral floats are written as integers, so the same value gives
+/// the same bytes whatever field order or number format produced it.
+pub fn canonical_json<T: Serialize>(object: &T) -> Result<Vec<u8>, String> {
+  let value = serde_json::to_value(object).map_err(|e| e.to_string())?;
+  let mut res = vec![];
+  write_canonical(&value, &mut res)?;
   Ok(res)
 }
 
+fn write_canonical(value: &Value, out: &mut Vec<u8>) -> Result<(), String> {
+  match value {
+    Value::Object(map) => {
+      let mut entries: Vec<(&String, &Value)> = map.iter().collect();
+      entries.sort_by_key(|(key, _)| *key);
+      out.push(b'{');
+      for (index, (key, value)) in entries.into_iter().enumerate() {
+        if index > 0 {
+          out.push(b',');
+        }
+        serde_json::to_writer(&mut *out, key).map_err(|e| e.to_string())?;
+        out.push(b':');
+        write_canonical(value, out)?;
+      }
+      out.push(b'}');
+    }
+    Value::Array(items) => {
+      out.push(b'[');
+      for (index, item) in items.iter().enumerate() {
+        if index > 0 {
+          out.push(b',');
+        }
+        write_canonical(item, out)?;
+      }
+      out.push(b']');
+    }
+    Value::Number(number) => {
+      out.extend_from_slice(canonical_number(number).as_bytes())
+    }
+    value => {
+      serde_json::to_writer(&mut *out, value).map_err(|e| e.to_string())?
+    }
+  }
+  Ok(())
+}
+
+// Largest float written as an integer, 2^53
+const MAX_CANONICAL_INTEGRAL_FLOAT: f64 = 9007199254740992.0;
+
+fn canonical_number(number: &Number) -> String {
+  if let Some(i) = number.as_i64() {
+    return i.to_string();
+  }
+  if let Some(u) = number.as_u64() {
+    return u.to_string();
+  }
+  match number.as_f64() {
+    Some(f) if f.fract() == 0.0 && f.abs() <= MAX_CANONICAL_INTEGRAL_FLOAT => {
+      (f as i64).to_string()
+    }
+    Some(f) => format!("{:e}", f),
+    None => number.to_string(),
+  }
+}
+
+/// Parse hex encoded Ed25519 public key
+pub fn ed25519_public_key(public_key: &str) -> Result<VerifyingKey, String> {
+  let bytes: [u8; 32] = hex::decode(public_key)
+    .map_err(|_| "Public key is not a valid hex string".to_string())?
+    .try_into()
+    .map_err(|_| "Public key must be 32 bytes long".to_string())?;
+  VerifyingKey::from_bytes(&bytes).map_err(|e| e.to_string())
+}
+
 pub mod path_helper {
   use std::path::{Path, PathBuf};
 
@@ -20,20 +204,78 @@ pub mod path_helper {
 
   use crate::sync::{Context, ContextGuard};
 
+  pub fn storage_data_path(ctx: &Context, storage_id: &str) -> PathBuf {
+    ctx.db_root_path.join("storage_data").join(storage_id)
+  }
+
   pub fn storage_object_path(
     ctx: &Context,
     storage_id: &str,
     object_id: Uuid,
   ) -> PathBuf {
+    storage_data_path(ctx, storage_id).join(&object_id.as_simple().to_string())
+  }
+
+  pub fn storage_history_path(ctx: &Context, storage_id: &str) -> PathBuf {
+    ctx.db_root_path.join("storage_history").join(storage_id)
+  }
+
+  // Append-only log of the remote actions of a storage object
+  pub fn storage_object_log_path(
+    ctx: &Context,
+    storage_id: &str,
+    object_id: Uuid,
+  ) -> PathBuf {
+    storage_history_path(ctx, storage_id)
+      .join(object_id.as_simple().to_string())
+  }
+
+  pub fn storage_details_path(ctx: &Context, storage_id: &str) -> PathBuf {
+    ctx.db_root_path.join("storage_details").join(storage_id)
+  }
+
+  // Legal holds of a storage, see retention
+  pub fn storage_legal_holds_path(ctx: &Context, storage_id: &str) -> PathBuf {
     ctx
       .db_root_path
-      .join("storage_data")
+      .join("storage_retention")
       .join(storage_id)
-      .join(&object_id.as_simple().to_string())
+      .join("legal_holds")
   }
 
-  pub fn storage_details_path(ctx: &Context, storage_id: &str) -> PathBuf {
-    ctx.db_root_path.join("storage_details").join(storage_id)
+  // Tombstones of the purged objects of a storage
+  pub fn storage_tombstones_path(ctx: &Context, storage_id: &str) -> PathBuf {
+    ctx
+      .db_root_path
+      .join("storage_retention")
+      .join(storage_id)
+      .join("tombstones")
+  }
+
+  // Stashed remote actions of objects with unknown state, see redaction
+  pub fn storage_stash_path(ctx: &Context, storage_id: &str) -> PathBuf {
+    ctx.db_root_path.join("storage_stash").join(storage_id)
+  }
+
+  // Object state snapshots along the remote action chain, append only
+  pub fn storage_object_checkpoint_path(
+    ctx: &Context,
+    storage_id: &str,
+    object_id: Uuid,
+  ) -> PathBuf {
+    ctx
+      .db_root_path
+      .join("storage_checkpoints")
+      .join(storage_id)
+      .join(object_id.as_simple().to_string())
+  }
+
+  pub fn storage_object_stash_path(
+    ctx: &Context,
+    storage_id: &str,
+    object_id: Uuid,
+  ) -> PathBuf {
+    storage_stash_path(ctx, storage_id).join(object_id.as_simple().to_string())
   }
 
   pub fn commit_index(ctx: &Context) -> PathBuf {
@@ -51,9 +293,60 @@ pub mod path_helper {
   pub fn commit_remote_log(ctx: &Context) -> PathBuf {
     ctx.db_root_path.join("commit_remote_log")
   }
+
+  pub fn write_journal(ctx: &Context) -> PathBuf {
+    ctx.db_root_path.join("write_journal")
+  }
+
+  pub fn commit_intent(ctx: &Context) -> PathBuf {
+    ctx.db_root_path.join("commit_intent")
+  }
   pub fn repo_details(ctx: &Context) -> PathBuf {
     ctx.db_root_path.join("repo_details")
   }
+  pub fn repo_config(ctx: &Context) -> PathBuf {
+    ctx.db_root_path.join("repo_config")
+  }
+  pub fn repo_format(ctx: &Context) -> PathBuf {
+    ctx.db_root_path.join("FORMAT")
+  }
+  // Target format of a running format migration
+  pub fn repo_format_migration(ctx: &Context) -> PathBuf {
+    ctx.db_root_path.join("FORMAT_MIGRATION")
+  }
+  pub fn repo_lock(ctx: &Context) -> PathBuf {
+    ctx.db_root_path.join("LOCK")
+  }
+  pub fn audit_log(ctx: &Context) -> PathBuf {
+    ctx.db_root_path.join("audit_log")
+  }
+  pub fn device_id(ctx: &Context) -> PathBuf {
+    ctx.db_root_path.join("device_id")
+  }
+  // Device records of a server
+  pub fn devices(ctx: &Context) -> PathBuf {
+    ctx.db_root_path.join("devices")
+  }
+  pub fn quarantine_path(ctx: &Context, storage_id: &str) -> PathBuf {
+    ctx.db_root_path.join("quarantine").join(storage_id)
+  }
+
+  pub fn raw_actions_path(ctx: &Context, storage_id: &str) -> PathBuf {
+    ctx.db_root_path.join("storage_raw").join(storage_id)
+  }
+
+  pub fn projection_path(ctx: &Context, name: &str) -> PathBuf {
+    ctx.db_root_path.join("projections").join(name)
+  }
+
+  pub fn blobs_path(ctx: &Context) -> PathBuf {
+    ctx.db_root_path.join("blobs")
+  }
+
+  // Content addressed blob, id is its sha256 hex digest
+  pub fn blob_path(ctx: &Context, blob_id: &str) -> PathBuf {
+    blobs_path(ctx).join(blob_id)
+  }
 }
 
 #[cfg(test)]
@@ -70,7 +363,68 @@ mod tests {
       name: "Peti".into(),
       age: 34,
     });
-    println!("{:?}", &signature);
     assert_eq!(signature.is_ok(), true);
   }
+  #[test]
+  fn test_ed25519_signature() {
+    #[derive(Serialize)]
+    struct User {
+      name: String,
+      age: i32,
+    }
+    let user = User {
+      name: "Peti".into(),
+      age: 34,
+    };
+    let key = SigningKey::generate(&mut rand_core::OsRng);
+    let signature = ed25519_signature(&key, &user).unwrap();
+    let public_key =
+      ed25519_public_key(&hex::encode(key.verifying_key().to_bytes())).unwrap();
+    assert!(ed25519_verify(&public_key, &user, &signature).unwrap());
+    let forged = sha1_signature(&user).unwrap();
+    assert!(!ed25519_verify(&public_key, &user, &forged).unwrap());
+    // Signature of the plain json form is still valid
+    let legacy =
+      hex::encode(key.sign(&serde_json::to_vec(&user).unwrap()).to_bytes());
+    assert!(ed25519_verify(&public_key, &user, &legacy).unwrap());
+  }
+  #[test]
+  fn test_canonical_json() {
+    let a = serde_json::json!({ "b": [1.0, 0.5, -2], "a": { "y": null, "x": "é\"" } });
+    assert_eq!(
+      String::from_utf8(canonical_json(&a).unwrap()).unwrap(),
+      r#"{"a":{"x":"é\"","y":null},"b":[1,5e-1,-2]}"#
+    );
+    #[derive(Serialize)]
+    struct User {
+      name: String,
+      age: f64,
+    }
+    let user = User {
+      name: "Peti".into(),
+      age: 34.0,
+    };
+    let reordered = serde_json::json!({ "age": 34, "name": "Peti" });
+    assert_eq!(
+      sha1_signature(&user).unwrap(),
+      sha1_signature(&reordered).unwrap()
+    );
+    let legacy =
+      SignatureAlgorithm::Sha1.digest(&serde_json::to_vec(&user).unwrap());
+    assert!(verify_object_signature(&user, &legacy).unwrap());
+    assert!(!verify_object_signature(&user, "x").unwrap());
+  }
+  #[test]
+  fn test_object_signature() {
+    let user = serde_json::json!({ "name": "Peti", "age": 34 });
+    let signature = object_signature(&user).unwrap();
+    assert!(signature.starts_with("sha256:"));
+    assert!(verify_object_signature(&user, &signature).unwrap());
+    let sha1 = sha1_signature(&user).unwrap();
+    assert!(verify_object_signature(&user, &sha1).unwrap());
+    assert!(verify_object_signature(&user, &format!("sha1:{}", sha1)).unwrap());
+    let other = serde_json::json!({ "name": "Peti", "age": 35 });
+    assert!(!verify_object_signature(&other, &signature).unwrap());
+    assert!(verify_object_signature(&user, "md5:00").is_err());
+  }
 }
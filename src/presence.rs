@@ -0,0 +1,134 @@
+//! Soft real-time presence of collaborators
+//! Clients announce the objects of a storage they have open, see
+//! Repository::announce_presence. The server keeps the latest
+//! announcement of every device per storage, and streams the changes to
+//! the watchers asking for presence, see Context::with_presence, so
+//! collaborative UIs can show who else is editing an object.
+//!
+//! Presence is kept in memory only. Announcements expire after
+//! PRESENCE_TTL, so clients announce again while objects stay open,
+//! and an empty announcement closes them.
+
+use std::{collections::HashMap, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Announcements expire after, unless announced again
+pub const PRESENCE_TTL: Duration = Duration::from_secs(30);
+
+/// Objects of a storage a device has open
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Presence {
+  pub device_id: String,
+  // Authenticated uid, or the one claimed if the server does not
+  // authenticate
+  pub uid: String,
+  pub storage_id: String,
+  // Empty once the device closed every object of the storage
+  pub object_ids: Vec<Uuid>,
+  // Announced at
+  pub at: DateTime<Utc>,
+}
+
+impl Presence {
+  pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+    now
+      .signed_duration_since(self.at)
+      .to_std()
+      .unwrap_or_default()
+      >= PRESENCE_TTL
+  }
+  pub fn has_open(&self, object_id: Uuid) -> bool {
+    self.object_ids.contains(&object_id)
+  }
+}
+
+// Latest presence by device and storage
+#[derive(Default)]
+pub(crate) struct PresenceMap {
+  entries: HashMap<(String, String), Presence>,
+}
+
+impl PresenceMap {
+  // Replace the presence of the device in the storage
+  // Returns whether its open objects changed
+  pub(crate) fn update(&mut self, presence: Presence) -> bool {
+    let key = (presence.device_id.clone(), presence.storage_id.clone());
+    let new_ids = presence.object_ids.clone();
+    let old = match new_ids.is_empty() {
+      true => self.entries.remove(&key),
+      false => self.entries.insert(key, presence),
+    };
+    old.map(|p| p.object_ids).unwrap_or_default() != new_ids
+  }
+  // Remove expired presences, returned with their objects closed
+  pub(crate) fn prune(&mut self, now: DateTime<Utc>) -> Vec<Presence> {
+    let mut expired = vec![];
+    self
+      .entries
+      .retain(|_, presence| match presence.is_expired(now) {
+        true => {
+          expired.push(Presence {
+            object_ids: vec![],
+            ..presence.clone()
+          });
+          false
+        }
+        false => true,
+      });
+    expired
+  }
+  // Presences not expired yet, in the storage if any
+  pub(crate) fn current(
+    &self,
+    storage_id: Option<&str>,
+    now: DateTime<Utc>,
+  ) -> Vec<Presence> {
+    self
+      .entries
+      .values()
+      .filter(|p| storage_id.is_none_or(|id| p.storage_id == id))
+      .filter(|p| !p.is_expired(now))
+      .cloned()
+      .collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_presence_map() {
+    let now = Utc::now();
+    let object_id = Uuid::new_v4();
+    let presence = Presence {
+      device_id: "d1".to_string(),
+      uid: "anna".to_string(),
+      storage_id: "users".to_string(),
+      object_ids: vec![object_id],
+      at: now,
+    };
+    let mut map = PresenceMap::default();
+    assert!(map.update(presence.clone()));
+    // Announcing again only refreshes it
+    assert!(!map.update(presence.clone()));
+    assert_eq!(map.current(Some("users"), now), vec![presence.clone()]);
+    assert!(map.current(Some("orders"), now).is_empty());
+    // Expired ones are pruned with their objects closed
+    let later = now + chrono::Duration::from_std(PRESENCE_TTL).unwrap();
+    assert!(map.current(None, later).is_empty());
+    let expired = map.prune(later);
+    assert_eq!(expired.len(), 1);
+    assert!(!expired[0].has_open(object_id));
+    // Empty announcement closes every object
+    map.update(presence.clone());
+    assert!(map.update(Presence {
+      object_ids: vec![],
+      ..presence
+    }));
+    assert!(map.current(None, now).is_empty());
+  }
+}
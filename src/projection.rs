@@ -0,0 +1,102 @@
+use std::{
+  fmt::Debug,
+  sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+  fs::{binary_init_empty, binary_read, binary_update},
+  prelude::path_helper,
+  sync::{Commit, Context, UniversalActionObject},
+};
+
+/// Derived read model maintained from the applied action stream
+/// e.g. totals per customer. The repository feeds it every action object
+/// it applies, local and remote alike, and persists its state after
+/// every commit. See Repository::add_projection
+pub trait Projection:
+  Default + Debug + Serialize + for<'de> Deserialize<'de> + Send + 'static
+{
+  /// Apply an action object of the given storage
+  fn apply(&mut self, storage_id: &str, action_object: &UniversalActionObject);
+}
+
+// Persisted projection state
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct ProjectionRecord<P> {
+  // Latest commit applied on the state
+  commit_id: Option<Uuid>,
+  state: P,
+}
+
+/// Registered projection
+/// Shares its state with the repository feeding it
+pub struct ProjectionHandle<P> {
+  name: String,
+  record: Arc<Mutex<ProjectionRecord<P>>>,
+}
+
+impl<P> Clone for ProjectionHandle<P> {
+  fn clone(&self) -> Self {
+    Self {
+      name: self.name.clone(),
+      record: self.record.clone(),
+    }
+  }
+}
+
+impl<P: Projection> ProjectionHandle<P> {
+  // Load persisted state
+  // Default state if there is none or it is unreadable (e.g. P changed),
+  // it is rebuilt from the commit log then
+  pub(crate) fn load(ctx: &Context, name: &str) -> Self {
+    let path = path_helper::projection_path(ctx, name);
+    let record = match ctx.backend().exists(&path) {
+      true => binary_read(ctx, path).unwrap_or_else(|e| {
+        warn!(projection = name, error = %e, "Projection state is unreadable");
+        ProjectionRecord::default()
+      }),
+      false => ProjectionRecord::default(),
+    };
+    Self {
+      name: name.to_string(),
+      record: Arc::new(Mutex::new(record)),
+    }
+  }
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+  /// Read the projection state
+  pub fn read<R>(&self, f: impl FnOnce(&P) -> R) -> R {
+    f(&self.record.lock().unwrap().state)
+  }
+  // Latest commit applied on the state
+  pub(crate) fn commit_id(&self) -> Option<Uuid> {
+    self.record.lock().unwrap().commit_id
+  }
+  // Apply every action object of the commit
+  pub(crate) fn apply(&self, commit: &Commit) -> Result<(), String> {
+    let mut record = self.record.lock().unwrap();
+    for aob_str in commit.serialized_actions() {
+      let aob: UniversalActionObject =
+        serde_json::from_str(aob_str).map_err(|e| e.to_string())?;
+      record.state.apply(aob.storage_id(), &aob);
+    }
+    record.commit_id = Some(commit.id());
+    Ok(())
+  }
+  // Reset state to its default
+  pub(crate) fn reset(&self) {
+    *self.record.lock().unwrap() = ProjectionRecord::default();
+  }
+  // Persist state
+  pub(crate) fn save(&self, ctx: &Context) -> Result<(), String> {
+    let path = path_helper::projection_path(ctx, &self.name);
+    if !ctx.backend().exists(&path) {
+      binary_init_empty(ctx, path.clone())?;
+    }
+    binary_update(ctx, path, &*self.record.lock().unwrap())
+  }
+}
@@ -0,0 +1,184 @@
+use std::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+/// Field comparison operator
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Op {
+  Eq,
+  Ne,
+  Lt,
+  Le,
+  Gt,
+  Ge,
+  /// Substring of a string, or element of an array
+  Contains,
+}
+
+/// Declarative query over storage objects
+/// Evaluated against the json representation of T,
+/// so it can be sent over the wire.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Query {
+  /// Compare the field at the dot separated path with value
+  /// Missing fields are null
+  Field {
+    path: String,
+    op: Op,
+    value: Value,
+  },
+  /// All of the queries match, true if empty
+  And(Vec<Query>),
+  /// Any of the queries match, false if empty
+  Or(Vec<Query>),
+  Not(Box<Query>),
+}
+
+impl Query {
+  pub fn field(path: &str, op: Op, value: impl Into<Value>) -> Self {
+    Self::Field {
+      path: path.to_string(),
+      op,
+      value: value.into(),
+    }
+  }
+  pub fn eq(path: &str, value: impl Into<Value>) -> Self {
+    Self::field(path, Op::Eq, value)
+  }
+  pub fn and(self, other: Query) -> Self {
+    match self {
+      Self::And(mut queries) => {
+        queries.push(other);
+        Self::And(queries)
+      }
+      query => Self::And(vec![query, other]),
+    }
+  }
+  pub fn or(self, other: Query) -> Self {
+    match self {
+      Self::Or(mut queries) => {
+        queries.push(other);
+        Self::Or(queries)
+      }
+      query => Self::Or(vec![query, other]),
+    }
+  }
+  /// Check whether the json object matches the query
+  pub fn matches(&self, object: &Value) -> bool {
+    match self {
+      Self::Field { path, op, value } => {
+        compare(field_at(object, path), *op, value)
+      }
+      Self::And(queries) => queries.iter().all(|q| q.matches(object)),
+      Self::Or(queries) => queries.iter().any(|q| q.matches(object)),
+      Self::Not(query) => !query.matches(object),
+    }
+  }
+}
+
+impl std::ops::Not for Query {
+  type Output = Query;
+  fn not(self) -> Self::Output {
+    Self::Not(Box::new(self))
+  }
+}
+
+// Numbers are compared by value, strings lexicographically
+// Other values are only (in)equal
+// Field at the dot separated path, null if missing
+// Array elements are addressed by their index
+pub(crate) fn field_at<'a>(object: &'a Value, path: &str) -> &'a Value {
+  path
+    .split('.')
+    .try_fold(object, |v, key| match v {
+      Value::Object(map) => map.get(key),
+      Value::Array(items) => {
+        key.parse::<usize>().ok().and_then(|i| items.get(i))
+      }
+      _ => None,
+    })
+    .unwrap_or(&Value::Null)
+}
+
+fn compare(field: &Value, op: Op, value: &Value) -> bool {
+  let ordering = match (field, value) {
+    (Value::Number(a), Value::Number(b)) => a
+      .as_f64()
+      .zip(b.as_f64())
+      .and_then(|(a, b)| a.partial_cmp(&b)),
+    (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+    (a, b) => (a == b).then_some(Ordering::Equal),
+  };
+  match op {
+    Op::Eq => ordering == Some(Ordering::Equal),
+    Op::Ne => ordering != Some(Ordering::Equal),
+    Op::Lt => ordering == Some(Ordering::Less),
+    Op::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+    Op::Gt => ordering == Some(Ordering::Greater),
+    Op::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+    Op::Contains => match (field, value) {
+      (Value::String(a), Value::String(b)) => a.contains(b.as_str()),
+      (Value::Array(items), value) => {
+        items.iter().any(|item| compare(item, Op::Eq, value))
+      }
+      _ => false,
+    },
+  }
+}
+
+/// Storage object matching a query
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QueryResult {
+  pub object_id: Uuid,
+  // T as json
+  pub object: Value,
+}
+
+#[cfg(test)]
+mod tests {
+  use serde_json::json;
+
+  use super::*;
+
+  #[test]
+  fn test_query_matches() {
+    let object = json!({
+      "name": "Peter",
+      "age": 31,
+      "address": { "city": "Budapest" },
+      "tags": ["admin", "dev"]
+    });
+    assert!(Query::eq("name", "Peter").matches(&object));
+    assert!(Query::field("age", Op::Gt, 30.5).matches(&object));
+    assert!(Query::eq("address.city", "Budapest")
+      .and(Query::field("tags", Op::Contains, "dev"))
+      .matches(&object));
+    assert!(Query::eq("tags.1", "dev").matches(&object));
+    assert!(Query::eq("missing", Value::Null).matches(&object));
+    assert!(!Query::field("age", Op::Lt, "31").matches(&object));
+    assert!(Query::eq("age", 1)
+      .or(Query::field("name", Op::Contains, "et"))
+      .matches(&object));
+    assert!(!Query::Or(vec![]).matches(&object));
+    assert!((!Query::eq("name", "Paul")).matches(&object));
+  }
+
+  #[test]
+  fn test_query_json() {
+    let query: Query = serde_json::from_value(json!({
+      "and": [
+        { "field": { "path": "age", "op": "ge", "value": 18 } },
+        { "not": { "field": { "path": "name", "op": "eq", "value": "x" } } }
+      ]
+    }))
+    .unwrap();
+    assert_eq!(
+      query,
+      Query::field("age", Op::Ge, 18).and(!Query::eq("name", "x"))
+    );
+  }
+}
@@ -0,0 +1,67 @@
+//! Redaction of action payloads in the history
+//! Sensitive payloads, e.g. an accidentally committed password, are
+//! replaced by a Redacted marker recording the kind and hash of the
+//! original action. Redacted action objects keep their ids, parents
+//! and object signatures, so action chains stay intact.
+//! The server records the operation in a signed redaction commit,
+//! carrying the redacted action objects in its meta, which replicas
+//! apply on pull. It also restores the current state of the redacted
+//! objects, as the state after a redacted action cannot be replayed,
+//! e.g. by fresh clones. See Repository::redact.
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{prelude::object_signature, retention::PURGED_ACTION};
+
+/// Commit meta key of the redacted action objects (JSON array)
+pub const REDACTION_META: &str = "redaction";
+
+/// Action kind of redacted action objects, e.g.
+/// {"Redacted": {"kind": "Patch", "hash": "sha256:<hex>"}}
+pub const REDACTED_ACTION: &str = "Redacted";
+
+/// Redacted action
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RedactedAction {
+  // Kind of the original action, Create, Patch or Restore
+  pub kind: String,
+  // Hash of the original action
+  pub hash: String,
+}
+
+// Redacted marker of the given action
+// Only actions with payload can be redacted, and only once
+pub(crate) fn redacted_action(action: &Value) -> Result<Value, String> {
+  let kind = match action {
+    Value::Object(map) if map.len() == 1 => map.keys().next().unwrap(),
+    _ => return Err("Only actions with payload can be redacted".into()),
+  };
+  if kind == REDACTED_ACTION || kind == PURGED_ACTION {
+    return Err("Action is already redacted".into());
+  }
+  let redacted = RedactedAction {
+    kind: kind.to_string(),
+    hash: object_signature(action)?,
+  };
+  Ok(json!({ REDACTED_ACTION: redacted }))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_redacted_action() {
+    let action = json!({ "Patch": { "SetPassword": "secret" } });
+    let redacted = redacted_action(&action).unwrap();
+    let marker: RedactedAction =
+      serde_json::from_value(redacted[REDACTED_ACTION].clone()).unwrap();
+    assert_eq!(marker.kind, "Patch");
+    assert_eq!(marker.hash, object_signature(&action).unwrap());
+    assert!(!redacted.to_string().contains("secret"));
+    // Nothing to redact, or already redacted
+    assert!(redacted_action(&json!("Remove")).is_err());
+    assert!(redacted_action(&redacted).is_err());
+  }
+}
@@ -0,0 +1,107 @@
+//! Retention of removed objects and legal holds
+//! Payloads of objects removed longer than the retention period are
+//! purged from object files and commit logs alike, keeping only a
+//! tombstone. Purged action objects keep their ids, parents and object
+//! signatures, so action chains stay intact, and record the hash of the
+//! purged action instead of it. Objects under legal hold are kept.
+
+use std::collections::BTreeSet;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::prelude::object_signature;
+
+/// Action kind of purged action objects, e.g. {"Purged": "sha256:<hex>"}
+pub const PURGED_ACTION: &str = "Purged";
+
+/// Retention policy of a storage, see Storage::with_retention
+#[derive(Debug, Clone, Default)]
+pub struct Retention {
+  pub(crate) purge_removed_after: Option<Duration>,
+}
+
+impl Retention {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  /// Purge objects removed longer than the given period
+  /// by Repository::purge
+  pub fn with_purge_removed_after(mut self, period: Duration) -> Self {
+    self.purge_removed_after = Some(period);
+    self
+  }
+  // Whether an object removed at the given time is due
+  pub(crate) fn is_due(&self, removed_at: DateTime<Utc>) -> bool {
+    self
+      .purge_removed_after
+      .is_some_and(|period| Utc::now() - removed_at >= period)
+  }
+}
+
+/// Legal holds of a storage
+/// Held objects are never purged, neither is anything if the whole
+/// storage is held.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LegalHolds {
+  pub storage: bool,
+  pub objects: BTreeSet<Uuid>,
+}
+
+impl LegalHolds {
+  pub fn is_held(&self, object_id: Uuid) -> bool {
+    self.storage || self.objects.contains(&object_id)
+  }
+}
+
+/// Purged object
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tombstone {
+  pub object_id: Uuid,
+  pub removed_at: DateTime<Utc>,
+  pub purged_at: DateTime<Utc>,
+  // Latest action and object state signature
+  pub last_action_id: Uuid,
+  pub object_signature: String,
+}
+
+/// Purge result of a storage
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct StoragePurgeReport {
+  pub storage_id: String,
+  pub purged_objects: Vec<Uuid>,
+  // Due objects kept by legal hold
+  pub held_objects: Vec<Uuid>,
+  pub purged_actions: usize,
+}
+
+/// Purge result of the repository
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct PurgeReport {
+  pub storages: Vec<StoragePurgeReport>,
+  pub rewritten_commits: usize,
+  // Blobs referred only by purged actions
+  pub deleted_blobs: usize,
+}
+
+// Purged action, recording the hash of the given one
+pub(crate) fn purged_action(action: &Value) -> Result<Value, String> {
+  Ok(json!({ PURGED_ACTION: object_signature(action)? }))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_due_after_retention_period() {
+    let retention =
+      Retention::new().with_purge_removed_after(Duration::days(30));
+    assert!(!retention.is_due(Utc::now() - Duration::days(29)));
+    assert!(retention.is_due(Utc::now() - Duration::days(31)));
+    // Without period nothing is purged
+    assert!(!Retention::new().is_due(Utc::now() - Duration::days(365)));
+  }
+}
@@ -0,0 +1,93 @@
+//! JSON Schema export for non-Rust clients
+//! Writes the schemas of the registered storage types and of the
+//! commit and action object envelopes to a directory, so HTTP gateway
+//! consumers can generate their types and validate payloads:
+//!
+//!   commit.schema.json
+//!   action_object.schema.json
+//!   storages/<storage_id>.object.schema.json (T)
+//!   storages/<storage_id>.action.schema.json (A)
+//!   index.json (storage ids with their schema files and hashes)
+//!
+//! Commits carry their action objects as JSON strings of the action
+//! object envelope. Its action is {"Create": T}, {"Patch": A},
+//! {"Restore": T}, "Remove", or {"Redacted": {"kind", "hash"}}.
+
+use std::{
+  fs,
+  path::{Path, PathBuf},
+};
+
+use schemars::schema_for;
+use serde_json::{json, Map, Value};
+
+use crate::sync::{Commit, Repository, UniversalActionObject};
+
+/// File name of the schema index
+pub const INDEX_FILE: &str = "index.json";
+
+/// JSON Schema of the commit envelope
+pub fn commit_schema() -> Value {
+  json!(schema_for!(Commit))
+}
+
+/// JSON Schema of the action object envelope
+/// Its action is any JSON, see the storage schemas
+pub fn action_object_schema() -> Value {
+  json!(schema_for!(UniversalActionObject))
+}
+
+/// Write the schemas of the envelopes and of every registered storage
+/// to the given directory, creating it if needed
+/// Existing schema files are overwritten. Returns the written paths
+pub fn export_schemas(
+  repo: &Repository,
+  dir: &Path,
+) -> Result<Vec<PathBuf>, String> {
+  let storages_dir = dir.join("storages");
+  fs::create_dir_all(&storages_dir).map_err(|e| {
+    format!(
+      "Error creating schema dir {}: {}",
+      storages_dir.display(),
+      e
+    )
+  })?;
+  let mut written = vec![
+    write_schema(&dir.join("commit.schema.json"), &commit_schema())?,
+    write_schema(
+      &dir.join("action_object.schema.json"),
+      &action_object_schema(),
+    )?,
+  ];
+  let mut index = Map::new();
+  for (storage_id, schema) in repo.storage_schemas() {
+    let object = format!("storages/{}.object.schema.json", storage_id);
+    let action = format!("storages/{}.action.schema.json", storage_id);
+    written.push(write_schema(&dir.join(&object), &parse(&schema.object)?)?);
+    written.push(write_schema(&dir.join(&action), &parse(&schema.action)?)?);
+    index.insert(
+      storage_id,
+      json!({ "object": object, "action": action, "hash": schema.hash }),
+    );
+  }
+  let index = json!({
+    "commit": "commit.schema.json",
+    "action_object": "action_object.schema.json",
+    "storages": index,
+  });
+  written.push(write_schema(&dir.join(INDEX_FILE), &index)?);
+  Ok(written)
+}
+
+fn parse(schema: &str) -> Result<Value, String> {
+  serde_json::from_str(schema)
+    .map_err(|e| format!("Error deser storage schema: {}", e))
+}
+
+fn write_schema(path: &Path, schema: &Value) -> Result<PathBuf, String> {
+  let s = serde_json::to_string_pretty(schema)
+    .map_err(|e| format!("Error serializing schema: {}", e))?;
+  fs::write(path, s)
+    .map_err(|e| format!("Error writing {}: {}", path.display(), e))?;
+  Ok(path.to_path_buf())
+}
@@ -1,23 +1,369 @@
-use crate::sync::Repository;
+use crate::audit::{AuditAction, AuditEvent};
+use crate::auth::{
+  AdminAccess, AuthProvider, AuthenticatedUid, POLICY_VIOLATION,
+};
+use crate::blob::{self, BlobAssembler};
+use crate::codec::ActionCodec;
+use crate::device::{DeviceRecord, DEVICE_ID_META};
+use crate::limits::{client_key, Limiter, Limits, Rejection};
+use crate::presence::Presence;
+use crate::query::{Query, QueryResult};
+use crate::sync::{Commit, Repository};
+use crate::usage::QUOTA_EXCEEDED;
 use async_stream::stream;
+use chrono::{DateTime, Utc};
+use futures::future::Either;
 use futures::pin_mut;
 use futures_util::stream::StreamExt;
+use health_api::health_check_response::ServingStatus;
+use health_api::health_server::Health;
+use health_api::{HealthCheckRequest, HealthCheckResponse};
+use prost::Message;
+use std::collections::HashSet;
+use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
 use sync_api::api_server::{Api, ApiServer};
-use sync_api::{CommitObj, PullRequest};
+use sync_api::{
+  BlobChunk, BlobList, CommitChunk, CommitObj, DeviceInfo, DeviceRequest,
+  InfoRequest, InfoResponse, ListDevicesRequest, ListDevicesResponse,
+  PresenceInfo, PresenceList, PresenceRequest, PublicKeyRequest,
+  PublicKeyResponse, PullRequest, QueryObject, QueryRequest, QueryResponse,
+  RegisterRequest, RegisterResponse, WatchRequest,
+};
+use tokio::sync::broadcast::error::RecvError;
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::codegen::futures_core::Stream;
-use tonic::{transport::Server, Request, Response, Status};
+use tonic::{transport::Server, Code, Request, Response, Status, Streaming};
+use tracing::{field, instrument, warn, Span};
 use uuid::Uuid;
 
 pub mod sync_api {
   tonic::include_proto!("sync_api");
 }
 
+pub mod health_api {
+  tonic::include_proto!("grpc.health.v1");
+}
+
+/// Wire protocol version
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest wire protocol version still served
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Features every server supports
+pub const SERVER_FEATURES: &[&str] = &[
+  "watch",
+  "partial_pull",
+  "remote_signatures",
+  "compaction",
+  "chunked_push",
+  "query",
+  "blobs",
+  "delta",
+  "cbor_actions",
+  "devices",
+  "presence",
+];
+
+/// Default largest sync message size in bytes (gRPC default)
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Default largest commit reassembled from chunks in bytes
+pub const DEFAULT_MAX_COMMIT_SIZE: usize = 256 * 1024 * 1024;
+
+/// Error prefix when a client cursor is unknown to the server,
+/// so its history must be downloaded from scratch
+pub const RESYNC_REQUIRED: &str = "Resync required";
+
+// Room left in a chunk for its other fields
+const CHUNK_HEADER_RESERVE: usize = 1024;
+// Encoding overhead of a single action object in a chunk
+const CHUNK_ACTION_OVERHEAD: usize = 16;
+
+// Service name of the sync api in health checks
+const API_SERVICE_NAME: &str = "sync_api.Api";
+
+/// Negotiate protocol version with a peer
+/// Legacy peers not sending their version (0) speak version 1.
+/// Newer peers are downgraded to our version, older unsupported
+/// ones are rejected.
+pub fn negotiate_protocol_version(peer_version: u32) -> Result<u32, String> {
+  let peer_version = peer_version.max(1);
+  if peer_version < MIN_PROTOCOL_VERSION {
+    return Err(format!(
+      "Unsupported protocol version {}. Supported: {}-{}",
+      peer_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+    ));
+  }
+  Ok(peer_version.min(PROTOCOL_VERSION))
+}
+
+// Commit sent to client using the negotiated protocol version
+// Action objects are encoded by codec, or left in the JSON object
+// if Json or failed to encode
+fn commit_obj(
+  commit: &Commit,
+  protocol_version: u32,
+  codec: ActionCodec,
+) -> CommitObj {
+  let mut res = CommitObj {
+    obj_json_string: String::new(),
+    client_id: String::new(),
+    protocol_version,
+    action_codec: String::new(),
+    encoded_actions: vec![],
+    presence: vec![],
+  };
+  if codec != ActionCodec::Json {
+    let mut header = commit.clone();
+    match codec.encode(&header.take_serialized_actions()) {
+      Ok(encoded_actions) => {
+        res.obj_json_string = serde_json::to_string(&header).unwrap();
+        res.action_codec = codec.name().to_string();
+        res.encoded_actions = encoded_actions;
+        return res;
+      }
+      Err(e) => warn!(error = %e, "Sending action objects as JSON"),
+    }
+  }
+  res.obj_json_string = serde_json::to_string(commit).unwrap();
+  res
+}
+
+// Watch message of presence changes
+fn presence_obj(changes: &[Presence], protocol_version: u32) -> CommitObj {
+  CommitObj {
+    obj_json_string: String::new(),
+    client_id: String::new(),
+    protocol_version,
+    action_codec: String::new(),
+    encoded_actions: vec![],
+    presence: changes.iter().map(PresenceInfo::from).collect(),
+  }
+}
+
+/// Split commit into chunks not larger than max_message_size
+/// First chunk holds the commit header, the rest its action objects.
+pub fn commit_chunks(
+  mut commit: Commit,
+  max_message_size: usize,
+  client_id: &str,
+  protocol_version: u32,
+) -> Result<Vec<CommitChunk>, String> {
+  let budget = max_message_size.saturating_sub(CHUNK_HEADER_RESERVE);
+  let actions = commit.take_serialized_actions();
+  let header_json_string =
+    serde_json::to_string(&commit).map_err(|e| e.to_string())?;
+  let chunk = |header_json_string, serialized_actions| CommitChunk {
+    header_json_string,
+    serialized_actions,
+    last: false,
+    client_id: client_id.to_string(),
+    protocol_version,
+  };
+  let mut chunks = vec![chunk(header_json_string, vec![])];
+  let mut batch = vec![];
+  let mut batch_size = 0;
+  for action in actions {
+    let size = action.len() + CHUNK_ACTION_OVERHEAD;
+    if size > budget {
+      return Err(format!(
+        "Action object of commit {} exceeds max message size",
+        commit.id()
+      ));
+    }
+    if batch_size + size > budget {
+      chunks.push(chunk(String::new(), std::mem::take(&mut batch)));
+      batch_size = 0;
+    }
+    batch.push(action);
+    batch_size += size;
+  }
+  if !batch.is_empty() {
+    chunks.push(chunk(String::new(), batch));
+  }
+  if let Some(last) = chunks.last_mut() {
+    last.last = true;
+  }
+  Ok(chunks)
+}
+
+/// Reassemble commit from its chunks
+/// Streams larger than the max commit size are rejected,
+/// see DEFAULT_MAX_COMMIT_SIZE
+pub struct ChunkAssembler {
+  max_message_size: usize,
+  max_commit_size: usize,
+  header: Option<Commit>,
+  actions: Vec<String>,
+  // Bytes received, measured as commit_chunks does
+  received_bytes: usize,
+  received_chunks: usize,
+}
+
+impl ChunkAssembler {
+  pub fn new(max_message_size: usize) -> Self {
+    Self {
+      max_message_size,
+      max_commit_size: DEFAULT_MAX_COMMIT_SIZE,
+      header: None,
+      actions: vec![],
+      received_bytes: 0,
+      received_chunks: 0,
+    }
+  }
+  /// Set largest reassembled commit in bytes
+  pub fn with_max_commit_size(mut self, max_commit_size: usize) -> Self {
+    self.max_commit_size = max_commit_size;
+    self
+  }
+  // Most chunks of a commit within the max commit size
+  // Any two consecutive chunks of commit_chunks hold more than a
+  // chunk budget, and the header and the last chunk come on top
+  fn max_chunks(&self) -> usize {
+    let budget = self.max_message_size.saturating_sub(CHUNK_HEADER_RESERVE);
+    2 * self.max_commit_size / budget.max(1) + 2
+  }
+  /// Add next chunk
+  /// Returns the commit once its terminal chunk arrived
+  pub fn push(&mut self, chunk: CommitChunk) -> Result<Option<Commit>, String> {
+    if chunk.encoded_len() > self.max_message_size {
+      return Err("Commit chunk exceeds max message size".to_string());
+    }
+    self.received_chunks += 1;
+    self.received_bytes += chunk.header_json_string.len()
+      + chunk
+        .serialized_actions
+        .iter()
+        .map(|action| action.len() + CHUNK_ACTION_OVERHEAD)
+        .sum::<usize>();
+    if self.received_bytes > self.max_commit_size {
+      return Err(format!(
+        "Chunked commit exceeds max commit size of {} bytes",
+        self.max_commit_size
+      ));
+    }
+    if self.received_chunks > self.max_chunks() {
+      return Err(format!(
+        "Chunked commit exceeds {} chunks",
+        self.max_chunks()
+      ));
+    }
+    if self.header.is_none() {
+      let header = serde_json::from_str(&chunk.header_json_string)
+        .map_err(|_| "First commit chunk has no valid header".to_string())?;
+      self.header = Some(header);
+    }
+    self.actions.extend(chunk.serialized_actions);
+    if !chunk.last {
+      return Ok(None);
+    }
+    let mut commit = self.header.take().unwrap();
+    commit.extend_serialized_actions(std::mem::take(&mut self.actions));
+    Ok(Some(commit))
+  }
+}
+
+// Collect remote commits after the given commit id
+// or all of them if commit id is empty
+// None if the commit id is unknown
+fn commits_after(
+  repo: &Repository,
+  commit_id_str: &str,
+) -> Result<Option<Vec<Commit>>, String> {
+  match commit_id_str.len() > 0 {
+    true => {
+      let after_id = Uuid::parse_str(commit_id_str)
+        .map_err(|_| "Wrong commit_id format".to_string())?;
+      repo.remote_commits_after(after_id)
+    }
+    false => repo
+      .remote_commits()
+      .map(Some)
+      .map_err(|_| "Error collecting remote logs".to_string()),
+  }
+}
+
+// Instruct client to download history from scratch
+fn resync_required(commit_id_str: &str) -> Status {
+  Status::out_of_range(format!(
+    "{}: unknown commit {}. History might be compacted",
+    RESYNC_REQUIRED, commit_id_str
+  ))
+}
+
+impl Repository {
+  // Merge pushed commit within the limits of client
+  fn merge_limited(
+    &self,
+    limiter: Option<&Limiter>,
+    client: &str,
+    commit_json: &str,
+    uid: Option<&str>,
+  ) -> Result<Commit, Rejection> {
+    if let Some(limiter) = limiter {
+      limiter
+        .reserve_commit(client, commit_json)
+        .inspect_err(|_| {
+          self.metrics().push_rejected();
+        })?;
+    }
+    let res = self.merge_pushed_commit(commit_json, uid).map_err(|e| {
+      if let Some(limiter) = limiter {
+        limiter.release_commit(client);
+      }
+      let code = match e {
+        _ if e.starts_with(POLICY_VIOLATION) => Code::PermissionDenied,
+        _ if e.starts_with(QUOTA_EXCEEDED) => Code::ResourceExhausted,
+        _ => Code::FailedPrecondition,
+      };
+      Rejection { code, message: e }
+    })?;
+    if let Some(device_id) = res.meta().get(DEVICE_ID_META) {
+      self.record_device_push(device_id);
+    }
+    Ok(res)
+  }
+  // Admin requests need admin access, denied ones are audited
+  // Returns the admin uid
+  fn check_admin<T>(
+    &self,
+    request: &Request<T>,
+    name: &str,
+  ) -> Result<String, Rejection> {
+    let uid = request
+      .extensions()
+      .get::<AuthenticatedUid>()
+      .map(|uid| uid.0.to_string());
+    match (request.extensions().get::<AdminAccess>(), uid) {
+      (Some(_), Some(uid)) => Ok(uid),
+      (_, uid) => {
+        let uid = uid.unwrap_or_else(|| client_key(request));
+        let action = AuditAction::AdminDenied {
+          request: name.to_string(),
+        };
+        let error = "Admin access required";
+        self
+          .record_audit_event(AuditEvent::new(&uid, action).with_error(error));
+        Err(Rejection {
+          code: Code::PermissionDenied,
+          message: error.to_string(),
+        })
+      }
+    }
+  }
+}
+
 #[tonic::async_trait]
 impl Api for Repository {
   type PullStream = ReceiverStream<Result<CommitObj, Status>>;
 
+  #[instrument(
+    skip_all,
+    fields(after_commit_id = %request.get_ref().after_commit_id)
+  )]
   async fn pull(
     &self,
     request: Request<PullRequest>, // Accept request of type HelloRequest
@@ -25,28 +371,60 @@ impl Api for Repository {
     // Return an instance of type HelloReply
     let (mut tx, rx) = tokio::sync::mpsc::channel(100);
 
-    // Get resources as Vec<SourceObject>
-    let commit_id_str = &request.into_inner().after_commit_id;
-
-    let res = match commit_id_str.len() > 0 {
-      true => {
-        let after_id = Uuid::parse_str(commit_id_str)
-          .map_err(|_| Status::invalid_argument("Wrong commit_id format"))?;
-        self.remote_commits_after(after_id).map_err(|_| {
-          Status::invalid_argument("Error collection remote logs")
-        })?
+    let request = request.into_inner();
+    let protocol_version = negotiate_protocol_version(request.protocol_version)
+      .map_err(Status::failed_precondition)?;
+    // Devices forced to resync must pull from scratch
+    let from_scratch = request.after_commit_id.is_empty();
+    if !request.device_id.is_empty() && !from_scratch {
+      let device = self.device(&request.device_id).map_err(Status::internal)?;
+      if device.is_some_and(|device| device.resync_required) {
+        return Err(Status::out_of_range(format!(
+          "{}: forced by the server admin",
+          RESYNC_REQUIRED
+        )));
       }
-      false => self.remote_commits().map_err(|_| {
-        Status::invalid_argument("Error collecting remote logs")
-      })?,
+    }
+
+    // Get resources as Vec<SourceObject>
+    let filter = self
+      .commit_filter(request.storage_ids)
+      .map_err(Status::failed_precondition)?;
+    let limit = match request.limit {
+      0 => usize::MAX,
+      limit => limit as usize,
     };
+    let res: Vec<Commit> = commits_after(self, &request.after_commit_id)
+      .map_err(Status::invalid_argument)?
+      .ok_or_else(|| resync_required(&request.after_commit_id))?
+      .into_iter()
+      .take(limit)
+      .map(|c| filter.apply(c))
+      .collect::<Result<_, _>>()
+      .map_err(Status::internal)?;
+    let res = match request.accept_delta {
+      true => self.delta_encode_commits(res),
+      false => res,
+    };
+    // Unsupported codecs fall back to JSON
+    let codec =
+      ActionCodec::from_name(&request.action_codec).unwrap_or_default();
+    if !request.device_id.is_empty() {
+      let cursor = res
+        .last()
+        .map(|commit| commit.id())
+        .or_else(|| Uuid::parse_str(&request.after_commit_id).ok());
+      self.record_device_pull(&request.device_id, from_scratch, cursor);
+    }
+    debug!(commits = res.len(), "Sending pulled commits");
 
     // Send the result items through the channel
+    let metrics = self.metrics().clone();
     tokio::spawn(async move {
       for commit in res.into_iter() {
-        let r: CommitObj = CommitObj {
-          obj_json_string: serde_json::to_string(&commit).unwrap(),
-        };
+        let r = commit_obj(&commit, protocol_version, codec);
+        metrics
+          .add_pull_bytes(r.obj_json_string.len() + r.encoded_actions.len());
         tx.send(Ok(r)).await.unwrap();
       }
     });
@@ -57,24 +435,711 @@ impl Api for Repository {
 
   // type PushStream = ReceiverStream<Result<CommitObj, Status>>;
 
+  #[instrument(skip_all, fields(uid = field::Empty, commit_id = field::Empty))]
   async fn push(
     &self,
     request: Request<CommitObj>, // Accept request of type HelloRequest
   ) -> Result<Response<CommitObj>, Status> {
-    let commit_obj = request.into_inner();
+    let uid = request
+      .extensions()
+      .get::<AuthenticatedUid>()
+      .map(|uid| uid.0.to_string());
+    if let Some(uid) = &uid {
+      Span::current().record("uid", uid.as_str());
+    }
+    let limiter = request.extensions().get::<Arc<Limiter>>().cloned();
+    let client = client_key(&request);
+    let pushed = request.into_inner();
+    let protocol_version = negotiate_protocol_version(pushed.protocol_version)
+      .map_err(Status::failed_precondition)?;
 
-    let res = self
-      .merge_pushed_commit(&commit_obj.obj_json_string)
-      .unwrap();
+    let res = self.merge_limited(
+      limiter.as_deref(),
+      &client,
+      &pushed.obj_json_string,
+      uid.as_deref(),
+    )?;
+    Span::current().record("commit_id", field::display(res.id()));
+    info!("Pushed commit merged");
 
     // let (mut tx, rx) = tokio::sync::mpsc::channel(100);
 
-    let res = CommitObj {
-      obj_json_string: serde_json::to_string(&res).unwrap(),
-    };
+    // Notify watchers except the pusher
+    self.publish_pushed_commit(res.clone(), pushed.client_id);
+
+    let res = commit_obj(&res, protocol_version, ActionCodec::Json);
     // tx.send(Ok(res)).await.unwrap();
 
     // Send back the receiver
     Ok(Response::new(res))
   }
+
+  type PushChunkedStream =
+    tokio_stream::Iter<std::vec::IntoIter<Result<CommitChunk, Status>>>;
+
+  #[instrument(skip_all, fields(uid = field::Empty, commit_id = field::Empty))]
+  async fn push_chunked(
+    &self,
+    request: Request<Streaming<CommitChunk>>,
+  ) -> Result<Response<Self::PushChunkedStream>, Status> {
+    let uid = request
+      .extensions()
+      .get::<AuthenticatedUid>()
+      .map(|uid| uid.0.to_string());
+    if let Some(uid) = &uid {
+      Span::current().record("uid", uid.as_str());
+    }
+    let limiter = request.extensions().get::<Arc<Limiter>>().cloned();
+    let client = client_key(&request);
+    let mut stream = request.into_inner();
+
+    // Reassemble pushed commit
+    let max_message_size = self.ctx().max_message_size;
+    let max_commit_size = limiter
+      .as_deref()
+      .and_then(Limiter::max_commit_size)
+      .unwrap_or(DEFAULT_MAX_COMMIT_SIZE);
+    let mut assembler = ChunkAssembler::new(max_message_size)
+      .with_max_commit_size(max_commit_size);
+    let (commit, client_id, protocol_version) = loop {
+      let chunk = stream
+        .message()
+        .await?
+        .ok_or_else(|| Status::aborted("Incomplete chunked commit"))?;
+      let client_id = chunk.client_id.clone();
+      let protocol_version = negotiate_protocol_version(chunk.protocol_version)
+        .map_err(Status::failed_precondition)?;
+      if let Some(commit) =
+        assembler.push(chunk).map_err(Status::resource_exhausted)?
+      {
+        break (commit, client_id, protocol_version);
+      }
+    };
+
+    let commit_json = serde_json::to_string(&commit)
+      .map_err(|e| Status::internal(e.to_string()))?;
+    let res = self.merge_limited(
+      limiter.as_deref(),
+      &client,
+      &commit_json,
+      uid.as_deref(),
+    )?;
+    Span::current().record("commit_id", field::display(res.id()));
+    info!("Pushed commit merged");
+
+    // Notify watchers except the pusher
+    self.publish_pushed_commit(res.clone(), client_id);
+
+    // Send merged commit back in chunks as well
+    let chunks = commit_chunks(res, max_message_size, "", protocol_version)
+      .map_err(Status::resource_exhausted)?;
+    Ok(Response::new(tokio_stream::iter(
+      chunks.into_iter().map(Ok).collect::<Vec<_>>(),
+    )))
+  }
+
+  type WatchStream = ReceiverStream<Result<CommitObj, Status>>;
+
+  #[instrument(
+    skip_all,
+    fields(
+      after_commit_id = %request.get_ref().after_commit_id,
+      client_id = %request.get_ref().client_id,
+    )
+  )]
+  async fn watch(
+    &self,
+    request: Request<WatchRequest>,
+  ) -> Result<Response<Self::WatchStream>, Status> {
+    let (tx, rx) = tokio::sync::mpsc::channel(100);
+
+    // Subscribe before collecting the missing commits,
+    // so no commit can be lost between the two steps
+    let mut subscriber = self.subscribe_pushed_commits();
+    let mut presence = self.subscribe_presence();
+    let mut shutdown = self.subscribe_server_shutdown();
+
+    let request = request.into_inner();
+    let protocol_version = negotiate_protocol_version(request.protocol_version)
+      .map_err(Status::failed_precondition)?;
+    let codec =
+      ActionCodec::from_name(&request.action_codec).unwrap_or_default();
+    // Presence of every storage if none given
+    let watched: HashSet<String> =
+      request.storage_ids.iter().cloned().collect();
+    let is_watched =
+      move |p: &Presence| watched.is_empty() || watched.contains(&p.storage_id);
+    let filter = self
+      .commit_filter(request.storage_ids)
+      .map_err(Status::failed_precondition)?;
+    let res: Vec<Commit> = commits_after(self, &request.after_commit_id)
+      .map_err(Status::invalid_argument)?
+      .ok_or_else(|| resync_required(&request.after_commit_id))?
+      .into_iter()
+      .map(|c| filter.apply(c))
+      .collect::<Result<_, _>>()
+      .map_err(Status::internal)?;
+    let current_presence: Vec<Presence> = match request.presence {
+      true => self
+        .current_presence(None)
+        .into_iter()
+        .filter(&is_watched)
+        .collect(),
+      false => vec![],
+    };
+
+    let subscribed = self.metrics().watch_subscriber();
+    tokio::spawn(async move {
+      // Counted until the stream ends
+      let _subscribed = subscribed;
+      // Send missing commits first
+      let mut sent = HashSet::new();
+      for commit in res.into_iter() {
+        sent.insert(commit.id());
+        let r = commit_obj(&commit, protocol_version, codec);
+        if tx.send(Ok(r)).await.is_err() {
+          return;
+        }
+      }
+      if !current_presence.is_empty() {
+        let r = presence_obj(&current_presence, protocol_version);
+        if tx.send(Ok(r)).await.is_err() {
+          return;
+        }
+      }
+      // Then stream new commits and presence changes as they land
+      loop {
+        let received = tokio::select! {
+          received = subscriber.recv() => Either::Left(received),
+          change = presence.recv(), if request.presence => Either::Right(change),
+          // Server is shutting down
+          _ = shutdown.wait_for(|stopping| *stopping) => return,
+        };
+        let received = match received {
+          Either::Left(received) => received,
+          Either::Right(change) => {
+            let change = match change {
+              Ok(change) if is_watched(&change) => change,
+              // Missed presence changes expire anyway
+              Ok(_) | Err(RecvError::Lagged(_)) => continue,
+              Err(RecvError::Closed) => return,
+            };
+            let r = presence_obj(&[change], protocol_version);
+            if tx.send(Ok(r)).await.is_err() {
+              return;
+            }
+            continue;
+          }
+        };
+        let commit = match received {
+          // Pusher already has its own commit
+          Ok((_, pusher_id))
+            if !pusher_id.is_empty() && pusher_id == request.client_id =>
+          {
+            continue
+          }
+          Ok((commit, _)) => commit,
+          // Subscriber missed commits, client must reconnect
+          Err(RecvError::Lagged(_)) => {
+            let _ = tx
+              .send(Err(Status::data_loss("Watch lagged behind. Reconnect")))
+              .await;
+            return;
+          }
+          Err(RecvError::Closed) => return,
+        };
+        if sent.contains(&commit.id()) {
+          continue;
+        }
+        let commit = match filter.apply(commit) {
+          Ok(commit) => commit,
+          Err(e) => {
+            let _ = tx.send(Err(Status::internal(e))).await;
+            return;
+          }
+        };
+        let r = commit_obj(&commit, protocol_version, codec);
+        // Client disconnected
+        if tx.send(Ok(r)).await.is_err() {
+          return;
+        }
+      }
+    });
+
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+
+  async fn public_key(
+    &self,
+    _request: Request<PublicKeyRequest>,
+  ) -> Result<Response<PublicKeyResponse>, Status> {
+    let public_key = self.public_key().ok_or_else(|| {
+      Status::failed_precondition("Repository has no signing key")
+    })?;
+    Ok(Response::new(PublicKeyResponse { public_key }))
+  }
+
+  async fn info(
+    &self,
+    _request: Request<InfoRequest>,
+  ) -> Result<Response<InfoResponse>, Status> {
+    let info = self.info().map_err(Status::internal)?;
+    Ok(Response::new(info))
+  }
+
+  #[instrument(skip_all, fields(storage_id = %request.get_ref().storage_id))]
+  async fn query(
+    &self,
+    request: Request<QueryRequest>,
+  ) -> Result<Response<QueryResponse>, Status> {
+    let request = request.into_inner();
+    let query: Query = serde_json::from_str(&request.query_json)
+      .map_err(|e| Status::invalid_argument(format!("Invalid query: {}", e)))?;
+    let results = self
+      .query(&request.storage_id, &query)
+      .map_err(Status::internal)?
+      .ok_or_else(|| {
+        Status::not_found(format!("Unknown storage {}", request.storage_id))
+      })?;
+    let objects = results
+      .into_iter()
+      .map(|result| QueryObject {
+        object_id: result.object_id.to_string(),
+        object_json: result.object.to_string(),
+      })
+      .collect();
+    Ok(Response::new(QueryResponse { objects }))
+  }
+
+  #[instrument(skip_all, fields(device_id = %request.get_ref().device_id))]
+  async fn register(
+    &self,
+    request: Request<RegisterRequest>,
+  ) -> Result<Response<RegisterResponse>, Status> {
+    let uid = request
+      .extensions()
+      .get::<AuthenticatedUid>()
+      .map(|uid| uid.0.to_string())
+      .unwrap_or_default();
+    let request = request.into_inner();
+    negotiate_protocol_version(request.protocol_version)
+      .map_err(Status::failed_precondition)?;
+    if request.device_id.is_empty() {
+      return Err(Status::invalid_argument("Missing device id"));
+    }
+    let app_version =
+      (!request.app_version.is_empty()).then_some(request.app_version);
+    let record = self
+      .register_device(&request.device_id, &uid, app_version)
+      .map_err(Status::internal)?;
+    info!("Device registered");
+    Ok(Response::new(RegisterResponse {
+      first_seen: record.first_seen.to_rfc3339(),
+    }))
+  }
+
+  async fn list_devices(
+    &self,
+    request: Request<ListDevicesRequest>,
+  ) -> Result<Response<ListDevicesResponse>, Status> {
+    self.check_admin(&request, "list_devices")?;
+    let devices = self.devices().map_err(Status::internal)?;
+    Ok(Response::new(ListDevicesResponse {
+      devices: devices.iter().map(DeviceInfo::from).collect(),
+    }))
+  }
+
+  #[instrument(skip_all, fields(device_id = %request.get_ref().device_id))]
+  async fn revoke_device(
+    &self,
+    request: Request<DeviceRequest>,
+  ) -> Result<Response<DeviceInfo>, Status> {
+    let uid = self.check_admin(&request, "revoke_device")?;
+    let record = self
+      .revoke_device_as(&uid, &request.into_inner().device_id)
+      .map_err(Status::not_found)?;
+    info!("Device revoked");
+    Ok(Response::new(DeviceInfo::from(&record)))
+  }
+
+  #[instrument(skip_all, fields(device_id = %request.get_ref().device_id))]
+  async fn force_resync(
+    &self,
+    request: Request<DeviceRequest>,
+  ) -> Result<Response<DeviceInfo>, Status> {
+    let uid = self.check_admin(&request, "force_resync")?;
+    let record = self
+      .force_resync_as(&uid, &request.into_inner().device_id)
+      .map_err(Status::not_found)?;
+    info!("Device resync forced");
+    Ok(Response::new(DeviceInfo::from(&record)))
+  }
+
+  #[instrument(skip_all, fields(device_id = %request.get_ref().device_id))]
+  async fn announce(
+    &self,
+    request: Request<PresenceRequest>,
+  ) -> Result<Response<PresenceList>, Status> {
+    let uid = request
+      .extensions()
+      .get::<AuthenticatedUid>()
+      .map(|uid| uid.0.to_string());
+    let request = request.into_inner();
+    if request.device_id.is_empty() {
+      return Err(Status::invalid_argument("Missing device id"));
+    }
+    let object_ids = request
+      .object_ids
+      .iter()
+      .map(|id| Uuid::parse_str(id))
+      .collect::<Result<Vec<_>, _>>()
+      .map_err(|_| Status::invalid_argument("Wrong object id format"))?;
+    self.update_presence(vec![Presence {
+      device_id: request.device_id.clone(),
+      uid: uid.unwrap_or(request.uid),
+      storage_id: request.storage_id.clone(),
+      object_ids,
+      at: Utc::now(),
+    }]);
+    let presence = self
+      .current_presence(Some(&request.storage_id))
+      .iter()
+      .filter(|p| p.device_id != request.device_id)
+      .map(PresenceInfo::from)
+      .collect();
+    Ok(Response::new(PresenceList { presence }))
+  }
+
+  async fn missing_blobs(
+    &self,
+    request: Request<BlobList>,
+  ) -> Result<Response<BlobList>, Status> {
+    let blob_ids = self.missing_blobs(request.into_inner().blob_ids);
+    Ok(Response::new(BlobList { blob_ids }))
+  }
+
+  #[instrument(skip_all)]
+  async fn push_blobs(
+    &self,
+    request: Request<Streaming<BlobChunk>>,
+  ) -> Result<Response<BlobList>, Status> {
+    let mut stream = request.into_inner();
+    let mut assembler = BlobAssembler::default();
+    while let Some(chunk) = stream.message().await? {
+      assembler.push(chunk);
+    }
+    let ctx = self.ctx();
+    let mut blob_ids = vec![];
+    for (id, data) in assembler.finish() {
+      blob::store_blob(&ctx, &id, &data).map_err(Status::invalid_argument)?;
+      blob_ids.push(id);
+    }
+    info!(blobs = blob_ids.len(), "Pushed blobs stored");
+    Ok(Response::new(BlobList { blob_ids }))
+  }
+
+  type PullBlobsStream =
+    tokio_stream::Iter<std::vec::IntoIter<Result<BlobChunk, Status>>>;
+
+  async fn pull_blobs(
+    &self,
+    request: Request<BlobList>,
+  ) -> Result<Response<Self::PullBlobsStream>, Status> {
+    let ctx = self.ctx();
+    let mut chunks = vec![];
+    for id in request.into_inner().blob_ids {
+      let data = blob::read_blob(&ctx, &id).map_err(Status::not_found)?;
+      chunks.extend(blob::blob_chunks(&id, &data).into_iter().map(Ok));
+    }
+    Ok(Response::new(tokio_stream::iter(chunks)))
+  }
+}
+
+impl TryFrom<QueryObject> for QueryResult {
+  type Error = String;
+  fn try_from(object: QueryObject) -> Result<Self, Self::Error> {
+    Ok(Self {
+      object_id: Uuid::parse_str(&object.object_id)
+        .map_err(|_| "Wrong id format in query result".to_string())?,
+      object: serde_json::from_str(&object.object_json)
+        .map_err(|_| "Query result deser error".to_string())?,
+    })
+  }
+}
+
+impl From<&DeviceRecord> for DeviceInfo {
+  fn from(record: &DeviceRecord) -> Self {
+    Self {
+      device_id: record.device_id.clone(),
+      uid: record.uid.clone(),
+      app_version: record.app_version.clone().unwrap_or_default(),
+      first_seen: record.first_seen.to_rfc3339(),
+      last_sync: record
+        .last_sync
+        .map(|at| at.to_rfc3339())
+        .unwrap_or_default(),
+      cursor: record.cursor.map(|id| id.to_string()).unwrap_or_default(),
+      revoked: record.revoked,
+      resync_required: record.resync_required,
+    }
+  }
+}
+
+impl TryFrom<DeviceInfo> for DeviceRecord {
+  type Error = String;
+  fn try_from(info: DeviceInfo) -> Result<Self, Self::Error> {
+    let parse_time = |time: &str| {
+      DateTime::parse_from_rfc3339(time)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|_| "Wrong time format in device info".to_string())
+    };
+    Ok(Self {
+      app_version: (!info.app_version.is_empty()).then_some(info.app_version),
+      first_seen: parse_time(&info.first_seen)?,
+      last_sync: match info.last_sync.is_empty() {
+        true => None,
+        false => Some(parse_time(&info.last_sync)?),
+      },
+      cursor: match info.cursor.is_empty() {
+        true => None,
+        false => Some(
+          Uuid::parse_str(&info.cursor)
+            .map_err(|_| "Wrong cursor format in device info".to_string())?,
+        ),
+      },
+      device_id: info.device_id,
+      uid: info.uid,
+      revoked: info.revoked,
+      resync_required: info.resync_required,
+    })
+  }
+}
+
+impl From<&Presence> for PresenceInfo {
+  fn from(presence: &Presence) -> Self {
+    Self {
+      device_id: presence.device_id.clone(),
+      uid: presence.uid.clone(),
+      storage_id: presence.storage_id.clone(),
+      object_ids: presence
+        .object_ids
+        .iter()
+        .map(|id| id.to_string())
+        .collect(),
+      at: presence.at.to_rfc3339(),
+    }
+  }
+}
+
+impl TryFrom<PresenceInfo> for Presence {
+  type Error = String;
+  fn try_from(info: PresenceInfo) -> Result<Self, Self::Error> {
+    Ok(Self {
+      object_ids: info
+        .object_ids
+        .iter()
+        .map(|id| Uuid::parse_str(id))
+        .collect::<Result<_, _>>()
+        .map_err(|_| "Wrong object id format in presence".to_string())?,
+      at: DateTime::parse_from_rfc3339(&info.at)
+        .map(|at| at.with_timezone(&Utc))
+        .map_err(|_| "Wrong time format in presence".to_string())?,
+      device_id: info.device_id,
+      uid: info.uid,
+      storage_id: info.storage_id,
+    })
+  }
+}
+
+/// Runtime the server runs on
+#[derive(Default)]
+pub enum ServerRuntime {
+  /// New single threaded runtime
+  #[default]
+  CurrentThread,
+  /// New multi threaded runtime with the given number of workers
+  MultiThread(usize),
+  /// Existing runtime
+  /// Server must not be started from one of its threads
+  Handle(tokio::runtime::Handle),
+}
+
+impl ServerRuntime {
+  // Run future to completion on the runtime
+  pub(crate) fn block_on<F: Future>(
+    self,
+    future: F,
+  ) -> Result<F::Output, String> {
+    let res = match self {
+      ServerRuntime::CurrentThread => {
+        tokio::runtime::Builder::new_current_thread()
+          .enable_all()
+          .thread_name("sync_server")
+          .build()
+          .map_err(|e| e.to_string())?
+          .block_on(future)
+      }
+      ServerRuntime::MultiThread(worker_threads) => {
+        tokio::runtime::Builder::new_multi_thread()
+          .enable_all()
+          .worker_threads(worker_threads)
+          .thread_name("sync_server")
+          .build()
+          .map_err(|e| e.to_string())?
+          .block_on(future)
+      }
+      ServerRuntime::Handle(handle) => handle.block_on(future),
+    };
+    Ok(res)
+  }
+}
+
+/// Server configuration
+#[derive(Default)]
+pub struct ServeConfig {
+  pub(crate) auth_provider: Option<Arc<dyn AuthProvider>>,
+  pub(crate) runtime: ServerRuntime,
+  pub(crate) limits: Option<Limits>,
+  pub(crate) admin_uids: HashSet<String>,
+}
+
+impl ServeConfig {
+  /// Authenticate every request by the given provider
+  pub fn with_auth(mut self, auth_provider: impl AuthProvider) -> Self {
+    self.auth_provider = Some(Arc::new(auth_provider));
+    self
+  }
+  /// Set runtime the server runs on
+  pub fn with_runtime(mut self, runtime: ServerRuntime) -> Self {
+    self.runtime = runtime;
+    self
+  }
+  /// Enforce rate limits and quotas on every client
+  pub fn with_limits(mut self, limits: Limits) -> Self {
+    self.limits = Some(limits);
+    self
+  }
+  /// Allow admin requests, e.g. listing and revoking devices, to the
+  /// given uids authenticated by the auth provider
+  /// Without auth provider admin requests are always denied
+  pub fn with_admin_uids(mut self, admin_uids: &[&str]) -> Self {
+    self.admin_uids = admin_uids.iter().map(|uid| uid.to_string()).collect();
+    self
+  }
+}
+
+/// Standard gRPC health service
+/// Server and its sync api are serving as long as it runs
+pub struct HealthService;
+
+impl HealthService {
+  fn status(service: &str) -> Result<HealthCheckResponse, String> {
+    match service {
+      "" | API_SERVICE_NAME => Ok(HealthCheckResponse {
+        status: ServingStatus::Serving as i32,
+      }),
+      _ => Err(format!("Unknown service: {}", service)),
+    }
+  }
+}
+
+#[tonic::async_trait]
+impl Health for HealthService {
+  async fn check(
+    &self,
+    request: Request<HealthCheckRequest>,
+  ) -> Result<Response<HealthCheckResponse>, Status> {
+    let status =
+      Self::status(&request.into_inner().service).map_err(Status::not_found)?;
+    Ok(Response::new(status))
+  }
+
+  type WatchStream = ReceiverStream<Result<HealthCheckResponse, Status>>;
+
+  async fn watch(
+    &self,
+    request: Request<HealthCheckRequest>,
+  ) -> Result<Response<Self::WatchStream>, Status> {
+    let status =
+      Self::status(&request.into_inner().service).map_err(Status::not_found)?;
+    let (tx, rx) = tokio::sync::mpsc::channel(1);
+    tokio::spawn(async move {
+      // Status never changes, keep stream open until client leaves
+      if tx.send(Ok(status)).await.is_ok() {
+        tx.closed().await;
+      }
+    });
+    Ok(Response::new(ReceiverStream::new(rx)))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MAX_MESSAGE_SIZE: usize = 8 * 1024;
+
+  fn commit(actions: usize) -> Commit {
+    let serialized_actions = (0..actions)
+      .map(|i| format!("{}{}", i, "x".repeat(1000)))
+      .collect::<Vec<_>>();
+    serde_json::from_value(serde_json::json!({
+      "id": Uuid::new_v4(),
+      "uid": "anna",
+      "dtime": Utc::now(),
+      "comment": "",
+      "ancestor_id": Uuid::nil(),
+      "serialized_actions": serialized_actions,
+      "remote_signature": null,
+    }))
+    .unwrap()
+  }
+
+  #[test]
+  fn test_reassemble_chunks() {
+    let commit = commit(50);
+    let chunks =
+      commit_chunks(commit.clone(), MAX_MESSAGE_SIZE, "", PROTOCOL_VERSION)
+        .unwrap();
+    assert!(chunks.len() > 2);
+    let mut assembler = ChunkAssembler::new(MAX_MESSAGE_SIZE);
+    let mut reassembled = None;
+    for chunk in chunks {
+      assert!(reassembled.is_none());
+      reassembled = assembler.push(chunk).unwrap();
+    }
+    let reassembled = reassembled.unwrap();
+    assert_eq!(reassembled.id(), commit.id());
+    assert_eq!(
+      reassembled.serialized_actions(),
+      commit.serialized_actions()
+    );
+  }
+
+  #[test]
+  fn test_reject_chunks_over_limit() {
+    let chunks =
+      commit_chunks(commit(50), MAX_MESSAGE_SIZE, "", PROTOCOL_VERSION)
+        .unwrap();
+    let mut assembler =
+      ChunkAssembler::new(MAX_MESSAGE_SIZE).with_max_commit_size(20_000);
+    let res = chunks
+      .into_iter()
+      .map(|chunk| assembler.push(chunk))
+      .find(|res| !matches!(res, Ok(None)));
+    assert!(res.unwrap().unwrap_err().contains("max commit size"));
+    // Endless stream of empty chunks
+    let mut chunks =
+      commit_chunks(commit(0), MAX_MESSAGE_SIZE, "", PROTOCOL_VERSION).unwrap();
+    let mut header = chunks.remove(0);
+    header.last = false;
+    let mut assembler =
+      ChunkAssembler::new(MAX_MESSAGE_SIZE).with_max_commit_size(20_000);
+    assert!(assembler.push(header.clone()).unwrap().is_none());
+    header.header_json_string = String::new();
+    let res = (0..100)
+      .map(|_| assembler.push(header.clone()))
+      .find(|res| !matches!(res, Ok(None)));
+    assert!(res.unwrap().unwrap_err().contains("chunks"));
+  }
 }
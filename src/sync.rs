@@ -1,34 +1,126 @@
 use std::{
+  any::Any,
+  borrow::Cow,
+  collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
   fmt::Debug,
-  ops::Deref,
-  path::PathBuf,
-  sync::{Arc, Mutex, MutexGuard},
+  future::Future,
+  io::{BufRead, Write},
+  marker::PhantomData,
+  ops::{Deref, DerefMut},
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Mutex, MutexGuard, OnceLock, RwLock, RwLockReadGuard,
+    RwLockWriteGuard,
+  },
+  time::{Duration, Instant},
 };
 
 use chrono::{DateTime, Utc};
-use futures_util::stream;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use futures_util::{stream, Stream, StreamExt};
+use schemars::{schema_for, JsonSchema};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tonic::{transport::Server, Request};
+use tokio::sync::broadcast;
+use tonic::{
+  service::interceptor::InterceptedService, transport::Server, Request,
+};
+use tracing::{field, instrument, Span};
 use uuid::Uuid;
 
 use crate::{
+  audit::{AuditAction, AuditEvent, AuditFilter, AuditLog, AuditSink},
+  auth::{
+    AccessPolicy, AuthProvider, Principal, ServerAuth, POLICY_VIOLATION,
+    VERIFIED_UID_META,
+  },
+  backend::{Backend, FsBackend, MemoryBackend, WriteBehindBackend},
+  blob::{self, BlobRef},
+  clock::{Hlc, HybridClock},
+  codec::ActionCodec,
+  config::RepoConfig,
+  conflict::{
+    Conflict, ConflictKind, ConflictResolver, MergeMode, Resolution, TakeLocal,
+    TakeRemote,
+  },
+  delta::{self, DeltaEncoder},
+  device::{
+    load_or_init_device_id, DeviceRecord, DeviceRegistry, DEVICE_ID_META,
+  },
+  diff::{self, ObjectDiff},
   fs::{
-    binary_continuous_append, binary_continuous_read,
-    binary_continuous_read_after_filter, binary_init, binary_init_empty,
-    binary_read, binary_update,
+    binary_continuous_append, binary_continuous_is_legacy,
+    binary_continuous_iter, binary_continuous_migrate, binary_continuous_read,
+    binary_continuous_read_after_filter, binary_continuous_recover,
+    binary_continuous_write, binary_init, binary_init_empty,
+    binary_legacy_read, binary_migrate, binary_read, binary_remove,
+    binary_update, binary_update_encoded, binary_write, encode, format_read,
+    format_write, ContinuousIter,
+  },
+  id::{IdGenerator, RandomIds},
+  limits::Limiter,
+  lock::RepoLock,
+  metrics::Metrics,
+  migration::{migrate_payload, Migrator},
+  mirror::{MirrorRow, ObjectMirror},
+  prelude::{
+    canonical_json, ed25519_public_key, ed25519_signature, ed25519_verify,
+    object_signature, path_helper, sha1_signature, verify_object_signature,
+    SignatureAlgorithm,
+  },
+  presence::{Presence, PresenceMap},
+  projection::{Projection, ProjectionHandle},
+  query::{Query, QueryResult},
+  redaction::{
+    redacted_action, RedactedAction, REDACTED_ACTION, REDACTION_META,
+  },
+  retention::{
+    purged_action, LegalHolds, PurgeReport, Retention, StoragePurgeReport,
+    Tombstone, PURGED_ACTION,
   },
-  prelude::{path_helper, sha1_signature},
-  server::sync_api::{
-    api_client::ApiClient,
-    api_server::{Api, ApiServer},
-    CommitObj, PullRequest,
+  server::{
+    health_api::health_server::HealthServer,
+    sync_api::{
+      api_server::{Api, ApiServer},
+      InfoResponse,
+    },
+    HealthService, ServeConfig, DEFAULT_MAX_MESSAGE_SIZE, PROTOCOL_VERSION,
+    SERVER_FEATURES,
   },
+  tls::{ClientTls, ServerTls},
+  trace::{TraceEvent, TraceRecorder},
+  transport::{PullOptions, Transport, WatchEvent},
+  usage::{
+    key_size, prefix_size, DiskUsage, Quota, StorageUsage, QUOTA_EXCEEDED,
+  },
+  verify::{StorageVerifyReport, VerifyReport},
+  worker::{RestartPolicy, TaskStatus, Worker},
 };
 
+// Remote commit notification channel capacity
+const REMOTE_COMMIT_CHANNEL_SIZE: usize = 100;
+// Storage change event channel capacity
+const CHANGE_EVENT_CHANNEL_SIZE: usize = 1000;
+// Snapshot archive format version
+const SNAPSHOT_VERSION: u32 = 1;
+// Delay between two remote watch connection attempts
+const WATCH_RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Default number of objects read in parallel, see Context
+pub const DEFAULT_READ_CONCURRENCY: usize = 4;
+/// Default number of commits pulled in one batch, see Context
+pub const DEFAULT_PULL_BATCH_SIZE: usize = 1000;
+/// Remote actions between two history checkpoints of an object
+pub const CHECKPOINT_EVERY: usize = 64;
+// Objects read by a worker in one batch of parallel reads
+const READ_BATCH_PER_WORKER: usize = 64;
+// Smaller batches are read sequentially, not worth spawning workers
+const PARALLEL_READ_MIN_OBJECTS: usize = 16;
+
 /// Action trait for Actionable types
 /// Implemented types can be used as storage patch objects.
-pub trait ActionExt: Clone + Send {
+/// Sync, as storage details are shared by concurrent readers.
+pub trait ActionExt: Clone + Send + Sync {
   /// Action can work with this
   /// type
   type ObjectType;
@@ -45,15 +137,50 @@ pub trait ActionExt: Clone + Send {
   /// This can be used in UI to display
   /// Patch actions
   fn display(&self) -> String;
+  /// Semantic collision check
+  /// True if this pending local action should not be silently
+  /// re-applied after the given remote action, e.g. both
+  /// set the same field. Colliding actions are resolved by
+  /// the storage ConflictResolver.
+  fn conflicts_with(&self, _remote: &Self) -> bool {
+    false
+  }
+  /// Commutativity check
+  /// True if applying this and the other action in either order
+  /// results the same object, e.g. both set different fields.
+  /// Storages in MergeMode::Crdt interleave concurrent commits
+  /// whose actions all commute.
+  fn commutes_with(&self, _other: &Self) -> bool {
+    false
+  }
+  /// Additive check
+  /// True if this action adds to the object state instead of setting
+  /// it, e.g. increments a counter field by a delta. Concurrent
+  /// additive actions on an object compose: local ones are re-applied
+  /// on top of the remote ones without collision, and storages in
+  /// MergeMode::Crdt interleave them, so no increment is lost.
+  /// Actions are composed as a whole, no per field vector clock is
+  /// kept: the server orders every commit, and additive actions
+  /// apply in any order.
+  fn is_additive(&self) -> bool {
+    false
+  }
+  /// Inverse action
+  /// Returns the action which reverts this one, by providing
+  /// the object state before this action was applied.
+  /// If None, revert restores the object state snapshot instead.
+  fn inverse(&self, _before: &Self::ObjectType) -> Option<Self> {
+    None
+  }
 }
 
-pub trait ObjectExt: Debug + Clone + Send {}
+pub trait ObjectExt: Debug + Clone + Send + Sync {}
 
 /// Generic acion representation
 /// Atomic action kinds with the following states:
 /// Create, Patch, Remove, Recover
 #[derive(Serialize, Deserialize, Clone, Debug)]
-enum ActionKind<T, A>
+pub enum ActionKind<T, A>
 where
   T: ObjectExt,
   A: ActionExt,
@@ -63,6 +190,51 @@ where
   Create(T),
   /// Patch object with action A
   Patch(A),
+  /// Restore object to the given T snapshot
+  /// (Used by revert when no inverse action available)
+  Restore(T),
+  /// Remove object, keeping it as a tombstone
+  /// Object data is left unchanged, Restore recovers it
+  Remove,
+  /// Redacted action, see redaction
+  /// Object state after it is unknown until a Restore
+  Redacted(RedactedAction),
+}
+
+impl<T, A> ActionKind<T, A>
+where
+  T: ObjectExt,
+  A: ActionExt<ObjectType = T>,
+{
+  /// Human readable action
+  pub fn display(&self) -> String {
+    match self {
+      ActionKind::Create(_) => "Created".to_string(),
+      ActionKind::Patch(action) => action.display(),
+      ActionKind::Restore(_) => "Restored".to_string(),
+      ActionKind::Remove => "Removed".to_string(),
+      ActionKind::Redacted(_) => "Redacted".to_string(),
+    }
+  }
+  // Apply action on an existing object
+  fn apply(
+    &self,
+    object: &T,
+    dtime: DateTime<Utc>,
+    uid: &str,
+  ) -> Result<T, String> {
+    match self {
+      ActionKind::Create(_) => {
+        Err("Create action cannot be applied on existing object".into())
+      }
+      ActionKind::Patch(action) => action.apply_patch(object, dtime, uid),
+      ActionKind::Restore(snapshot) => Ok(snapshot.clone()),
+      ActionKind::Remove => Ok(object.clone()),
+      ActionKind::Redacted(_) => {
+        Err("Redacted action cannot be applied".to_string())
+      }
+    }
+  }
 }
 
 /// ActionObject must be produced by a StorageObject
@@ -93,14 +265,72 @@ where
   // Create(T) or Patch(A)
   action: ActionKind<T, A>,
   // Signature of the initial/patched object as json string
-  // Sha1
+  // Prefixed by its algorithm e.g. sha256:<hex>, unprefixed if legacy sha1
   object_signature: String,
+  // Hash of the storage schema the action object was created with
+  // Not serialized if None, to keep older signatures valid
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  schema_hash: Option<String>,
+  // Hybrid logical clock, orders concurrent actions
+  // Not serialized if None, to keep older signatures valid
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  clock: Option<Hlc>,
   // Remote action object signature
-  // serialized (ActionObject as json) with none remote_signature
-  // Sha1
+  // serialized (UniversalActionObject as json) with none remote_signature
+  // Ed25519, signed by the server
   remote_signature: Option<String>,
 }
 
+impl<T, A> ActionObject<T, A>
+where
+  T: ObjectExt,
+  A: ActionExt,
+{
+  pub fn id(&self) -> Uuid {
+    self.id
+  }
+  pub fn storage_id(&self) -> &str {
+    &self.storage_id
+  }
+  pub fn object_id(&self) -> Uuid {
+    self.object_id
+  }
+  pub fn uid(&self) -> &str {
+    &self.uid
+  }
+  pub fn dtime(&self) -> DateTime<Utc> {
+    self.dtime
+  }
+  /// Commit it belongs to, None if not committed yet
+  pub fn commit_id(&self) -> Option<Uuid> {
+    self.commit_id
+  }
+  /// Previous action of the object, None for create
+  pub fn parent_action_id(&self) -> Option<Uuid> {
+    self.parent_action_id
+  }
+  /// Create, Patch, Restore, Remove or Redacted action
+  pub fn action(&self) -> &ActionKind<T, A> {
+    &self.action
+  }
+  /// Signature of the object state after the action
+  pub fn object_signature(&self) -> &str {
+    &self.object_signature
+  }
+  /// Hash of the storage schema it was created with
+  pub fn schema_hash(&self) -> Option<&str> {
+    self.schema_hash.as_deref()
+  }
+  /// Hybrid logical clock, None if created by an older version
+  pub fn clock(&self) -> Option<Hlc> {
+    self.clock
+  }
+  /// Server signature, None if not pushed yet
+  pub fn remote_signature(&self) -> Option<&str> {
+    self.remote_signature.as_deref()
+  }
+}
+
 impl<T, A> ActionObject<T, A>
 where
   T: ObjectExt + Serialize,
@@ -114,12 +344,21 @@ where
   fn is_remote(&self) -> bool {
     self.remote_signature.is_some()
   }
-  // Check if patch
+  // Check if patch, redacted ones included
   fn is_kind_patch(&self) -> bool {
-    if let ActionKind::Patch(_) = self.action {
-      return true;
+    match &self.action {
+      ActionKind::Patch(_) => true,
+      ActionKind::Redacted(redacted) => redacted.kind == "Patch",
+      _ => false,
     }
-    false
+  }
+  // Check if restore
+  fn is_kind_restore(&self) -> bool {
+    matches!(self.action, ActionKind::Restore(_))
+  }
+  // Check if redacted
+  fn is_kind_redacted(&self) -> bool {
+    matches!(self.action, ActionKind::Redacted(_))
   }
   // Check if create
   fn is_kind_create(&self) -> bool {
@@ -128,29 +367,23 @@ where
     }
     false
   }
-  // Check if remote signature correct
-  fn has_valid_remote_signature(&self) -> Result<bool, String> {
-    if let Some(remote_signature) = &self.remote_signature {
-      let self_clone = (*self).clone();
-      let without_signature: ActionObject<T, A> = ActionObject {
-        remote_signature: None,
-        ..self_clone
-      };
-      let signature = sha1_signature(&without_signature)?;
-      return Ok(&signature == remote_signature);
-    }
-    Ok(false)
-  }
   // Reset dtime
   // Should apply only when remote update occurs
   fn reset_dtime(&mut self) {
     self.dtime = Utc::now();
   }
+  // Order of concurrent actions, by their clocks, then their ids
+  // Actions of older versions without clock come first
+  fn clock_order(&self) -> (Option<Hlc>, Uuid) {
+    (self.clock, self.id)
+  }
 }
 
 /// Universal Action Object
 /// Deserializing Action Object without any action kind type
-#[derive(Serialize, Deserialize)]
+/// Read-only view for tooling inspecting raw commits without knowing
+/// the storage types, see Commit::action_objects
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct UniversalActionObject {
   // Unique ID
   id: Uuid,
@@ -172,47 +405,126 @@ pub struct UniversalActionObject {
   // Action as AnyValue
   action: Value,
   // Signature of the initial/patched object as json string
-  // Sha1
+  // Prefixed by its algorithm e.g. sha256:<hex>, unprefixed if legacy sha1
   object_signature: String,
+  // Hash of the storage schema the action object was created with
+  // Not serialized if None, to keep older signatures valid
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  schema_hash: Option<String>,
+  // Hybrid logical clock, orders concurrent actions
+  // Not serialized if None, to keep older signatures valid
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  clock: Option<Hlc>,
   // Remote action object signature
-  // serialized (ActionObject as json) with none remote_signature
-  // Sha1
+  // serialized (UniversalActionObject as json) with none remote_signature
+  // Ed25519, signed by the server
   remote_signature: Option<String>,
 }
 
 impl UniversalActionObject {
-  fn id(&self) -> Uuid {
+  pub fn id(&self) -> Uuid {
     self.id
   }
-  fn object_id(&self) -> Uuid {
+  pub fn storage_id(&self) -> &str {
+    &self.storage_id
+  }
+  pub fn object_id(&self) -> Uuid {
     self.object_id
   }
-  fn parent_action_id(&self) -> Option<Uuid> {
+  pub fn uid(&self) -> &str {
+    &self.uid
+  }
+  pub fn dtime(&self) -> DateTime<Utc> {
+    self.dtime
+  }
+  pub fn commit_id(&self) -> Option<Uuid> {
+    self.commit_id
+  }
+  /// Hybrid logical clock, None if created by an older version
+  pub fn clock(&self) -> Option<Hlc> {
+    self.clock
+  }
+  /// Action kind as json, e.g. {"Create": T}, {"Patch": A} or "Remove"
+  pub fn action(&self) -> &Value {
+    &self.action
+  }
+  /// Action kind name, Create, Patch, Restore or Remove
+  pub fn action_kind(&self) -> &str {
+    match &self.action {
+      Value::String(kind) => kind,
+      Value::Object(map) => map.keys().next().map_or("", |kind| kind),
+      _ => "",
+    }
+  }
+  /// Action payload as json, the created object, the patch action
+  /// or the restored object. None for Remove
+  pub fn payload(&self) -> Option<&Value> {
+    match &self.action {
+      Value::Object(map) => map.values().next(),
+      _ => None,
+    }
+  }
+  /// Previous action of the object, None for create
+  pub fn parent_action_id(&self) -> Option<Uuid> {
     self.parent_action_id
   }
-  fn object_signature(&self) -> &str {
+  /// Signature of the object state after the action
+  pub fn object_signature(&self) -> &str {
     &self.object_signature
   }
-  fn remote_signature(&self) -> Option<&str> {
+  /// Hash of the storage schema it was created with
+  pub fn schema_hash(&self) -> Option<&str> {
+    self.schema_hash.as_deref()
+  }
+  /// Server signature, None if not pushed yet
+  pub fn remote_signature(&self) -> Option<&str> {
     self.remote_signature.as_deref()
   }
-  fn is_remote(&self) -> bool {
+  pub fn is_remote(&self) -> bool {
     self.remote_signature.is_some()
   }
-  fn is_local(&self) -> bool {
+  pub fn is_local(&self) -> bool {
     !self.is_remote()
   }
-  fn remote_sign(&mut self) -> Result<(), String> {
+  /// Whether its action is purged by retention, see Repository::purge
+  pub fn is_purged(&self) -> bool {
+    self.action_kind() == PURGED_ACTION
+  }
+  /// Whether its action is redacted, see Repository::redact
+  pub fn is_redacted(&self) -> bool {
+    self.action_kind() == REDACTED_ACTION
+  }
+  fn remote_sign(&mut self, key: &SigningKey) -> Result<(), String> {
     if self.is_remote() {
       return Err("Already signed action object".to_string());
     }
-    let signature = sha1_signature(&self)?;
+    let signature = ed25519_signature(key, &self)?;
     self.remote_signature = Some(signature);
     Ok(())
   }
+  fn has_valid_remote_signature(
+    &self,
+    key: &VerifyingKey,
+  ) -> Result<bool, String> {
+    match &self.remote_signature {
+      Some(signature) => {
+        let without_signature = UniversalActionObject {
+          remote_signature: None,
+          action: self.action.clone(),
+          storage_id: self.storage_id.clone(),
+          uid: self.uid.clone(),
+          object_signature: self.object_signature.clone(),
+          schema_hash: self.schema_hash.clone(),
+          ..*self
+        };
+        ed25519_verify(key, &without_signature, signature)
+      }
+      None => Ok(false),
+    }
+  }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct Commit {
   id: Uuid,
   uid: String,
@@ -220,20 +532,124 @@ pub struct Commit {
   comment: String,
   ancestor_id: Uuid,
   serialized_actions: Vec<String>, // ActionObject JSONs in Vec
-  remote_signature: Option<String>, // Remote signature
+  remote_signature: Option<String>, // Remote Ed25519 signature
+  // Key/value annotations, e.g. app_version or device_id
+  // Not serialized if empty, to keep older signatures valid
+  #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+  meta: BTreeMap<String, String>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  tags: Vec<String>,
+  // Position in the remote history, assigned by the server
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  seq: Option<u64>,
 }
 
 impl Commit {
-  fn new(uid: String, comment: String) -> Self {
+  fn new(id: Uuid, uid: String, comment: String) -> Self {
     Self {
-      id: Uuid::new_v4(),
+      id,
       uid,
       dtime: Utc::now(),
       comment,
       ancestor_id: Uuid::default(),
       serialized_actions: vec![],
       remote_signature: None,
+      meta: BTreeMap::new(),
+      tags: vec![],
+      seq: None,
+    }
+  }
+  pub fn id(&self) -> Uuid {
+    self.id
+  }
+  pub fn uid(&self) -> &str {
+    &self.uid
+  }
+  pub fn dtime(&self) -> DateTime<Utc> {
+    self.dtime
+  }
+  pub fn comment(&self) -> &str {
+    &self.comment
+  }
+  /// Latest remote commit when it was created or merged
+  pub fn ancestor_id(&self) -> Uuid {
+    self.ancestor_id
+  }
+  /// Key/value annotations
+  pub fn meta(&self) -> &BTreeMap<String, String> {
+    &self.meta
+  }
+  /// Pusher uid verified by the server, None if the server
+  /// does not authenticate or the commit is not pushed yet
+  pub fn verified_uid(&self) -> Option<&str> {
+    self.meta.get(VERIFIED_UID_META).map(String::as_str)
+  }
+  pub fn tags(&self) -> &[String] {
+    &self.tags
+  }
+  /// Sequence number assigned by the server when merged
+  /// Totally orders remote commits independent of clocks.
+  /// None for local commits and commits of older servers.
+  pub fn seq(&self) -> Option<u64> {
+    self.seq
+  }
+  /// Action object JSONs
+  pub fn serialized_actions(&self) -> &[String] {
+    &self.serialized_actions
+  }
+  /// Action objects decoded without their storage types
+  pub fn action_objects(&self) -> Result<Vec<UniversalActionObject>, String> {
+    self
+      .serialized_actions
+      .iter()
+      .map(|aob| {
+        serde_json::from_str(aob)
+          .map_err(|e| format!("Error deser action object: {}", e))
+      })
+      .collect()
+  }
+  /// Action objects of the storage decoded as its types
+  /// Purged action objects are skipped, see Commit::action_objects
+  pub fn decoded_actions<T, A>(
+    &self,
+    storage_id: &str,
+  ) -> Result<Vec<ActionObject<T, A>>, String>
+  where
+    T: ObjectExt + for<'de> Deserialize<'de>,
+    A: ActionExt<ObjectType = T> + for<'de> Deserialize<'de>,
+  {
+    let mut res = vec![];
+    for (uaob, aob_str) in
+      self.action_objects()?.iter().zip(&self.serialized_actions)
+    {
+      if uaob.storage_id == storage_id && !uaob.is_purged() {
+        res.push(deserialize_action_object::<T, A>(aob_str)?);
+      }
     }
+    Ok(res)
+  }
+  /// Blobs referred by its action objects
+  pub fn blobs(&self) -> Vec<BlobRef> {
+    blob::blob_refs(&self.serialized_actions)
+  }
+  /// Latest clock of its action objects
+  pub fn clock(&self) -> Option<Hlc> {
+    self
+      .serialized_actions
+      .iter()
+      .filter_map(|aob| {
+        serde_json::from_str::<UniversalActionObject>(aob)
+          .ok()
+          .and_then(|uaob| uaob.clock)
+      })
+      .max()
+  }
+  // Take action objects, leaving only the commit header
+  pub(crate) fn take_serialized_actions(&mut self) -> Vec<String> {
+    std::mem::take(&mut self.serialized_actions)
+  }
+  pub(crate) fn extend_serialized_actions(&mut self, actions: Vec<String>) {
+    self.serialized_actions.extend(actions);
   }
   fn add_action_object(&mut self, aob: impl Serialize) {
     self
@@ -246,33 +662,242 @@ impl Commit {
   fn set_ancestor_id(&mut self, ancestor_id: Uuid) {
     self.ancestor_id = ancestor_id;
   }
-  fn is_remote(&self) -> bool {
+  /// Server signature, None if not pushed yet
+  pub fn remote_signature(&self) -> Option<&str> {
+    self.remote_signature.as_deref()
+  }
+  /// Whether it is merged and signed by the server
+  pub fn is_remote(&self) -> bool {
     self.remote_signature.is_some()
   }
-  fn is_local(&self) -> bool {
+  pub fn is_local(&self) -> bool {
     !self.is_remote()
   }
-  fn add_remote_signature(&mut self) -> Result<(), String> {
+  fn add_remote_signature(&mut self, key: &SigningKey) -> Result<(), String> {
     if self.is_remote() {
       return Err("Commit already has remote signature!".into());
     }
-    let signature = sha1_signature(&self)?;
+    let signature = ed25519_signature(key, &self)?;
     self.remote_signature = Some(signature);
     Ok(())
   }
-  fn has_valid_remote_signature(&self) -> Result<bool, String> {
+  // Check commit and all of its action objects
+  // are signed by the given remote key
+  fn has_valid_remote_signature(
+    &self,
+    key: &VerifyingKey,
+  ) -> Result<bool, String> {
     let mut copied = self.clone();
-    let sig1 = copied.remote_signature.take();
-    let sig2 = sha1_signature(&self)?;
-    if let Some(sig1) = sig1 {
-      if sig1 == sig2 {
-        return Ok(true);
+    let signature = match copied.remote_signature.take() {
+      Some(signature) => signature,
+      None => return Ok(false),
+    };
+    if !ed25519_verify(key, &copied, &signature)? {
+      return Ok(false);
+    }
+    for aob_str in &self.serialized_actions {
+      let uaob: UniversalActionObject = serde_json::from_str(aob_str)
+        .map_err(|_| "Error while deser aob into universal aob".to_string())?;
+      if !uaob.has_valid_remote_signature(key)? {
+        return Ok(false);
       }
     }
-    Ok(false)
+    Ok(true)
+  }
+}
+
+/// Commit metadata, see Repository::commit_ctx_with_meta
+/// Pushed and merged along with the commit
+#[derive(Debug, Clone, Default)]
+pub struct CommitMeta {
+  meta: BTreeMap<String, String>,
+  tags: Vec<String>,
+}
+
+impl CommitMeta {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  /// Add key/value annotation, e.g. ("app_version", "1.2.0")
+  pub fn with_value(mut self, key: &str, value: &str) -> Self {
+    self.meta.insert(key.to_string(), value.to_string());
+    self
+  }
+  pub fn with_tag(mut self, tag: &str) -> Self {
+    if !self.tags.iter().any(|t| t == tag) {
+      self.tags.push(tag.to_string());
+    }
+    self
+  }
+}
+
+/// Commit builder, see Repository::commit
+/// Scopes the commit context to a closure, so nothing is committed
+/// unless the closure succeeds
+pub struct CommitBuilder<'a> {
+  repo: &'a Repository,
+  comment: String,
+  meta: CommitMeta,
+}
+
+impl<'a> CommitBuilder<'a> {
+  pub fn comment(mut self, comment: &str) -> Self {
+    self.comment = comment.to_string();
+    self
+  }
+  pub fn tag(mut self, tag: &str) -> Self {
+    self.meta = self.meta.with_tag(tag);
+    self
+  }
+  /// Add key/value annotation, see CommitMeta::with_value
+  pub fn meta(mut self, key: &str, value: &str) -> Self {
+    self.meta = self.meta.with_value(key, value);
+    self
+  }
+  /// Run f with the commit context, then commit
+  /// If f errors, the context is aborted and nothing is stored.
+  /// Returns the commit id once every action object got applied
+  pub fn run(
+    self,
+    f: impl FnOnce(&mut CommitContextGuard<'a>) -> Result<(), String>,
+  ) -> Result<Uuid, String> {
+    let mut ctx = self.repo.commit_ctx_with_meta(&self.comment, self.meta);
+    match f(&mut ctx) {
+      Ok(()) => ctx.commit()?.into_result(),
+      Err(e) => {
+        ctx.abort();
+        Err(e)
+      }
+    }
+  }
+}
+
+/// Commit filter, see Repository::commits
+/// Every given criterion must match
+#[derive(Debug, Clone, Default)]
+pub struct CommitFilter {
+  uid: Option<String>,
+  storage_id: Option<String>,
+  tags: Vec<String>,
+  meta: Vec<(String, String)>,
+  from: Option<DateTime<Utc>>,
+  to: Option<DateTime<Utc>>,
+  comment: Option<String>,
+  // Pagination over the matching commits
+  offset: usize,
+  limit: Option<usize>,
+}
+
+impl CommitFilter {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  /// Commits of the given user
+  pub fn with_uid(mut self, uid: &str) -> Self {
+    self.uid = Some(uid.to_string());
+    self
+  }
+  /// Commits having action objects of the storage
+  pub fn with_storage_id(mut self, storage_id: &str) -> Self {
+    self.storage_id = Some(storage_id.to_string());
+    self
+  }
+  /// Commits having the tag
+  pub fn with_tag(mut self, tag: &str) -> Self {
+    self.tags.push(tag.to_string());
+    self
+  }
+  /// Commits annotated with the key/value pair
+  pub fn with_value(mut self, key: &str, value: &str) -> Self {
+    self.meta.push((key.to_string(), value.to_string()));
+    self
+  }
+  /// Commits made in the [from, to) time range
+  pub fn with_time_range(
+    mut self,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+  ) -> Self {
+    self.from = from;
+    self.to = to;
+    self
+  }
+  /// Commits whose comment contains text
+  pub fn with_comment(mut self, text: &str) -> Self {
+    self.comment = Some(text.to_string());
+    self
+  }
+  /// Skip the first offset matching commits
+  pub fn with_offset(mut self, offset: usize) -> Self {
+    self.offset = offset;
+    self
+  }
+  /// Return at most limit commits
+  pub fn with_limit(mut self, limit: usize) -> Self {
+    self.limit = Some(limit);
+    self
+  }
+  fn matches(&self, commit: &Commit) -> bool {
+    self.uid.as_ref().is_none_or(|uid| &commit.uid == uid)
+      && self.tags.iter().all(|tag| commit.tags.contains(tag))
+      && self
+        .meta
+        .iter()
+        .all(|(key, value)| commit.meta.get(key) == Some(value))
+      && self.from.is_none_or(|from| commit.dtime >= from)
+      && self.to.is_none_or(|to| commit.dtime < to)
+      && self
+        .comment
+        .as_ref()
+        .is_none_or(|text| commit.comment.contains(text.as_str()))
+      && self.storage_id.as_ref().is_none_or(|storage_id| {
+        commit.serialized_actions.iter().any(|aob_str| {
+          serde_json::from_str::<UniversalActionObject>(aob_str)
+            .is_ok_and(|aob| &aob.storage_id == storage_id)
+        })
+      })
   }
 }
 
+/// Prefix of errors of writes based on an outdated object revision
+pub const REVISION_CONFLICT: &str = "Revision conflict";
+
+// Exported object record, see Storage::export_json
+#[derive(Serialize)]
+struct ExportRecord<'a, T, A>
+where
+  T: ObjectExt,
+  A: ActionExt<ObjectType = T>,
+{
+  id: Uuid,
+  object: &'a T,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  actions: Option<Vec<&'a ActionObject<T, A>>>,
+}
+
+// Imported object record, see Storage::import_json
+#[derive(Deserialize)]
+struct ImportRecord<T> {
+  object: T,
+}
+
+/// Storage object history entry
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HistoryEntry {
+  pub action_id: Uuid,
+  pub dtime: DateTime<Utc>,
+  // Hybrid logical clock, None if created by an older version
+  pub clock: Option<Hlc>,
+  pub uid: String,
+  pub commit_id: Option<Uuid>,
+  // Human readable action (ActionExt::display)
+  pub display: String,
+  // Signature of the object state after the action
+  pub object_signature: String,
+  // Remote or still pending local action
+  pub is_remote: bool,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct StorageObject<T, A>
 where
@@ -283,7 +908,12 @@ where
   id: Uuid,
   // StorageId
   storage_id: String,
-  // Remote actions
+  // Schema version of the object payloads
+  #[serde(default)]
+  schema_version: u32,
+  // Remote actions not yet appended to the action log
+  // Objects stored before the log have all of them here
+  #[serde(default = "Vec::new")]
   remote_actions: Vec<ActionObject<T, A>>,
   // Local actions
   local_actions: Vec<ActionObject<T, A>>,
@@ -291,6 +921,92 @@ where
   remote_object: Option<T>,
   // Latest local object
   local_object: T,
+  // Latest logged remote action, None if nothing logged
+  #[serde(default)]
+  log_head: Option<LogHead>,
+  // Logged remote actions, read on first use
+  #[serde(skip, default = "ActionLog::default")]
+  action_log: ActionLog<T, A>,
+}
+
+// Latest remote action of the action log
+// Kept with the object state, so neither removal checks nor new
+// actions need the log to be read
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct LogHead {
+  action_id: Uuid,
+  dtime: DateTime<Utc>,
+  uid: String,
+  // Object removed by the logged actions
+  removed: bool,
+  // Number of logged actions, None if logged before counting them
+  #[serde(default)]
+  len: Option<usize>,
+}
+
+// Object state after the first position remote actions
+// Replays of the object history start from the latest one before
+#[derive(Serialize, Deserialize, Debug)]
+enum StoredCheckpoint {
+  V1 {
+    schema_version: u32,
+    position: usize,
+    action_id: Uuid,
+    object: String,
+  },
+}
+
+// Checkpoint of the current schema version
+#[derive(Debug, Clone)]
+struct Checkpoint<T> {
+  position: usize,
+  action_id: Uuid,
+  object: T,
+}
+
+type ActionLogReader<T, A> =
+  Arc<dyn Fn() -> Result<Vec<ActionObject<T, A>>, String> + Send + Sync>;
+
+type CheckpointReader<T> = Arc<dyn Fn() -> Vec<Checkpoint<T>> + Send + Sync>;
+
+// Remote actions of the action log, read once on first use
+#[derive(Clone)]
+struct ActionLog<T, A>
+where
+  T: ObjectExt,
+  A: ActionExt<ObjectType = T>,
+{
+  read: Option<ActionLogReader<T, A>>,
+  actions: OnceLock<Vec<ActionObject<T, A>>>,
+  read_checkpoints: Option<CheckpointReader<T>>,
+  checkpoints: OnceLock<Vec<Checkpoint<T>>>,
+}
+
+impl<T, A> Default for ActionLog<T, A>
+where
+  T: ObjectExt,
+  A: ActionExt<ObjectType = T>,
+{
+  fn default() -> Self {
+    Self {
+      read: None,
+      actions: OnceLock::new(),
+      read_checkpoints: None,
+      checkpoints: OnceLock::new(),
+    }
+  }
+}
+
+impl<T, A> Debug for ActionLog<T, A>
+where
+  T: ObjectExt,
+  A: ActionExt<ObjectType = T>,
+{
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("ActionLog")
+      .field("loaded", &self.actions.get().map(|a| a.len()))
+      .finish()
+  }
 }
 
 /// Implementing deref for StorageObject<T, A>
@@ -308,8 +1024,12 @@ where
 
 impl<T, A> StorageObject<T, A>
 where
-  T: ObjectExt + Serialize + for<'de> Deserialize<'de>,
-  A: ActionExt<ObjectType = T> + Serialize + for<'de> Deserialize<'de> + Debug,
+  T: ObjectExt + Serialize + for<'de> Deserialize<'de> + JsonSchema,
+  A: ActionExt<ObjectType = T>
+    + Serialize
+    + for<'de> Deserialize<'de>
+    + Debug
+    + JsonSchema,
 {
   /// Create ActionObject from Action
   /// and add it to the given Commit
@@ -318,6 +1038,9 @@ where
     action: A,
     commit: &mut CommitContextGuard,
   ) -> Result<(), String> {
+    if self.is_removed() {
+      return Err(format!("Object {} is removed", self.id));
+    }
     let aob = self.create_action_object(
       &commit.ctx,
       &commit.temp_commit,
@@ -326,26 +1049,114 @@ where
     commit.add_action_object(aob);
     Ok(())
   }
+  /// Patch object only if it is still at the given revision
+  /// Compare-and-set on top of the action log: fails with a revision
+  /// conflict error if the object changed since the caller read it.
+  /// Changes stored after this object was read fail at commit.
+  pub fn patch_if(
+    &self,
+    revision: Uuid,
+    action: A,
+    commit: &mut CommitContextGuard,
+  ) -> Result<(), String> {
+    if self.revision() != revision {
+      return Err(format!(
+        "{} on object {}: expected revision {}, found {}",
+        REVISION_CONFLICT,
+        self.id,
+        revision,
+        self.revision()
+      ));
+    }
+    self.patch(action, commit)
+  }
+  /// Revision token, the id of the latest action on the object
+  /// Changes with every local or remote action, see patch_if
+  pub fn revision(&self) -> Uuid {
+    self.last_action_id().unwrap_or_default()
+  }
+  /// Remove object by adding a Remove action object to the given Commit
+  /// Objects referencing it are removed as well, or the commit is
+  /// rejected, depending on the declared references
+  pub fn remove(&self, commit: &mut CommitContextGuard) -> Result<(), String> {
+    if self.is_removed() {
+      return Err(format!("Object {} is already removed", self.id));
+    }
+    let aob = self.create_action_object(
+      &commit.ctx,
+      &commit.temp_commit,
+      ActionKind::Remove,
+    )?;
+    commit.add_action_object(aob);
+    commit.cascade_remove(&self.storage_id, self.id)
+  }
+  /// Storage object id
+  /// Objects of other storages reference it by this id
+  pub fn id(&self) -> Uuid {
+    self.id
+  }
+  /// Check whether object is removed
+  /// Removed objects are kept as tombstones, but are skipped by
+  /// storage iterators, queries and counts
+  pub fn is_removed(&self) -> bool {
+    match Self::latest_removal(self.local_actions.iter()) {
+      Some(removed) => removed,
+      None => self.is_remote_removed(),
+    }
+  }
+  // Check whether object is removed by its remote actions
+  fn is_remote_removed(&self) -> bool {
+    Self::latest_removal(self.remote_actions.iter())
+      .or(self.log_head.as_ref().map(|head| head.removed))
+      .unwrap_or(false)
+  }
+  // Check whether object is removed after the first count actions
+  fn is_removed_after(&self, count: usize) -> Result<bool, String> {
+    Ok(Self::latest_removal(self.actions()?.take(count)).unwrap_or(false))
+  }
+  // Removal state set by the latest Create, Restore or Remove action
+  // None if there is none of them
+  fn latest_removal<'a>(
+    actions: impl Iterator<Item = &'a ActionObject<T, A>>,
+  ) -> Option<bool>
+  where
+    T: 'a,
+    A: 'a,
+  {
+    actions
+      .filter(|aob| !aob.is_kind_patch())
+      .last()
+      .map(|aob| matches!(aob.action, ActionKind::Remove))
+  }
   // Create new Storage Object by providing a ActionKind::Create
   // Action Object
-  fn new_from_aob(aob: ActionObject<T, A>) -> Result<Self, String> {
+  fn new_from_aob(
+    aob: ActionObject<T, A>,
+    schema_version: u32,
+  ) -> Result<Self, String> {
     if let ActionKind::Create(data) = aob.action.clone() {
       let res = match aob.is_local() {
         true => Self {
           id: aob.object_id,
           storage_id: aob.storage_id.clone(),
+          schema_version,
           remote_actions: vec![],
           local_actions: vec![aob],
           remote_object: None,
           local_object: data,
+          log_head: None,
+          action_log: ActionLog::default(),
         },
         false => Self {
           id: aob.object_id,
           storage_id: aob.storage_id.clone(),
+          schema_version,
           remote_actions: vec![aob],
           local_actions: vec![],
           remote_object: Some(data.clone()),
           local_object: data,
+          log_head: None,
+          action_log: ActionLog::default(),
         },
       };
       return Ok(res);
@@ -362,6 +1173,10 @@ where
   fn is_remote_object(&self) -> bool {
     !self.is_local_object()
   }
+  /// Check whether object has not yet pushed local actions
+  pub fn has_local_changes(&self) -> bool {
+    !self.local_actions.is_empty()
+  }
   // Clear all local changes
   // If object is local (no remote actions and object state)
   // we should not be here. That object should be removed without
@@ -380,35 +1195,563 @@ where
   }
   // Rebuild local objects
   // Only should use when remote update occurs
-  fn rebuild_local_objects(&mut self) -> Result<(), String> {
+  // Local actions conflicting with the remote action
+  // are resolved by the given resolver
+  fn rebuild_local_objects(
+    &mut self,
+    remote_action: Option<&A>,
+    remote_clock: Option<Hlc>,
+    resolver: &dyn ConflictResolver<T, A>,
+  ) -> Result<Vec<Conflict<T, A>>, String> {
     // First set remote object as local one
     if let Some(remote_object) = &self.remote_object {
       self.local_object = remote_object.to_owned();
     } else {
       return Err("Only remote object can be rebuild".to_string());
     }
+    let mut conflicts = vec![];
+    let mut local_actions: Vec<ActionObject<T, A>> = vec![];
+    // Replayed in clock order, dtimes of clients might be skewed
+    let mut replayed = std::mem::take(&mut self.local_actions);
+    replayed.sort_by_key(|aob| aob.clock_order());
     // Re apply action objects and update their object signature & dtimes
-    for action_object in &mut self.local_actions {
-      if let ActionKind::Patch(action) = &action_object.action {
+    for mut action_object in replayed {
+      if !action_object.is_kind_create() {
         // Create patched data
-        let patched_data = action.apply_patch(
+        let patched_data = action_object.action.apply(
           &self.local_object,
           action_object.dtime,
           &action_object.uid,
-        )?;
-        // Calculate new signature
-        let signature = sha1_signature(&patched_data)?;
-        // Set new signature
+        );
+        // Detect conflict
+        let kind = match (&patched_data, &action_object.action, remote_action) {
+          (Err(e), _, _) => Some(ConflictKind::ApplyError(e.to_string())),
+          // Additive actions compose with additive remote ones
+          (Ok(_), ActionKind::Patch(action), Some(remote_action))
+            if action.conflicts_with(remote_action)
+              && !(action.is_additive() && remote_action.is_additive()) =>
+          {
+            Some(ConflictKind::Collision)
+          }
+          _ => None,
+        };
+        let patched_data = match (kind, &action_object.action) {
+          (Some(kind), ActionKind::Patch(action)) => {
+            let conflict = Conflict {
+              object_id: self.id,
+              action_id: action_object.id,
+              object: self.local_object.clone(),
+              local_action: action.clone(),
+              remote_action: remote_action.cloned(),
+              local_clock: action_object.clock,
+              remote_clock,
+              kind,
+            };
+            let resolution = resolver.resolve(&conflict)?;
+            conflicts.push(conflict);
+            match resolution {
+              // Drop local action
+              Resolution::TakeRemote => continue,
+              Resolution::TakeLocal => patched_data?,
+              Resolution::Replace(merged) => {
+                let patched_data = merged.apply_patch(
+                  &self.local_object,
+                  action_object.dtime,
+                  &action_object.uid,
+                )?;
+                action_object.action = ActionKind::Patch(merged);
+                patched_data
+              }
+            }
+          }
+          _ => patched_data?,
+        };
+        // Calculate new signature
+        let signature = object_signature(&patched_data)?;
+        // Set new signature
         action_object.object_signature = signature;
+        // Relink parent, as local actions might be dropped
+        action_object.parent_action_id = local_actions
+          .last()
+          .map(|i| i.id)
+          .or(self.last_remote_action_id());
         // Reset dtimes
+        // Clock is kept, it orders the action by when it was created
         action_object.reset_dtime();
         // set local object to patched data
         self.local_object = patched_data;
       }
+      local_actions.push(action_object);
+    }
+    self.local_actions = local_actions;
+    Ok(conflicts)
+  }
+  // Remote actions stored in the action log
+  // Read on first use
+  fn logged_actions(&self) -> Result<&[ActionObject<T, A>], String> {
+    if let Some(actions) = self.action_log.actions.get() {
+      return Ok(actions);
+    }
+    let actions = match (&self.log_head, &self.action_log.read) {
+      (None, _) => vec![],
+      (Some(_), Some(read)) => read()?,
+      (Some(_), None) => {
+        return Err(format!("No action log of object {}", self.id))
+      }
+    };
+    Ok(self.action_log.actions.get_or_init(|| actions))
+  }
+  // Remote actions in order
+  fn remote_chain(
+    &self,
+  ) -> Result<impl DoubleEndedIterator<Item = &ActionObject<T, A>>, String> {
+    Ok(
+      self
+        .logged_actions()?
+        .iter()
+        .chain(self.remote_actions.iter()),
+    )
+  }
+  // All actions in order
+  // Remote actions first, then pending local ones
+  fn actions(
+    &self,
+  ) -> Result<impl DoubleEndedIterator<Item = &ActionObject<T, A>>, String> {
+    Ok(self.remote_chain()?.chain(self.local_actions.iter()))
+  }
+  // Move the logged remote actions back to the object,
+  // so the action log is written anew on save
+  fn unlog_actions(&mut self) -> Result<(), String> {
+    let mut actions = self.logged_actions()?.to_vec();
+    actions.append(&mut self.remote_actions);
+    self.remote_actions = actions;
+    self.log_head = None;
+    self.action_log = ActionLog::default();
+    Ok(())
+  }
+  // Replace remote action by its redacted version
+  // Returns whether it is found
+  fn redact(&mut self, redacted: ActionObject<T, A>) -> Result<bool, String> {
+    if !self.remote_chain()?.any(|aob| aob.id == redacted.id) {
+      return Ok(false);
+    }
+    // Log is written anew on save
+    self.unlog_actions()?;
+    let aob = self
+      .remote_actions
+      .iter_mut()
+      .find(|aob| aob.id == redacted.id)
+      .unwrap();
+    if aob.parent_action_id != redacted.parent_action_id
+      || aob.object_signature != redacted.object_signature
+    {
+      return Err(format!("Redacted action {} does not match", redacted.id));
+    }
+    *aob = redacted;
+    Ok(true)
+  }
+  // Object continuing its remote chain with the stashed remote actions
+  // and the restore after them, see Storage::stash_action_object
+  // Objects created by a redacted action have no chain before.
+  fn restore_stashed(
+    object: Option<Self>,
+    stashed: Vec<ActionObject<T, A>>,
+    restore: ActionObject<T, A>,
+    schema_version: u32,
+    resolver: &dyn ConflictResolver<T, A>,
+  ) -> Result<(Self, Vec<Conflict<T, A>>), String> {
+    let data = match &restore.action {
+      ActionKind::Restore(data) => data.clone(),
+      _ => return Err("Object state is unknown until a restore".into()),
+    };
+    if restore.is_local()
+      || !verify_object_signature(&data, &restore.object_signature)?
+    {
+      return Err("Restore signature error!".into());
+    }
+    let mut object = object.unwrap_or_else(|| Self {
+      id: restore.object_id,
+      storage_id: restore.storage_id.clone(),
+      schema_version,
+      remote_actions: vec![],
+      local_actions: vec![],
+      remote_object: None,
+      local_object: data.clone(),
+      log_head: None,
+      action_log: ActionLog::default(),
+    });
+    let mut parent_action_id = object.last_remote_action_id();
+    for aob in stashed.iter().chain(std::iter::once(&restore)) {
+      if aob.parent_action_id != parent_action_id {
+        return Err("Action Object parent id mismatch".into());
+      }
+      parent_action_id = Some(aob.id);
+    }
+    let remote_clock = restore.clock;
+    object.remote_actions.extend(stashed);
+    object.remote_actions.push(restore);
+    object.remote_object = Some(data);
+    let conflicts =
+      object.rebuild_local_objects(None, remote_clock, resolver)?;
+    Ok((object, conflicts))
+  }
+  // Object state after the given action, None if unknown
+  // The state after a redacted action is unknown until a restore
+  fn state_after(
+    aob: &ActionObject<T, A>,
+    object: Option<&T>,
+    known: bool,
+  ) -> Result<Option<T>, String> {
+    match (&aob.action, object, known) {
+      (ActionKind::Redacted(_), _, _) => Ok(None),
+      (ActionKind::Restore(data), _, false) => Ok(Some(data.clone())),
+      (_, _, false) => Ok(None),
+      (ActionKind::Create(data), _, _) => Ok(Some(data.clone())),
+      (action, Some(object), _) => {
+        action.apply(object, aob.dtime, &aob.uid).map(Some)
+      }
+      (_, None, _) => Err("Action chain must start with create".into()),
+    }
+  }
+  // Replay the first count actions
+  // None if no action replayed
+  // Starts from the latest checkpoint within them, if any
+  fn replay(&self, count: usize) -> Result<Option<T>, String> {
+    let (skip, mut object) = match self.verified_checkpoint(count)? {
+      Some(checkpoint) => {
+        (checkpoint.position, Some(checkpoint.object.clone()))
+      }
+      None => (0, None),
+    };
+    let mut known = true;
+    for aob in self.actions()?.take(count).skip(skip) {
+      match Self::state_after(aob, object.as_ref(), known)? {
+        Some(next) => {
+          object = Some(next);
+          known = true;
+        }
+        None => known = false,
+      }
+    }
+    match known {
+      true => Ok(object),
+      false => Err("Object state after a redacted action is unknown".into()),
+    }
+  }
+  // Stored checkpoints in order, read on first use
+  fn checkpoints(&self) -> &[Checkpoint<T>] {
+    self.action_log.checkpoints.get_or_init(|| {
+      match &self.action_log.read_checkpoints {
+        Some(read) => read(),
+        None => vec![],
+      }
+    })
+  }
+  // Latest checkpoint within the first count actions that is still
+  // on the remote action chain and matches the object signature of
+  // its action
+  fn verified_checkpoint(
+    &self,
+    count: usize,
+  ) -> Result<Option<&Checkpoint<T>>, String> {
+    let candidates = self.checkpoints().iter().rev().filter(|checkpoint| {
+      checkpoint.position > 0 && checkpoint.position <= count
+    });
+    for checkpoint in candidates {
+      match self.remote_chain()?.nth(checkpoint.position - 1) {
+        Some(aob)
+          if aob.id == checkpoint.action_id
+            && verify_object_signature(
+              &checkpoint.object,
+              &aob.object_signature,
+            )? =>
+        {
+          return Ok(Some(checkpoint))
+        }
+        _ => continue,
+      }
+    }
+    Ok(None)
+  }
+  // Checkpoint the remote object state, if at least CHECKPOINT_EVERY
+  // remote actions followed the latest stored checkpoint
+  // Returns whether written
+  fn write_checkpoint(&self, ctx: &Context) -> Result<bool, String> {
+    let (object, action_id) =
+      match (&self.remote_object, self.last_remote_action_id()) {
+        (Some(object), Some(action_id)) => (object, action_id),
+        _ => return Ok(false),
+      };
+    let position = match self.log_head.as_ref().and_then(|head| head.len) {
+      Some(len) => len + self.remote_actions.len(),
+      None => self.remote_chain()?.count(),
+    };
+    let checkpointed = self.checkpoints().last().map_or(0, |c| c.position);
+    if position < checkpointed + CHECKPOINT_EVERY {
+      return Ok(false);
+    }
+    let path = path_helper::storage_object_checkpoint_path(
+      ctx,
+      &self.storage_id,
+      self.id,
+    );
+    let checkpoint = StoredCheckpoint::V1 {
+      schema_version: self.schema_version,
+      position,
+      action_id,
+      object: serde_json::to_string(object).map_err(|e| e.to_string())?,
+    };
+    match ctx.backend().exists(&path) {
+      true => binary_continuous_append(ctx, path, checkpoint)?,
+      false => binary_continuous_write(ctx, path, &[checkpoint])?,
+    }
+    Ok(true)
+  }
+  // Position of the given action in the action chain
+  fn action_position(&self, action_id: Uuid) -> Result<usize, String> {
+    self
+      .actions()?
+      .position(|aob| aob.id == action_id)
+      .ok_or(format!("Action {} not found in object history", action_id))
+  }
+  // Object state right before the given action
+  fn object_before_action(&self, action_id: Uuid) -> Result<T, String> {
+    self
+      .replay(self.action_position(action_id)?)?
+      .ok_or("No object state before create action".into())
+  }
+  // Object state JSON right after the given action, None if unknown
+  fn state_json_after(&self, action_id: Uuid) -> Option<String> {
+    let state = match self.last_remote_action_id() == Some(action_id) {
+      true => self.remote_object.clone(),
+      false => self
+        .replay(self.action_position(action_id).ok()? + 1)
+        .ok()?,
+    };
+    serde_json::to_string(&state?).ok()
+  }
+  // Mirrored state of the object
+  fn mirror_row(&self) -> Result<MirrorRow, String> {
+    let (dtime, uid) =
+      match self.local_actions.last().or(self.remote_actions.last()) {
+        Some(latest) => (latest.dtime, latest.uid.clone()),
+        None => {
+          let head = self.log_head.as_ref().ok_or("Empty action history")?;
+          (head.dtime, head.uid.clone())
+        }
+      };
+    Ok(MirrorRow {
+      object_id: self.id,
+      dtime,
+      uid,
+      data: serde_json::to_value(&self.local_object)
+        .map_err(|e| e.to_string())?,
+    })
+  }
+  /// Object history
+  /// Remote and local actions in order. Remote actions are read
+  /// from the action log of the object on first use
+  pub fn history(&self) -> Result<Vec<HistoryEntry>, String> {
+    let res = self
+      .actions()?
+      .map(|aob| HistoryEntry {
+        action_id: aob.id,
+        dtime: aob.dtime,
+        clock: aob.clock,
+        uid: aob.uid.to_string(),
+        commit_id: aob.commit_id,
+        display: aob.action.display(),
+        object_signature: aob.object_signature.to_string(),
+        is_remote: aob.is_remote(),
+      })
+      .collect();
+    Ok(res)
+  }
+  /// Unsynced local changes
+  /// Field level changes from the remote to the local state,
+  /// and the pending local actions, e.g. to review them before push
+  pub fn diff(&self) -> Result<ObjectDiff, String> {
+    let remote = self
+      .remote_object
+      .as_ref()
+      .map(serde_json::to_value)
+      .transpose()
+      .map_err(|e| e.to_string())?;
+    let local =
+      serde_json::to_value(&self.local_object).map_err(|e| e.to_string())?;
+    Ok(ObjectDiff {
+      object_id: self.id,
+      changes: diff::diff_values(remote.as_ref(), &local),
+      remote,
+      local,
+      pending_actions: self
+        .local_actions
+        .iter()
+        .map(|aob| aob.action.display())
+        .collect(),
+    })
+  }
+  /// Object state right after the given action
+  pub fn object_at_action(&self, action_id: Uuid) -> Result<T, String> {
+    self
+      .replay(self.action_position(action_id)? + 1)?
+      .ok_or("Empty action history".into())
+  }
+  /// Object state at the given time
+  /// Replays every action applied until dtime.
+  /// None if the object did not exist at that time
+  pub fn object_at(&self, dtime: DateTime<Utc>) -> Result<Option<T>, String> {
+    self.replay(self.actions()?.take_while(|aob| aob.dtime <= dtime).count())
+  }
+  // Object state after the actions of the given commits
+  // None if the object did not exist or was removed by then
+  fn object_at_commits(
+    &self,
+    commit_ids: &HashSet<Uuid>,
+  ) -> Result<Option<T>, String> {
+    let count = self
+      .actions()?
+      .take_while(|aob| {
+        aob.commit_id.is_some_and(|id| commit_ids.contains(&id))
+      })
+      .count();
+    if self.is_removed_after(count)? {
+      return Ok(None);
+    }
+    self.replay(count)
+  }
+  // Replay the action chain checking parent links and signatures
+  // Returns the local and remote object states it results in
+  fn verify_actions(&self) -> Result<(T, Option<T>), String> {
+    let mut parent_action_id = None;
+    let mut object: Option<T> = None;
+    let mut remote_object = None;
+    let mut known = true;
+    let remote_count = self.remote_chain()?.count();
+    let count = remote_count + self.local_actions.len();
+    for (index, aob) in self.actions()?.enumerate() {
+      if aob.object_id != self.id || aob.storage_id != self.storage_id {
+        return Err(format!("Action {} belongs to another object", aob.id));
+      }
+      if aob.parent_action_id != parent_action_id {
+        return Err(format!("Action {} parent id mismatch", aob.id));
+      }
+      if aob.is_kind_create() && object.is_some() {
+        return Err(format!("Create action {} on existing object", aob.id));
+      }
+      parent_action_id = Some(aob.id);
+      let next = Self::state_after(aob, object.as_ref(), known)?;
+      known = next.is_some();
+      // Unknown states after redacted actions are taken from the cache,
+      // checked by the signature of the latest remote and local action
+      let next = match next {
+        Some(next) => next,
+        None if index + 1 == remote_count => {
+          self.remote_object.clone().ok_or("No remote object")?
+        }
+        None if index + 1 == count => self.local_object.clone(),
+        None => continue,
+      };
+      known = true;
+      if !verify_object_signature(&next, &aob.object_signature)? {
+        return Err(format!("Action {} signature mismatch", aob.id));
+      }
+      if index + 1 == remote_count {
+        remote_object = Some(next.clone());
+      }
+      object = Some(next);
+    }
+    match object {
+      Some(object) => Ok((object, remote_object)),
+      None => Err("Empty action chain".into()),
+    }
+  }
+  // Check action chain invariants, see verify::check_storage_object
+  // Remote signatures are verified if any key given, valid if
+  // signed by one of them
+  pub(crate) fn check_invariants(
+    &self,
+    keys: &[VerifyingKey],
+  ) -> Result<(), String> {
+    let (local_object, remote_object) = self.verify_actions()?;
+    if let Some(aob) = self.remote_chain()?.find(|aob| aob.is_local()) {
+      return Err(format!("Remote action {} is not remote signed", aob.id));
+    }
+    if let Some(aob) = self.local_actions.iter().find(|aob| aob.is_remote()) {
+      return Err(format!("Local action {} is remote signed", aob.id));
+    }
+    if !keys.is_empty() {
+      for aob in self.remote_chain()? {
+        let uaob: UniversalActionObject = serde_json::to_value(aob)
+          .and_then(serde_json::from_value)
+          .map_err(|e| e.to_string())?;
+        let mut valid = false;
+        for key in keys {
+          valid = valid || uaob.has_valid_remote_signature(key)?;
+        }
+        if !valid {
+          return Err(format!("Action {} remote signature mismatch", aob.id));
+        }
+      }
+    }
+    if canonical_json(&local_object)? != canonical_json(&self.local_object)? {
+      return Err("Local object does not match its actions".into());
+    }
+    if canonical_json(&remote_object)? != canonical_json(&self.remote_object)? {
+      return Err("Remote object does not match its actions".into());
     }
     Ok(())
   }
-  // Create action object by providing a Context, Commit and Action object.
+  // Rewrite legacy object signatures with the default algorithm
+  // Remote actions are re-signed with signing_key, without it
+  // they keep their legacy signatures.
+  // Returns the rewritten action objects
+  fn rehash_actions(
+    &mut self,
+    signing_key: Option<&SigningKey>,
+  ) -> Result<Vec<UniversalActionObject>, String> {
+    self.unlog_actions()?;
+    let remote_count = self.remote_actions.len();
+    let mut object: Option<T> = None;
+    let mut known = true;
+    let mut res = vec![];
+    for (index, aob) in self
+      .remote_actions
+      .iter_mut()
+      .chain(self.local_actions.iter_mut())
+      .enumerate()
+    {
+      // Signatures of unknown states after redacted actions are kept
+      let next = match Self::state_after(aob, object.as_ref(), known)? {
+        Some(next) => next,
+        None => {
+          known = false;
+          continue;
+        }
+      };
+      known = true;
+      let is_remote = index < remote_count;
+      let (algorithm, _) = SignatureAlgorithm::parse(&aob.object_signature)?;
+      if algorithm != SignatureAlgorithm::DEFAULT
+        && (!is_remote || signing_key.is_some())
+      {
+        aob.object_signature = object_signature(&next)?;
+        let mut uaob: UniversalActionObject = serde_json::to_value(&*aob)
+          .and_then(serde_json::from_value)
+          .map_err(|e| e.to_string())?;
+        if let (true, Some(key)) = (is_remote, signing_key) {
+          uaob.remote_signature = None;
+          uaob.remote_sign(key)?;
+          aob.remote_signature = uaob.remote_signature.clone();
+        }
+        res.push(uaob);
+      }
+      object = Some(next);
+    }
+    Ok(res)
+  }
+  // Create action object by providing a Commit and Action object.
+  // Action object claims the commit uid
   // If Patch returns error, we return it back to the caller
   fn create_action_object(
     &self,
@@ -418,36 +1761,40 @@ where
   ) -> Result<ActionObject<T, A>, String> {
     let dtime = Utc::now();
     let object_signature = match &action {
-      ActionKind::Create(t) => sha1_signature(t)?,
-      ActionKind::Patch(t) => sha1_signature(&t.apply_patch(
+      ActionKind::Create(t) => object_signature(t)?,
+      kind => object_signature(&kind.apply(
         &self.local_object,
         dtime,
         &commit.uid,
       )?)?,
     };
     let res = ActionObject {
-      id: Uuid::new_v4(),
+      id: ctx.new_id(),
       storage_id: self.storage_id.clone(),
       object_id: self.id.clone(),
-      uid: ctx.uid.to_owned(),
+      uid: commit.uid.to_owned(),
       dtime,
       commit_id: Some(commit.id),
-      parent_action_id: self.local_actions.last().map(|i| i.id),
+      parent_action_id: self.last_action_id(),
       action,
       object_signature,
+      schema_hash: Some(StorageSchema::new::<T, A>()?.hash),
+      clock: Some(ctx.clock().tick()),
       remote_signature: None, // todo! This is really None always here? Can remote apply here?
     };
     Ok(res)
   }
   // Add action object
+  // Returns the updated object and the resolved conflicts
   fn add_action_object(
     &mut self,
     action_object: ActionObject<T, A>,
-  ) -> Result<Self, String> {
+    resolver: &dyn ConflictResolver<T, A>,
+  ) -> Result<(Self, Vec<Conflict<T, A>>), String> {
     if action_object.is_local() {
-      return self.add_local_action_object(action_object);
+      return Ok((self.add_local_action_object(action_object)?, vec![]));
     } else {
-      return self.add_remote_action_object(action_object);
+      return self.add_remote_action_object(action_object, resolver);
     }
   }
   // Add local action object to Storage Object
@@ -463,24 +1810,26 @@ where
     }
     // Check if action object is a patch one
     // ActionKind::Create(T) should be handled at storage level
-    if let ActionKind::Patch(action) = &action_object.action {
+    if !action_object.is_kind_create() {
       // Check parent id
-      // This way it works for when no local_actions and parent id must be None
-      if action_object.parent_action_id
-        != self.local_actions.last().map(|i| i.id)
-      {
-        return Err("Local patch error. Parent id is wrong".into());
+      // Local actions continue the remote action chain
+      if action_object.parent_action_id != self.last_action_id() {
+        return Err(format!(
+          "{} on object {}: it changed since it was read",
+          REVISION_CONFLICT, self.id
+        ));
       }
       // Patch T
-      let patched_object = action.apply_patch(
+      let patched_object = action_object.action.apply(
         &self.local_object,
         action_object.dtime,
         &action_object.uid,
       )?;
       // Check signature
-      if &action_object.object_signature
-        != &crate::prelude::sha1_signature(&patched_object)?
-      {
+      if !verify_object_signature(
+        &patched_object,
+        &action_object.object_signature,
+      )? {
         return Err("Local patch signature error!".into());
       }
       // Replace T with the patched one
@@ -499,15 +1848,14 @@ where
   fn add_remote_action_object(
     &mut self,
     action_object: ActionObject<T, A>,
-  ) -> Result<Self, String> {
+    resolver: &dyn ConflictResolver<T, A>,
+  ) -> Result<(Self, Vec<Conflict<T, A>>), String> {
     // Check if action object is a remote one
     if !action_object.is_remote() {
       return Err("Only remote action object can be added here".into());
     }
     // Check action object parent id
-    if self.remote_actions.last().map(|i| i.id)
-      != action_object.parent_action_id
-    {
+    if self.last_remote_action_id() != action_object.parent_action_id {
       return Err("Action Object parent id mismatch".into());
     }
     // Check if storage object is a remote one
@@ -516,19 +1864,20 @@ where
         "We cannot add remote action object to local storage object".into(),
       );
     }
-    // Only ActionKind::Patch(A) can be managed here
+    // Only ActionKind::Patch(A) and Restore(T) can be managed here
     // ActionKind::Create(T) should be managed at storage level
-    if let ActionKind::Patch(action) = &action_object.action {
+    if !action_object.is_kind_create() {
       // Patch T
-      let patched_object = action.apply_patch(
+      let patched_object = action_object.action.apply(
         self.remote_object.as_ref().unwrap(),
         action_object.dtime,
         &action_object.uid,
       )?;
       // Check signature
-      if &action_object.object_signature
-        != &crate::prelude::sha1_signature(&patched_object)?
-      {
+      if !verify_object_signature(
+        &patched_object,
+        &action_object.object_signature,
+      )? {
         return Err("Remote Patch signature error!".into());
       }
       // Check remote signature
@@ -536,46 +1885,460 @@ where
       if action_object.remote_signature.is_none() {
         return Err("Patch remote signature missing!".into());
       }
+      let remote_action = match &action_object.action {
+        ActionKind::Patch(action) => Some(action.clone()),
+        _ => None,
+      };
+      let remote_clock = action_object.clock;
       // Replace T with the patched one
       self.remote_object = Some(patched_object);
       // Insert action object
       self.remote_actions.push(action_object);
       // Rebuild local action objects
-      self.rebuild_local_objects()?;
+      let conflicts = self.rebuild_local_objects(
+        remote_action.as_ref(),
+        remote_clock,
+        resolver,
+      )?;
       // Save to FS
       // self.save_to_fs(ctx)?;
       // Return current local object
       // Important! We return LOCAL, as its the latest version of our
       // data object.
-      return Ok(self.to_owned());
+      return Ok((self.to_owned(), conflicts));
     }
     Err("Patch must have Patch action kind!".into())
   }
+  // Promote the first pending local action object to remote
+  // after the remote accepted and signed it
+  fn promote_local_action_object(
+    &mut self,
+    action_object: ActionObject<T, A>,
+  ) -> Result<(), String> {
+    if !action_object.is_remote() {
+      return Err("Only remote signed action object can be promoted".into());
+    }
+    match self.local_actions.first() {
+      Some(first) if first.id == action_object.id => {}
+      _ => {
+        return Err(
+          "Promoted action must be the first pending local action".into(),
+        )
+      }
+    }
+    // Object state after the promoted action
+    let object = match (&action_object.action, &self.remote_object) {
+      (ActionKind::Create(data), _) => data.clone(),
+      (action, Some(remote_object)) => {
+        action.apply(remote_object, action_object.dtime, &action_object.uid)?
+      }
+      (_, None) => {
+        return Err("Cannot promote patch of a local only object".into())
+      }
+    };
+    // Check signature
+    if !verify_object_signature(&object, &action_object.object_signature)? {
+      return Err("Promoted action signature error!".into());
+    }
+    self.local_actions.remove(0);
+    self.remote_object = Some(object);
+    self.remote_actions.push(action_object);
+    Ok(())
+  }
+  // Latest action id
+  // Every new action must be the child of it
+  fn last_action_id(&self) -> Option<Uuid> {
+    self
+      .local_actions
+      .last()
+      .map(|i| i.id)
+      .or(self.last_remote_action_id())
+  }
+  // Latest remote action id, read from the log head if all logged
+  fn last_remote_action_id(&self) -> Option<Uuid> {
+    self
+      .remote_actions
+      .last()
+      .map(|i| i.id)
+      .or(self.log_head.as_ref().map(|head| head.action_id))
+  }
   // Init storage object from FS
+  // Payloads of older schema versions are migrated
+  // to the current one. Only the object state is read,
+  // logged remote actions are read on first use
   fn read_from_fs(
     ctx: &Context,
     storage_id: &str,
     object_id: Uuid,
+    migrator: Option<&Arc<dyn Migrator>>,
   ) -> Result<Self, String> {
-    binary_read(path_helper::storage_object_path(ctx, storage_id, object_id))
+    let path = path_helper::storage_object_path(ctx, storage_id, object_id);
+    let current_version = migrator.map(|m| m.schema_version()).unwrap_or(0);
+    let (schema_version, mut value) = match binary_read(ctx, path.clone()) {
+      Ok(StoredObject::V1 {
+        schema_version,
+        object,
+      }) => (
+        schema_version,
+        serde_json::from_str::<Value>(&object).map_err(|e| e.to_string())?,
+      ),
+      // Objects stored before schema versioning
+      // Only json format objects can be migrated as values
+      Err(_) => match binary_read::<Self>(ctx, path.clone()) {
+        Ok(mut res) => {
+          res.schema_version = current_version;
+          return Ok(res);
+        }
+        Err(e) => (0, binary_read::<Value>(ctx, path).map_err(|_| e)?),
+      },
+    };
+    if schema_version > current_version {
+      return Err(format!(
+        "Object {} schema version {} is newer than {}",
+        object_id, schema_version, current_version
+      ));
+    }
+    if let Some(migrator) = migrator {
+      migrate_object_payloads(migrator.as_ref(), schema_version, &mut value)?;
+    }
+    let mut res: Self =
+      serde_json::from_value(value).map_err(|e| e.to_string())?;
+    res.schema_version = current_version;
+    if let Some(head) = &res.log_head {
+      let (checkpoint_ctx, checkpoint_storage_id) =
+        (ctx.clone(), storage_id.to_string());
+      res.action_log.read_checkpoints = Some(Arc::new(move || {
+        read_checkpoints(
+          &checkpoint_ctx,
+          &checkpoint_storage_id,
+          object_id,
+          current_version,
+        )
+      }));
+      let (ctx, storage_id) = (ctx.clone(), storage_id.to_string());
+      let (head, migrator) = (head.action_id, migrator.cloned());
+      res.action_log.read = Some(Arc::new(move || {
+        read_action_log(&ctx, &storage_id, object_id, head, migrator.as_ref())
+      }));
+    }
+    Ok(res)
+  }
+  // Persisted form of the storage object state
+  // Its remote actions must be logged first, see write_log
+  fn to_stored(
+    &self,
+    log_head: Option<LogHead>,
+  ) -> Result<StoredObject, String> {
+    let state = Self {
+      id: self.id,
+      storage_id: self.storage_id.clone(),
+      schema_version: self.schema_version,
+      remote_actions: vec![],
+      local_actions: self.local_actions.clone(),
+      remote_object: self.remote_object.clone(),
+      local_object: self.local_object.clone(),
+      log_head,
+      action_log: ActionLog::default(),
+    };
+    Ok(StoredObject::V1 {
+      schema_version: self.schema_version,
+      object: serde_json::to_string(&state).map_err(|e| e.to_string())?,
+    })
+  }
+  // Append the not yet logged remote actions to the action log
+  // The log is written anew if nothing logged yet, e.g. for objects
+  // stored before the log. Returns the new log head
+  fn write_log(&self, ctx: &Context) -> Result<Option<LogHead>, String> {
+    let last = match self.remote_actions.last() {
+      Some(last) => last,
+      None => return Ok(self.log_head.clone()),
+    };
+    let path =
+      path_helper::storage_object_log_path(ctx, &self.storage_id, self.id);
+    let entries = self
+      .remote_actions
+      .iter()
+      .map(|aob| StoredAction::new(self.schema_version, aob))
+      .collect::<Result<Vec<_>, String>>()?;
+    let len = match &self.log_head {
+      Some(head) => head.len.map(|len| len + entries.len()),
+      None => Some(entries.len()),
+    };
+    match self.log_head.is_some() {
+      true => {
+        for entry in entries {
+          binary_continuous_append(ctx, path.clone(), entry)?;
+        }
+      }
+      false => binary_continuous_write(ctx, path, &entries)?,
+    }
+    Ok(Some(LogHead {
+      action_id: last.id,
+      dtime: last.dtime,
+      uid: last.uid.clone(),
+      removed: self.is_remote_removed(),
+      len,
+    }))
+  }
+  // Init storage object files of a new object
+  fn init_fs(&self, ctx: &Context) -> Result<(), String> {
+    let log_head = self.write_log(ctx)?;
+    let object_path =
+      path_helper::storage_object_path(ctx, &self.storage_id, self.id);
+    binary_init(ctx, object_path, self.to_stored(log_head)?)?;
+    Ok(())
   }
   // Update storage object file
+  // Remote actions are logged before, so the object state never
+  // refers to actions missing from the log
   fn save_to_fs(&self, ctx: &Context) -> Result<(), String> {
+    let log_head = self.write_log(ctx)?;
     let object_path =
       path_helper::storage_object_path(ctx, &self.storage_id, self.id);
-    binary_update(object_path, &self)
+    binary_update(ctx, object_path, self.to_stored(log_head)?)
+  }
+}
+
+// Logged remote action
+// Payloads of older schema versions are migrated when read
+#[derive(Serialize, Deserialize, Debug)]
+enum StoredAction {
+  V1 { schema_version: u32, action: String },
+}
+
+impl StoredAction {
+  fn new<T, A>(
+    schema_version: u32,
+    action_object: &ActionObject<T, A>,
+  ) -> Result<Self, String>
+  where
+    T: ObjectExt + Serialize,
+    A: ActionExt<ObjectType = T> + Serialize,
+  {
+    Ok(StoredAction::V1 {
+      schema_version,
+      action: serde_json::to_string(action_object)
+        .map_err(|e| e.to_string())?,
+    })
+  }
+}
+
+// Read the checkpoints of an object
+// Unreadable ones and those of other schema versions are skipped,
+// replays without them start from the first action
+fn read_checkpoints<T>(
+  ctx: &Context,
+  storage_id: &str,
+  object_id: Uuid,
+  schema_version: u32,
+) -> Vec<Checkpoint<T>>
+where
+  T: for<'de> Deserialize<'de>,
+{
+  let path =
+    path_helper::storage_object_checkpoint_path(ctx, storage_id, object_id);
+  if !ctx.backend().exists(&path) {
+    return vec![];
+  }
+  let stored =
+    binary_continuous_read::<StoredCheckpoint, StoredCheckpoint>(ctx, path)
+      .unwrap_or_default();
+  stored
+    .into_iter()
+    .filter_map(|checkpoint| match checkpoint {
+      StoredCheckpoint::V1 {
+        schema_version: version,
+        position,
+        action_id,
+        object,
+      } if version == schema_version => Some(Checkpoint {
+        position,
+        action_id,
+        object: serde_json::from_str(&object).ok()?,
+      }),
+      _ => None,
+    })
+    .collect()
+}
+
+// Remove the checkpoints of an object, if any
+fn remove_checkpoints(
+  ctx: &Context,
+  storage_id: &str,
+  object_id: Uuid,
+) -> Result<(), String> {
+  let path =
+    path_helper::storage_object_checkpoint_path(ctx, storage_id, object_id);
+  match ctx.backend().exists(&path) {
+    true => binary_remove(ctx, path),
+    false => Ok(()),
+  }
+}
+
+// Read the remote action chain ending with head from the action log
+// Entries not on the chain, e.g. left by a rolled back commit, are
+// skipped. Of entries logged more than once the latest one is taken.
+fn read_action_log<T, A>(
+  ctx: &Context,
+  storage_id: &str,
+  object_id: Uuid,
+  head: Uuid,
+  migrator: Option<&Arc<dyn Migrator>>,
+) -> Result<Vec<ActionObject<T, A>>, String>
+where
+  T: ObjectExt + for<'de> Deserialize<'de>,
+  A: ActionExt<ObjectType = T> + for<'de> Deserialize<'de>,
+{
+  let path = path_helper::storage_object_log_path(ctx, storage_id, object_id);
+  let current_version = migrator.map(|m| m.schema_version()).unwrap_or(0);
+  let mut logged: HashMap<Uuid, ActionObject<T, A>> = HashMap::new();
+  for entry in binary_continuous_read::<StoredAction, StoredAction>(ctx, path)?
+  {
+    let StoredAction::V1 {
+      schema_version,
+      action,
+    } = entry;
+    if schema_version > current_version {
+      return Err(format!(
+        "Action of object {} schema version {} is newer than {}",
+        object_id, schema_version, current_version
+      ));
+    }
+    let mut value: Value =
+      serde_json::from_str(&action).map_err(|e| e.to_string())?;
+    if let Some(migrator) = migrator {
+      let mut payloads = vec![];
+      action_payloads(&mut value, &mut payloads);
+      for payload in payloads {
+        *payload =
+          migrate_payload(migrator.as_ref(), schema_version, payload.take())?;
+      }
+    }
+    let aob: ActionObject<T, A> =
+      serde_json::from_value(value).map_err(|e| e.to_string())?;
+    logged.insert(aob.id, aob);
+  }
+  let mut res = vec![];
+  let mut next = Some(head);
+  while let Some(action_id) = next {
+    let aob = logged.remove(&action_id).ok_or(format!(
+      "Action {} missing from the action log of object {}",
+      action_id, object_id
+    ))?;
+    next = aob.parent_action_id;
+    res.push(aob);
+  }
+  res.reverse();
+  Ok(res)
+}
+
+// Persisted storage object
+// Object is stored as json, so payloads of older schema versions
+// can be migrated as json values in the binary format as well.
+#[derive(Serialize, Deserialize, Debug)]
+enum StoredObject {
+  V1 { schema_version: u32, object: String },
+}
+
+// Migrate the T payloads of a serialized storage object:
+// local and remote objects, create and restore actions
+fn migrate_object_payloads(
+  migrator: &dyn Migrator,
+  from_version: u32,
+  object: &mut Value,
+) -> Result<(), String> {
+  let mut payloads = vec![];
+  if let Value::Object(fields) = object {
+    for (key, value) in fields.iter_mut() {
+      match key.as_str() {
+        "local_object" => payloads.push(value),
+        "remote_object" if !value.is_null() => payloads.push(value),
+        "remote_actions" | "local_actions" => {
+          for action in value.as_array_mut().into_iter().flatten() {
+            action_payloads(action, &mut payloads);
+          }
+        }
+        _ => (),
+      }
+    }
+  }
+  for payload in payloads {
+    *payload = migrate_payload(migrator, from_version, payload.take())?;
+  }
+  Ok(())
+}
+
+// Collect the T payloads of a serialized action object:
+// the one of a create or restore action
+fn action_payloads<'a>(
+  action: &'a mut Value,
+  payloads: &mut Vec<&'a mut Value>,
+) {
+  let kind = action.get_mut("action").and_then(Value::as_object_mut);
+  for (kind, payload) in kind.into_iter().flatten() {
+    if kind == "Create" || kind == "Restore" {
+      payloads.push(payload);
+    }
   }
 }
 
+// Updated storage object with its resolved conflicts
+type AppliedObject<T, A> = (StorageObject<T, A>, Vec<Conflict<T, A>>);
+
+// Key extractor of a unique constraint
+// None if the object is not constrained
+type UniqueKey<T> = Arc<dyn Fn(&T) -> Option<String> + Send + Sync>;
+
 /// Generic Storage that can hold Vec<T>
 /// and perform patch A operations
-#[derive(Clone, Debug)]
+#[derive(Clone)]
 pub struct Storage<T, A>
 where
   T: ObjectExt,
   A: ActionExt<ObjectType = T>,
 {
-  inner: Arc<Mutex<StorageInner<T, A>>>,
+  // Readers are never blocked by disk writes, see update_fs
+  inner: Arc<RwLock<StorageInner<T, A>>>,
+  // Serializes storage details writes, so an older snapshot never
+  // overwrites a newer one
+  details_write: Arc<Mutex<()>>,
+  // Resolver for conflicting local actions during remote updates
+  conflict_resolver: Arc<dyn ConflictResolver<T, A>>,
+  // How the server merges concurrent commits
+  merge_mode: MergeMode,
+  // Conflicts resolved during remote updates
+  conflicts: Arc<Mutex<Vec<Conflict<T, A>>>>,
+  // Unique constraints by name
+  unique_constraints: Vec<(String, UniqueKey<T>)>,
+  // Schema migrator of stored object payloads
+  migrator: Option<Arc<dyn Migrator>>,
+  // Object change notifications
+  change_tx: broadcast::Sender<ChangeEvent>,
+  // Storage details changed by applied action objects, not yet written
+  // They are written once per commit, see flush_details
+  details_dirty: Arc<AtomicBool>,
+  // Soft quota checked with commits
+  quota: Option<Quota>,
+  // Bytes used as last measured or estimated, see check_quota
+  used_bytes: Arc<Mutex<Option<u64>>>,
+  // Purge policy of removed objects, see Repository::purge
+  retention: Option<Retention>,
+  // Objects whose state is unknown after a redacted remote action
+  // Their remote actions are stashed until a restore, see redaction
+  redacted_ids: Arc<RwLock<HashSet<Uuid>>>,
+}
+
+impl<T, A> Debug for Storage<T, A>
+where
+  T: ObjectExt,
+  A: ActionExt<ObjectType = T> + Debug,
+{
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_struct("Storage")
+      .field("inner", &self.inner)
+      .field("conflicts", &self.conflicts)
+      .finish()
+  }
 }
 
 impl<T, A> Deref for Storage<T, A>
@@ -583,13 +2346,111 @@ where
   T: ObjectExt,
   A: ActionExt<ObjectType = T>,
 {
-  type Target = Mutex<StorageInner<T, A>>;
+  type Target = RwLock<StorageInner<T, A>>;
 
   fn deref(&self) -> &Self::Target {
     &self.inner
   }
 }
 
+/// Lazy storage object iterator
+/// Reads storage objects one at a time
+pub struct StorageIter<T, A> {
+  ctx: Context,
+  storage_id: String,
+  ids: std::vec::IntoIter<Uuid>,
+  migrator: Option<Arc<dyn Migrator>>,
+  _phantom: PhantomData<(T, A)>,
+}
+
+impl<T, A> Iterator for StorageIter<T, A>
+where
+  T: ObjectExt + Serialize + for<'de> Deserialize<'de> + JsonSchema,
+  A: ActionExt<ObjectType = T>
+    + Serialize
+    + for<'de> Deserialize<'de>
+    + Debug
+    + JsonSchema,
+{
+  type Item = Result<StorageObject<T, A>, String>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    let object_id = self.ids.next()?;
+    Some(StorageObject::read_from_fs(
+      &self.ctx,
+      &self.storage_id,
+      object_id,
+      self.migrator.as_ref(),
+    ))
+  }
+
+  // Skipped objects are not read
+  fn nth(&mut self, n: usize) -> Option<Self::Item> {
+    let object_id = self.ids.nth(n)?;
+    Some(StorageObject::read_from_fs(
+      &self.ctx,
+      &self.storage_id,
+      object_id,
+      self.migrator.as_ref(),
+    ))
+  }
+
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    self.ids.size_hint()
+  }
+}
+
+impl<T, A> StorageIter<T, A>
+where
+  T: ObjectExt + Serialize + for<'de> Deserialize<'de> + JsonSchema,
+  A: ActionExt<ObjectType = T>
+    + Serialize
+    + for<'de> Deserialize<'de>
+    + Debug
+    + JsonSchema,
+{
+  // Read the remaining objects in batches, each batch in parallel
+  // by the context read concurrency, passing them to f in order
+  fn for_each_parallel(
+    self,
+    mut f: impl FnMut(StorageObject<T, A>) -> Result<(), String>,
+  ) -> Result<(), String> {
+    let workers = self.ctx.read_concurrency.max(1);
+    let ids: Vec<Uuid> = self.ids.collect();
+    let read = |id: &Uuid| {
+      StorageObject::<T, A>::read_from_fs(
+        &self.ctx,
+        &self.storage_id,
+        *id,
+        self.migrator.as_ref(),
+      )
+    };
+    for batch in ids.chunks(workers * READ_BATCH_PER_WORKER) {
+      let objects =
+        match workers > 1 && batch.len() >= PARALLEL_READ_MIN_OBJECTS {
+          false => batch.iter().map(read).collect(),
+          true => std::thread::scope(|scope| {
+            let chunk_size = batch.len().div_ceil(workers);
+            let handles: Vec<_> = batch
+              .chunks(chunk_size)
+              .map(|chunk| {
+                scope.spawn(|| chunk.iter().map(read).collect::<Vec<_>>())
+              })
+              .collect();
+            handles
+              .into_iter()
+              .flat_map(|handle| handle.join().unwrap())
+              .collect::<Vec<_>>()
+          }),
+        };
+      for object in objects {
+        f(object?)?;
+      }
+    }
+    Ok(())
+  }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct StorageInner<T, A>
 where
@@ -599,14 +2460,81 @@ where
   id: String,
   member_ids: Vec<Uuid>,
   members: Vec<StorageObject<T, A>>,
+  // Schema version of the stored objects
+  #[serde(default)]
+  schema_version: u32,
+  // JSON Schema of the storage types
+  #[serde(default)]
+  schema: Option<StorageSchema>,
+  // Removed members, kept as tombstones
+  #[serde(default)]
+  removed_ids: Vec<Uuid>,
 }
 
-impl<T, A> Storage<T, A>
-where
-  T: ObjectExt + Serialize + for<'de> Deserialize<'de> + 'static,
+/// JSON Schema of the storage object (T) and action (A) types
+/// Recorded in the storage details
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StorageSchema {
+  // T schema as json
+  pub object: String,
+  // A schema as json
+  pub action: String,
+  // Sha1 of the object and action schemas
+  // Embedded into every action object
+  pub hash: String,
+}
+
+impl StorageSchema {
+  fn new<T: JsonSchema, A: JsonSchema>() -> Result<Self, String> {
+    let object =
+      serde_json::to_string(&schema_for!(T)).map_err(|e| e.to_string())?;
+    let action =
+      serde_json::to_string(&schema_for!(A)).map_err(|e| e.to_string())?;
+    let hash = sha1_signature(&(&object, &action))?;
+    Ok(Self {
+      object,
+      action,
+      hash,
+    })
+  }
+}
+
+// Storage details stored before schema versioning
+#[derive(Deserialize)]
+struct LegacyStorageInner<T, A>
+where
+  T: ObjectExt,
+  A: ActionExt<ObjectType = T>,
+{
+  id: String,
+  member_ids: Vec<Uuid>,
+  members: Vec<StorageObject<T, A>>,
+}
+
+impl<T, A> From<LegacyStorageInner<T, A>> for StorageInner<T, A>
+where
+  T: ObjectExt,
+  A: ActionExt<ObjectType = T>,
+{
+  fn from(legacy: LegacyStorageInner<T, A>) -> Self {
+    Self {
+      id: legacy.id,
+      member_ids: legacy.member_ids,
+      members: legacy.members,
+      schema_version: 0,
+      schema: None,
+      removed_ids: vec![],
+    }
+  }
+}
+
+impl<T, A> Storage<T, A>
+where
+  T: ObjectExt + Serialize + for<'de> Deserialize<'de> + JsonSchema + 'static,
   A: ActionExt<ObjectType = T>
     + Serialize
     + for<'de> Deserialize<'de>
+    + JsonSchema
     + 'static
     + Debug,
 {
@@ -616,31 +2544,246 @@ where
   pub fn load_or_init(
     repo: &Repository,
     storage_id: String,
+  ) -> Result<Self, String> {
+    Self::load(repo, storage_id, None)
+  }
+
+  /// Init a storage with a schema migrator
+  /// Objects of older schema versions are migrated to the
+  /// migrator schema version during load
+  pub fn load_or_init_with_migrator(
+    repo: &Repository,
+    storage_id: String,
+    migrator: impl Migrator + 'static,
+  ) -> Result<Self, String> {
+    Self::load(repo, storage_id, Some(Arc::new(migrator)))
+  }
+
+  fn load(
+    repo: &Repository,
+    storage_id: String,
+    migrator: Option<Arc<dyn Migrator>>,
   ) -> Result<Self, String> {
     let ctx = repo.ctx();
+    let schema_version =
+      migrator.as_ref().map(|m| m.schema_version()).unwrap_or(0);
     let storage_details_path =
       path_helper::storage_details_path(&ctx, &storage_id);
-    let inner: StorageInner<T, A> = match storage_details_path.exists() {
-      true => binary_read(storage_details_path)?,
+    let inner: StorageInner<T, A> = match ctx
+      .backend()
+      .exists(&storage_details_path)
+    {
+      true => binary_read(&ctx, storage_details_path.clone()).or_else(|e| {
+        match binary_read::<LegacyStorageInner<T, A>>(
+          &ctx,
+          storage_details_path,
+        ) {
+          Ok(legacy) => Ok(legacy.into()),
+          Err(_) => Err(e),
+        }
+      })?,
       false => binary_init(
+        &ctx,
         storage_details_path,
         StorageInner {
           id: storage_id,
           member_ids: Vec::default(),
           members: Vec::default(),
+          schema_version,
+          schema: None,
+          removed_ids: Vec::default(),
         },
       )?,
     };
-    Ok(Self {
-      inner: Arc::new(Mutex::new(inner)),
-    })
+    if inner.schema_version > schema_version {
+      return Err(format!(
+        "Storage {} schema version {} is newer than {}",
+        inner.id, inner.schema_version, schema_version
+      ));
+    }
+    let redacted_ids = ctx
+      .backend()
+      .scan(&path_helper::storage_stash_path(&ctx, &inner.id))?
+      .iter()
+      .filter_map(|path| path.file_name())
+      .filter_map(|name| Uuid::parse_str(&name.to_string_lossy()).ok())
+      .collect();
+    let res = Self {
+      inner: Arc::new(RwLock::new(inner)),
+      details_write: Arc::new(Mutex::new(())),
+      conflict_resolver: Arc::new(TakeLocal),
+      merge_mode: MergeMode::default(),
+      conflicts: Arc::new(Mutex::new(vec![])),
+      unique_constraints: vec![],
+      change_tx: broadcast::channel(CHANGE_EVENT_CHANNEL_SIZE).0,
+      details_dirty: Arc::new(AtomicBool::new(false)),
+      quota: None,
+      used_bytes: Arc::new(Mutex::new(None)),
+      retention: None,
+      redacted_ids: Arc::new(RwLock::new(redacted_ids)),
+      migrator,
+    };
+    res.migrate_schema(&ctx, schema_version)?;
+    res.record_schema(&ctx)?;
+    Ok(res)
+  }
+
+  // Record the JSON Schema of the current storage types
+  fn record_schema(&self, ctx: &Context) -> Result<(), String> {
+    let schema = Some(StorageSchema::new::<T, A>()?);
+    {
+      let mut inner = self.inner.write().unwrap();
+      if inner.schema == schema {
+        return Ok(());
+      }
+      if inner.schema.is_some() {
+        info!(storage_id = %inner.id, "Storage schema changed");
+      }
+      inner.schema = schema;
+    }
+    self.update_fs(ctx)
+  }
+
+  // Rewrite objects of older schema versions
+  // Storage schema version is updated only after all objects are
+  // migrated, so an interrupted migration continues on next load.
+  fn migrate_schema(
+    &self,
+    ctx: &Context,
+    schema_version: u32,
+  ) -> Result<(), String> {
+    if self.inner.read().unwrap().schema_version == schema_version {
+      return Ok(());
+    }
+    for object in self.iter_all(ctx) {
+      object?.save_to_fs(ctx)?;
+    }
+    self.inner.write().unwrap().schema_version = schema_version;
+    self.update_fs(ctx)
+  }
+
+  /// Set conflict resolver
+  /// Resolver is consulted when a remote update conflicts with
+  /// pending local actions. Default is TakeLocal.
+  /// Must be set before registering the storage.
+  pub fn with_conflict_resolver(
+    mut self,
+    resolver: impl ConflictResolver<T, A> + 'static,
+  ) -> Self {
+    self.conflict_resolver = Arc::new(resolver);
+    self
+  }
+
+  /// Set how the server merges concurrent commits
+  /// Default is MergeMode::Rebase.
+  /// Must be set before registering the storage.
+  pub fn with_merge_mode(mut self, merge_mode: MergeMode) -> Self {
+    self.merge_mode = merge_mode;
+    self
+  }
+
+  /// Add unique constraint
+  /// No two objects can have the same Some key, e.g. email.
+  /// Commits violating it are rejected when checked (commit() and
+  /// server merge), so they are neither applied nor accepted.
+  /// Must be set before registering the storage.
+  pub fn with_unique_constraint<K: Serialize>(
+    mut self,
+    name: &str,
+    key: impl Fn(&T) -> Option<K> + Send + Sync + 'static,
+  ) -> Self {
+    let key = move |object: &T| {
+      key(object).and_then(|k| serde_json::to_string(&k).ok())
+    };
+    self
+      .unique_constraints
+      .push((name.to_string(), Arc::new(key)));
+    self
+  }
+
+  /// Set soft quota
+  /// Commits exceeding it are rejected when checked (commit() and
+  /// server merge), like unique constraint violations.
+  /// Must be set before registering the storage.
+  pub fn with_quota(mut self, quota: Quota) -> Self {
+    self.quota = Some(quota);
+    self
+  }
+
+  /// Set retention policy of removed objects
+  /// Due objects are purged by Repository::purge, unless under legal
+  /// hold. Must be set before registering the storage.
+  pub fn with_retention(mut self, retention: Retention) -> Self {
+    self.retention = Some(retention);
+    self
+  }
+
+  /// Subscribe to object changes
+  /// Events are sent after the change is saved, for local commits
+  /// and remote merges alike. Slow receivers miss the oldest events,
+  /// see broadcast::error::RecvError::Lagged.
+  pub fn subscribe(&self) -> broadcast::Receiver<ChangeEvent> {
+    self.change_tx.subscribe()
+  }
+
+  // Change event of the given action object, before it is applied
+  // Restoring a removed object is a creation
+  fn change_event(&self, action_object: &ActionObject<T, A>) -> ChangeEvent {
+    let object_id = action_object.object_id;
+    let kind = match action_object.action {
+      ActionKind::Create(_) => ChangeKind::Created,
+      ActionKind::Remove => ChangeKind::Removed,
+      ActionKind::Restore(_)
+        if self.inner.read().unwrap().removed_ids.contains(&object_id) =>
+      {
+        ChangeKind::Created
+      }
+      ActionKind::Patch(_)
+      | ActionKind::Restore(_)
+      | ActionKind::Redacted(_) => ChangeKind::Patched,
+    };
+    ChangeEvent {
+      object_id,
+      kind,
+      source: match action_object.is_local() {
+        true => ChangeSource::Local,
+        false => ChangeSource::Remote,
+      },
+      commit_id: action_object.commit_id,
+    }
+  }
+
+  /// Take the conflicts resolved during remote updates
+  pub fn take_conflicts(&self) -> Vec<Conflict<T, A>> {
+    std::mem::take(self.conflicts.lock().unwrap().deref_mut())
   }
 
   fn storage_id(&self) -> String {
-    self.inner.lock().unwrap().id.to_owned()
+    self.inner.read().unwrap().id.to_owned()
+  }
+
+  /// Schema version of the stored objects
+  pub fn schema_version(&self) -> u32 {
+    self.inner.read().unwrap().schema_version
+  }
+
+  /// Recorded JSON Schema of the storage types
+  pub fn schema(&self) -> Option<StorageSchema> {
+    self.inner.read().unwrap().schema.clone()
+  }
+
+  fn schema_hash(&self) -> Option<String> {
+    self
+      .inner
+      .read()
+      .unwrap()
+      .schema
+      .as_ref()
+      .map(|s| s.hash.clone())
   }
 
   // Get a single storage object by object id
+  // Removed objects are returned as well, see StorageObject::is_removed
   pub fn get_object_by_id(
     &self,
     ctx: &Context,
@@ -649,7 +2792,7 @@ where
     // Check whether id is member
     if self
       .inner
-      .lock()
+      .read()
       .unwrap()
       .member_ids
       .iter()
@@ -662,7 +2805,12 @@ where
       ));
     }
     // read binary
-    StorageObject::read_from_fs(ctx, &self.inner.lock().unwrap().id, object_id)
+    StorageObject::read_from_fs(
+      ctx,
+      &self.inner.read().unwrap().id,
+      object_id,
+      self.migrator.as_ref(),
+    )
   }
 
   // Get All
@@ -670,10 +2818,229 @@ where
     &self,
     ctx: &Context,
   ) -> Result<Vec<StorageObject<T, A>>, String> {
-    let ids = self.inner.lock().unwrap().member_ids.clone();
     let mut res = Vec::new();
-    for id in ids {
-      res.push(self.get_object_by_id(ctx, id)?);
+    self.iter(ctx).for_each_parallel(|so| {
+      res.push(so);
+      Ok(())
+    })?;
+    Ok(res)
+  }
+
+  /// Object state as of the given commit
+  /// Replays its actions up to and including the commit.
+  /// None if the object did not exist or was removed by then
+  pub fn get_object_at(
+    &self,
+    ctx: &Context,
+    object_id: Uuid,
+    commit_id: Uuid,
+  ) -> Result<Option<T>, String> {
+    let commit_ids = CommitLog::ids_until(ctx, commit_id)?;
+    self
+      .get_object_by_id(ctx, object_id)?
+      .object_at_commits(&commit_ids)
+  }
+
+  /// State of every object existing as of the given commit
+  /// In the same stable order as iter
+  pub fn get_all_at(
+    &self,
+    ctx: &Context,
+    commit_id: Uuid,
+  ) -> Result<Vec<(Uuid, T)>, String> {
+    let commit_ids = CommitLog::ids_until(ctx, commit_id)?;
+    let mut res = vec![];
+    for object in self.iter_all(ctx) {
+      let object = object?;
+      if let Some(data) = object.object_at_commits(&commit_ids)? {
+        res.push((object.id, data));
+      }
+    }
+    Ok(res)
+  }
+
+  /// Write current object states as line-delimited JSON
+  /// One {"id", "object"} record per line, in the same order as iter.
+  /// Returns the number of exported objects
+  pub fn export_json(
+    &self,
+    ctx: &Context,
+    writer: impl Write,
+  ) -> Result<usize, String> {
+    self.export_records(ctx, writer, false)
+  }
+
+  /// Same as export_json, every record extended with the
+  /// full action history of the object under "actions"
+  pub fn export_json_with_history(
+    &self,
+    ctx: &Context,
+    writer: impl Write,
+  ) -> Result<usize, String> {
+    self.export_records(ctx, writer, true)
+  }
+
+  fn export_records(
+    &self,
+    ctx: &Context,
+    mut writer: impl Write,
+    with_history: bool,
+  ) -> Result<usize, String> {
+    let mut count = 0;
+    for object in self.iter(ctx) {
+      let object = object?;
+      let record = ExportRecord {
+        id: object.id,
+        object: &object.local_object,
+        actions: match with_history {
+          true => Some(object.actions()?.collect()),
+          false => None,
+        },
+      };
+      serde_json::to_writer(&mut writer, &record)
+        .map_err(|e| format!("Export error: {}", e))?;
+      writeln!(writer).map_err(|e| format!("Export error: {}", e))?;
+      count += 1;
+    }
+    writer.flush().map_err(|e| format!("Export error: {}", e))?;
+    Ok(count)
+  }
+
+  /// Create objects from line-delimited JSON records
+  /// Records are the ones of export_json, their ids and action
+  /// histories are ignored: every object is created anew.
+  /// Empty lines are skipped. Returns the number of created objects
+  pub fn import_json(
+    &self,
+    reader: impl BufRead,
+    commit: &mut CommitContextGuard,
+  ) -> Result<usize, String> {
+    let mut objects = vec![];
+    for (index, line) in reader.lines().enumerate() {
+      let line = line.map_err(|e| format!("Import error: {}", e))?;
+      if line.trim().is_empty() {
+        continue;
+      }
+      let record: ImportRecord<T> = serde_json::from_str(&line)
+        .map_err(|e| format!("Invalid record on line {}: {}", index + 1, e))?;
+      objects.push(record.object);
+    }
+    let count = objects.len();
+    self.create_objects(objects, commit);
+    Ok(count)
+  }
+
+  /// Lazy iterator over all storage objects
+  /// Objects are read one at a time, in stable (creation) order.
+  /// Removed objects are skipped.
+  pub fn iter(&self, ctx: &Context) -> StorageIter<T, A> {
+    let inner = self.inner.read().unwrap();
+    let removed: HashSet<&Uuid> = inner.removed_ids.iter().collect();
+    let ids: Vec<Uuid> = inner
+      .member_ids
+      .iter()
+      .filter(|id| !removed.contains(id))
+      .copied()
+      .collect();
+    StorageIter {
+      ctx: ctx.clone(),
+      storage_id: inner.id.to_owned(),
+      ids: ids.into_iter(),
+      migrator: self.migrator.clone(),
+      _phantom: PhantomData,
+    }
+  }
+
+  // Iterator over all storage objects including the removed ones
+  fn iter_all(&self, ctx: &Context) -> StorageIter<T, A> {
+    let inner = self.inner.read().unwrap();
+    StorageIter {
+      ctx: ctx.clone(),
+      storage_id: inner.id.to_owned(),
+      ids: inner.member_ids.clone().into_iter(),
+      migrator: self.migrator.clone(),
+      _phantom: PhantomData,
+    }
+  }
+
+  /// Async stream over all storage objects
+  /// Same order as iter
+  pub fn stream(
+    &self,
+    ctx: &Context,
+  ) -> impl Stream<Item = Result<StorageObject<T, A>, String>> {
+    stream::iter(self.iter(ctx))
+  }
+
+  /// Get a page of storage objects
+  /// Uses the same stable order as iter
+  pub fn get_page(
+    &self,
+    ctx: &Context,
+    offset: usize,
+    limit: usize,
+  ) -> Result<Vec<StorageObject<T, A>>, String> {
+    self.iter(ctx).skip(offset).take(limit).collect()
+  }
+
+  /// Number of storage objects
+  /// Removed objects are not counted
+  pub fn len(&self) -> usize {
+    let inner = self.inner.read().unwrap();
+    inner.member_ids.len() - inner.removed_ids.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Number of objects matching the filter
+  /// Objects are read one at a time, without filter
+  /// only the member index is used
+  pub fn count(
+    &self,
+    ctx: &Context,
+    filter: Option<&dyn Fn(&T) -> bool>,
+  ) -> Result<usize, String> {
+    match filter {
+      Some(filter) => {
+        self.fold(ctx, 0, |count, object| count + filter(object) as usize)
+      }
+      None => Ok(self.len()),
+    }
+  }
+
+  /// Check whether any object matches the filter
+  /// Stops reading at the first match, without filter
+  /// only the member index is used
+  pub fn exists(
+    &self,
+    ctx: &Context,
+    filter: Option<&dyn Fn(&T) -> bool>,
+  ) -> Result<bool, String> {
+    let filter = match filter {
+      Some(filter) => filter,
+      None => return Ok(!self.is_empty()),
+    };
+    for so in self.iter(ctx) {
+      if filter(&so?.local_object) {
+        return Ok(true);
+      }
+    }
+    Ok(false)
+  }
+
+  /// Fold all objects into a single value, e.g. sum, min or max
+  /// Objects are read one at a time
+  pub fn fold<B>(
+    &self,
+    ctx: &Context,
+    init: B,
+    mut f: impl FnMut(B, &T) -> B,
+  ) -> Result<B, String> {
+    let mut res = init;
+    for so in self.iter(ctx) {
+      res = f(res, &so?.local_object);
     }
     Ok(res)
   }
@@ -684,15 +3051,13 @@ where
     ctx: &Context,
     filter: impl Fn(&T) -> bool,
   ) -> Result<StorageObject<T, A>, String> {
-    let ids = self.inner.lock().unwrap().member_ids.clone();
-    let mut res = Vec::new();
-    for id in ids {
-      let so = self.get_object_by_id(ctx, id)?;
+    for so in self.iter(ctx) {
+      let so = so?;
       if filter(&so) {
-        res.push(so);
+        return Ok(so);
       }
     }
-    res.first().cloned().ok_or("Object not found".into())
+    Err("Object not found".into())
   }
 
   // Get by filter
@@ -701,17 +3066,54 @@ where
     ctx: &Context,
     filter: impl Fn(&T) -> bool,
   ) -> Result<Vec<StorageObject<T, A>>, String> {
-    let ids = self.inner.lock().unwrap().member_ids.clone();
     let mut res = Vec::new();
-    for id in ids {
-      let so = self.get_object_by_id(ctx, id)?;
+    self.iter(ctx).for_each_parallel(|so| {
       if filter(&so) {
         res.push(so);
       }
-    }
+      Ok(())
+    })?;
+    Ok(res)
+  }
+
+  /// Get objects matching the query
+  /// Query is evaluated against the json representation of T
+  pub fn query(
+    &self,
+    ctx: &Context,
+    query: &Query,
+  ) -> Result<Vec<StorageObject<T, A>>, String> {
+    let mut res = Vec::new();
+    self.iter(ctx).for_each_parallel(|so| {
+      let object =
+        serde_json::to_value(so.deref()).map_err(|e| e.to_string())?;
+      if query.matches(&object) {
+        res.push(so);
+      }
+      Ok(())
+    })?;
     Ok(res)
   }
 
+  // Query results with object json for remote queries
+  fn query_results(
+    &self,
+    ctx: &Context,
+    query: &Query,
+  ) -> Result<Vec<QueryResult>, String> {
+    self
+      .query(ctx, query)?
+      .into_iter()
+      .map(|so| {
+        Ok(QueryResult {
+          object_id: so.id,
+          object: serde_json::to_value(so.deref())
+            .map_err(|e| e.to_string())?,
+        })
+      })
+      .collect()
+  }
+
   /// Get by filter
   /// Apply a given patch to result vec items
   pub fn patch_by_filter(
@@ -727,707 +3129,7742 @@ where
     Ok(())
   }
 
+  /// Create many objects in the given commit
+  /// Storage details are written once, when the commit is applied
+  pub fn create_objects(&self, data: Vec<T>, commit: &mut CommitContextGuard) {
+    for data in data {
+      self.create_object(data, commit);
+    }
+  }
+
   /// Create a Create action object which will create
   /// a new Storage Object
   /// and adds it to a given Commit
   pub fn create_object(&self, data: T, commit: &mut CommitContextGuard) {
-    let object_signature = sha1_signature(&data).unwrap();
+    let object_signature = object_signature(&data).unwrap();
     let aob: ActionObject<T, A> = ActionObject {
-      id: Uuid::new_v4(),
+      id: commit.new_id(),
       storage_id: self.storage_id(),
-      object_id: Uuid::new_v4(),
-      uid: commit.ctx.uid.to_string(),
+      object_id: commit.new_id(),
+      uid: commit.temp_commit.uid.to_string(),
       dtime: Utc::now(),
       commit_id: Some(commit.temp_commit.id),
       parent_action_id: None,
       action: ActionKind::Create(data),
       object_signature,
+      schema_hash: self.schema_hash(),
+      clock: Some(commit.clock().tick()),
       remote_signature: None,
     };
     commit.add_action_object(aob);
   }
 
   // Add action object to storage object
+  // and save the updated storage object
   pub fn add_action_object(
     &self,
     ctx: &Context,
     action_object: ActionObject<T, A>,
   ) -> Result<StorageObject<T, A>, String> {
+    let event = self.change_event(&action_object);
+    let (storage_object, _) = self.apply_action_object(ctx, action_object)?;
+    self.save_applied_object(ctx, &storage_object)?;
+    self.flush_details(ctx)?;
+    // Error only means there is no subscriber
+    let _ = self.change_tx.send(event);
+    Ok(storage_object)
+  }
+
+  // Add action object to storage object
+  // Returns the updated object and the resolved conflicts
+  // Nothing is written to the fs here, use save_applied_object
+  fn apply_action_object(
+    &self,
+    ctx: &Context,
+    action_object: ActionObject<T, A>,
+  ) -> Result<AppliedObject<T, A>, String> {
     let object_id = action_object.object_id;
+    // Restore of an object with stashed actions
+    if self.redacted_ids.read().unwrap().contains(&object_id) {
+      return self.unstash_action_objects(ctx, action_object);
+    }
     // Create a new one
     let data = match action_object.is_kind_create() {
       true => {
         // Create new storage object
-        let new_storage_object = StorageObject::new_from_aob(action_object)?;
+        let new_storage_object =
+          StorageObject::new_from_aob(action_object, self.schema_version())?;
         // Check storage id
         if &self.storage_id() != &new_storage_object.storage_id {
           panic!("Wrong storage id during creating storage object");
         }
-        // Get data
-        let data = new_storage_object.clone();
-        // Get Object path
-        let path = path_helper::storage_object_path(
-          ctx,
-          &new_storage_object.storage_id,
-          new_storage_object.id,
-        );
-        // Init in FS and save its content as binary
-        binary_init(path, new_storage_object)?;
-        // Add new object ID as storage member ID
-        self.inner.lock().unwrap().member_ids.push(object_id);
+        // Check if object exists
+        if self.is_member(object_id) {
+          return Err(format!("Object {} already exists", object_id));
+        }
         // Return data
-        data
+        (new_storage_object, vec![])
       }
       // Try to patch existing one
       false => self
         .get_object_by_id(ctx, object_id)?
-        .add_action_object(action_object)?
-        .clone(),
+        .add_action_object(action_object, self.conflict_resolver.as_ref())?,
     };
     Ok(data)
   }
 
-  fn update_fs(&self, ctx: &Context) -> Result<(), String> {
-    binary_update(
-      path_helper::storage_details_path(ctx, &self.storage_id()),
-      self.inner.lock().unwrap().deref(),
-    )
+  // Save applied storage object
+  // New objects are initiated in fs and added as members.
+  // Storage details are only written by flush_details
+  fn save_applied_object(
+    &self,
+    ctx: &Context,
+    storage_object: &StorageObject<T, A>,
+  ) -> Result<(), String> {
+    match self.is_member(storage_object.id) {
+      true => storage_object.save_to_fs(ctx)?,
+      false => {
+        // Init in FS and save its content as binary
+        storage_object.init_fs(ctx)?;
+        // Add new object ID as storage member ID
+        self
+          .inner
+          .write()
+          .unwrap()
+          .member_ids
+          .push(storage_object.id);
+      }
+    }
+    self.set_removed(storage_object.id, storage_object.is_removed());
+    self.details_dirty.store(true, Ordering::Relaxed);
+    // Restored from its stashed actions
+    if self
+      .redacted_ids
+      .write()
+      .unwrap()
+      .remove(&storage_object.id)
+    {
+      binary_remove(
+        ctx,
+        path_helper::storage_object_stash_path(
+          ctx,
+          &self.storage_id(),
+          storage_object.id,
+        ),
+      )?;
+    }
+    Ok(())
   }
 
-  /// Register a callback to a given repository
-  /// Repository will use this callback to update storage
-  pub fn register(self, repo: &Repository) -> Result<Self, String> {
-    let _self = self.clone();
-    let ctx = repo.ctx().deref().to_owned();
-    repo.add_storage_hook(Box::new(
-      move |aobstr: &str,
-            callback_mode: CallbackMode|
-            -> Option<Result<(), String>> {
-        // Try to deserialize action object
-        if let Ok(aob) = serde_json::from_str::<ActionObject<T, A>>(aobstr) {
-          // Check if storage target is ok
-          if &aob.storage_id != &self.storage_id() {
-            return None;
-          }
-          match self.add_action_object(&ctx, aob) {
-            Ok(aob) => {
-              // Save updated storage object if needed
-              match callback_mode {
-                CallbackMode::Apply => {
-                  aob
-                    .save_to_fs(&ctx)
-                    .expect("Error writing StorageObject update to fs");
-                  let res = self.update_fs(&ctx);
-                  return Some(res);
-                }
-                CallbackMode::Check => {
-                  let res: Result<(), String> = Ok(());
-                  return Some(res);
-                }
-              }
-            }
-            Err(e) => return Some(Err(e)),
-          };
-        }
-        None
-      },
-    ))?;
-    Ok(_self)
+  // Keep remote action aside while the object state is unknown,
+  // from a redacted action until a restore, see redaction
+  // Returns whether it is kept aside
+  fn stash_action_object(
+    &self,
+    ctx: &Context,
+    action_object: &ActionObject<T, A>,
+  ) -> Result<bool, String> {
+    let object_id = action_object.object_id;
+    let pending = self.redacted_ids.read().unwrap().contains(&object_id);
+    let stashed = action_object.is_kind_redacted()
+      || (pending && !action_object.is_kind_restore());
+    if !stashed {
+      return Ok(false);
+    }
+    let path = path_helper::storage_object_stash_path(
+      ctx,
+      &self.storage_id(),
+      object_id,
+    );
+    if !pending {
+      binary_init_empty(ctx, path.clone())?;
+      self.redacted_ids.write().unwrap().insert(object_id);
+    }
+    let aob_str =
+      serde_json::to_string(action_object).map_err(|e| e.to_string())?;
+    binary_continuous_append(ctx, path, aob_str)?;
+    Ok(true)
   }
-}
 
-// Repository Mode
-// Local, Remote or Server
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
-pub enum Mode {
-  Server { server_addr: String },
-  Remote { remote_url: String },
-  Local,
-}
+  // Object continued by its stashed actions and the given restore
+  // Stash is removed once the object is saved
+  fn unstash_action_objects(
+    &self,
+    ctx: &Context,
+    restore: ActionObject<T, A>,
+  ) -> Result<AppliedObject<T, A>, String> {
+    let path = path_helper::storage_object_stash_path(
+      ctx,
+      &self.storage_id(),
+      restore.object_id,
+    );
+    let stashed = binary_continuous_read::<String, String>(ctx, path)?
+      .iter()
+      .map(|aob_str| deserialize_action_object::<T, A>(aob_str))
+      .collect::<Result<Vec<_>, _>>()?;
+    let object = match self.is_member(restore.object_id) {
+      true => Some(self.get_object_by_id(ctx, restore.object_id)?),
+      false => None,
+    };
+    StorageObject::restore_stashed(
+      object,
+      stashed,
+      restore,
+      self.schema_version(),
+      self.conflict_resolver.as_ref(),
+    )
+  }
 
-impl Mode {
-  pub fn server(server_addr: String) -> Self {
-    Self::Server { server_addr }
+  // Replace remote action by its redacted version, see redaction
+  // Actions not pulled yet are redacted once pulled
+  fn redact_action_object(
+    &self,
+    ctx: &Context,
+    redacted: ActionObject<T, A>,
+  ) -> Result<(), String> {
+    let object_id = redacted.object_id;
+    if self.redacted_ids.read().unwrap().contains(&object_id) {
+      let path = path_helper::storage_object_stash_path(
+        ctx,
+        &self.storage_id(),
+        object_id,
+      );
+      let mut stashed =
+        binary_continuous_read::<String, String>(ctx, path.clone())?;
+      for aob_str in &mut stashed {
+        if deserialize_action_object::<T, A>(aob_str)?.id == redacted.id {
+          *aob_str =
+            serde_json::to_string(&redacted).map_err(|e| e.to_string())?;
+        }
+      }
+      binary_continuous_write(ctx, path, &stashed)?;
+    }
+    if !self.is_member(object_id) {
+      return Ok(());
+    }
+    let mut object = self.get_object_by_id(ctx, object_id)?;
+    if object.redact(redacted)? {
+      object.save_to_fs(ctx)?;
+      // Checkpoints may hold the redacted data
+      remove_checkpoints(ctx, &self.storage_id(), object_id)?;
+    }
+    Ok(())
   }
-  pub fn remote(remote_url: String) -> Self {
-    Self::Remote { remote_url }
+
+  // Restore and, if removed, remove action objects of the current
+  // remote state of an object, see Repository::redact
+  fn restore_action_objects(
+    &self,
+    ctx: &Context,
+    commit: &Commit,
+    object_id: Uuid,
+  ) -> Result<Vec<String>, String> {
+    let object = self.get_object_by_id(ctx, object_id)?;
+    let data = object
+      .remote_object
+      .clone()
+      .ok_or(format!("Object {} has no remote state", object_id))?;
+    let restore: ActionObject<T, A> = ActionObject {
+      id: ctx.new_id(),
+      storage_id: object.storage_id.clone(),
+      object_id,
+      uid: commit.uid.clone(),
+      dtime: commit.dtime,
+      commit_id: Some(commit.id),
+      parent_action_id: object.last_remote_action_id(),
+      object_signature: object_signature(&data)?,
+      action: ActionKind::Restore(data),
+      schema_hash: self.schema_hash(),
+      clock: Some(ctx.clock().tick()),
+      remote_signature: None,
+    };
+    let mut res =
+      vec![serde_json::to_string(&restore).map_err(|e| e.to_string())?];
+    if object.is_remote_removed() {
+      let remove = ActionObject {
+        id: ctx.new_id(),
+        parent_action_id: Some(restore.id),
+        action: ActionKind::Remove,
+        clock: Some(ctx.clock().tick()),
+        ..restore
+      };
+      res.push(serde_json::to_string(&remove).map_err(|e| e.to_string())?);
+    }
+    Ok(res)
   }
-  pub fn local() -> Self {
-    Self::Local
+  // Object state JSON after the given action, see delta
+  fn baseline(
+    &self,
+    ctx: &Context,
+    object_id: Uuid,
+    action_id: Uuid,
+  ) -> Result<Option<String>, String> {
+    if !self.inner.read().unwrap().member_ids.contains(&object_id) {
+      return Ok(None);
+    }
+    Ok(
+      self
+        .get_object_by_id(ctx, object_id)?
+        .state_json_after(action_id),
+    )
   }
-}
-
-/// Storage Context
-/// containing operational details
-/// such as db root path or uid
-pub struct ContextGuard<'a> {
-  mutex_guard: MutexGuard<'a, Context>,
-}
-
-impl<'a> Deref for ContextGuard<'a> {
-  type Target = Context;
 
-  fn deref(&self) -> &Self::Target {
-    self.mutex_guard.deref()
+  // Checkpoint the histories of the member objects
+  // Objects with unknown state are skipped, see redaction
+  // Returns the number of written checkpoints
+  fn checkpoint_histories(&self, ctx: &Context) -> Result<usize, String> {
+    let member_ids = self.inner.read().unwrap().member_ids.clone();
+    let mut written = 0;
+    for id in member_ids {
+      if self.redacted_ids.read().unwrap().contains(&id) {
+        continue;
+      }
+      if self.get_object_by_id(ctx, id)?.write_checkpoint(ctx)? {
+        written += 1;
+      }
+    }
+    Ok(written)
   }
-}
 
-impl<'a> ContextGuard<'a> {
-  fn new(mutex_guard: MutexGuard<'static, Context>) -> Self {
-    Self { mutex_guard }
+  // Write storage details if applied objects changed them
+  fn flush_details(&self, ctx: &Context) -> Result<(), String> {
+    match self.details_dirty.swap(false, Ordering::Relaxed) {
+      true => self.update_fs(ctx),
+      false => Ok(()),
+    }
   }
-}
 
-#[derive(Clone)]
-pub struct Context {
-  pub db_root_path: PathBuf,
-  pub uid: String,
-}
+  // Replace in-memory storage details with the stored ones
+  fn reload_details(&self, ctx: &Context) -> Result<(), String> {
+    let path = path_helper::storage_details_path(ctx, &self.storage_id());
+    let inner: StorageInner<T, A> = binary_read(ctx, path)?;
+    let redacted_ids = ctx
+      .backend()
+      .scan(&path_helper::storage_stash_path(ctx, &inner.id))?
+      .iter()
+      .filter_map(|path| path.file_name())
+      .filter_map(|name| Uuid::parse_str(&name.to_string_lossy()).ok())
+      .collect();
+    *self.inner.write().unwrap() = inner;
+    *self.redacted_ids.write().unwrap() = redacted_ids;
+    self.details_dirty.store(false, Ordering::Relaxed);
+    Ok(())
+  }
 
-impl Context {
-  pub fn init(db_root_path: PathBuf, uid: String) -> Self {
-    Self { db_root_path, uid }
+  fn is_member(&self, object_id: Uuid) -> bool {
+    self.inner.read().unwrap().member_ids.contains(&object_id)
   }
-}
 
-pub struct CommitContextGuard<'a> {
-  ctx: MutexGuard<'a, Context>,
-  commit_log: MutexGuard<'a, CommitLog>,
-  repo_details: MutexGuard<'a, RepoDetails>,
-  storage_hooks: MutexGuard<
-    'a,
-    Vec<Box<dyn Fn(&str, CallbackMode) -> Option<Result<(), String>> + Send>>,
-  >,
-  temp_commit: Commit,
-}
+  // Keep removed ids in sync with the object state
+  fn set_removed(&self, object_id: Uuid, removed: bool) {
+    let mut inner = self.inner.write().unwrap();
+    let position = inner.removed_ids.iter().position(|i| *i == object_id);
+    match (removed, position) {
+      (true, None) => inner.removed_ids.push(object_id),
+      (false, Some(position)) => {
+        inner.removed_ids.remove(position);
+      }
+      _ => (),
+    }
+  }
 
-impl<'a> Deref for CommitContextGuard<'a> {
-  type Target = MutexGuard<'a, Context>;
+  /// Remove object by id
+  /// See StorageObject::remove
+  pub fn remove_object(
+    &self,
+    commit: &mut CommitContextGuard,
+    object_id: Uuid,
+  ) -> Result<(), String> {
+    self.get_object_by_id(commit, object_id)?.remove(commit)
+  }
 
-  fn deref(&self) -> &Self::Target {
-    &self.ctx
+  // Check the given serialized action objects in order
+  // Returns the indexes of the checked action objects of this storage
+  // Unique constraints are checked for objects updated by local
+  // action objects, on server side for all of them.
+  fn check_action_objects(
+    &self,
+    ctx: &Context,
+    aob_strs: &[String],
+    is_server: bool,
+  ) -> Result<Vec<usize>, String> {
+    let updated = self.updated_objects(ctx, aob_strs, is_server)?;
+    self.check_unique_constraints(
+      ctx,
+      &updated.objects,
+      &updated.constrained,
+    )?;
+    self.check_quota(ctx, aob_strs, &updated)?;
+    Ok(updated.checked)
   }
-}
 
-impl<'a> CommitContextGuard<'a> {
-  fn new(repo: &'a Repository, commit_comment: &str) -> Self {
-    let uid = repo.ctx.lock().unwrap().uid.to_string();
-    Self {
-      ctx: repo.ctx.lock().unwrap(),
-      commit_log: repo.commit_log.lock().unwrap(),
-      repo_details: repo.repo_details.lock().unwrap(),
-      storage_hooks: repo.storage_hooks.lock().unwrap(),
-      temp_commit: Commit::new(uid, commit_comment.to_string()),
+  // Check quota of the storage after the updates
+  // Bytes used are measured once, then estimated by adding the size of
+  // the checked action objects and their blobs until measured again.
+  fn check_quota(
+    &self,
+    ctx: &Context,
+    aob_strs: &[String],
+    updated: &UpdatedObjects<T, A>,
+  ) -> Result<(), String> {
+    let quota = match &self.quota {
+      Some(quota) if !updated.constrained.is_empty() => quota,
+      _ => return Ok(()),
+    };
+    if updated.objects.values().all(|so| so.is_removed()) {
+      return Ok(());
+    }
+    let storage_id = self.storage_id();
+    if let Some(max_objects) = quota.max_objects {
+      let inner = self.inner.read().unwrap();
+      let mut objects = inner.member_ids.len() - inner.removed_ids.len();
+      for (id, so) in &updated.objects {
+        let was_live =
+          inner.member_ids.contains(id) && !inner.removed_ids.contains(id);
+        match (was_live, so.is_removed()) {
+          (false, false) => objects += 1,
+          (true, true) => objects -= 1,
+          _ => (),
+        }
+      }
+      if objects > max_objects {
+        return Err(format!(
+          "{}: storage {} would have {} objects, limit is {}",
+          QUOTA_EXCEEDED, storage_id, objects, max_objects
+        ));
+      }
+    }
+    if let Some(max_bytes) = quota.max_bytes {
+      let checked = updated
+        .checked
+        .iter()
+        .map(|index| aob_strs[*index].clone())
+        .collect::<Vec<_>>();
+      let added = checked.iter().map(|aob| aob.len() as u64).sum::<u64>()
+        + blob::blob_refs(&checked)
+          .iter()
+          .map(|blob| blob.size())
+          .sum::<u64>();
+      let mut used_bytes = self.used_bytes.lock().unwrap();
+      let used = match *used_bytes {
+        Some(used) => used,
+        None => self.measure_usage(ctx)?.total_bytes(),
+      };
+      if used + added > max_bytes {
+        return Err(format!(
+          "{}: storage {} would use {} bytes, limit is {}",
+          QUOTA_EXCEEDED,
+          storage_id,
+          used + added,
+          max_bytes
+        ));
+      }
+      *used_bytes = Some(used + added);
     }
+    Ok(())
   }
-  // Use only for merge a given Commit to the local FS
-  fn new_merge(repo: &'a Repository, temp_commit: Commit) -> Self {
-    Self {
-      ctx: repo.ctx.lock().unwrap(),
-      commit_log: repo.commit_log.lock().unwrap(),
-      repo_details: repo.repo_details.lock().unwrap(),
-      storage_hooks: repo.storage_hooks.lock().unwrap(),
-      temp_commit,
+
+  // Apply the given serialized action objects of this storage
+  // on working copies, so actions on the same object in one commit
+  // are checked as a chain. Nothing is written to the fs.
+  // Constrained objects are the ones updated by local action
+  // objects, on server side all of them.
+  fn updated_objects(
+    &self,
+    ctx: &Context,
+    aob_strs: &[String],
+    is_server: bool,
+  ) -> Result<UpdatedObjects<T, A>, String> {
+    let storage_id = self.storage_id();
+    let mut objects: HashMap<Uuid, StorageObject<T, A>> = HashMap::new();
+    let mut constrained = HashSet::new();
+    let mut skipped = HashSet::new();
+    let mut checked = vec![];
+    for (index, aob_str) in aob_strs.iter().enumerate() {
+      let aob = match serde_json::from_str::<ActionObject<T, A>>(aob_str) {
+        Ok(aob) if aob.storage_id == storage_id => aob,
+        _ => continue,
+      };
+      // Stashed and restored objects are not checked, their state
+      // is unknown before the restore
+      if aob.is_kind_redacted()
+        || skipped.contains(&aob.object_id)
+        || self.redacted_ids.read().unwrap().contains(&aob.object_id)
+      {
+        skipped.insert(aob.object_id);
+        objects.remove(&aob.object_id);
+        checked.push(index);
+        continue;
+      }
+      if is_server || aob.is_local() {
+        constrained.insert(aob.object_id);
+      }
+      match (aob.is_kind_create(), objects.entry(aob.object_id)) {
+        (true, Entry::Occupied(_)) => {
+          return Err(format!("Object {} already exists", aob.object_id))
+        }
+        (true, Entry::Vacant(v)) => {
+          if self.is_member(aob.object_id) {
+            return Err(format!("Object {} already exists", aob.object_id));
+          }
+          v.insert(StorageObject::new_from_aob(aob, self.schema_version())?);
+        }
+        (false, entry) => {
+          let object = match entry {
+            Entry::Occupied(o) => o.into_mut(),
+            Entry::Vacant(v) => {
+              v.insert(self.get_object_by_id(ctx, aob.object_id)?)
+            }
+          };
+          // Local rebuild is not part of the check
+          object.add_action_object(aob, &TakeRemote)?;
+        }
+      }
+      checked.push(index);
     }
+    Ok(UpdatedObjects {
+      objects,
+      constrained,
+      checked,
+    })
   }
-  pub fn add_action_object<
-    T: ObjectExt + Serialize,
-    A: ActionExt + Serialize,
-  >(
-    &mut self,
-    aob: ActionObject<T, A>,
-  ) {
-    let _ = self.temp_commit.add_action_object(aob);
+
+  // Post commit reference states of the objects updated by
+  // the given serialized action objects
+  fn reference_states(
+    &self,
+    ctx: &Context,
+    aob_strs: &[String],
+    is_server: bool,
+    extractor: &ReferenceExtractor,
+  ) -> Result<Vec<ReferenceState>, String> {
+    let updated = self.updated_objects(ctx, aob_strs, is_server)?;
+    Ok(
+      updated
+        .objects
+        .iter()
+        .map(|(id, so)| ReferenceState {
+          object_id: *id,
+          removed: so.is_removed(),
+          reference: extractor(&so.local_object),
+          constrained: updated.constrained.contains(id),
+        })
+        .collect(),
+    )
   }
-}
 
-impl<'a> Drop for CommitContextGuard<'a> {
-  fn drop(&mut self) {
-    match self.temp_commit.remote_signature.is_some() {
-      // Store remote commit
-      true => {
-        CommitLog::add_remote_commit(&self.ctx, self.temp_commit.clone())
-          .expect("Error adding remote commit to commit file");
+  // Ids of the objects referencing the given object id
+  fn referrers(
+    &self,
+    ctx: &Context,
+    object_id: Uuid,
+    extractor: &ReferenceExtractor,
+  ) -> Result<Vec<Uuid>, String> {
+    let mut res = vec![];
+    for so in self.iter(ctx) {
+      let so = so?;
+      if extractor(&so.local_object) == Some(object_id) {
+        res.push(so.id);
       }
-      // Store local commit
-      false => {
-        CommitLog::add_local_commit(&self.ctx, self.temp_commit.clone())
-          .expect("Error adding local commit to commit file");
+    }
+    Ok(res)
+  }
+
+  // Check unique keys of the constrained updated objects against
+  // the other updated objects and the stored ones
+  fn check_unique_constraints(
+    &self,
+    ctx: &Context,
+    updated: &HashMap<Uuid, StorageObject<T, A>>,
+    constrained: &HashSet<Uuid>,
+  ) -> Result<(), String> {
+    if self.unique_constraints.is_empty() || constrained.is_empty() {
+      return Ok(());
+    }
+    // Keys of the not constrained objects by constraint
+    let mut keys: Vec<HashMap<String, Uuid>> =
+      vec![HashMap::new(); self.unique_constraints.len()];
+    let add_keys = |keys: &mut Vec<HashMap<String, Uuid>>, object: &T, id| {
+      for ((_, key), keys) in self.unique_constraints.iter().zip(keys) {
+        if let Some(key) = key(object) {
+          keys.insert(key, id);
+        }
+      }
+    };
+    for so in self.iter(ctx) {
+      let so = so?;
+      if !updated.contains_key(&so.id) {
+        add_keys(&mut keys, &so.local_object, so.id);
       }
     }
-    for aob_str in &self.temp_commit.serialized_actions {
-      for hook in self.storage_hooks.deref() {
-        let res = hook(aob_str, CallbackMode::Apply);
-        if res.is_some() {
-          break;
+    for (id, so) in updated {
+      if !constrained.contains(id) && !so.is_removed() {
+        add_keys(&mut keys, &so.local_object, *id);
+      }
+    }
+    for id in constrained {
+      let object = match &updated[id] {
+        so if so.is_removed() => continue,
+        so => &so.local_object,
+      };
+      for ((name, key), keys) in self.unique_constraints.iter().zip(&mut keys) {
+        let key = match key(object) {
+          Some(key) => key,
+          None => continue,
+        };
+        if let Some(other) = keys.insert(key.clone(), *id) {
+          return Err(format!(
+            "Unique constraint {} violated by objects {} and {}. Key: {}",
+            name, id, other, key
+          ));
         }
       }
     }
+    Ok(())
   }
-}
 
-#[derive(Default, Serialize, Deserialize, Debug)]
-struct CommitIndex {
-  latest_local_commit_id: Option<Uuid>,
-  latest_remote_commit_id: Option<Uuid>,
-}
+  // Promote pending local action object to remote one
+  // after remote accepted and signed it
+  fn promote_action_object(
+    &self,
+    ctx: &Context,
+    action_object: ActionObject<T, A>,
+  ) -> Result<(), String> {
+    let mut storage_object =
+      self.get_object_by_id(ctx, action_object.object_id)?;
+    storage_object.promote_local_action_object(action_object)?;
+    storage_object.save_to_fs(ctx)
+  }
 
-impl CommitIndex {
-  fn init(ctx: &Context) {
-    binary_init(path_helper::commit_index(ctx), Self::default());
+  // Current version of the given pending local action object
+  // Local actions are rebuilt during remote updates, so pushed
+  // commits must carry their latest version.
+  // None if it was dropped meanwhile (e.g. by conflict resolution)
+  fn rebase_action_object(
+    &self,
+    ctx: &Context,
+    action_object: ActionObject<T, A>,
+  ) -> Result<Option<String>, String> {
+    if !self.is_member(action_object.object_id) {
+      return Ok(None);
+    }
+    let storage_object = self.get_object_by_id(ctx, action_object.object_id)?;
+    storage_object
+      .local_actions
+      .iter()
+      .find(|i| i.id == action_object.id)
+      .map(|i| serde_json::to_string(i).map_err(|e| e.to_string()))
+      .transpose()
   }
-  fn load(ctx: &Context) -> Self {
-    binary_read(path_helper::commit_index(&ctx))
-      .expect("Error reading commit index")
+
+  // Continue the remote action chains with the given pushed action
+  // objects, concurrent to the given remote ones. Parents and object
+  // signatures are updated, as if they were created after the
+  // concurrent ones. Errors if any of them does not commute.
+  fn interleave_action_objects(
+    &self,
+    ctx: &Context,
+    pushed: &[String],
+    concurrent: &[String],
+  ) -> Result<Vec<String>, String> {
+    let mut concurrent_actions: HashMap<Uuid, Vec<ActionObject<T, A>>> =
+      HashMap::new();
+    for aob_str in concurrent {
+      let aob = deserialize_action_object::<T, A>(aob_str)?;
+      concurrent_actions
+        .entry(aob.object_id)
+        .or_default()
+        .push(aob);
+    }
+    // Pushed ones continue the chains in clock order
+    let mut pushed = pushed
+      .iter()
+      .map(|aob_str| {
+        deserialize_action_object::<T, A>(aob_str).map(|aob| (aob, aob_str))
+      })
+      .collect::<Result<Vec<_>, _>>()?;
+    pushed.sort_by_key(|(aob, _)| aob.clock_order());
+    // Latest action id and state of the objects
+    let mut heads: HashMap<Uuid, (Option<Uuid>, T)> = HashMap::new();
+    let mut res = vec![];
+    for (mut aob, aob_str) in pushed {
+      let action = match &aob.action {
+        // New objects cannot have concurrent actions
+        ActionKind::Create(data) => {
+          heads.insert(aob.object_id, (Some(aob.id), data.clone()));
+          res.push(aob_str.to_string());
+          continue;
+        }
+        ActionKind::Patch(action) => action,
+        _ => {
+          return Err(format!(
+            "Action {} on object {} cannot be interleaved",
+            aob.id, aob.object_id
+          ))
+        }
+      };
+      let commutes = concurrent_actions
+        .get(&aob.object_id)
+        .into_iter()
+        .flatten()
+        .all(|other| match &other.action {
+          ActionKind::Patch(other) => {
+            action.commutes_with(other)
+              || (action.is_additive() && other.is_additive())
+          }
+          _ => false,
+        });
+      if !commutes {
+        return Err(format!(
+          "Action {} does not commute with concurrent actions on object {}",
+          aob.id, aob.object_id
+        ));
+      }
+      let (parent_action_id, object) = match heads.remove(&aob.object_id) {
+        Some(head) => head,
+        None => {
+          let object = self.get_object_by_id(ctx, aob.object_id)?;
+          let state = object
+            .remote_object
+            .clone()
+            .ok_or(format!("Object {} has no remote state", aob.object_id))?;
+          (object.last_remote_action_id(), state)
+        }
+      };
+      let patched = aob.action.apply(&object, aob.dtime, &aob.uid)?;
+      aob.parent_action_id = parent_action_id;
+      aob.object_signature = object_signature(&patched)?;
+      heads.insert(aob.object_id, (Some(aob.id), patched));
+      res.push(serde_json::to_string(&aob).map_err(|e| e.to_string())?);
+    }
+    Ok(res)
   }
-  fn save_fs(&self, ctx: &Context) -> Result<(), String> {
-    binary_update(path_helper::commit_index(ctx), &self)
+
+  // Discard local changes of every storage object
+  // Local only objects are removed, remote ones are reset
+  // to their latest remote state
+  fn clean_local_changes(
+    &self,
+    ctx: &Context,
+    dry_run: bool,
+  ) -> Result<StorageCleanReport, String> {
+    let mut report = StorageCleanReport {
+      storage_id: self.storage_id(),
+      ..StorageCleanReport::default()
+    };
+    let ids = self.inner.read().unwrap().member_ids.clone();
+    for id in ids {
+      let mut storage_object = self.get_object_by_id(ctx, id)?;
+      if storage_object.local_actions.is_empty() {
+        continue;
+      }
+      report.discarded_actions += storage_object.local_actions.len();
+      match storage_object.is_local_object() {
+        true => {
+          report.removed_objects.push(id);
+          if !dry_run {
+            binary_remove(
+              ctx,
+              path_helper::storage_object_path(
+                ctx,
+                &storage_object.storage_id,
+                id,
+              ),
+            )?;
+            self.inner.write().unwrap().member_ids.retain(|i| *i != id);
+            self.set_removed(id, false);
+          }
+        }
+        false => {
+          report.cleared_objects.push(id);
+          if !dry_run {
+            storage_object.clear_local_changes()?;
+            storage_object.save_to_fs(ctx)?;
+            self.set_removed(id, storage_object.is_removed());
+          }
+        }
+      }
+    }
+    if !dry_run && report.discarded_actions > 0 {
+      self.update_fs(ctx)?;
+    }
+    Ok(report)
   }
-  fn latest_local_commit_id(ctx: &Context) -> Option<Uuid> {
-    let s = Self::load(ctx);
-    s.latest_local_commit_id
+
+  // Rewrite legacy object signatures of every stored object
+  // Returns the rewritten action objects
+  fn rehash(
+    &self,
+    ctx: &Context,
+    signing_key: Option<&SigningKey>,
+  ) -> Result<Vec<UniversalActionObject>, String> {
+    let mut res = vec![];
+    for object in self.iter_all(ctx) {
+      let mut object = object?;
+      let rehashed = object.rehash_actions(signing_key)?;
+      if !rehashed.is_empty() {
+        object.save_to_fs(ctx)?;
+        res.extend(rehashed);
+      }
+    }
+    Ok(res)
   }
-  fn latest_remote_commit_id(ctx: &Context) -> Option<Uuid> {
-    let s = Self::load(ctx);
-    s.latest_local_commit_id
+
+  /// Legal holds of the storage, see retention
+  pub fn legal_holds(&self, ctx: &Context) -> Result<LegalHolds, String> {
+    let path = path_helper::storage_legal_holds_path(ctx, &self.storage_id());
+    match ctx.backend().exists(&path) {
+      true => binary_read(ctx, path),
+      false => Ok(LegalHolds::default()),
+    }
   }
-  fn set_latest_local_id(
+
+  /// Place or release legal hold on the whole storage
+  pub fn set_legal_hold(
+    &self,
     ctx: &Context,
-    latest_local: Option<Uuid>,
+    held: bool,
   ) -> Result<(), String> {
-    let mut s = Self::load(ctx);
-    s.latest_local_commit_id = latest_local;
-    s.save_fs(ctx)
+    let mut holds = self.legal_holds(ctx)?;
+    holds.storage = held;
+    binary_write(
+      ctx,
+      path_helper::storage_legal_holds_path(ctx, &self.storage_id()),
+      holds,
+    )
   }
-  fn set_latest_remote_id(
+
+  /// Place or release legal hold on a single object
+  pub fn set_object_legal_hold(
+    &self,
     ctx: &Context,
-    latest_remote: Option<Uuid>,
+    object_id: Uuid,
+    held: bool,
   ) -> Result<(), String> {
-    let mut s = Self::load(ctx);
-    s.latest_remote_commit_id = latest_remote;
-    s.save_fs(ctx)
+    let mut holds = self.legal_holds(ctx)?;
+    match held {
+      true => holds.objects.insert(object_id),
+      false => holds.objects.remove(&object_id),
+    };
+    binary_write(
+      ctx,
+      path_helper::storage_legal_holds_path(ctx, &self.storage_id()),
+      holds,
+    )
   }
-}
 
-/// Commit Log
-/// contains all the repository related logs
-#[derive(Default, Serialize, Deserialize, Debug)]
-pub struct CommitLog;
+  /// Tombstones of the purged objects
+  pub fn tombstones(&self, ctx: &Context) -> Result<Vec<Tombstone>, String> {
+    let path = path_helper::storage_tombstones_path(ctx, &self.storage_id());
+    match ctx.backend().exists(&path) {
+      true => binary_read(ctx, path),
+      false => Ok(vec![]),
+    }
+  }
 
-impl CommitLog {
-  fn init(ctx: &Context) -> Result<(), String> {
-    // Init latest log
-    // binary_init::<HashMap<String, Uuid>>(
-    //   path_helper::commit_latest(ctx),
-    //   HashMap::default(),
-    // )?;
-    // Init local log
-    binary_init_empty(path_helper::commit_local_log(ctx))?;
-    // Init remote log
-    binary_init_empty(path_helper::commit_remote_log(ctx))?;
-    // Init commit index
-    CommitIndex::init(ctx);
-    Ok(())
+  // Purge objects removed longer than the retention period
+  // Removals not pushed yet are skipped. Returns the purged action
+  // objects, remote signed if signing key is given.
+  fn purge(
+    &self,
+    ctx: &Context,
+    signing_key: Option<&SigningKey>,
+  ) -> Result<(StoragePurgeReport, Vec<UniversalActionObject>), String> {
+    let storage_id = self.storage_id();
+    let mut report = StoragePurgeReport {
+      storage_id: storage_id.clone(),
+      ..StoragePurgeReport::default()
+    };
+    let mut res = vec![];
+    let retention = match &self.retention {
+      Some(retention) => retention,
+      None => return Ok((report, res)),
+    };
+    let holds = self.legal_holds(ctx)?;
+    let mut tombstones = self.tombstones(ctx)?;
+    let removed_ids = self.inner.read().unwrap().removed_ids.clone();
+    for id in removed_ids {
+      let object = self.get_object_by_id(ctx, id)?;
+      if object.has_local_changes() {
+        continue;
+      }
+      let removal = object
+        .remote_chain()?
+        .rfind(|aob| !aob.is_kind_patch())
+        .filter(|aob| matches!(aob.action, ActionKind::Remove));
+      let removed_at = match removal {
+        Some(aob) if retention.is_due(aob.dtime) => aob.dtime,
+        _ => continue,
+      };
+      if holds.is_held(id) {
+        report.held_objects.push(id);
+        continue;
+      }
+      let last = object.remote_chain()?.next_back().unwrap();
+      tombstones.push(Tombstone {
+        object_id: id,
+        removed_at,
+        purged_at: Utc::now(),
+        last_action_id: last.id,
+        object_signature: last.object_signature.clone(),
+      });
+      for aob in object.remote_chain()? {
+        let mut uaob: UniversalActionObject = serde_json::to_value(aob)
+          .and_then(serde_json::from_value)
+          .map_err(|e| e.to_string())?;
+        uaob.action = purged_action(&uaob.action)?;
+        if let Some(key) = signing_key {
+          uaob.remote_signature = None;
+          uaob.remote_sign(key)?;
+        }
+        res.push(uaob);
+      }
+      binary_remove(
+        ctx,
+        path_helper::storage_object_path(ctx, &storage_id, id),
+      )?;
+      let log_path = path_helper::storage_object_log_path(ctx, &storage_id, id);
+      if ctx.backend().exists(&log_path) {
+        binary_remove(ctx, log_path)?;
+      }
+      remove_checkpoints(ctx, &storage_id, id)?;
+      {
+        let mut inner = self.inner.write().unwrap();
+        inner.member_ids.retain(|member_id| *member_id != id);
+        inner.removed_ids.retain(|removed_id| *removed_id != id);
+      }
+      report.purged_objects.push(id);
+    }
+    if !report.purged_objects.is_empty() {
+      report.purged_actions = res.len();
+      binary_write(
+        ctx,
+        path_helper::storage_tombstones_path(ctx, &storage_id),
+        tombstones,
+      )?;
+      self.update_fs(ctx)?;
+    }
+    Ok((report, res))
   }
 
-  fn load_locals(ctx: &Context) -> Result<Vec<Commit>, String> {
-    let locals = binary_continuous_read(path_helper::commit_local_log(ctx))?;
-    Ok(locals)
+  // Check object files against the members and verify every object
+  // Valid orphan files are re-linked, invalid ones are deleted.
+  // Cached object states not matching their action chain are rewritten.
+  fn fsck(
+    &self,
+    ctx: &Context,
+    dry_run: bool,
+  ) -> Result<StorageFsckReport, String> {
+    let storage_id = self.storage_id();
+    let mut report = StorageFsckReport {
+      storage_id: storage_id.clone(),
+      ..StorageFsckReport::default()
+    };
+    let mut files = vec![];
+    let data_path = path_helper::storage_data_path(ctx, &storage_id);
+    for path in ctx.backend().scan(&data_path)? {
+      let object_id = path
+        .file_name()
+        .and_then(|name| Uuid::parse_str(&name.to_string_lossy()).ok());
+      match object_id {
+        Some(id)
+          if path == path_helper::storage_object_path(ctx, &storage_id, id) =>
+        {
+          files.push((id, path))
+        }
+        _ => report.deleted_files.push(path),
+      }
+    }
+    // Action logs without object file
+    let history_path = path_helper::storage_history_path(ctx, &storage_id);
+    for path in ctx.backend().scan(&history_path)? {
+      let object_id = path
+        .file_name()
+        .and_then(|name| Uuid::parse_str(&name.to_string_lossy()).ok());
+      match object_id {
+        Some(id)
+          if path
+            == path_helper::storage_object_log_path(ctx, &storage_id, id)
+            && files.iter().any(|(file_id, _)| *file_id == id) => {}
+        _ => report.deleted_files.push(path),
+      }
+    }
+    let (member_ids, removed_ids) = {
+      let inner = self.inner.read().unwrap();
+      (inner.member_ids.clone(), inner.removed_ids.clone())
+    };
+    report.missing_objects = member_ids
+      .iter()
+      .filter(|id| !files.iter().any(|(file_id, _)| file_id == *id))
+      .copied()
+      .collect();
+    for (id, path) in files {
+      let is_member = member_ids.contains(&id);
+      let verified = StorageObject::<T, A>::read_from_fs(
+        ctx,
+        &storage_id,
+        id,
+        self.migrator.as_ref(),
+      )
+      .and_then(|so| match so.id == id {
+        true => Ok(so),
+        false => Err(format!("Object file contains object {}", so.id)),
+      })
+      .and_then(|so| so.verify_actions().map(|states| (so, states)));
+      let (mut so, (local_object, remote_object)) = match (verified, is_member)
+      {
+        (Ok(verified), _) => verified,
+        (Err(e), true) => {
+          report.corrupted_objects.push((id, e));
+          continue;
+        }
+        (Err(_), false) => {
+          report.deleted_files.push(path);
+          let log_path =
+            path_helper::storage_object_log_path(ctx, &storage_id, id);
+          if ctx.backend().exists(&log_path) {
+            report.deleted_files.push(log_path);
+          }
+          continue;
+        }
+      };
+      let stale = canonical_json(&local_object)?
+        != canonical_json(&so.local_object)?
+        || canonical_json(&remote_object)?
+          != canonical_json(&so.remote_object)?;
+      match is_member {
+        true if stale || removed_ids.contains(&id) != so.is_removed() => {
+          report.repaired_objects.push(id)
+        }
+        true => continue,
+        false => report.relinked_objects.push(id),
+      }
+      if dry_run {
+        continue;
+      }
+      if stale {
+        so.local_object = local_object;
+        so.remote_object = remote_object;
+        so.save_to_fs(ctx)?;
+      }
+      if !is_member {
+        self.inner.write().unwrap().member_ids.push(id);
+      }
+      self.set_removed(id, so.is_removed());
+    }
+    if !dry_run && !report.is_clean() {
+      for path in &report.deleted_files {
+        binary_remove(ctx, path.clone())?;
+      }
+      let mut inner = self.inner.write().unwrap();
+      let missing = &report.missing_objects;
+      inner.member_ids.retain(|id| !missing.contains(id));
+      let member_ids = inner.member_ids.clone();
+      inner.removed_ids.retain(|id| member_ids.contains(id));
+      drop(inner);
+      self.update_fs(ctx)?;
+    }
+    Ok(report)
   }
-  fn load_remotes(ctx: &Context) -> Result<Vec<Commit>, String> {
-    let remotes = binary_continuous_read(path_helper::commit_remote_log(ctx))?;
-    Ok(remotes)
+
+  // Check invariants of every member object
+  fn verify(
+    &self,
+    ctx: &Context,
+    keys: &[VerifyingKey],
+  ) -> Result<StorageVerifyReport, String> {
+    let storage_id = self.storage_id();
+    let member_ids = self.inner.read().unwrap().member_ids.clone();
+    let mut report = StorageVerifyReport {
+      storage_id: storage_id.clone(),
+      objects: member_ids.len(),
+      ..StorageVerifyReport::default()
+    };
+    for id in member_ids {
+      let checked = StorageObject::<T, A>::read_from_fs(
+        ctx,
+        &storage_id,
+        id,
+        self.migrator.as_ref(),
+      )
+      .and_then(|so| so.check_invariants(keys));
+      if let Err(e) = checked {
+        report.violations.push((id, e));
+      }
+    }
+    Ok(report)
   }
-  fn load_remotes_after(
+
+  // Create inverse action objects for the given serialized action objects
+  // Only action objects of this storage are reverted, in reverse order.
+  // Returns the serialized inverse action objects
+  fn revert_action_objects(
+    &self,
     ctx: &Context,
-    after_id: Uuid,
-  ) -> Result<Vec<Commit>, String> {
-    let remotes = binary_continuous_read_after_filter(
-      path_helper::commit_remote_log(ctx),
-      |i: &Commit| i.id == after_id,
-    )?;
-    Ok(remotes)
+    commit: &Commit,
+    aob_strs: &[String],
+  ) -> Result<Vec<String>, String> {
+    let storage_id = self.storage_id();
+    // Working copies, so inverse actions of the same object are chained
+    let mut objects: HashMap<Uuid, StorageObject<T, A>> = HashMap::new();
+    let mut res = vec![];
+    for aob_str in aob_strs.iter().rev() {
+      let aob = match serde_json::from_str::<ActionObject<T, A>>(aob_str) {
+        Ok(aob) if aob.storage_id == storage_id => aob,
+        _ => continue,
+      };
+      let object = match objects.entry(aob.object_id) {
+        Entry::Occupied(o) => o.into_mut(),
+        Entry::Vacant(v) => {
+          v.insert(self.get_object_by_id(ctx, aob.object_id)?)
+        }
+      };
+      let inverse = match &aob.action {
+        // Created object is reverted by removing it, as a tombstone
+        ActionKind::Create(_) => ActionKind::Remove,
+        ActionKind::Patch(action) => {
+          let before = object.object_before_action(aob.id)?;
+          match action.inverse(&before) {
+            Some(inverse) => ActionKind::Patch(inverse),
+            None => ActionKind::Restore(before),
+          }
+        }
+        // Restore of a removed object is reverted by removing it again
+        ActionKind::Restore(_)
+          if object.is_removed_after(object.action_position(aob.id)?)? =>
+        {
+          ActionKind::Remove
+        }
+        ActionKind::Restore(_) | ActionKind::Remove => {
+          ActionKind::Restore(object.object_before_action(aob.id)?)
+        }
+        ActionKind::Redacted(_) => {
+          return Err("Redacted action cannot be reverted".to_string())
+        }
+      };
+      let inverse_aob = object.create_action_object(ctx, commit, inverse)?;
+      object.add_local_action_object(inverse_aob.clone())?;
+      res.push(serde_json::to_string(&inverse_aob).map_err(|e| e.to_string())?);
+    }
+    Ok(res)
   }
-  fn add_local_commit(
+
+  // Create baseline Create action objects
+  // reflecting object states after the squashed commits.
+  // Create action takes the id of the last squashed action,
+  // so later actions remain chained to it.
+  // Objects removed at the horizon get a Create and a Remove action,
+  // the latter taking the id of the last squashed action.
+  fn compact_action_objects(
+    &self,
     ctx: &Context,
-    mut local_commit: Commit,
-  ) -> Result<(), String> {
-    // Set ancestor ID
-    if let Some(last_local_commit_id) = CommitIndex::latest_local_commit_id(ctx)
-    {
-      local_commit.set_ancestor_id(last_local_commit_id);
+    baseline: &Commit,
+    squashed: &HashSet<Uuid>,
+  ) -> Result<Vec<String>, String> {
+    let mut res = vec![];
+    for object in self.iter_all(ctx) {
+      let object = object?;
+      let last_squashed = object
+        .remote_chain()?
+        .rev()
+        .find(|aob| aob.commit_id.is_some_and(|id| squashed.contains(&id)));
+      let last_squashed = match last_squashed {
+        Some(aob) => aob,
+        // Object created after the horizon
+        None => continue,
+      };
+      let data = object.object_at_action(last_squashed.id)?;
+      let removed = object
+        .is_removed_after(object.action_position(last_squashed.id)? + 1)?;
+      let mut aob: ActionObject<T, A> = ActionObject {
+        id: last_squashed.id,
+        storage_id: object.storage_id.clone(),
+        object_id: object.id,
+        uid: last_squashed.uid.clone(),
+        dtime: last_squashed.dtime,
+        commit_id: Some(baseline.id),
+        parent_action_id: None,
+        object_signature: object_signature(&data)?,
+        action: ActionKind::Create(data),
+        schema_hash: self.schema_hash(),
+        clock: last_squashed.clock,
+        remote_signature: None,
+      };
+      if removed {
+        let create = ActionObject {
+          id: ctx.new_id(),
+          ..aob.clone()
+        };
+        res.push(serde_json::to_string(&create).map_err(|e| e.to_string())?);
+        aob.parent_action_id = Some(create.id);
+        aob.action = ActionKind::Remove;
+      }
+      res.push(serde_json::to_string(&aob).map_err(|e| e.to_string())?);
     }
-    // Set commit index
-    CommitIndex::set_latest_local_id(ctx, Some(local_commit.id))?;
-    // Save local commit
-    binary_continuous_append(path_helper::commit_local_log(ctx), local_commit)
+    Ok(res)
   }
-  fn add_remote_commit(
+
+  // Details are encoded under the read lock and written after it is
+  // released
+  fn update_fs(&self, ctx: &Context) -> Result<(), String> {
+    let _write = self.details_write.lock().unwrap();
+    let (storage_id, data) = {
+      let inner = self.inner.read().unwrap();
+      (inner.id.clone(), encode(ctx, inner.deref())?)
+    };
+    binary_update_encoded(
+      ctx,
+      path_helper::storage_details_path(ctx, &storage_id),
+      &data,
+    )
+  }
+
+  /// Storage status
+  /// Counts objects with not yet pushed local actions
+  pub fn status(&self, ctx: &Context) -> Result<StorageStatus, String> {
+    let mut res = StorageStatus {
+      storage_id: self.storage_id(),
+      ..StorageStatus::default()
+    };
+    for object in self.iter(ctx) {
+      res.objects += 1;
+      if object?.has_local_changes() {
+        res.dirty_objects += 1;
+      }
+    }
+    Ok(res)
+  }
+
+  /// Storage disk usage
+  /// Reads every object to find the blobs they refer to
+  pub fn usage(&self, ctx: &Context) -> Result<StorageUsage, String> {
+    let res = self.measure_usage(ctx)?;
+    *self.used_bytes.lock().unwrap() = Some(res.total_bytes());
+    Ok(res)
+  }
+
+  fn measure_usage(&self, ctx: &Context) -> Result<StorageUsage, String> {
+    let storage_id = self.storage_id();
+    let backend = ctx.backend();
+    let mut res = StorageUsage {
+      object_bytes: prefix_size(
+        backend,
+        &path_helper::storage_data_path(ctx, &storage_id),
+      )?,
+      log_bytes: prefix_size(
+        backend,
+        &path_helper::storage_history_path(ctx, &storage_id),
+      )?,
+      storage_id,
+      ..StorageUsage::default()
+    };
+    let mut blobs = HashMap::new();
+    for object in self.iter(ctx) {
+      let object = object?;
+      res.objects += 1;
+      for blob in blob::object_blob_refs(&object.local_object) {
+        blobs.insert(blob.id().to_string(), blob.size());
+      }
+    }
+    res.blob_bytes = blobs.values().sum();
+    Ok(res)
+  }
+
+  // Replace mirrored rows of the storage with the current objects
+  fn reset_mirror(
+    &self,
     ctx: &Context,
-    remote_commit: Commit,
+    mirror: &dyn ObjectMirror,
   ) -> Result<(), String> {
-    let mut commit_index = CommitIndex::load(ctx);
-    // check ancestor ID
-    if let Some(last_remote_commit_id) = commit_index.latest_remote_commit_id {
-      if remote_commit.ancestor_id != last_remote_commit_id {
-        return Err("Remote commit ancestor ID error! Please pull".into());
-      }
+    let mut rows = self
+      .iter(ctx)
+      .map(|object| object.and_then(|object| object.mirror_row()));
+    mirror.reset(&self.storage_id(), &mut rows)
+  }
+
+  // Update mirrored row of an applied object
+  fn mirror_object(
+    &self,
+    mirror: &dyn ObjectMirror,
+    storage_object: &StorageObject<T, A>,
+  ) {
+    let res = match storage_object.is_removed() {
+      true => mirror.remove(&self.storage_id(), storage_object.id),
+      false => storage_object
+        .mirror_row()
+        .and_then(|row| mirror.upsert(&self.storage_id(), row)),
+    };
+    if let Err(e) = res {
+      warn!(object_id = %storage_object.id, error = %e, "Mirror update failed");
     }
-    // Set commit index
-    CommitIndex::set_latest_remote_id(ctx, Some(remote_commit.id))?;
-    // Save remote commit
-    binary_continuous_append(path_helper::commit_remote_log(ctx), remote_commit)
+  }
+
+  // Rewrite storage details and objects in the to context format
+  fn migrate_format(&self, from: &Context, to: &Context) -> Result<(), String> {
+    let data = encode(to, self.inner.read().unwrap().deref())?;
+    binary_update_encoded(
+      to,
+      path_helper::storage_details_path(to, &self.storage_id()),
+      &data,
+    )?;
+    let storage_id = self.storage_id();
+    binary_migrate::<LegalHolds>(
+      from,
+      to,
+      path_helper::storage_legal_holds_path(from, &storage_id),
+    )?;
+    binary_migrate::<Vec<Tombstone>>(
+      from,
+      to,
+      path_helper::storage_tombstones_path(from, &storage_id),
+    )?;
+    for path in from
+      .backend()
+      .scan(&path_helper::storage_stash_path(from, &storage_id))?
+    {
+      binary_continuous_migrate::<String>(from, to, path)?;
+    }
+    // Action logs and checkpoints are rewritten as well
+    for object in self.iter_all(from) {
+      let mut object = object?;
+      binary_continuous_migrate::<StoredCheckpoint>(
+        from,
+        to,
+        path_helper::storage_object_checkpoint_path(
+          from,
+          &storage_id,
+          object.id,
+        ),
+      )?;
+      object.unlog_actions()?;
+      object.save_to_fs(to)?;
+    }
+    Ok(())
+  }
+
+  /// Register a callback to a given repository
+  /// Repository will use this callback to update storage
+  /// Errors if a storage with the same id is already registered
+  pub fn register(self, repo: &Repository) -> Result<Self, String> {
+    repo.add_storage_handle(self.storage_id(), Box::new(self.clone()))?;
+    let _self = self.clone();
+    let ctx = repo.ctx().deref().to_owned();
+    let cleaner = self.clone();
+    let cleaner_ctx = ctx.clone();
+    repo.add_storage_cleaner(Box::new(move |dry_run: bool| {
+      cleaner.clean_local_changes(&cleaner_ctx, dry_run)
+    }))?;
+    let reverter = self.clone();
+    let reverter_ctx = ctx.clone();
+    repo.add_storage_reverter(Box::new(
+      move |commit: &Commit, aob_strs: &[String]| {
+        reverter.revert_action_objects(&reverter_ctx, commit, aob_strs)
+      },
+    ))?;
+    let restorer = self.clone();
+    let restorer_ctx = ctx.clone();
+    repo.add_storage_restorer(
+      self.storage_id(),
+      Box::new(move |commit: &Commit, object_id: Uuid| {
+        restorer.restore_action_objects(&restorer_ctx, commit, object_id)
+      }),
+    );
+    let baseline = self.clone();
+    let baseline_ctx = ctx.clone();
+    repo.add_storage_baseline(
+      self.storage_id(),
+      Box::new(move |object_id: Uuid, action_id: Uuid| {
+        baseline.baseline(&baseline_ctx, object_id, action_id)
+      }),
+    );
+    let compactor = self.clone();
+    let compactor_ctx = ctx.clone();
+    repo.add_storage_compactor(Box::new(
+      move |baseline: &Commit, squashed: &HashSet<Uuid>| {
+        compactor.compact_action_objects(&compactor_ctx, baseline, squashed)
+      },
+    ))?;
+    let fsck = self.clone();
+    let fsck_ctx = ctx.clone();
+    repo.add_storage_fsck(Box::new(move |dry_run: bool| {
+      fsck.fsck(&fsck_ctx, dry_run)
+    }));
+    let verifier = self.clone();
+    let verifier_ctx = ctx.clone();
+    repo.add_storage_verifier(Box::new(move |keys: &[VerifyingKey]| {
+      verifier.verify(&verifier_ctx, keys)
+    }));
+    let flusher = self.clone();
+    let flusher_ctx = ctx.clone();
+    repo.add_storage_flusher(Box::new(move || {
+      flusher.flush_details(&flusher_ctx)
+    }));
+    let reloader = self.clone();
+    let reloader_ctx = ctx.clone();
+    repo.add_storage_reloader(Box::new(move || {
+      reloader.reload_details(&reloader_ctx)
+    }));
+    let checkpointer = self.clone();
+    let checkpointer_ctx = ctx.clone();
+    repo.add_storage_checkpointer(Box::new(move || {
+      checkpointer.checkpoint_histories(&checkpointer_ctx)
+    }));
+    let rehasher = self.clone();
+    let rehasher_ctx = ctx.clone();
+    repo.add_storage_rehasher(Box::new(move |signing_key| {
+      rehasher.rehash(&rehasher_ctx, signing_key)
+    }));
+    if self.retention.is_some() {
+      let purger = self.clone();
+      let purger_ctx = ctx.clone();
+      repo.add_storage_purger(Box::new(move |signing_key| {
+        purger.purge(&purger_ctx, signing_key)
+      }));
+    }
+    let reporter = self.clone();
+    let reporter_ctx = ctx.clone();
+    repo
+      .add_storage_reporter(Box::new(move || reporter.status(&reporter_ctx)))?;
+    let meter = self.clone();
+    let meter_ctx = ctx.clone();
+    repo.add_storage_meter(Box::new(move || meter.usage(&meter_ctx)));
+    let counter = self.clone();
+    repo.add_storage_counter(Box::new(move || {
+      (counter.storage_id(), counter.len())
+    }));
+    let querier = self.clone();
+    let querier_ctx = ctx.clone();
+    repo.add_storage_querier(
+      self.storage_id(),
+      Box::new(move |query: &Query| querier.query_results(&querier_ctx, query)),
+    )?;
+    if let Some(schema) = self.schema() {
+      repo.add_storage_schema(self.storage_id(), schema);
+    }
+    let migrator = self.clone();
+    repo.add_storage_migrator(
+      self.storage_id(),
+      Box::new(move |from: &Context, to: &Context| {
+        migrator.migrate_format(from, to)
+      }),
+    )?;
+    let checker = self.clone();
+    let checker_ctx = ctx.clone();
+    let is_server =
+      matches!(repo.repo_details.lock().unwrap().mode, Mode::Server { .. });
+    repo.add_storage_checker(Box::new(move |aob_strs: &[String]| {
+      checker.check_action_objects(&checker_ctx, aob_strs, is_server)
+    }))?;
+    let (states, states_ctx) = (self.clone(), ctx.clone());
+    let (referrers, referrers_ctx) = (self.clone(), ctx.clone());
+    let contains = self.clone();
+    let remover = self.clone();
+    repo.add_storage_referencer(
+      self.storage_id(),
+      StorageReferencer {
+        states: Box::new(move |aob_strs, extractor| {
+          states.reference_states(&states_ctx, aob_strs, is_server, extractor)
+        }),
+        referrers: Box::new(move |object_id, extractor| {
+          referrers.referrers(&referrers_ctx, object_id, extractor)
+        }),
+        contains: Box::new(move |object_id| {
+          let inner = contains.inner.read().unwrap();
+          inner.member_ids.contains(&object_id)
+            && !inner.removed_ids.contains(&object_id)
+        }),
+        remover: Box::new(move |ctx, commit, object_id| {
+          let aob = remover
+            .get_object_by_id(ctx, object_id)?
+            .create_action_object(ctx, commit, ActionKind::Remove)?;
+          serde_json::to_string(&aob).map_err(|e| e.to_string())
+        }),
+      },
+    );
+    let rebaser = self.clone();
+    let rebaser_ctx = ctx.clone();
+    repo.add_storage_rebaser(
+      self.storage_id(),
+      Box::new(move |aobstr: &str| {
+        let aob = deserialize_action_object::<T, A>(aobstr)?;
+        rebaser.rebase_action_object(&rebaser_ctx, aob)
+      }),
+    );
+    if self.merge_mode == MergeMode::Crdt {
+      let interleaver = self.clone();
+      let interleaver_ctx = ctx.clone();
+      repo.add_storage_interleaver(
+        self.storage_id(),
+        Box::new(move |pushed: &[String], concurrent: &[String]| {
+          interleaver.interleave_action_objects(
+            &interleaver_ctx,
+            pushed,
+            concurrent,
+          )
+        }),
+      );
+    }
+    let mirrorer = self.clone();
+    let mirrorer_ctx = ctx.clone();
+    repo.add_storage_mirrorer(Box::new(move |mirror: &dyn ObjectMirror| {
+      mirrorer.reset_mirror(&mirrorer_ctx, mirror)
+    }))?;
+    let object_mirror = repo.object_mirror.clone();
+    let resolved_conflicts = repo.resolved_conflicts.clone();
+    repo.add_storage_hook(
+      self.storage_id(),
+      Box::new(move |aobstr: &str, callback_mode: CallbackMode| {
+        let aob = deserialize_action_object::<T, A>(aobstr)?;
+        match callback_mode {
+          // Kept aside while the object state is unknown
+          CallbackMode::Apply if self.stash_action_object(&ctx, &aob)? => {
+            Ok(())
+          }
+          // Save updated storage object
+          CallbackMode::Apply => {
+            let event = self.change_event(&aob);
+            self
+              .apply_action_object(&ctx, aob)
+              .and_then(|(storage_object, conflicts)| {
+                for conflict in &conflicts {
+                  warn!(
+                    object_id = %conflict.object_id,
+                    action_id = %conflict.action_id,
+                    kind = ?conflict.kind,
+                    "Conflict resolved"
+                  );
+                }
+                resolved_conflicts
+                  .fetch_add(conflicts.len(), Ordering::Relaxed);
+                self.conflicts.lock().unwrap().extend(conflicts);
+                self.save_applied_object(&ctx, &storage_object)?;
+                if let Some(mirror) = object_mirror.lock().unwrap().as_ref() {
+                  self.mirror_object(mirror.as_ref(), &storage_object);
+                }
+                Ok(())
+              })
+              .map(|_| {
+                let _ = self.change_tx.send(event);
+              })
+          }
+          CallbackMode::Promote => self.promote_action_object(&ctx, aob),
+          CallbackMode::Redact => self.redact_action_object(&ctx, aob),
+        }
+      }),
+    );
+    repo.apply_raw_actions(&_self.storage_id())?;
+    Ok(_self)
   }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct RepoDetails {
-  mode: Mode,
+// Working copies of the objects updated by a commit
+struct UpdatedObjects<T, A>
+where
+  T: ObjectExt,
+  A: ActionExt<ObjectType = T>,
+{
+  objects: HashMap<Uuid, StorageObject<T, A>>,
+  constrained: HashSet<Uuid>,
+  // Indexes of the action objects of the storage
+  checked: Vec<usize>,
 }
 
-impl RepoDetails {
-  fn init(ctx: &Context, mode: Mode) -> Result<(), String> {
-    binary_init(path_helper::repo_details(ctx), RepoDetails { mode })?;
-    Ok(())
+/// Kind of an object change
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum ChangeKind {
+  Created,
+  Patched,
+  Removed,
+}
+
+/// Origin of an object change
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum ChangeSource {
+  // Local commit
+  Local,
+  // Merged remote commit
+  Remote,
+}
+
+/// Object change notification, see Storage::subscribe
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangeEvent {
+  pub object_id: Uuid,
+  pub kind: ChangeKind,
+  pub source: ChangeSource,
+  pub commit_id: Option<Uuid>,
+}
+
+/// Local changes of a storage discarded by a clean operation
+#[derive(Default, Debug)]
+pub struct StorageCleanReport {
+  pub storage_id: String,
+  // Local only objects to remove
+  pub removed_objects: Vec<Uuid>,
+  // Remote objects reset to their remote state
+  pub cleared_objects: Vec<Uuid>,
+  // Number of local actions discarded
+  pub discarded_actions: usize,
+}
+
+/// Inconsistencies of a storage found by fsck
+#[derive(Default, Debug)]
+pub struct StorageFsckReport {
+  pub storage_id: String,
+  // Members without object file, dropped from the members
+  pub missing_objects: Vec<Uuid>,
+  // Valid object files not being members, added to the members
+  pub relinked_objects: Vec<Uuid>,
+  // Invalid files not being members, deleted
+  pub deleted_files: Vec<PathBuf>,
+  // Members with state not matching their action chain, rewritten
+  pub repaired_objects: Vec<Uuid>,
+  // Members with broken action chain or unreadable file
+  // Only reported, they need a clean or a clone
+  pub corrupted_objects: Vec<(Uuid, String)>,
+}
+
+impl StorageFsckReport {
+  /// True if no inconsistency found
+  pub fn is_clean(&self) -> bool {
+    self.missing_objects.is_empty()
+      && self.relinked_objects.is_empty()
+      && self.deleted_files.is_empty()
+      && self.repaired_objects.is_empty()
+      && self.corrupted_objects.is_empty()
   }
-  fn load(ctx: &Context) -> Result<Self, String> {
-    binary_read(path_helper::repo_details(ctx))
+}
+
+/// Result of a repository fsck
+#[derive(Default, Debug)]
+pub struct FsckReport {
+  pub dry_run: bool,
+  pub storages: Vec<StorageFsckReport>,
+  // Storages found on disk but not registered, so not checked
+  pub unregistered_storages: Vec<String>,
+}
+
+impl FsckReport {
+  /// True if no inconsistency found
+  pub fn is_clean(&self) -> bool {
+    self.storages.iter().all(|s| s.is_clean())
   }
 }
 
-enum CallbackMode {
-  Check,
-  Apply,
+/// Storage part of the repository status
+#[derive(Default, Debug, Clone, Serialize)]
+pub struct StorageStatus {
+  pub storage_id: String,
+  pub objects: usize,
+  // Objects with not yet pushed local actions
+  pub dirty_objects: usize,
 }
 
-pub struct Repository {
-  ctx: Arc<Mutex<Context>>,
-  commit_log: Arc<Mutex<CommitLog>>,
-  repo_details: Arc<Mutex<RepoDetails>>,
-  storage_hooks: Arc<
-    Mutex<
-      Vec<Box<dyn Fn(&str, CallbackMode) -> Option<Result<(), String>> + Send>>,
-    >,
-  >,
+/// Repository status
+/// Similar to git status, e.g. to show a sync indicator
+#[derive(Debug, Clone)]
+pub struct RepoStatus {
+  pub mode: Mode,
+  // Not yet pushed local commits
+  pub local_commits: usize,
+  pub storages: Vec<StorageStatus>,
+  pub last_pull: Option<DateTime<Utc>>,
+  pub last_push: Option<DateTime<Utc>>,
 }
 
-impl Repository {
-  /// Load repository
-  pub fn load(ctx: Context) -> Result<Self, String> {
-    // Load commit log
-    let commit_log = CommitLog;
-    // Load repo details
-    let repo_details = RepoDetails::load(&ctx)?;
-    // Create res
-    let res = Self {
-      ctx: Arc::new(Mutex::new(ctx)),
-      commit_log: Arc::new(Mutex::new(commit_log)),
-      repo_details: Arc::new(Mutex::new(repo_details)),
-      storage_hooks: Arc::new(Mutex::new(vec![])),
-    };
-    Ok(res)
+impl RepoStatus {
+  /// True if there is nothing to push
+  pub fn is_clean(&self) -> bool {
+    self.local_commits == 0
+      && self.storages.iter().all(|s| s.dirty_objects == 0)
   }
-  /// Init repository
-  pub fn init(ctx: Context, mode: Mode) -> Result<Self, String> {
-    // Check if repository inited
-    if Self::load(ctx.clone()).is_ok() {
-      return Err("Existing repository. Cannot init a new one".into());
+}
+
+/// Result of a pull
+#[derive(Default, Debug, Clone)]
+pub struct PullSummary {
+  // Remote commits applied to the local state
+  pub commits_applied: usize,
+  // Commits already known, e.g. pulled from another remote
+  pub commits_skipped: usize,
+  // Distinct objects touched by the applied commits
+  pub objects_changed: usize,
+  // Local actions conflicting with the applied commits
+  // (see Storage::take_conflicts)
+  pub conflicts: usize,
+}
+
+/// Phase of a running pull or push
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPhase {
+  /// Receiving pulled commits, their total is not known yet
+  Receiving,
+  /// Applying the received commits
+  Applying,
+  /// Pushing local commits
+  Pushing,
+}
+
+/// Progress of a running pull or push
+/// See Repository::on_sync_progress
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncProgress {
+  pub remote: String,
+  pub phase: SyncPhase,
+  // Commits transferred or applied in the phase
+  pub commits: usize,
+  // Commits of the phase, None while receiving
+  // Grows batch by batch while applying, see Context::pull_batch_size
+  pub total: Option<usize>,
+  // Serialized size of the commits in bytes
+  pub bytes: u64,
+  // Storages of the current commit
+  pub storage_ids: Vec<String>,
+}
+
+impl SyncProgress {
+  fn new(remote: &str, phase: SyncPhase, total: Option<usize>) -> Self {
+    Self {
+      remote: remote.to_string(),
+      phase,
+      commits: 0,
+      total,
+      bytes: 0,
+      storage_ids: vec![],
     }
-    // Init commit log
-    CommitLog::init(&ctx)?;
-    // Load commit log
-    let commit_log = CommitLog;
-    // Init repo details
-    RepoDetails::init(&ctx, mode)?;
-    // Load repo details
-    let repo_details = RepoDetails::load(&ctx)?;
-    // Create res
-    let res = Self {
-      ctx: Arc::new(Mutex::new(ctx)),
-      commit_log: Arc::new(Mutex::new(commit_log)),
-      repo_details: Arc::new(Mutex::new(repo_details)),
-      storage_hooks: Arc::new(Mutex::new(vec![])),
-    };
-    Ok(res)
   }
-  // Clone remote repository to local
-  fn clone(remote_url: &str) -> Result<Self, String> {
-    // TODO! Fix path and UID
-    let ctx = Context::init(PathBuf::from("./data"), "mezeipetister".into());
-    // Check if repository inited
-    if Self::load(ctx.clone()).is_ok() {
-      return Err("Existing repository. Cannot clone again".into());
-    }
+  // Count a transferred or applied commit
+  fn advance(&mut self, bytes: usize) {
+    self.commits += 1;
+    self.bytes += bytes as u64;
+  }
+}
+
+// Outcome of merging a single remote commit
+enum MergeResult {
+  Applied,
+  // Already in the remote log
+  Known,
+  // Does not continue the remote log
+  Diverged,
+}
+
+// Ids of every storage stored in the repository
+fn stored_storage_ids(ctx: &Context) -> Result<Vec<String>, String> {
+  let details_root = ctx.db_root_path.join("storage_details");
+  let mut res = vec![];
+  for key in ctx.backend().scan(&details_root)? {
+    let storage_id = key
+      .strip_prefix(&details_root)
+      .map_err(|e| e.to_string())?
+      .to_string_lossy()
+      .to_string();
+    res.push(storage_id);
+  }
+  Ok(res)
+}
+
+/// Remote server info
+#[derive(Debug, Clone)]
+pub struct RemoteInfo {
+  pub protocol_version: u32,
+  pub repository_id: Uuid,
+  pub latest_remote_commit_id: Option<Uuid>,
+  pub storage_count: usize,
+  pub features: Vec<String>,
+}
+
+// Server hook validating a pushed commit before merging it
+type PreMergeHook = Box<dyn Fn(&Commit) -> Result<(), String> + Send>;
+
+// Client hook validating a local commit before storing it
+type PreCommitHook = Box<dyn Fn(&Commit) -> Result<(), String> + Send>;
+
+// Hook notified about the progress of pulls and pushes
+type SyncProgressHook = Box<dyn Fn(&SyncProgress) + Send>;
+
+// Server hook notified about a merged commit
+type PostMergeHook = Box<dyn Fn(&Commit) + Send>;
+
+/// Action on removing an object referenced by another storage
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnDelete {
+  /// Commit is rejected while referencing objects exist
+  Restrict,
+  /// Referencing objects are removed as well
+  Cascade,
+}
+
+// Referenced object id of a source storage object
+// None if the object has no reference, or it is not of the source type
+type ReferenceExtractor = Arc<dyn Fn(&dyn Any) -> Option<Uuid> + Send + Sync>;
+
+// Reference between storages declared on the repository
+struct Reference {
+  source_storage: String,
+  target_storage: String,
+  extractor: ReferenceExtractor,
+  on_delete: OnDelete,
+}
+
+// Post commit state of an object updated by a commit
+struct ReferenceState {
+  object_id: Uuid,
+  removed: bool,
+  reference: Option<Uuid>,
+  // Updated by local action objects, on server side always
+  constrained: bool,
+}
+
+type ReferenceStates = Box<
+  dyn Fn(&[String], &ReferenceExtractor) -> Result<Vec<ReferenceState>, String>
+    + Send,
+>;
+
+type ReferenceReferrers =
+  Box<dyn Fn(Uuid, &ReferenceExtractor) -> Result<Vec<Uuid>, String> + Send>;
+
+type ReferenceRemover =
+  Box<dyn Fn(&Context, &Commit, Uuid) -> Result<String, String> + Send>;
+
+// Storage callbacks enforcing references between storages
+struct StorageReferencer {
+  // Reference states of the objects updated by
+  // the given serialized action objects
+  states: ReferenceStates,
+  // Ids of the objects referencing the given object id
+  referrers: ReferenceReferrers,
+  // Check whether the object exists and it is not removed
+  contains: Box<dyn Fn(Uuid) -> bool + Send>,
+  // Serialized Remove action object of the given object
+  remover: ReferenceRemover,
+}
+
+type ProjectionApplier = Box<dyn Fn(&Commit) -> Result<(), String> + Send>;
+
+type ProjectionSaver = Box<dyn Fn(&Context) -> Result<(), String> + Send>;
+
+// Projection callbacks fed by the repository
+struct ProjectionFeed {
+  name: String,
+  // Apply the action objects of a commit
+  apply: ProjectionApplier,
+  // Persist state in the given context
+  save: ProjectionSaver,
+  // Reset state to its default
+  reset: Box<dyn Fn() + Send>,
+}
+
+impl ProjectionFeed {
+  // Rebuild state by replaying remote, then local commits
+  fn rebuild(&self, ctx: &Context) -> Result<(), String> {
+    (self.reset)();
+    for commit in CommitLog::load_remotes(ctx)?
+      .iter()
+      .chain(CommitLog::load_locals(ctx)?.iter())
+    {
+      (self.apply)(commit)?;
+    }
+    (self.save)(ctx)
+  }
+}
+
+// Storage callback returning the objects matching a query
+type StorageQuerier =
+  Box<dyn Fn(&Query) -> Result<Vec<QueryResult>, String> + Send>;
+
+// Storage callback checking object files and objects
+// Bool param is the dry run flag
+type StorageFsck =
+  Box<dyn Fn(bool) -> Result<StorageFsckReport, String> + Send>;
+
+// Storage callback checking action chain invariants of its objects
+// Remote signatures are verified against the given keys
+type StorageVerifier =
+  Box<dyn Fn(&[VerifyingKey]) -> Result<StorageVerifyReport, String> + Send>;
+
+// Storage callback rewriting legacy object signatures
+// Remote action objects are re-signed with the given key
+type StorageRehasher = Box<
+  dyn Fn(Option<&SigningKey>) -> Result<Vec<UniversalActionObject>, String>
+    + Send,
+>;
+type StoragePurger = Box<
+  dyn Fn(
+      Option<&SigningKey>,
+    ) -> Result<(StoragePurgeReport, Vec<UniversalActionObject>), String>
+    + Send,
+>;
+
+// Storage callback returning the storage status
+type StorageReporter = Box<dyn Fn() -> Result<StorageStatus, String> + Send>;
+
+// Storage callback measuring the storage disk usage
+type StorageMeter = Box<dyn Fn() -> Result<StorageUsage, String> + Send>;
+
+// Storage callback rebuilding the mirrored rows of the storage
+type StorageMirrorer =
+  Box<dyn Fn(&dyn ObjectMirror) -> Result<(), String> + Send>;
+
+// Storage callback returning its storage id and object count
+type StorageCounter = Box<dyn Fn() -> (String, usize) + Send>;
+
+// Storage callback writing storage details changed by a commit
+type StorageFlusher = Box<dyn Fn() -> Result<(), String> + Send>;
+
+// Storage callback reloading storage details after a rolled back commit
+type StorageReloader = Box<dyn Fn() -> Result<(), String> + Send>;
+
+// Storage callback checkpointing object histories
+// Returns the number of written checkpoints
+type StorageCheckpointer = Box<dyn Fn() -> Result<usize, String> + Send>;
+
+// Storage callback discarding local changes
+// Bool param is the dry run flag
+type StorageCleaner =
+  Box<dyn Fn(bool) -> Result<StorageCleanReport, String> + Send>;
+
+// Storage callback checking the given serialized action objects
+// Returns the indexes of the checked action objects of the storage
+type StorageChecker =
+  Box<dyn Fn(&[String]) -> Result<Vec<usize>, String> + Send>;
+
+// Storage callback applying or promoting the given
+// serialized action object of the storage
+type StorageHook = Box<dyn Fn(&str, CallbackMode) -> Result<(), String> + Send>;
+
+// Storage callback returning the current version
+// of the given serialized pending local action object
+type StorageRebaser =
+  Box<dyn Fn(&str) -> Result<Option<String>, String> + Send>;
+
+// Storage callback rewriting the given serialized pushed action
+// objects to follow the given concurrent remote ones
+type StorageInterleaver =
+  Box<dyn Fn(&[String], &[String]) -> Result<Vec<String>, String> + Send>;
+
+// Storage callback creating the action objects restoring the current
+// remote state of the given object, see Repository::redact
+type StorageRestorer =
+  Box<dyn Fn(&Commit, Uuid) -> Result<Vec<String>, String> + Send>;
+
+// Storage callback returning the object state JSON after the given
+// object and action id, None if unknown, see delta
+type StorageBaseline =
+  Box<dyn Fn(Uuid, Uuid) -> Result<Option<String>, String> + Send>;
+
+// Storage callback creating the baseline Create action objects
+// for the given baseline commit and squashed commit ids
+type StorageCompactor =
+  Box<dyn Fn(&Commit, &HashSet<Uuid>) -> Result<Vec<String>, String> + Send>;
+
+// Storage callback rewriting storage data
+// from the first context format into the second one
+type StorageMigrator =
+  Box<dyn Fn(&Context, &Context) -> Result<(), String> + Send>;
+
+// Storage callback creating inverse action objects
+// for the given commit, from the given serialized action objects
+type StorageReverter =
+  Box<dyn Fn(&Commit, &[String]) -> Result<Vec<String>, String> + Send>;
+
+/// Clean operation report
+/// In dry run mode it reports what would be lost
+#[derive(Default, Debug)]
+pub struct CleanReport {
+  pub dry_run: bool,
+  // Not yet pushed local commits
+  pub local_commits: Vec<Commit>,
+  pub storages: Vec<StorageCleanReport>,
+}
+
+// Repository Mode
+// Local, Remote or Server
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum Mode {
+  Server {
+    server_addr: String,
+    tls: Option<ServerTls>,
+    // Address of the metrics listener, disabled if None
+    metrics_addr: Option<String>,
+  },
+  Remote {
+    remote_url: String,
+    tls: Option<ClientTls>,
+  },
+  Local,
+}
+
+impl Mode {
+  pub fn server(server_addr: String) -> Self {
+    Self::Server {
+      server_addr,
+      tls: None,
+      metrics_addr: None,
+    }
+  }
+  pub fn server_tls(server_addr: String, tls: ServerTls) -> Self {
+    Self::Server {
+      server_addr,
+      tls: Some(tls),
+      metrics_addr: None,
+    }
+  }
+  pub fn remote(remote_url: String) -> Self {
+    Self::Remote {
+      remote_url,
+      tls: None,
+    }
+  }
+  pub fn remote_tls(remote_url: String, tls: ClientTls) -> Self {
+    Self::Remote {
+      remote_url,
+      tls: Some(tls),
+    }
+  }
+  pub fn local() -> Self {
+    Self::Local
+  }
+  /// Serve metrics at GET /metrics on the given address
+  /// Server mode only, requires the "metrics" feature
+  pub fn with_metrics_addr(mut self, addr: String) -> Self {
+    if let Self::Server { metrics_addr, .. } = &mut self {
+      *metrics_addr = Some(addr);
+    }
+    self
+  }
+}
+
+/// Storage Context
+/// containing operational details
+/// such as db root path or uid
+/// Held by readers concurrently, commits wait for them
+pub struct ContextGuard<'a> {
+  read_guard: RwLockReadGuard<'a, Context>,
+}
+
+impl<'a> Deref for ContextGuard<'a> {
+  type Target = Context;
+
+  fn deref(&self) -> &Self::Target {
+    self.read_guard.deref()
+  }
+}
+
+#[derive(Clone)]
+pub struct Context {
+  pub db_root_path: PathBuf,
+  pub uid: String,
+  // Auth token sent to the remote server
+  pub auth_token: Option<String>,
+  // Repository name on a remote hosting many, see RepoHub
+  pub remote_repo: Option<String>,
+  // Application version reported when registering the device
+  pub app_version: Option<String>,
+  // Storage backend every repository data is read and written via
+  pub backend: Arc<dyn Backend>,
+  // Compression of newly written objects and commit log records
+  pub compression: Compression,
+  // Serialization format, shared by every clone of the context,
+  // so a format migration is seen by all registered storages
+  format: Arc<RwLock<Format>>,
+  // Largest sync message sent or accepted in bytes
+  // Larger commits are pushed in chunks
+  pub max_message_size: usize,
+  // Number of objects read in parallel by get_all and alike
+  pub read_concurrency: usize,
+  // Commits pulled and applied in one batch
+  pub pull_batch_size: usize,
+  // Ask for delta encoded action objects on pull
+  pub delta_sync: bool,
+  // Ask for presence changes on watch, see presence
+  pub presence: bool,
+  // Encoding of action objects in commit logs and pulled commits
+  pub action_codec: ActionCodec,
+  // Interval of the background flusher of a write-behind backend
+  pub flush_interval: Option<Duration>,
+  // Interval of the background history checkpointer
+  pub checkpoint_interval: Option<Duration>,
+  // Generator of new commit, action object and object ids
+  pub id_generator: Arc<dyn IdGenerator>,
+  // Shared by every clone of the context
+  metrics: Arc<Metrics>,
+  // Clock of new action objects, shared by every clone of the context
+  clock: Arc<HybridClock>,
+}
+
+impl Context {
+  pub fn init(db_root_path: PathBuf, uid: String) -> Self {
+    Self {
+      db_root_path,
+      uid,
+      auth_token: None,
+      remote_repo: None,
+      app_version: None,
+      backend: Arc::new(FsBackend),
+      compression: Compression::None,
+      format: Arc::new(RwLock::new(Format::default())),
+      max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+      read_concurrency: DEFAULT_READ_CONCURRENCY,
+      pull_batch_size: DEFAULT_PULL_BATCH_SIZE,
+      delta_sync: false,
+      presence: false,
+      action_codec: ActionCodec::Json,
+      flush_interval: None,
+      checkpoint_interval: None,
+      id_generator: Arc::new(RandomIds),
+      metrics: Arc::new(Metrics::default()),
+      clock: Arc::new(HybridClock::default()),
+    }
+  }
+  /// Context of a repository kept in a new MemoryBackend
+  pub fn in_memory(uid: String) -> Self {
+    Self::init(PathBuf::from("/"), uid).with_backend(MemoryBackend::new())
+  }
+  /// Replace the default file system backend
+  pub fn with_backend(mut self, backend: impl Backend + 'static) -> Self {
+    self.backend = Arc::new(backend);
+    self
+  }
+  /// Set auth token for remote requests
+  pub fn with_auth_token(mut self, auth_token: &str) -> Self {
+    self.auth_token = Some(auth_token.to_string());
+    self
+  }
+  /// Set repository name sent to a remote RepoHub
+  pub fn with_remote_repo(mut self, remote_repo: &str) -> Self {
+    self.remote_repo = Some(remote_repo.to_string());
+    self
+  }
+  /// Set application version reported to the remote with the device
+  /// registration, see device
+  pub fn with_app_version(mut self, app_version: &str) -> Self {
+    self.app_version = Some(app_version.to_string());
+    self
+  }
+  /// Compress newly written data
+  /// Existing data is read whatever compression it was written with
+  pub fn with_compression(mut self, compression: Compression) -> Self {
+    self.compression = compression;
+    self
+  }
+  /// Serialization format of a new repository
+  /// Loaded repositories use the format recorded at their init
+  pub fn with_format(mut self, format: Format) -> Self {
+    self.format = Arc::new(RwLock::new(format));
+    self
+  }
+  /// Set largest sync message size in bytes
+  pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+    self.max_message_size = max_message_size;
+    self
+  }
+  /// Set number of objects read in parallel, 1 reads sequentially
+  pub fn with_read_concurrency(mut self, read_concurrency: usize) -> Self {
+    self.read_concurrency = read_concurrency;
+    self
+  }
+  /// Set number of commits pulled and applied in one batch
+  /// An interrupted pull resumes after the last applied batch
+  pub fn with_pull_batch_size(mut self, pull_batch_size: usize) -> Self {
+    self.pull_batch_size = pull_batch_size;
+    self
+  }
+  /// Pull large action objects as deltas against object states
+  /// already held, if the remote supports it
+  /// Falls back to whole ones when a delta cannot be decoded
+  pub fn with_delta_sync(mut self, delta_sync: bool) -> Self {
+    self.delta_sync = delta_sync;
+    self
+  }
+  /// Stream the presence of other devices along with the watched
+  /// remote commits, see Repository::presence
+  pub fn with_presence(mut self, presence: bool) -> Self {
+    self.presence = presence;
+    self
+  }
+  /// Encode action objects by codec in new commit log records,
+  /// and ask for them encoded on pull and watch
+  /// Records and responses in other codecs remain readable
+  pub fn with_action_codec(mut self, action_codec: ActionCodec) -> Self {
+    self.action_codec = action_codec;
+    self
+  }
+  /// Buffer writes of the backend in memory
+  /// Buffered writes are flushed in batches every interval by a
+  /// background task, see worker, and by Repository::flush. After a crash the
+  /// repository is at its latest flushed commit, the later ones are
+  /// lost. Set after with_backend, before the repository is opened.
+  pub fn with_write_behind(
+    mut self,
+    interval: Duration,
+  ) -> Result<Self, String> {
+    let journal = path_helper::write_journal(&self);
+    self.backend =
+      Arc::new(WriteBehindBackend::open(self.backend.clone(), journal)?);
+    self.flush_interval = Some(interval);
+    Ok(self)
+  }
+  /// Checkpoint object histories every interval in the background,
+  /// see Repository::checkpoint_histories
+  pub fn with_checkpoint_interval(mut self, interval: Duration) -> Self {
+    self.checkpoint_interval = Some(interval);
+    self
+  }
+  /// Generate ids with the given generator instead of random UUIDv4,
+  /// e.g. TimeOrderedIds
+  pub fn with_id_generator(
+    mut self,
+    id_generator: impl IdGenerator + 'static,
+  ) -> Self {
+    self.id_generator = Arc::new(id_generator);
+    self
+  }
+  /// New id from the id generator
+  pub fn new_id(&self) -> Uuid {
+    self.id_generator.new_id()
+  }
+  pub fn format(&self) -> Format {
+    *self.format.read().unwrap()
+  }
+  fn set_format(&self, format: Format) {
+    *self.format.write().unwrap() = format;
+  }
+  pub fn backend(&self) -> &dyn Backend {
+    self.backend.as_ref()
+  }
+  pub fn metrics(&self) -> &Arc<Metrics> {
+    &self.metrics
+  }
+  /// Hybrid logical clock of new action objects
+  pub fn clock(&self) -> &HybridClock {
+    &self.clock
+  }
+}
+
+pub struct CommitContextGuard<'a> {
+  ctx: RwLockWriteGuard<'a, Context>,
+  commit_log: MutexGuard<'a, CommitLog>,
+  repo_details: MutexGuard<'a, RepoDetails>,
+  storage_hooks: MutexGuard<'a, HashMap<String, StorageHook>>,
+  storage_checkers: MutexGuard<'a, Vec<StorageChecker>>,
+  storage_flushers: Arc<Mutex<Vec<StorageFlusher>>>,
+  storage_reloaders: Arc<Mutex<Vec<StorageReloader>>>,
+  references: Arc<Mutex<Vec<Reference>>>,
+  storage_referencers: Arc<Mutex<HashMap<String, StorageReferencer>>>,
+  projections: Arc<Mutex<Vec<ProjectionFeed>>>,
+  remote_commit_tx: broadcast::Sender<Commit>,
+  unknown_storage_policy: UnknownStoragePolicy,
+  trace_recorder: Option<Arc<TraceRecorder>>,
+  // Local commits only, None when merging
+  pre_commit_hooks: Option<Arc<Mutex<Vec<PreCommitHook>>>>,
+  temp_commit: Commit,
+  // Committed or aborted explicitly
+  finalized: bool,
+}
+
+impl<'a> Deref for CommitContextGuard<'a> {
+  type Target = RwLockWriteGuard<'a, Context>;
+
+  fn deref(&self) -> &Self::Target {
+    &self.ctx
+  }
+}
+
+impl<'a> CommitContextGuard<'a> {
+  fn new(
+    repo: &'a Repository,
+    commit_comment: &str,
+    commit_meta: CommitMeta,
+  ) -> Self {
+    let (id, uid) = {
+      let ctx = repo.ctx.read().unwrap();
+      (ctx.new_id(), ctx.uid.to_string())
+    };
+    let mut temp_commit = Commit::new(id, uid, commit_comment.to_string());
+    temp_commit.meta = commit_meta.meta;
+    temp_commit
+      .meta
+      .entry(DEVICE_ID_META.to_string())
+      .or_insert_with(|| repo.device_id.clone());
+    temp_commit.tags = commit_meta.tags;
+    Self {
+      ctx: repo.ctx.write().unwrap(),
+      commit_log: repo.commit_log.lock().unwrap(),
+      repo_details: repo.repo_details.lock().unwrap(),
+      storage_hooks: repo.storage_hooks.lock().unwrap(),
+      storage_checkers: repo.storage_checkers.lock().unwrap(),
+      storage_flushers: repo.storage_flushers.clone(),
+      storage_reloaders: repo.storage_reloaders.clone(),
+      references: repo.references.clone(),
+      storage_referencers: repo.storage_referencers.clone(),
+      projections: repo.projections.clone(),
+      remote_commit_tx: repo.remote_commit_tx.clone(),
+      unknown_storage_policy: *repo.unknown_storage_policy.lock().unwrap(),
+      trace_recorder: repo.trace_recorder.lock().unwrap().clone(),
+      pre_commit_hooks: Some(repo.pre_commit_hooks.clone()),
+      temp_commit,
+      finalized: false,
+    }
+  }
+  // Use only for merge a given Commit to the local FS
+  // or for applying an already prepared local Commit
+  fn new_merge(repo: &'a Repository, temp_commit: Commit) -> Self {
+    Self {
+      ctx: repo.ctx.write().unwrap(),
+      commit_log: repo.commit_log.lock().unwrap(),
+      repo_details: repo.repo_details.lock().unwrap(),
+      storage_hooks: repo.storage_hooks.lock().unwrap(),
+      storage_checkers: repo.storage_checkers.lock().unwrap(),
+      storage_flushers: repo.storage_flushers.clone(),
+      storage_reloaders: repo.storage_reloaders.clone(),
+      references: repo.references.clone(),
+      storage_referencers: repo.storage_referencers.clone(),
+      projections: repo.projections.clone(),
+      remote_commit_tx: repo.remote_commit_tx.clone(),
+      unknown_storage_policy: *repo.unknown_storage_policy.lock().unwrap(),
+      trace_recorder: repo.trace_recorder.lock().unwrap().clone(),
+      pre_commit_hooks: None,
+      temp_commit,
+      finalized: false,
+    }
+  }
+  pub fn add_action_object<
+    T: ObjectExt + Serialize,
+    A: ActionExt + Serialize,
+  >(
+    &mut self,
+    aob: ActionObject<T, A>,
+  ) {
+    let _ = self.temp_commit.add_action_object(aob);
+  }
+  /// Commit
+  /// Runs the pre commit hooks and checks every action object against
+  /// its storage first, and only stores and applies the commit if all
+  /// of them are valid.
+  /// Errors if nothing got stored, a partially written commit record
+  /// is rolled back. Once stored, returns the report of applying its
+  /// action objects, see CommitReport::into_result
+  /// If any of them fails to apply, the commit is rolled back.
+  #[instrument(
+    level = "debug",
+    skip_all,
+    fields(
+      commit_id = %self.temp_commit.id,
+      remote = self.temp_commit.is_remote(),
+      actions = self.temp_commit.serialized_actions.len(),
+    )
+  )]
+  pub fn commit(mut self) -> Result<CommitReport, String> {
+    self.finalized = true;
+    // Pre commit hooks can veto local commits
+    if let Some(hooks) = &self.pre_commit_hooks {
+      if !self.temp_commit.is_remote() {
+        for hook in hooks.lock().unwrap().iter() {
+          hook(&self.temp_commit)?;
+        }
+      }
+    }
+    // Purged action objects of pulled history are stored, not applied
+    let live = live_action_objects(&self.temp_commit.serialized_actions);
+    check_action_objects(
+      &self.storage_checkers,
+      &live,
+      self.unknown_storage_policy,
+    )?;
+    check_references(
+      &self.references.lock().unwrap(),
+      &self.storage_referencers.lock().unwrap(),
+      &live,
+    )?;
+    CommitIntent::write(&self.ctx, &self.temp_commit)?;
+    // Commit record might be partially written
+    if let Err(e) = self.store() {
+      return match self.roll_back() {
+        Ok(_) => Err(e),
+        Err(rollback) => Err(format!("{}, rollback: {}", e, rollback)),
+      };
+    }
+    debug!("Commit stored");
+    let report = self.apply();
+    CommitIntent::clear(&self.ctx)?;
+    if let Some(recorder) = &self.trace_recorder {
+      recorder.record(&TraceEvent::applied(&self.temp_commit, &report));
+    }
+    match report.is_ok() {
+      true => debug!("Commit applied"),
+      false => warn!(errors = ?report.errors, "Commit rolled back"),
+    }
+    Ok(report)
+  }
+  /// Abort
+  /// Discards the temp commit, nothing is stored.
+  /// Dropping the context without commit does the same.
+  pub fn abort(mut self) {
+    self.finalized = true;
+  }
+  // Add Remove action objects of the objects referencing
+  // the removed one by cascade references, recursively
+  fn cascade_remove(
+    &mut self,
+    storage_id: &str,
+    object_id: Uuid,
+  ) -> Result<(), String> {
+    let references = self.references.clone();
+    let references = references.lock().unwrap();
+    let referencers = self.storage_referencers.clone();
+    let referencers = referencers.lock().unwrap();
+    let mut removed = HashSet::from([(storage_id.to_string(), object_id)]);
+    let mut queue = vec![(storage_id.to_string(), object_id)];
+    while let Some((target_storage, id)) = queue.pop() {
+      for reference in references.iter().filter(|r| {
+        r.target_storage == target_storage && r.on_delete == OnDelete::Cascade
+      }) {
+        let source = match referencers.get(&reference.source_storage) {
+          Some(source) => source,
+          None => continue,
+        };
+        for referrer in (source.referrers)(id, &reference.extractor)? {
+          let key = (reference.source_storage.clone(), referrer);
+          if !removed.insert(key.clone()) {
+            continue;
+          }
+          let aob = (source.remover)(&self.ctx, &self.temp_commit, referrer)?;
+          self.temp_commit.serialized_actions.push(aob);
+          queue.push(key);
+        }
+      }
+    }
+    Ok(())
+  }
+  // Restore the files touched by the commit and drop it from the log,
+  // as recovery does after a crash, then reload the storage details
+  fn roll_back(&self) -> Result<(), String> {
+    CommitIntent::recover(&self.ctx)?;
+    for reloader in self.storage_reloaders.lock().unwrap().iter() {
+      reloader()?;
+    }
+    Ok(())
+  }
+  // Store commit in the commit log
+  fn store(&mut self) -> Result<(), String> {
+    // Empty local commits are not stored
+    // Remote ones (e.g. filtered by storage subscription) must be,
+    // to keep the remote commit chain
+    if self.temp_commit.is_local()
+      && self.temp_commit.serialized_actions.is_empty()
+    {
+      return Ok(());
+    }
+    match self.temp_commit.remote_signature.is_some() {
+      // Store remote commit
+      true => CommitLog::add_remote_commit(&self.ctx, self.temp_commit.clone()),
+      // Store local commit
+      false => CommitLog::add_local_commit(&self.ctx, self.temp_commit.clone()),
+    }
+  }
+  // Keep action object of an unknown storage by the policy
+  fn keep_unknown(
+    &self,
+    uaob: &UniversalActionObject,
+    aob_str: &str,
+  ) -> Result<(), String> {
+    match self.unknown_storage_policy {
+      UnknownStoragePolicy::Reject => {
+        Err(format!("No storage {} registered", uaob.storage_id))
+      }
+      UnknownStoragePolicy::Quarantine => {
+        warn!("Action object of unknown storage quarantined");
+        binary_write(
+          &self.ctx,
+          path_helper::quarantine_path(&self.ctx, &uaob.storage_id)
+            .join(uaob.id.as_simple().to_string()),
+          aob_str,
+        )
+      }
+      // Keyed by action id, so pulling the commit again stores it once
+      UnknownStoragePolicy::StoreRaw => binary_write(
+        &self.ctx,
+        path_helper::raw_actions_path(&self.ctx, &uaob.storage_id)
+          .join(uaob.id.as_simple().to_string()),
+        aob_str,
+      ),
+    }
+  }
+  // Replace the redacted action objects in the commit logs and
+  // through the storage hooks, see Repository::redact
+  // Commits changed on server side are re-signed.
+  fn apply_redaction(&self, redaction: &str) -> Result<(), String> {
+    let redacted: Vec<UniversalActionObject> =
+      serde_json::from_str(redaction).map_err(|e| e.to_string())?;
+    if let Some(uaob) = redacted
+      .iter()
+      .find(|uaob| !uaob.is_redacted() || uaob.is_local())
+    {
+      return Err(format!("Action {} is not a redacted remote one", uaob.id));
+    }
+    let signing_key = match self.repo_details.mode {
+      Mode::Server { .. } => Some(self.repo_details.signing_key()?),
+      _ => None,
+    };
+    let replacements = redacted
+      .iter()
+      .map(|uaob| (uaob.id, uaob.clone()))
+      .collect::<HashMap<_, _>>();
+    let (_, replaced) = CommitLog::rewrite_actions(
+      &self.ctx,
+      &replacements,
+      signing_key.as_ref(),
+    )?;
+    delete_orphan_blobs(&self.ctx, &replaced)?;
+    for uaob in &redacted {
+      if let Some(hook) = self.storage_hooks.get(&uaob.storage_id) {
+        let aob_str = serde_json::to_string(uaob).map_err(|e| e.to_string())?;
+        hook(&aob_str, CallbackMode::Redact)?;
+      }
+    }
+    Ok(())
+  }
+  // Apply action objects through the storage hooks
+  // Reports the result of every action object
+  fn apply(&self) -> CommitReport {
+    let mut report = CommitReport {
+      commit_id: self.temp_commit.id,
+      actions: vec![],
+      errors: vec![],
+    };
+    // Redactions first, the restoring action objects follow them
+    if let (true, Some(redaction)) = (
+      self.temp_commit.is_remote(),
+      self.temp_commit.meta.get(REDACTION_META),
+    ) {
+      if let Err(e) = self.apply_redaction(redaction) {
+        report.errors.push(format!("Redaction: {}", e));
+      }
+    }
+    for aob_str in &self.temp_commit.serialized_actions {
+      let uaob = match serde_json::from_str::<UniversalActionObject>(aob_str) {
+        Ok(uaob) => uaob,
+        Err(e) => {
+          report
+            .errors
+            .push(format!("Error deser action object: {}", e));
+          continue;
+        }
+      };
+      if uaob.is_purged() {
+        trace!(action_id = %uaob.id, "Purged action object skipped");
+        continue;
+      }
+      let _span = debug_span!(
+        "apply",
+        storage_id = %uaob.storage_id,
+        object_id = %uaob.object_id,
+        action_id = %uaob.id,
+      )
+      .entered();
+      let result = match self.storage_hooks.get(&uaob.storage_id) {
+        Some(hook) => hook(aob_str, CallbackMode::Apply),
+        None => self.keep_unknown(&uaob, aob_str),
+      };
+      match &result {
+        Ok(_) => trace!("Action object applied"),
+        Err(e) => warn!(error = %e, "Action object failed to apply"),
+      }
+      report.actions.push(ActionReport {
+        action_id: uaob.id,
+        storage_id: uaob.storage_id,
+        object_id: uaob.object_id,
+        result,
+      });
+    }
+    // Write storage details once per commit
+    for flusher in self.storage_flushers.lock().unwrap().iter() {
+      if let Err(e) = flusher() {
+        report.errors.push(e);
+      }
+    }
+    // Partially applied commit is rolled back, so it never stays in
+    // the log with its objects half updated
+    if !report.is_ok() {
+      if let Err(e) = self.roll_back() {
+        report.errors.push(format!("Rollback: {}", e));
+      }
+      return report;
+    }
+    // Feed projections with every stored commit
+    if self.temp_commit.is_remote()
+      || !self.temp_commit.serialized_actions.is_empty()
+    {
+      for feed in self.projections.lock().unwrap().iter() {
+        let fed =
+          (feed.apply)(&self.temp_commit).and_then(|_| (feed.save)(&self.ctx));
+        if let Err(e) = fed {
+          report
+            .errors
+            .push(format!("Projection {}: {}", feed.name, e));
+          break;
+        }
+      }
+    }
+    // Notify subscribers about the applied remote commit
+    // Error only means there is no active subscriber
+    if !self.temp_commit.serialized_actions.is_empty()
+      && self.temp_commit.is_remote()
+    {
+      let _ = self.remote_commit_tx.send(self.temp_commit.clone());
+    }
+    report
+  }
+}
+
+// Dropping a context without commit discards it
+impl<'a> Drop for CommitContextGuard<'a> {
+  fn drop(&mut self) {
+    if !self.finalized && !self.temp_commit.serialized_actions.is_empty() {
+      warn!(
+        "Commit {} dropped without commit, its {} action objects are discarded",
+        self.temp_commit.id,
+        self.temp_commit.serialized_actions.len()
+      );
+    }
+  }
+}
+
+/// Report of a stored commit
+/// If some of its action objects failed to apply, the commit is
+/// rolled back, and their errors are reported here
+#[must_use]
+#[derive(Debug, Clone)]
+pub struct CommitReport {
+  pub commit_id: Uuid,
+  // Apply result of every action object, in commit order
+  pub actions: Vec<ActionReport>,
+  // Errors of the commit level steps, e.g. writing storage details
+  pub errors: Vec<String>,
+}
+
+/// Apply result of an action object
+#[derive(Debug, Clone)]
+pub struct ActionReport {
+  pub action_id: Uuid,
+  pub storage_id: String,
+  pub object_id: Uuid,
+  pub result: Result<(), String>,
+}
+
+impl CommitReport {
+  /// Check whether everything got applied
+  pub fn is_ok(&self) -> bool {
+    self.errors.is_empty() && self.actions.iter().all(|a| a.result.is_ok())
+  }
+  /// Commit id, or the collected errors if anything failed to apply
+  pub fn into_result(self) -> Result<Uuid, String> {
+    if self.is_ok() {
+      return Ok(self.commit_id);
+    }
+    let errors: Vec<String> = self
+      .actions
+      .iter()
+      .filter_map(|a| match &a.result {
+        Ok(_) => None,
+        Err(e) => Some(format!("action {}: {}", a.action_id, e)),
+      })
+      .chain(self.errors.iter().cloned())
+      .collect();
+    Err(format!(
+      "Commit {} stored, but failed to apply: {}",
+      self.commit_id,
+      errors.join("; ")
+    ))
+  }
+}
+
+// Write-ahead record of a commit being stored and applied
+// Holds the before images of every file the commit touches, so a commit
+// interrupted by a crash is rolled back when the repository is loaded
+#[derive(Serialize, Deserialize, Debug)]
+struct CommitIntent {
+  commit_id: Uuid,
+  is_remote: bool,
+  // Paths relative to the repository root, None if the file was absent
+  files: Vec<(PathBuf, Option<Vec<u8>>)>,
+}
+
+impl CommitIntent {
+  // Record before images of the object, its action log and checkpoints,
+  // and of the storage details files
+  // Commits without action objects change no files
+  fn write(ctx: &Context, commit: &Commit) -> Result<(), String> {
+    if commit.serialized_actions.is_empty() {
+      return Ok(());
+    }
+    let mut paths = BTreeSet::new();
+    for aob_str in &commit.serialized_actions {
+      let uaob: UniversalActionObject = serde_json::from_str(aob_str)
+        .map_err(|_| "Error while deser aob into universal aob".to_string())?;
+      let (storage_id, object_id) = (&uaob.storage_id, uaob.object_id);
+      paths
+        .insert(path_helper::storage_object_path(ctx, storage_id, object_id));
+      paths.insert(path_helper::storage_object_log_path(
+        ctx, storage_id, object_id,
+      ));
+      paths.insert(path_helper::storage_object_checkpoint_path(
+        ctx, storage_id, object_id,
+      ));
+      paths.insert(path_helper::storage_details_path(ctx, storage_id));
+      // Action objects of unknown storages kept aside by the policy
+      let key = uaob.id.as_simple().to_string();
+      paths.insert(path_helper::quarantine_path(ctx, storage_id).join(&key));
+      paths.insert(path_helper::raw_actions_path(ctx, storage_id).join(key));
+    }
+    let mut files = vec![];
+    for path in paths {
+      let before = match ctx.backend().exists(&path) {
+        true => Some(ctx.backend().get(&path)?),
+        false => None,
+      };
+      let relative = path
+        .strip_prefix(&ctx.db_root_path)
+        .map_err(|e| e.to_string())?
+        .to_path_buf();
+      files.push((relative, before));
+    }
+    let intent = CommitIntent {
+      commit_id: commit.id,
+      is_remote: commit.is_remote(),
+      files,
+    };
+    binary_write(ctx, path_helper::commit_intent(ctx), intent)
+  }
+  fn clear(ctx: &Context) -> Result<(), String> {
+    let path = path_helper::commit_intent(ctx);
+    match ctx.backend().exists(&path) {
+      true => binary_remove(ctx, path),
+      false => Ok(()),
+    }
+  }
+  // Roll back the interrupted or failed commit, if any
+  // Restores the before images, then drops the commit from the log
+  // Returns the rolled back commit id
+  fn recover(ctx: &Context) -> Result<Option<Uuid>, String> {
+    let path = path_helper::commit_intent(ctx);
+    if !ctx.backend().exists(&path) {
+      return Ok(None);
+    }
+    let intent: CommitIntent = binary_read(ctx, path.clone())?;
+    for (relative, before) in intent.files {
+      let file = ctx.db_root_path.join(relative);
+      match before {
+        Some(data) => ctx.backend().put(&file, &data)?,
+        None if ctx.backend().exists(&file) => ctx.backend().delete(&file)?,
+        None => (),
+      }
+    }
+    // Commit record might be partially written
+    CommitLog::recover(ctx)?;
+    let commit_id = intent.commit_id;
+    match intent.is_remote {
+      true if CommitIndex::latest_remote_commit_id(ctx) == Some(commit_id) => {
+        let mut remotes = CommitLog::load_remotes(ctx)?;
+        remotes.pop();
+        CommitLog::replace_remotes(ctx, remotes)?;
+      }
+      false if CommitIndex::latest_local_commit_id(ctx) == Some(commit_id) => {
+        CommitLog::remove_local_commit(ctx, commit_id)?;
+      }
+      _ => (),
+    }
+    binary_remove(ctx, path)?;
+    Ok(Some(commit_id))
+  }
+}
+
+// Storage id of a serialized action object
+fn storage_id_of(aob_str: &str) -> Result<String, String> {
+  #[derive(Deserialize)]
+  struct StorageIdOf {
+    storage_id: String,
+  }
+  serde_json::from_str::<StorageIdOf>(aob_str)
+    .map(|aob| aob.storage_id)
+    .map_err(|e| format!("Error deser action object: {}", e))
+}
+
+// Typed action object of a serialized one
+fn deserialize_action_object<T, A>(
+  aob_str: &str,
+) -> Result<ActionObject<T, A>, String>
+where
+  T: ObjectExt + for<'de> Deserialize<'de>,
+  A: ActionExt<ObjectType = T> + for<'de> Deserialize<'de>,
+{
+  serde_json::from_str(aob_str)
+    .map_err(|e| format!("Error deser action object: {}", e))
+}
+
+// Check every action object of a commit
+// Each action object must be checked by exactly one storage
+fn check_action_objects(
+  checkers: &[StorageChecker],
+  aob_strs: &[String],
+  unknown_storage_policy: UnknownStoragePolicy,
+) -> Result<(), String> {
+  let mut checked = vec![false; aob_strs.len()];
+  for checker in checkers {
+    for index in checker(aob_strs)? {
+      checked[index] = true;
+    }
+  }
+  // Action objects of unknown storages are kept aside when applied
+  if unknown_storage_policy != UnknownStoragePolicy::Reject {
+    return Ok(());
+  }
+  if let Some(index) = checked.iter().position(|checked| !checked) {
+    let storage_id =
+      serde_json::from_str::<UniversalActionObject>(&aob_strs[index])
+        .map(|uaob| uaob.storage_id)
+        .unwrap_or_default();
+    return Err(format!("Unknown storage {}", storage_id));
+  }
+  Ok(())
+}
+
+// Delete blobs of the given replaced action objects
+// not referred by any remaining commit
+// Returns the number of deleted blobs
+fn delete_orphan_blobs(
+  ctx: &Context,
+  replaced: &[String],
+) -> Result<usize, String> {
+  let candidates = blob::blob_refs(replaced);
+  if candidates.is_empty() {
+    return Ok(0);
+  }
+  let mut referred = HashSet::new();
+  for commit in CommitLog::load_locals(ctx)?
+    .iter()
+    .chain(CommitLog::load_remotes(ctx)?.iter())
+  {
+    referred
+      .extend(commit.blobs().into_iter().map(|blob| blob.id().to_string()));
+  }
+  let mut deleted = 0;
+  for blob in candidates {
+    let path = path_helper::blob_path(ctx, blob.id());
+    if !referred.contains(blob.id()) && ctx.backend().exists(&path) {
+      binary_remove(ctx, path)?;
+      deleted += 1;
+    }
+  }
+  Ok(deleted)
+}
+
+// Action objects but the purged ones, see retention
+fn live_action_objects(aob_strs: &[String]) -> Cow<'_, [String]> {
+  let is_purged = |aob_str: &String| {
+    aob_str.contains(PURGED_ACTION)
+      && serde_json::from_str::<UniversalActionObject>(aob_str)
+        .is_ok_and(|uaob| uaob.is_purged())
+  };
+  match aob_strs.iter().any(is_purged) {
+    true => Cow::Owned(
+      aob_strs
+        .iter()
+        .filter(|aob_str| !is_purged(aob_str))
+        .cloned()
+        .collect(),
+    ),
+    false => Cow::Borrowed(aob_strs),
+  }
+}
+
+// Check references between storages
+// Constrained objects must reference existing, not removed objects,
+// and removed objects must not be referenced by any object.
+// References of not registered storages are not checked.
+fn check_references(
+  references: &[Reference],
+  referencers: &HashMap<String, StorageReferencer>,
+  aob_strs: &[String],
+) -> Result<(), String> {
+  for reference in references {
+    let (source, target) = match (
+      referencers.get(&reference.source_storage),
+      referencers.get(&reference.target_storage),
+    ) {
+      (Some(source), Some(target)) => (source, target),
+      _ => continue,
+    };
+    let states = |referencer: &StorageReferencer| {
+      (referencer.states)(aob_strs, &reference.extractor).map(|states| {
+        states
+          .into_iter()
+          .map(|state| (state.object_id, state))
+          .collect::<HashMap<_, _>>()
+      })
+    };
+    let sources = states(source)?;
+    let targets = states(target)?;
+    let exists = |id: Uuid| match targets.get(&id) {
+      Some(state) => !state.removed,
+      None => (target.contains)(id),
+    };
+    for state in sources.values().filter(|s| s.constrained && !s.removed) {
+      match state.reference {
+        Some(id) if !exists(id) => {
+          return Err(format!(
+            "Object {} of storage {} references missing object {} \
+             of storage {}",
+            state.object_id,
+            reference.source_storage,
+            id,
+            reference.target_storage
+          ))
+        }
+        _ => (),
+      }
+    }
+    for state in targets.values().filter(|s| s.constrained && s.removed) {
+      for referrer in (source.referrers)(state.object_id, &reference.extractor)?
+      {
+        // Referrers updated by the commit are checked by their new state
+        let referenced = match sources.get(&referrer) {
+          Some(s) => !s.removed && s.reference == Some(state.object_id),
+          None => true,
+        };
+        if referenced {
+          return Err(format!(
+            "Object {} of storage {} cannot be removed, it is referenced \
+             by object {} of storage {}",
+            state.object_id,
+            reference.target_storage,
+            referrer,
+            reference.source_storage
+          ));
+        }
+      }
+    }
+  }
+  Ok(())
+}
+
+#[derive(Default, Serialize, Deserialize, Debug)]
+struct CommitIndex {
+  latest_local_commit_id: Option<Uuid>,
+  latest_remote_commit_id: Option<Uuid>,
+  // Last successful pull and push
+  #[serde(default)]
+  last_pull: Option<DateTime<Utc>>,
+  #[serde(default)]
+  last_push: Option<DateTime<Utc>>,
+  // Latest commit pulled from each remote
+  #[serde(default)]
+  remote_cursors: BTreeMap<String, Uuid>,
+  // Sequence number of the latest remote commit, if it has one
+  #[serde(default)]
+  latest_remote_seq: Option<u64>,
+  // Latest clock of the repository, restored on open
+  #[serde(default)]
+  latest_clock: Option<Hlc>,
+}
+
+impl CommitIndex {
+  fn init(ctx: &Context) {
+    binary_init(ctx, path_helper::commit_index(ctx), Self::default());
+  }
+  fn load(ctx: &Context) -> Self {
+    binary_read(ctx, path_helper::commit_index(&ctx))
+      .expect("Error reading commit index")
+  }
+  fn save_fs(&self, ctx: &Context) -> Result<(), String> {
+    binary_update(ctx, path_helper::commit_index(ctx), &self)
+  }
+  fn latest_local_commit_id(ctx: &Context) -> Option<Uuid> {
+    let s = Self::load(ctx);
+    s.latest_local_commit_id
+  }
+  fn latest_remote_commit_id(ctx: &Context) -> Option<Uuid> {
+    let s = Self::load(ctx);
+    s.latest_remote_commit_id
+  }
+  fn set_latest_local_id(
+    ctx: &Context,
+    latest_local: Option<Uuid>,
+  ) -> Result<(), String> {
+    let mut s = Self::load(ctx);
+    s.latest_local_commit_id = latest_local;
+    s.latest_clock = Some(ctx.clock().latest());
+    s.save_fs(ctx)
+  }
+  fn set_latest_remote(
+    ctx: &Context,
+    latest_remote: Option<&Commit>,
+  ) -> Result<(), String> {
+    let mut s = Self::load(ctx);
+    s.latest_remote_commit_id = latest_remote.map(|c| c.id);
+    s.latest_remote_seq = latest_remote.and_then(|c| c.seq);
+    // Later local actions are ordered after the remote ones
+    if let Some(clock) = latest_remote.and_then(|c| c.clock()) {
+      ctx.clock().observe(clock);
+    }
+    s.latest_clock = Some(ctx.clock().latest());
+    s.save_fs(ctx)
+  }
+  // Sequence number of the next remote commit
+  // Counted from the remote log if the latest commit has none yet
+  fn next_remote_seq(ctx: &Context) -> Result<u64, String> {
+    let s = Self::load(ctx);
+    match (s.latest_remote_seq, s.latest_remote_commit_id) {
+      (Some(seq), _) => Ok(seq + 1),
+      (None, None) => Ok(1),
+      (None, Some(_)) => {
+        let remotes =
+          CommitLog::iter(ctx, path_helper::commit_remote_log(ctx))?;
+        Ok(remotes.count() as u64 + 1)
+      }
+    }
+  }
+  // Latest commit known by the given remote
+  // Falls back to the latest remote commit
+  fn remote_cursor(ctx: &Context, remote: &str) -> Option<Uuid> {
+    let s = Self::load(ctx);
+    s.remote_cursors
+      .get(remote)
+      .copied()
+      .or(s.latest_remote_commit_id)
+  }
+  fn set_remote_cursor(
+    ctx: &Context,
+    remote: &str,
+    commit_id: Option<Uuid>,
+  ) -> Result<(), String> {
+    let mut s = Self::load(ctx);
+    match commit_id {
+      Some(commit_id) => s.remote_cursors.insert(remote.to_string(), commit_id),
+      None => s.remote_cursors.remove(remote),
+    };
+    s.save_fs(ctx)
+  }
+  fn set_last_pull(ctx: &Context, dtime: DateTime<Utc>) -> Result<(), String> {
+    let mut s = Self::load(ctx);
+    s.last_pull = Some(dtime);
+    s.save_fs(ctx)
+  }
+  fn set_last_push(ctx: &Context, dtime: DateTime<Utc>) -> Result<(), String> {
+    let mut s = Self::load(ctx);
+    s.last_push = Some(dtime);
+    s.save_fs(ctx)
+  }
+}
+
+pub use crate::fs::{Compression, Format, LogRecovery};
+
+/// Commit log recovery report
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CommitLogRecovery {
+  pub local: LogRecovery,
+  pub remote: LogRecovery,
+}
+
+// On-disk commit versions
+// Format changes add a new variant converted into the latest Commit,
+// so logs written by older versions remain readable.
+#[derive(Serialize, Deserialize)]
+enum StoredCommit {
+  V1(StoredCommitBase),
+  // Metadata added
+  V2 {
+    commit: StoredCommitBase,
+    meta: BTreeMap<String, String>,
+    tags: Vec<String>,
+  },
+  // Sequence number added
+  V3 {
+    commit: StoredCommitBase,
+    meta: BTreeMap<String, String>,
+    tags: Vec<String>,
+    seq: Option<u64>,
+  },
+  // Action objects encoded by a codec, see Context::with_action_codec
+  // serialized_actions of the commit are left empty
+  V4 {
+    commit: StoredCommitBase,
+    meta: BTreeMap<String, String>,
+    tags: Vec<String>,
+    seq: Option<u64>,
+    action_codec: ActionCodec,
+    encoded_actions: Vec<u8>,
+  },
+}
+
+// Commit fields of every version
+// Commit itself skips empty fields, which the binary format cannot do
+#[derive(Serialize, Deserialize)]
+struct StoredCommitBase {
+  id: Uuid,
+  uid: String,
+  dtime: DateTime<Utc>,
+  comment: String,
+  ancestor_id: Uuid,
+  serialized_actions: Vec<String>,
+  remote_signature: Option<String>,
+}
+
+impl StoredCommit {
+  // Commit in the latest version, with its action objects encoded
+  // Json codec commits are kept as V3, readable by older versions
+  fn new(mut commit: Commit, codec: ActionCodec) -> Result<Self, String> {
+    let encoded_actions = match codec {
+      ActionCodec::Json => None,
+      codec => Some(codec.encode(&commit.take_serialized_actions())?),
+    };
+    let base = StoredCommitBase {
+      id: commit.id,
+      uid: commit.uid,
+      dtime: commit.dtime,
+      comment: commit.comment,
+      ancestor_id: commit.ancestor_id,
+      serialized_actions: commit.serialized_actions,
+      remote_signature: commit.remote_signature,
+    };
+    Ok(match encoded_actions {
+      Some(encoded_actions) => StoredCommit::V4 {
+        commit: base,
+        meta: commit.meta,
+        tags: commit.tags,
+        seq: commit.seq,
+        action_codec: codec,
+        encoded_actions,
+      },
+      None => StoredCommit::V3 {
+        commit: base,
+        meta: commit.meta,
+        tags: commit.tags,
+        seq: commit.seq,
+      },
+    })
+  }
+}
+
+impl TryFrom<StoredCommit> for Commit {
+  type Error = String;
+  fn try_from(stored: StoredCommit) -> Result<Self, String> {
+    let (mut commit, meta, tags, seq) = match stored {
+      StoredCommit::V1(commit) => (commit, BTreeMap::new(), vec![], None),
+      StoredCommit::V2 { commit, meta, tags } => (commit, meta, tags, None),
+      StoredCommit::V3 {
+        commit,
+        meta,
+        tags,
+        seq,
+      } => (commit, meta, tags, seq),
+      StoredCommit::V4 {
+        mut commit,
+        meta,
+        tags,
+        seq,
+        action_codec,
+        encoded_actions,
+      } => {
+        commit.serialized_actions = action_codec.decode(&encoded_actions)?;
+        (commit, meta, tags, seq)
+      }
+    };
+    Ok(Commit {
+      id: commit.id,
+      uid: commit.uid,
+      dtime: commit.dtime,
+      comment: commit.comment,
+      ancestor_id: commit.ancestor_id,
+      serialized_actions: std::mem::take(&mut commit.serialized_actions),
+      remote_signature: commit.remote_signature,
+      meta,
+      tags,
+      seq,
+    })
+  }
+}
+
+type CommitIter = ContinuousIter<StoredCommit, Commit>;
+
+/// Commit Log
+/// contains all the repository related logs
+#[derive(Default, Serialize, Deserialize, Debug)]
+pub struct CommitLog;
+
+impl CommitLog {
+  fn init(ctx: &Context) -> Result<(), String> {
+    // Init latest log
+    // binary_init::<HashMap<String, Uuid>>(
+    //   path_helper::commit_latest(ctx),
+    //   HashMap::default(),
+    // )?;
+    // Init local log
+    binary_init_empty(ctx, path_helper::commit_local_log(ctx))?;
+    // Init remote log
+    binary_init_empty(ctx, path_helper::commit_remote_log(ctx))?;
+    // Init commit index
+    CommitIndex::init(ctx);
+    Ok(())
+  }
+
+  // Read commits of a log, whatever version they were stored in
+  fn read(ctx: &Context, path: PathBuf) -> Result<Vec<Commit>, String> {
+    binary_continuous_read::<StoredCommit, _>(ctx, path)
+  }
+  // Lazy iterator over commits of a log
+  fn iter(ctx: &Context, path: PathBuf) -> Result<CommitIter, String> {
+    binary_continuous_iter(ctx, path)
+  }
+  // Append commit to a log in the latest version
+  // Action objects are encoded by the codec of the context
+  fn append(
+    ctx: &Context,
+    path: PathBuf,
+    commit: Commit,
+  ) -> Result<(), String> {
+    let stored = StoredCommit::new(commit, ctx.action_codec)?;
+    binary_continuous_append(ctx, path, stored)
+  }
+  // Ids of the commits up to and including commit_id
+  // Remote commits first, then local ones
+  // Errors if the commit is in neither log, e.g. after compaction
+  fn ids_until(
+    ctx: &Context,
+    commit_id: Uuid,
+  ) -> Result<HashSet<Uuid>, String> {
+    let remotes = Self::iter(ctx, path_helper::commit_remote_log(ctx))?;
+    let locals = Self::iter(ctx, path_helper::commit_local_log(ctx))?;
+    let mut ids = HashSet::new();
+    for commit in remotes.chain(locals) {
+      let id = commit?.id;
+      ids.insert(id);
+      if id == commit_id {
+        return Ok(ids);
+      }
+    }
+    Err(format!("Commit {} not found", commit_id))
+  }
+  fn load_locals(ctx: &Context) -> Result<Vec<Commit>, String> {
+    Self::read(ctx, path_helper::commit_local_log(ctx))
+  }
+  fn load_remotes(ctx: &Context) -> Result<Vec<Commit>, String> {
+    Self::read(ctx, path_helper::commit_remote_log(ctx))
+  }
+  // None if after_id is not in the remote log
+  fn load_remotes_after(
+    ctx: &Context,
+    after_id: Uuid,
+  ) -> Result<Option<Vec<Commit>>, String> {
+    binary_continuous_read_after_filter::<StoredCommit, _>(
+      ctx,
+      path_helper::commit_remote_log(ctx),
+      |i: &Commit| i.id == after_id,
+    )
+  }
+  // Replace the whole remote log
+  fn replace_remotes(
+    ctx: &Context,
+    remotes: Vec<Commit>,
+  ) -> Result<(), String> {
+    binary_init_empty(ctx, path_helper::commit_remote_log(ctx))?;
+    let latest_remote = remotes.last().cloned();
+    for commit in remotes {
+      Self::append(ctx, path_helper::commit_remote_log(ctx), commit)?;
+    }
+    CommitIndex::set_latest_remote(ctx, latest_remote.as_ref())
+  }
+  // Replace the given action objects in both logs
+  // Changed remote commits are re-signed if signing key is given.
+  // Returns the number of changed commits and the replaced action
+  // objects.
+  fn rewrite_actions(
+    ctx: &Context,
+    replacements: &HashMap<Uuid, UniversalActionObject>,
+    signing_key: Option<&SigningKey>,
+  ) -> Result<(usize, Vec<String>), String> {
+    let mut changed_commits = 0;
+    let mut replaced = vec![];
+    for path in [
+      path_helper::commit_local_log(ctx),
+      path_helper::commit_remote_log(ctx),
+    ] {
+      let mut commits = Self::read(ctx, path.clone())?;
+      for commit in &mut commits {
+        let mut changed = false;
+        for aob_str in &mut commit.serialized_actions {
+          let uaob: UniversalActionObject = serde_json::from_str(aob_str)
+            .map_err(|_| {
+              "Error while deser aob into universal aob".to_string()
+            })?;
+          if let Some(replacement) = replacements.get(&uaob.id) {
+            let replacement =
+              serde_json::to_string(replacement).map_err(|e| e.to_string())?;
+            replaced.push(std::mem::replace(aob_str, replacement));
+            changed = true;
+          }
+        }
+        if !changed {
+          continue;
+        }
+        changed_commits += 1;
+        if let (true, Some(key)) = (commit.is_remote(), signing_key) {
+          commit.remote_signature = None;
+          commit.add_remote_signature(key)?;
+        }
+      }
+      binary_init_empty(ctx, path.clone())?;
+      for commit in commits {
+        Self::append(ctx, path.clone(), commit)?;
+      }
+    }
+    Ok((changed_commits, replaced))
+  }
+  // Rewrite commit logs and index in the to context format
+  fn migrate(from: &Context, to: &Context) -> Result<(), String> {
+    for path in [
+      path_helper::commit_local_log(from),
+      path_helper::commit_remote_log(from),
+    ] {
+      let commits = Self::read(from, path.clone())?;
+      binary_init_empty(to, path.clone())?;
+      for commit in commits {
+        Self::append(to, path.clone(), commit)?;
+      }
+    }
+    CommitIndex::load(from).save_fs(to)
+  }
+  // Rewrite logs in the unframed format of older versions
+  // Their records are commits as stored by V1
+  fn upgrade_legacy(ctx: &Context) -> Result<(), String> {
+    for path in [
+      path_helper::commit_local_log(ctx),
+      path_helper::commit_remote_log(ctx),
+    ] {
+      if !ctx.backend().exists(&path)
+        || !binary_continuous_is_legacy(ctx, &path)?
+      {
+        continue;
+      }
+      let stored = binary_legacy_read::<StoredCommitBase>(ctx, path.clone())?
+        .into_iter()
+        .map(|base| {
+          Commit::try_from(StoredCommit::V1(base))
+            .and_then(|commit| StoredCommit::new(commit, ctx.action_codec))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+      info!(path = ?path, commits = stored.len(), "Upgraded legacy commit log");
+      binary_continuous_write(ctx, path, &stored)?;
+    }
+    Ok(())
+  }
+  // Truncate both logs at their first bad record
+  fn recover(ctx: &Context) -> Result<CommitLogRecovery, String> {
+    let local =
+      binary_continuous_recover(ctx, path_helper::commit_local_log(ctx))?;
+    let remote =
+      binary_continuous_recover(ctx, path_helper::commit_remote_log(ctx))?;
+    // Point commit index to the last salvaged commits
+    let latest_local = Self::load_locals(ctx)?.last().map(|c| c.id);
+    CommitIndex::set_latest_local_id(ctx, latest_local)?;
+    let latest_remote = Self::load_remotes(ctx)?.pop();
+    CommitIndex::set_latest_remote(ctx, latest_remote.as_ref())?;
+    Ok(CommitLogRecovery { local, remote })
+  }
+  // Discard all local commits
+  fn clear_locals(ctx: &Context) -> Result<(), String> {
+    binary_init_empty(ctx, path_helper::commit_local_log(ctx))?;
+    CommitIndex::set_latest_local_id(ctx, None)
+  }
+  // Remove local commit after it got accepted by the remote
+  fn remove_local_commit(ctx: &Context, commit_id: Uuid) -> Result<(), String> {
+    let locals = Self::load_locals(ctx)?;
+    binary_init_empty(ctx, path_helper::commit_local_log(ctx))?;
+    let mut latest_local = None;
+    for commit in locals.into_iter().filter(|c| c.id != commit_id) {
+      latest_local = Some(commit.id);
+      Self::append(ctx, path_helper::commit_local_log(ctx), commit)?;
+    }
+    CommitIndex::set_latest_local_id(ctx, latest_local)
+  }
+  fn add_local_commit(
+    ctx: &Context,
+    mut local_commit: Commit,
+  ) -> Result<(), String> {
+    // Set ancestor ID
+    // Local commits continue the remote commit chain
+    if let Some(last_commit_id) = CommitIndex::latest_local_commit_id(ctx)
+      .or(CommitIndex::latest_remote_commit_id(ctx))
+    {
+      local_commit.set_ancestor_id(last_commit_id);
+    }
+    // Set commit index
+    CommitIndex::set_latest_local_id(ctx, Some(local_commit.id))?;
+    // Save local commit
+    Self::append(ctx, path_helper::commit_local_log(ctx), local_commit)
+  }
+  fn add_remote_commit(
+    ctx: &Context,
+    remote_commit: Commit,
+  ) -> Result<(), String> {
+    let mut commit_index = CommitIndex::load(ctx);
+    // check ancestor ID
+    if let Some(last_remote_commit_id) = commit_index.latest_remote_commit_id {
+      if remote_commit.ancestor_id != last_remote_commit_id {
+        return Err("Remote commit ancestor ID error! Please pull".into());
+      }
+    }
+    // Set commit index
+    CommitIndex::set_latest_remote(ctx, Some(&remote_commit))?;
+    // Save remote commit
+    Self::append(ctx, path_helper::commit_remote_log(ctx), remote_commit)
+  }
+}
+
+// Filter remote commits by storage ids
+pub(crate) struct StorageCommitFilter {
+  // Empty means no filter
+  storage_ids: HashSet<String>,
+  signing_key: Option<SigningKey>,
+}
+
+impl StorageCommitFilter {
+  pub(crate) fn apply(&self, mut commit: Commit) -> Result<Commit, String> {
+    let signing_key = match &self.signing_key {
+      Some(signing_key) => signing_key,
+      None => return Ok(commit),
+    };
+    let mut serialized_actions = vec![];
+    for aob_str in &commit.serialized_actions {
+      let uaob: UniversalActionObject = serde_json::from_str(aob_str)
+        .map_err(|_| "Error while deser aob into universal aob".to_string())?;
+      if self.storage_ids.contains(&uaob.storage_id) {
+        serialized_actions.push(aob_str.to_string());
+      }
+    }
+    // Nothing filtered out, original signature is still valid
+    if serialized_actions.len() == commit.serialized_actions.len() {
+      return Ok(commit);
+    }
+    commit.serialized_actions = serialized_actions;
+    commit.remote_signature = None;
+    commit.add_remote_signature(signing_key)?;
+    Ok(commit)
+  }
+}
+
+// Repository snapshot archive
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+  version: u32,
+  dtime: DateTime<Utc>,
+  // Repository data relative paths with their raw content
+  entries: Vec<(PathBuf, Vec<u8>)>,
+}
+
+/// Name of the remote a repository is cloned from
+pub const DEFAULT_REMOTE: &str = "origin";
+
+// Named remote server
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct RemoteDetails {
+  url: String,
+  tls: Option<ClientTls>,
+  // Pinned Ed25519 public key of the remote server
+  // Commits pulled from it are verified against it
+  public_key: Option<[u8; 32]>,
+}
+
+impl RemoteDetails {
+  fn public_key(&self) -> Option<VerifyingKey> {
+    self
+      .public_key
+      .and_then(|key| VerifyingKey::from_bytes(&key).ok())
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RepoDetails {
+  // Repository id, generated at init
+  id: Uuid,
+  // Remote mode holds the default remote
+  mode: Mode,
+  // Ed25519 secret key signing remote commits
+  // Server mode only
+  signing_key: Option<[u8; 32]>,
+  // Remotes by name, remote mode only
+  remotes: BTreeMap<String, RemoteDetails>,
+  default_remote: Option<String>,
+  // Subscribed storage ids
+  // Remote sends only their action objects, all if empty
+  storage_ids: Vec<String>,
+}
+
+// Persisted repository details
+#[derive(Serialize, Deserialize, Debug)]
+enum StoredRepoDetails<D> {
+  V1(D),
+}
+
+// Repository details stored before versioning, only their mode
+#[derive(Serialize, Deserialize)]
+struct LegacyRepoDetails {
+  mode: LegacyMode,
+}
+
+// Mode stored before versioning, without TLS and metrics
+#[derive(Serialize, Deserialize)]
+enum LegacyMode {
+  Server { server_addr: String },
+  Remote { remote_url: String },
+  Local,
+}
+
+impl From<LegacyMode> for Mode {
+  fn from(mode: LegacyMode) -> Self {
+    match mode {
+      LegacyMode::Server { server_addr } => Mode::server(server_addr),
+      LegacyMode::Remote { remote_url } => Mode::remote(remote_url),
+      LegacyMode::Local => Mode::Local,
+    }
+  }
+}
+
+// New Ed25519 secret key signing remote commits
+fn new_signing_key() -> [u8; 32] {
+  SigningKey::generate(&mut rand_core::OsRng).to_bytes()
+}
+
+impl RepoDetails {
+  fn init(ctx: &Context, mode: Mode) -> Result<(), String> {
+    binary_init(
+      ctx,
+      path_helper::repo_details(ctx),
+      StoredRepoDetails::V1(Self::new(ctx, mode)),
+    )?;
+    Ok(())
+  }
+  fn new(ctx: &Context, mode: Mode) -> Self {
+    // Server generates its own keypair
+    let signing_key = match mode {
+      Mode::Server { .. } => Some(new_signing_key()),
+      _ => None,
+    };
+    // Remote given at init is the default one
+    let mut remotes = BTreeMap::new();
+    let mut default_remote = None;
+    if let Mode::Remote { remote_url, tls } = &mode {
+      remotes.insert(
+        DEFAULT_REMOTE.to_string(),
+        RemoteDetails {
+          url: remote_url.to_string(),
+          tls: tls.clone(),
+          public_key: None,
+        },
+      );
+      default_remote = Some(DEFAULT_REMOTE.to_string());
+    }
+    RepoDetails {
+      id: ctx.new_id(),
+      mode,
+      signing_key,
+      remotes,
+      default_remote,
+      storage_ids: vec![],
+    }
+  }
+  // Load repository details, upgrading the ones of older versions
+  // Servers created before commit signing get their signing key
+  fn load(ctx: &Context) -> Result<Self, String> {
+    let path = path_helper::repo_details(ctx);
+    let (mut details, mut upgraded) =
+      match binary_read::<StoredRepoDetails<Self>>(ctx, path.clone()) {
+        Ok(StoredRepoDetails::V1(details)) => (details, false),
+        Err(e) => match binary_read::<Self>(ctx, path.clone()) {
+          Ok(details) => (details, true),
+          Err(_) => {
+            let legacy =
+              binary_read::<LegacyRepoDetails>(ctx, path).map_err(|_| e)?;
+            (Self::new(ctx, legacy.mode.into()), true)
+          }
+        },
+      };
+    if matches!(details.mode, Mode::Server { .. })
+      && details.signing_key.is_none()
+    {
+      details.signing_key = Some(new_signing_key());
+      upgraded = true;
+    }
+    if upgraded {
+      details.save(ctx)?;
+    }
+    Ok(details)
+  }
+  fn save(&self, ctx: &Context) -> Result<(), String> {
+    binary_update(
+      ctx,
+      path_helper::repo_details(ctx),
+      StoredRepoDetails::V1(self),
+    )
+  }
+  fn signing_key(&self) -> Result<SigningKey, String> {
+    self
+      .signing_key
+      .map(|key| SigningKey::from_bytes(&key))
+      .ok_or("Repository has no signing key".to_string())
+  }
+  // Keys remote action objects can be signed with
+  // Own key in server mode, pinned remote keys in remote mode
+  fn trusted_keys(&self) -> Vec<VerifyingKey> {
+    let own_key = self.signing_key().ok().map(|key| key.verifying_key());
+    own_key
+      .into_iter()
+      .chain(self.remotes.values().filter_map(|r| r.public_key()))
+      .collect()
+  }
+  // Remote by name, or the default remote if None
+  fn remote(&self, name: Option<&str>) -> Result<&RemoteDetails, String> {
+    let name = name
+      .or(self.default_remote.as_deref())
+      .ok_or("Repository is not in remote mode".to_string())?;
+    self
+      .remotes
+      .get(name)
+      .ok_or(format!("Unknown remote: {}", name))
+  }
+  fn remote_mut(
+    &mut self,
+    name: Option<&str>,
+  ) -> Result<&mut RemoteDetails, String> {
+    let name = name
+      .or(self.default_remote.as_deref())
+      .ok_or("Repository is not in remote mode".to_string())?
+      .to_string();
+    self
+      .remotes
+      .get_mut(&name)
+      .ok_or(format!("Unknown remote: {}", name))
+  }
+  fn default_remote_name(&self) -> Result<String, String> {
+    self
+      .default_remote
+      .clone()
+      .ok_or("Repository is not in remote mode".to_string())
+  }
+}
+
+enum CallbackMode {
+  Apply,
+  // Promote pushed local action to remote
+  Promote,
+  // Replace remote action by its redacted version
+  Redact,
+}
+
+/// Handling of action objects whose storage is not registered
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownStoragePolicy {
+  /// Reject the whole commit
+  #[default]
+  Reject,
+  /// Accept the commit, but keep the action objects aside unapplied,
+  /// see Repository::quarantined
+  Quarantine,
+  /// Accept the commit, and log the action objects per storage.
+  /// They are applied once a storage with the same id gets registered.
+  StoreRaw,
+}
+
+pub struct Repository {
+  ctx: Arc<RwLock<Context>>,
+  commit_log: Arc<Mutex<CommitLog>>,
+  repo_details: Arc<Mutex<RepoDetails>>,
+  // Typed storage handles by storage id, see Repository::storage
+  storages: Arc<Mutex<HashMap<String, Box<dyn Any + Send>>>>,
+  // Hooks by storage id
+  storage_hooks: Arc<Mutex<HashMap<String, StorageHook>>>,
+  storage_cleaners: Arc<Mutex<Vec<StorageCleaner>>>,
+  storage_reverters: Arc<Mutex<Vec<StorageReverter>>>,
+  storage_rebasers: Arc<Mutex<HashMap<String, StorageRebaser>>>,
+  // Interleavers by storage id, of storages in MergeMode::Crdt
+  storage_interleavers: Arc<Mutex<HashMap<String, StorageInterleaver>>>,
+  storage_checkers: Arc<Mutex<Vec<StorageChecker>>>,
+  storage_compactors: Arc<Mutex<Vec<StorageCompactor>>>,
+  storage_restorers: Arc<Mutex<HashMap<String, StorageRestorer>>>,
+  // Delta bases by storage id
+  storage_baselines: Arc<Mutex<HashMap<String, StorageBaseline>>>,
+  // Migrators by storage id
+  storage_migrators: Arc<Mutex<Vec<(String, StorageMigrator)>>>,
+  // Schema hashes by storage id
+  storage_schemas: Arc<Mutex<HashMap<String, StorageSchema>>>,
+  storage_reporters: Arc<Mutex<Vec<StorageReporter>>>,
+  storage_meters: Arc<Mutex<Vec<StorageMeter>>>,
+  storage_counters: Arc<Mutex<Vec<StorageCounter>>>,
+  metrics: Arc<Metrics>,
+  storage_fscks: Arc<Mutex<Vec<StorageFsck>>>,
+  storage_verifiers: Arc<Mutex<Vec<StorageVerifier>>>,
+  storage_rehashers: Arc<Mutex<Vec<StorageRehasher>>>,
+  storage_purgers: Arc<Mutex<Vec<StoragePurger>>>,
+  storage_flushers: Arc<Mutex<Vec<StorageFlusher>>>,
+  storage_reloaders: Arc<Mutex<Vec<StorageReloader>>>,
+  storage_checkpointers: Arc<Mutex<Vec<StorageCheckpointer>>>,
+  // Queriers by storage id
+  storage_queriers: Arc<Mutex<Vec<(String, StorageQuerier)>>>,
+  // References between storages, and their callbacks by storage id
+  references: Arc<Mutex<Vec<Reference>>>,
+  storage_referencers: Arc<Mutex<HashMap<String, StorageReferencer>>>,
+  projections: Arc<Mutex<Vec<ProjectionFeed>>>,
+  // Server hooks around merging pushed commits
+  pre_merge_hooks: Arc<Mutex<Vec<PreMergeHook>>>,
+  // Client hooks validating local commits
+  pre_commit_hooks: Arc<Mutex<Vec<PreCommitHook>>>,
+  sync_progress_hooks: Arc<Mutex<Vec<SyncProgressHook>>>,
+  access_policy: Arc<Mutex<Option<Arc<dyn AccessPolicy>>>>,
+  object_mirror: Arc<Mutex<Option<Arc<dyn ObjectMirror>>>>,
+  trace_recorder: Arc<Mutex<Option<Arc<TraceRecorder>>>>,
+  // Serializes appends to the audit log
+  audit_log: Arc<Mutex<AuditLog>>,
+  // Runtime of the background tasks and remote calls
+  worker: Arc<Worker>,
+  // Serializes updates of the server side device records
+  devices: Arc<Mutex<DeviceRegistry>>,
+  // Remotes the device is registered with in this session
+  registered_remotes: Arc<Mutex<HashSet<String>>>,
+  // Presence of other devices, as announced to the server
+  // or streamed by the remote watch
+  presence: Arc<Mutex<PresenceMap>>,
+  presence_tx: broadcast::Sender<Presence>,
+  storage_mirrorers: Arc<Mutex<Vec<StorageMirrorer>>>,
+  post_merge_hooks: Arc<Mutex<Vec<PostMergeHook>>>,
+  remote_commit_tx: broadcast::Sender<Commit>,
+  // Server side channel of pushed commits with their pusher client id
+  pushed_commit_tx: broadcast::Sender<(Commit, String)>,
+  // Set once the server is shutting down, ends open watch streams
+  server_shutdown_tx: Arc<tokio::sync::watch::Sender<bool>>,
+  // Random id of this repository instance, sent to the remote
+  // so it does not stream our own pushed commits back
+  client_id: Uuid,
+  // Stable id of the repository across sessions, see device
+  device_id: String,
+  // Conflicts resolved by storages during remote updates
+  resolved_conflicts: Arc<AtomicUsize>,
+  unknown_storage_policy: Arc<Mutex<UnknownStoragePolicy>>,
+  // Held as long as any repository handle lives
+  // None for in-memory repositories
+  _lock: Arc<Option<RepoLock>>,
+}
+
+// Flushes the write-behind backend once the flusher ends,
+// or is cancelled with the worker runtime
+struct FinalFlush(Arc<dyn Backend>);
+
+impl Drop for FinalFlush {
+  fn drop(&mut self) {
+    if let Err(e) = self.0.flush() {
+      warn!(error = %e, "Write-behind flush failed");
+    }
+  }
+}
+
+// Lock repository against other processes
+// In-memory repositories are private to the process, so not locked
+fn acquire_lock(
+  ctx: &Context,
+  wait: Option<Duration>,
+) -> Result<Option<RepoLock>, String> {
+  match ctx.backend().is_persistent() {
+    true => RepoLock::acquire(path_helper::repo_lock(ctx), wait).map(Some),
+    false => Ok(None),
+  }
+}
+
+impl Repository {
+  /// Load repository
+  /// Errors if the repository is locked by another process
+  pub fn load(ctx: Context) -> Result<Self, String> {
+    Self::try_load(ctx, None)
+  }
+  /// Load repository
+  /// Waits up to wait duration if the repository is locked
+  /// by another process, errors immediately if None
+  pub fn try_load(
+    ctx: Context,
+    wait: Option<Duration>,
+  ) -> Result<Self, String> {
+    let lock = acquire_lock(&ctx, wait)?;
+    Self::open(ctx, lock)
+  }
+  // Open repository holding its lock
+  fn open(ctx: Context, lock: Option<RepoLock>) -> Result<Self, String> {
+    let marker = path_helper::repo_format_migration(&ctx);
+    if ctx.backend().exists(&marker) {
+      return Err(format!(
+        "Format migration to {:?} was interrupted. \
+         Restore the snapshot taken before it",
+        format_read(&ctx, marker)?
+      ));
+    }
+    ctx.set_format(format_read(&ctx, path_helper::repo_format(&ctx))?);
+    CommitLog::upgrade_legacy(&ctx)?;
+    // Roll back commit interrupted by a crash
+    if let Some(commit_id) = CommitIntent::recover(&ctx)? {
+      warn!(commit_id = %commit_id, "Rolled back interrupted commit");
+    }
+    // Load commit log
+    let commit_log = CommitLog;
+    // Load repo details
+    let repo_details = RepoDetails::load(&ctx)?;
+    // Continue the clock of the previous session
+    if let Some(clock) = CommitIndex::load(&ctx).latest_clock {
+      ctx.clock().observe(clock);
+    }
+    let device_id = load_or_init_device_id(&ctx)?;
+    let metrics = ctx.metrics().clone();
+    let flush_interval = ctx.flush_interval;
+    let checkpoint_interval = ctx.checkpoint_interval;
+    // Create res
+    let res = Self {
+      ctx: Arc::new(RwLock::new(ctx)),
+      commit_log: Arc::new(Mutex::new(commit_log)),
+      repo_details: Arc::new(Mutex::new(repo_details)),
+      storages: Arc::new(Mutex::new(HashMap::new())),
+      storage_hooks: Arc::new(Mutex::new(HashMap::new())),
+      storage_cleaners: Arc::new(Mutex::new(vec![])),
+      storage_reverters: Arc::new(Mutex::new(vec![])),
+      storage_rebasers: Arc::new(Mutex::new(HashMap::new())),
+      storage_interleavers: Arc::new(Mutex::new(HashMap::new())),
+      storage_checkers: Arc::new(Mutex::new(vec![])),
+      storage_compactors: Arc::new(Mutex::new(vec![])),
+      storage_restorers: Arc::new(Mutex::new(HashMap::new())),
+      storage_baselines: Arc::new(Mutex::new(HashMap::new())),
+      storage_migrators: Arc::new(Mutex::new(vec![])),
+      storage_schemas: Arc::new(Mutex::new(HashMap::new())),
+      storage_reporters: Arc::new(Mutex::new(vec![])),
+      storage_meters: Arc::new(Mutex::new(vec![])),
+      storage_counters: Arc::new(Mutex::new(vec![])),
+      metrics,
+      storage_fscks: Arc::new(Mutex::new(vec![])),
+      storage_verifiers: Arc::new(Mutex::new(vec![])),
+      storage_rehashers: Arc::new(Mutex::new(vec![])),
+      storage_purgers: Arc::new(Mutex::new(vec![])),
+      storage_flushers: Arc::new(Mutex::new(vec![])),
+      storage_reloaders: Arc::new(Mutex::new(vec![])),
+      storage_checkpointers: Arc::new(Mutex::new(vec![])),
+      storage_queriers: Arc::new(Mutex::new(vec![])),
+      references: Arc::new(Mutex::new(vec![])),
+      storage_referencers: Arc::new(Mutex::new(HashMap::new())),
+      projections: Arc::new(Mutex::new(vec![])),
+      pre_merge_hooks: Arc::new(Mutex::new(vec![])),
+      pre_commit_hooks: Arc::new(Mutex::new(vec![])),
+      sync_progress_hooks: Arc::new(Mutex::new(vec![])),
+      access_policy: Arc::new(Mutex::new(None)),
+      object_mirror: Arc::new(Mutex::new(None)),
+      trace_recorder: Arc::new(Mutex::new(None)),
+      audit_log: Arc::new(Mutex::new(AuditLog)),
+      worker: Arc::new(Worker::new()),
+      devices: Arc::new(Mutex::new(DeviceRegistry)),
+      registered_remotes: Arc::new(Mutex::new(HashSet::new())),
+      presence: Arc::new(Mutex::new(PresenceMap::default())),
+      presence_tx: broadcast::channel(REMOTE_COMMIT_CHANNEL_SIZE).0,
+      storage_mirrorers: Arc::new(Mutex::new(vec![])),
+      post_merge_hooks: Arc::new(Mutex::new(vec![])),
+      remote_commit_tx: broadcast::channel(REMOTE_COMMIT_CHANNEL_SIZE).0,
+      pushed_commit_tx: broadcast::channel(REMOTE_COMMIT_CHANNEL_SIZE).0,
+      server_shutdown_tx: Arc::new(tokio::sync::watch::channel(false).0),
+      client_id: Uuid::new_v4(),
+      device_id,
+      resolved_conflicts: Arc::new(AtomicUsize::new(0)),
+      unknown_storage_policy: Arc::new(Mutex::new(
+        UnknownStoragePolicy::default(),
+      )),
+      _lock: Arc::new(lock),
+    };
+    if let Some(interval) = flush_interval {
+      res.start_flusher(interval)?;
+    }
+    if let Some(interval) = checkpoint_interval {
+      res.start_checkpointer(interval)?;
+    }
+    Ok(res)
+  }
+  // Flush the write-behind backend every interval in the background
+  // Stops once the repository is dropped, flushing a last time
+  fn start_flusher(&self, interval: Duration) -> Result<(), String> {
+    let ctx = Arc::downgrade(&self.ctx);
+    let backend = self.ctx().backend.clone();
+    let policy = RestartPolicy::OnFailure { delay: interval };
+    self.worker.spawn("sync_flush", policy, move || {
+      let ctx = ctx.clone();
+      let last_flush = FinalFlush(backend.clone());
+      async move {
+        let _last_flush = last_flush;
+        loop {
+          tokio::time::sleep(interval).await;
+          let ctx = match ctx.upgrade() {
+            Some(ctx) => ctx,
+            None => return Ok(()),
+          };
+          let res = tokio::task::spawn_blocking(move || {
+            ctx.read().unwrap().backend().flush()
+          })
+          .await
+          .map_err(|e| e.to_string())?;
+          if let Err(e) = res {
+            warn!(error = %e, "Write-behind flush failed");
+          }
+        }
+      }
+    })
+  }
+  // Checkpoint object histories every interval in the background
+  // Stops once the repository is dropped
+  fn start_checkpointer(&self, interval: Duration) -> Result<(), String> {
+    let ctx = Arc::downgrade(&self.ctx);
+    let commit_log = Arc::downgrade(&self.commit_log);
+    let checkpointers = Arc::downgrade(&self.storage_checkpointers);
+    let policy = RestartPolicy::OnFailure { delay: interval };
+    self.worker.spawn("sync_checkpoint", policy, move || {
+      let (ctx, commit_log, checkpointers) =
+        (ctx.clone(), commit_log.clone(), checkpointers.clone());
+      async move {
+        loop {
+          tokio::time::sleep(interval).await;
+          let (ctx, commit_log, checkpointers) = match (
+            ctx.upgrade(),
+            commit_log.upgrade(),
+            checkpointers.upgrade(),
+          ) {
+            (Some(ctx), Some(commit_log), Some(checkpointers)) => {
+              (ctx, commit_log, checkpointers)
+            }
+            _ => return Ok(()),
+          };
+          let res = tokio::task::spawn_blocking(move || {
+            Self::run_checkpointers(&ctx, &commit_log, &checkpointers)
+          })
+          .await
+          .map_err(|e| e.to_string())?;
+          match res {
+            Ok(written) => debug!(written, "History checkpoints written"),
+            Err(e) => warn!(error = %e, "History checkpointing failed"),
+          }
+        }
+      }
+    })
+  }
+  // Run storage checkpointers between commits
+  fn run_checkpointers(
+    ctx: &RwLock<Context>,
+    commit_log: &Mutex<CommitLog>,
+    checkpointers: &Mutex<Vec<StorageCheckpointer>>,
+  ) -> Result<usize, String> {
+    // Lock in the same order as commit contexts
+    let _ctx = ctx.read().unwrap();
+    let _commit_log = commit_log.lock().unwrap();
+    let mut written = 0;
+    for checkpointer in checkpointers.lock().unwrap().iter() {
+      written += checkpointer()?;
+    }
+    Ok(written)
+  }
+  /// Checkpoint long object histories
+  /// Objects get a checkpoint of their remote state every
+  /// CHECKPOINT_EVERY remote actions. Replays of their history, e.g.
+  /// StorageObject::object_at, start from the latest checkpoint before
+  /// the replayed action, verified against its object signature.
+  /// See Context::with_checkpoint_interval to run it in the background.
+  /// Returns the number of written checkpoints
+  pub fn checkpoint_histories(&self) -> Result<usize, String> {
+    Self::run_checkpointers(
+      &self.ctx,
+      &self.commit_log,
+      &self.storage_checkpointers,
+    )
+  }
+  /// Write the buffered changes of a write-behind context
+  /// Waits for the running commit, so only whole commits are written.
+  /// Nothing to do for other contexts
+  pub fn flush(&self) -> Result<(), String> {
+    self.ctx().backend().flush()
+  }
+  /// Init repository
+  pub fn init(ctx: Context, mode: Mode) -> Result<Self, String> {
+    let lock = acquire_lock(&ctx, None)?;
+    // Check if repository inited
+    if ctx.backend().exists(&path_helper::repo_details(&ctx)) {
+      return Err("Existing repository. Cannot init a new one".into());
+    }
+    // Record format
+    format_write(&ctx, path_helper::repo_format(&ctx), ctx.format())?;
+    // Init commit log
+    CommitLog::init(&ctx)?;
+    // Init repo details
+    RepoDetails::init(&ctx, mode)?;
+    Self::open(ctx, lock)
+  }
+  /// Init repository by the given config, persisting it
+  pub fn init_with_config(config: &RepoConfig) -> Result<Self, String> {
+    let repo = Self::init(config.context()?, config.mode()?)?;
+    config.write(&repo.ctx())?;
+    Ok(repo)
+  }
+  /// Load repository by the given config
+  /// Errors if the config mode or format differ from the ones the
+  /// repository was inited with. Other settings of the config replace
+  /// the persisted ones.
+  pub fn load_with_config(config: &RepoConfig) -> Result<Self, String> {
+    let mode = config.mode()?;
+    let repo = Self::load(config.context()?)?;
+    let ctx = repo.ctx();
+    let repo_mode = repo.repo_details.lock().unwrap().mode.clone();
+    if std::mem::discriminant(&mode) != std::mem::discriminant(&repo_mode) {
+      return Err(format!(
+        "Config mode {} differs from the repository mode",
+        config.mode
+      ));
+    }
+    if config.context()?.format() != ctx.format() {
+      return Err(format!(
+        "Config format {} differs from the repository format",
+        config.format
+      ));
+    }
+    if RepoConfig::read(&ctx)?.as_ref() != Some(config) {
+      config.write(&ctx)?;
+    }
+    drop(ctx);
+    Ok(repo)
+  }
+  /// Config the repository was last inited or loaded with, if any
+  pub fn config(&self) -> Result<Option<RepoConfig>, String> {
+    RepoConfig::read(&self.ctx())
+  }
+  /// Init local repository kept in memory
+  /// Every data is lost when the repository is dropped.
+  /// Use Context::in_memory with init for server or remote mode.
+  pub fn init_in_memory(uid: String) -> Result<Self, String> {
+    Self::init(Context::in_memory(uid), Mode::Local)
+  }
+  /// Clone remote repository to local
+  /// Inits a new repository in remote mode, lets the caller register
+  /// its storages, then performs a full pull, so every remote commit
+  /// is replayed through the registered storage hooks.
+  /// Returns the working repository and the registered storages.
+  /// If any step fails, files created by the clone are removed.
+  pub fn clone<S>(
+    ctx: Context,
+    remote_url: &str,
+    register_storages: impl FnOnce(&Repository) -> Result<S, String>,
+  ) -> Result<(Self, S), String> {
+    Self::clone_mode(
+      ctx,
+      Mode::remote(remote_url.to_string()),
+      register_storages,
+    )
+  }
+  /// Clone remote repository to local over TLS
+  pub fn clone_tls<S>(
+    ctx: Context,
+    remote_url: &str,
+    tls: ClientTls,
+    register_storages: impl FnOnce(&Repository) -> Result<S, String>,
+  ) -> Result<(Self, S), String> {
+    let mode = Mode::remote_tls(remote_url.to_string(), tls);
+    Self::clone_mode(ctx, mode, register_storages)
+  }
+  fn clone_mode<S>(
+    ctx: Context,
+    mode: Mode,
+    register_storages: impl FnOnce(&Repository) -> Result<S, String>,
+  ) -> Result<(Self, S), String> {
+    // Check if repository inited
+    if Self::load(ctx.clone()).is_ok() {
+      return Err("Existing repository. Cannot clone again".into());
+    }
+    // Files of the db root before the clone
+    // A failed clone removes every other one, so it can be retried
+    let existing = ctx
+      .backend()
+      .scan(&ctx.db_root_path)?
+      .into_iter()
+      .collect::<HashSet<_>>();
+    // Repository is dropped before cleanup, so nothing is written after
+    let e = match Self::clone_pull(ctx.clone(), mode, register_storages) {
+      Ok(res) => return Ok(res),
+      Err(e) => e,
+    };
+    for key in ctx.backend().scan(&ctx.db_root_path)? {
+      if !existing.contains(&key) {
+        ctx
+          .backend()
+          .delete(&key)
+          .map_err(|cleanup| format!("{}, cleanup: {}", e, cleanup))?;
+      }
+    }
+    Err(e)
+  }
+  fn clone_pull<S>(
+    ctx: Context,
+    mode: Mode,
+    register_storages: impl FnOnce(&Repository) -> Result<S, String>,
+  ) -> Result<(Self, S), String> {
+    // Init repository in remote mode
+    let repo = Self::init(ctx, mode)?;
+    // Register storages before pull, so hooks can build storage state
+    let storages = register_storages(&repo)?;
+    // Full pull, as we do not have any remote commit yet
+    repo.proceed_pull()?;
+    Ok((repo, storages))
+  }
+
+  /// Pull default remote repository
+  pub fn proceed_pull(&self) -> Result<PullSummary, String> {
+    let remote = self.repo_details.lock().unwrap().default_remote_name()?;
+    self.proceed_pull_from(&remote)
+  }
+  /// Pull the given remote repository
+  /// Verifies and applies the new remote commits in batches,
+  /// see Context::with_pull_batch_size
+  #[instrument(skip(self))]
+  pub fn proceed_pull_from(&self, remote: &str) -> Result<PullSummary, String> {
+    let remote_details = self
+      .repo_details
+      .lock()
+      .unwrap()
+      .remote(Some(remote))?
+      .clone();
+
+    let storage_ids = self.subscribed_storages();
+    let batch_size = self.ctx().pull_batch_size.max(1);
+    let limit = u32::try_from(batch_size).unwrap_or(u32::MAX);
+    let mut accept_delta = self.ctx().delta_sync;
+    let codec = self.ctx().action_codec;
+
+    let mut summary = PullSummary::default();
+    let mut changed_objects = HashSet::new();
+    let conflicts_before = self.resolved_conflicts.load(Ordering::Relaxed);
+    let mut known_ids = None;
+    let mut received = SyncProgress::new(remote, SyncPhase::Receiving, None);
+    let mut applied = SyncProgress::new(remote, SyncPhase::Applying, Some(0));
+
+    self.worker.block_on(async {
+      let mut transport = self.connect_remote(&remote_details).await?;
+
+      self
+        .ensure_remote_public_key(remote, &mut transport)
+        .await?;
+      self.ensure_device_registered(remote, &mut transport).await;
+
+      self.report_sync_progress(&received, None);
+      // Pull in batches, each applied before pulling the next one
+      // The remote cursor moves with every applied commit, so an
+      // interrupted pull resumes after the last applied one
+      loop {
+        // Get latest commit id known by the remote
+        // Context guard must be released before merging commits
+        let after_commit_id = CommitIndex::remote_cursor(&self.ctx(), remote)
+          .map(|i| i.to_string())
+          .unwrap_or_default();
+        let mut sizes = vec![];
+        let commits = transport
+          .pull(
+            after_commit_id,
+            storage_ids.clone(),
+            PullOptions {
+              limit,
+              accept_delta,
+              codec,
+              device_id: &self.device_id,
+            },
+            |commit, size| {
+              sizes.push(size);
+              received.advance(size);
+              self.report_sync_progress(&received, Some(commit));
+            },
+          )
+          .await?;
+        // Older remotes send every commit at once
+        let done = commits.len() < batch_size;
+        // Blobs first, so applied objects never refer to missing ones
+        self.download_blobs(&mut transport, &commits).await?;
+        applied.total = Some(received.commits);
+        let mut fallback = false;
+        for (commit, size) in commits.into_iter().zip(sizes) {
+          let commit = match self.delta_decode_commit(commit) {
+            Ok(commit) => commit,
+            // Pull the rest whole, resuming from this commit
+            Err(e) => {
+              warn!(error = %e, "Delta decoding failed, pulling whole");
+              (accept_delta, fallback) = (false, true);
+              break;
+            }
+          };
+          self.report_sync_progress(&applied, Some(&commit));
+          let object_ids = commit
+            .serialized_actions
+            .iter()
+            .filter_map(|aob| {
+              serde_json::from_str::<UniversalActionObject>(aob)
+                .ok()
+                .map(|uaob| uaob.object_id)
+            })
+            .collect::<Vec<Uuid>>();
+          match self.merge_remote_commit(remote, commit, &mut known_ids)? {
+            MergeResult::Applied => {
+              summary.commits_applied += 1;
+              changed_objects.extend(object_ids);
+            }
+            MergeResult::Known => summary.commits_skipped += 1,
+            MergeResult::Diverged => {
+              return Err("Remote commit ancestor ID error! Please pull".into())
+            }
+          }
+          applied.advance(size);
+        }
+        // Applied batch is durable on a write-behind context as well
+        self.flush()?;
+        if done && !fallback {
+          return Ok::<_, String>(());
+        }
+      }
+    })?;
+    self.report_sync_progress(&applied, None);
+    summary.objects_changed = changed_objects.len();
+    summary.conflicts =
+      self.resolved_conflicts.load(Ordering::Relaxed) - conflicts_before;
+
+    CommitIndex::set_last_pull(&self.ctx(), Utc::now())?;
+    info!(
+      commits_applied = summary.commits_applied,
+      commits_skipped = summary.commits_skipped,
+      objects_changed = summary.objects_changed,
+      conflicts = summary.conflicts,
+      "Pulled remote commits"
+    );
+    Ok(summary)
+  }
+  // Merge commit pulled from the given remote and move its cursor
+  // Commits already pulled from another remote are skipped.
+  // Known commit ids are loaded into known_ids once needed.
+  #[instrument(skip_all, fields(remote = remote, commit_id = %commit.id))]
+  fn merge_remote_commit(
+    &self,
+    remote: &str,
+    commit: Commit,
+    known_ids: &mut Option<HashSet<Uuid>>,
+  ) -> Result<MergeResult, String> {
+    let commit_id = commit.id;
+    self.record_trace_event(|| TraceEvent::Pulled {
+      at: Utc::now(),
+      remote: remote.to_string(),
+      commit: commit.clone(),
+    });
+    let latest_remote_id = CommitIndex::latest_remote_commit_id(&self.ctx());
+    let continues = match latest_remote_id {
+      Some(latest_remote_id) => commit.ancestor_id == latest_remote_id,
+      None => true,
+    };
+    let res = if continues {
+      self.verify_remote_commit(remote, &commit)?;
+      self.merge_commit_ctx(commit).commit()?.into_result()?;
+      MergeResult::Applied
+    } else {
+      if known_ids.is_none() {
+        let remotes = CommitLog::load_remotes(&self.ctx())?;
+        *known_ids = Some(remotes.iter().map(|c| c.id).collect());
+      }
+      if !known_ids.as_ref().unwrap().contains(&commit_id) {
+        return Ok(MergeResult::Diverged);
+      }
+      MergeResult::Known
+    };
+    CommitIndex::set_remote_cursor(&self.ctx(), remote, Some(commit_id))?;
+    Ok(res)
+  }
+  /// Repository status
+  /// Pending local commits, dirty objects per storage
+  /// and last sync times
+  pub fn status(&self) -> Result<RepoStatus, String> {
+    let (local_commits, commit_index) = {
+      let ctx = self.ctx();
+      (CommitLog::load_locals(&ctx)?.len(), CommitIndex::load(&ctx))
+    };
+    let mode = self.repo_details.lock().unwrap().mode.clone();
+    let mut storages = vec![];
+    for reporter in self.storage_reporters.lock().unwrap().iter() {
+      storages.push(reporter()?);
+    }
+    Ok(RepoStatus {
+      mode,
+      local_commits,
+      storages,
+      last_pull: commit_index.last_pull,
+      last_push: commit_index.last_push,
+    })
+  }
+  /// Disk usage of the repository and of its registered storages
+  /// Refreshes the usage the soft storage quotas are checked against
+  pub fn disk_usage(&self) -> Result<DiskUsage, String> {
+    let mut storages = vec![];
+    for meter in self.storage_meters.lock().unwrap().iter() {
+      storages.push(meter()?);
+    }
+    let ctx = self.ctx();
+    let backend = ctx.backend();
+    Ok(DiskUsage {
+      storages,
+      commit_log_bytes: key_size(
+        backend,
+        &path_helper::commit_local_log(&ctx),
+      )? + key_size(
+        backend,
+        &path_helper::commit_remote_log(&ctx),
+      )?,
+      blob_bytes: prefix_size(backend, &path_helper::blobs_path(&ctx))?,
+      total_bytes: prefix_size(backend, &ctx.db_root_path)?,
+    })
+  }
+  /// Subscribe only to the given storages
+  /// Remote sends only their action objects on pull and watch.
+  /// Should be set before the first pull (e.g. in the clone register
+  /// callback), as already pulled commits are not pulled again.
+  /// Empty list subscribes to every storage.
+  pub fn subscribe_storages(&self, storage_ids: &[&str]) -> Result<(), String> {
+    let ctx = self.ctx();
+    let mut repo_details = self.repo_details.lock().unwrap();
+    repo_details.storage_ids =
+      storage_ids.iter().map(|id| id.to_string()).collect();
+    repo_details.save(&ctx)
+  }
+  /// Subscribed storage ids, empty if subscribed to all
+  pub fn subscribed_storages(&self) -> Vec<String> {
+    self.repo_details.lock().unwrap().storage_ids.clone()
+  }
+  /// Commit filter keeping only the given storages action objects
+  /// Filtered commits are signed again with the repository key
+  pub(crate) fn commit_filter(
+    &self,
+    storage_ids: Vec<String>,
+  ) -> Result<StorageCommitFilter, String> {
+    let signing_key = match storage_ids.is_empty() {
+      true => None,
+      false => Some(self.repo_details.lock().unwrap().signing_key()?),
+    };
+    Ok(StorageCommitFilter {
+      storage_ids: storage_ids.into_iter().collect(),
+      signing_key,
+    })
+  }
+  /// Pin default remote server public key
+  /// Remote commits are only accepted if signed by its private pair
+  pub fn pin_remote_public_key(&self, public_key: &str) -> Result<(), String> {
+    self.pin_public_key(None, public_key)
+  }
+  /// Pin public key of the given remote
+  pub fn pin_public_key_of(
+    &self,
+    remote: &str,
+    public_key: &str,
+  ) -> Result<(), String> {
+    self.pin_public_key(Some(remote), public_key)
+  }
+  fn pin_public_key(
+    &self,
+    remote: Option<&str>,
+    public_key: &str,
+  ) -> Result<(), String> {
+    let public_key = ed25519_public_key(public_key)?;
+    let ctx = self.ctx();
+    let mut repo_details = self.repo_details.lock().unwrap();
+    repo_details.remote_mut(remote)?.public_key = Some(public_key.to_bytes());
+    repo_details.save(&ctx)
+  }
+  /// Add named remote, e.g. a mirror of the default remote
+  /// Remote mode only
+  pub fn add_remote(
+    &self,
+    name: &str,
+    remote_url: &str,
+    tls: Option<ClientTls>,
+  ) -> Result<(), String> {
+    let ctx = self.ctx();
+    let mut repo_details = self.repo_details.lock().unwrap();
+    if !matches!(repo_details.mode, Mode::Remote { .. }) {
+      return Err("Remotes can only be added in remote mode".to_string());
+    }
+    if repo_details.remotes.contains_key(name) {
+      return Err(format!("Remote {} already exists", name));
+    }
+    repo_details.remotes.insert(
+      name.to_string(),
+      RemoteDetails {
+        url: remote_url.to_string(),
+        tls,
+        public_key: None,
+      },
+    );
+    repo_details.save(&ctx)
+  }
+  /// Remove named remote
+  /// Default remote cannot be removed
+  pub fn remove_remote(&self, name: &str) -> Result<(), String> {
+    let ctx = self.ctx();
+    let mut repo_details = self.repo_details.lock().unwrap();
+    if repo_details.default_remote.as_deref() == Some(name) {
+      return Err(format!("Cannot remove default remote {}", name));
+    }
+    if repo_details.remotes.remove(name).is_none() {
+      return Err(format!("Unknown remote: {}", name));
+    }
+    repo_details.save(&ctx)?;
+    CommitIndex::set_remote_cursor(&ctx, name, None)
+  }
+  /// Set default remote used by pull, push and watch
+  pub fn set_default_remote(&self, name: &str) -> Result<(), String> {
+    let ctx = self.ctx();
+    let mut repo_details = self.repo_details.lock().unwrap();
+    let remote = repo_details.remote(Some(name))?.clone();
+    repo_details.mode = Mode::Remote {
+      remote_url: remote.url,
+      tls: remote.tls,
+    };
+    repo_details.default_remote = Some(name.to_string());
+    repo_details.save(&ctx)
+  }
+  /// Remote names and urls
+  pub fn remotes(&self) -> Vec<(String, String)> {
+    let repo_details = self.repo_details.lock().unwrap();
+    repo_details
+      .remotes
+      .iter()
+      .map(|(name, remote)| (name.to_string(), remote.url.to_string()))
+      .collect()
+  }
+  /// Default remote name, None if not in remote mode
+  pub fn default_remote(&self) -> Option<String> {
+    self.repo_details.lock().unwrap().default_remote.clone()
+  }
+  /// Hex encoded public key of the repository signing key
+  /// None if the repository is not in server mode
+  pub fn public_key(&self) -> Option<String> {
+    let repo_details = self.repo_details.lock().unwrap();
+    let signing_key = repo_details.signing_key().ok()?;
+    Some(hex::encode(signing_key.verifying_key().to_bytes()))
+  }
+  // Connect to remote using the transport of its url scheme
+  // Every request carries the context auth token if any
+  async fn connect_remote(
+    &self,
+    remote: &RemoteDetails,
+  ) -> Result<Transport, String> {
+    let (auth_token, remote_repo) = {
+      let ctx = self.ctx();
+      (ctx.auth_token.clone(), ctx.remote_repo.clone())
+    };
+    Transport::connect(
+      &remote.url,
+      remote.tls.as_ref(),
+      auth_token,
+      remote_repo,
+    )
+    .await
+  }
+  // Fetch and pin remote public key if there is no pinned one yet
+  // (trust on first use)
+  async fn ensure_remote_public_key(
+    &self,
+    remote: &str,
+    transport: &mut Transport,
+  ) -> Result<(), String> {
+    if self
+      .repo_details
+      .lock()
+      .unwrap()
+      .remote(Some(remote))?
+      .public_key
+      .is_some()
+    {
+      return Ok(());
+    }
+    let public_key = transport.public_key().await?;
+    self.pin_public_key(Some(remote), &public_key)
+  }
+  // Check remote commit signature with the pinned public key of remote
+  fn verify_remote_commit(
+    &self,
+    remote: &str,
+    commit: &Commit,
+  ) -> Result<(), String> {
+    let public_key = self
+      .repo_details
+      .lock()
+      .unwrap()
+      .remote(Some(remote))?
+      .public_key()
+      .ok_or("No pinned remote public key".to_string())?;
+    match commit.has_valid_remote_signature(&public_key)? {
+      true => Ok(()),
+      false => Err(format!("Invalid remote commit signature {}", commit.id)),
+    }
+  }
+  /// Push repository local commits to the default remote
+  /// Accepted commits are moved to the remote commit log,
+  /// and their local actions are promoted to remote ones
+  pub fn proceed_push(&self) -> Result<(), String> {
+    let remote = self.repo_details.lock().unwrap().default_remote_name()?;
+    self.proceed_push_to(&remote)
+  }
+  /// Push repository local commits to the given remote
+  #[instrument(skip(self))]
+  pub fn proceed_push_to(&self, remote: &str) -> Result<(), String> {
+    // Before push operation
+    // Proceed pull
+    self.proceed_pull_from(remote)?;
+    self.push_local_commits(remote)
+  }
+  // Push local commits, rebased on the latest pulled remote commit
+  // Remote might have moved on since, see MergeMode::Crdt
+  fn push_local_commits(&self, remote: &str) -> Result<(), String> {
+    let remote_details = self
+      .repo_details
+      .lock()
+      .unwrap()
+      .remote(Some(remote))?
+      .clone();
+
+    let local_commits = self.local_commits()?;
+
+    let pushed = self.worker.block_on(async {
+      let mut transport = self.connect_remote(&remote_details).await?;
+
+      let mut pushed = 0;
+      let mut progress = SyncProgress::new(
+        remote,
+        SyncPhase::Pushing,
+        Some(local_commits.len()),
+      );
+
+      for commit in local_commits {
+        let commit_id = commit.id;
+        // Rebase commit on the latest remote state
+        let commit = match self.rebase_local_commit(commit)? {
+          Some(commit) => commit,
+          // All of its actions are dropped
+          None => {
+            CommitLog::remove_local_commit(&self.ctx(), commit_id)?;
+            continue;
+          }
+        };
+        debug!(commit_id = %commit_id, "Pushing local commit");
+        self.report_sync_progress(&progress, Some(&commit));
+        let size = serde_json::to_string(&commit).map_or(0, |s| s.len());
+        self.upload_blobs(&mut transport, &commit).await?;
+        let max_message_size = self.ctx().max_message_size;
+        let remote_commit = transport
+          .push(commit, &self.client_id.to_string(), max_message_size)
+          .await?;
+        // Remote interleaved it after concurrent commits, see
+        // MergeMode::Crdt, so they are merged before promoting it
+        if Some(remote_commit.ancestor_id)
+          != CommitIndex::latest_remote_commit_id(&self.ctx())
+        {
+          self
+            .pull_concurrent(remote, &mut transport, &remote_commit)
+            .await?;
+        }
+        self.promote_local_commit(remote, remote_commit)?;
+        progress.advance(size);
+        pushed += 1;
+      }
+      self.report_sync_progress(&progress, None);
+
+      Ok::<usize, String>(pushed)
+    })?;
+
+    info!(pushed, "Pushed local commits");
+    CommitIndex::set_last_push(&self.ctx(), Utc::now())?;
+
+    // After push operation
+    // Proceed pull to update local storages
+    self.proceed_pull_from(remote)?;
+
+    Ok(())
+  }
+  // Merge remote commits pushed concurrently to the given
+  // interleaved commit of ours
+  async fn pull_concurrent(
+    &self,
+    remote: &str,
+    transport: &mut Transport,
+    interleaved: &Commit,
+  ) -> Result<(), String> {
+    let after_commit_id = CommitIndex::remote_cursor(&self.ctx(), remote)
+      .map(|i| i.to_string())
+      .unwrap_or_default();
+    let codec = self.ctx().action_codec;
+    let commits = transport
+      .pull(
+        after_commit_id,
+        self.subscribed_storages(),
+        PullOptions {
+          limit: 0,
+          accept_delta: false,
+          codec,
+          device_id: &self.device_id,
+        },
+        |_, _| (),
+      )
+      .await?
+      .into_iter()
+      .take_while(|commit| commit.id != interleaved.id)
+      .collect::<Vec<_>>();
+    self.download_blobs(transport, &commits).await?;
+    let mut known_ids = None;
+    for commit in commits {
+      if let MergeResult::Diverged =
+        self.merge_remote_commit(remote, commit, &mut known_ids)?
+      {
+        return Err("Remote commit ancestor ID error! Please pull".into());
+      }
+    }
+    Ok(())
+  }
+  // Rebase local commit on the latest remote commit
+  // Ancestor is set to the latest remote commit, and action objects
+  // are replaced with their current version, as remote updates might
+  // rebuilt them. None if every action object got dropped.
+  fn rebase_local_commit(
+    &self,
+    mut commit: Commit,
+  ) -> Result<Option<Commit>, String> {
+    commit.ancestor_id =
+      CommitIndex::latest_remote_commit_id(&self.ctx()).unwrap_or_default();
+    let rebasers = self.storage_rebasers.lock().unwrap();
+    let mut serialized_actions = vec![];
+    for aob_str in &commit.serialized_actions {
+      let storage_id = storage_id_of(aob_str)?;
+      let rebaser = rebasers
+        .get(&storage_id)
+        .ok_or(format!("Unknown storage {}", storage_id))?;
+      if let Some(aob) = rebaser(aob_str)? {
+        serialized_actions.push(aob);
+      }
+    }
+    if serialized_actions.is_empty() {
+      return Ok(None);
+    }
+    commit.serialized_actions = serialized_actions;
+    Ok(Some(commit))
+  }
+  // Promote pushed local commit to remote one
+  // after the remote accepted and signed it
+  fn promote_local_commit(
+    &self,
+    remote: &str,
+    remote_commit: Commit,
+  ) -> Result<(), String> {
+    self.verify_remote_commit(remote, &remote_commit)?;
+    self.record_trace_event(|| TraceEvent::Promoted {
+      at: Utc::now(),
+      remote: remote.to_string(),
+      commit: remote_commit.clone(),
+    });
+    self.promote_commit(Some(remote), remote_commit)
+  }
+  // Move commit from local log to remote log, promoting its actions
+  // Remote cursor is moved if remote given
+  fn promote_commit(
+    &self,
+    remote: Option<&str>,
+    remote_commit: Commit,
+  ) -> Result<(), String> {
+    // Lock in the same order as commit contexts
+    let ctx = self.ctx();
+    let _commit_log = self.commit_log.lock().unwrap();
+    // Move commit from local log to remote log
+    CommitLog::add_remote_commit(&ctx, remote_commit.clone())?;
+    CommitLog::remove_local_commit(&ctx, remote_commit.id)?;
+    if let Some(remote) = remote {
+      CommitIndex::set_remote_cursor(&ctx, remote, Some(remote_commit.id))?;
+    }
+    // Promote local actions to remote ones
+    let hooks = self.storage_hooks.lock().unwrap();
+    for aob_str in &remote_commit.serialized_actions {
+      if let Some(hook) = hooks.get(&storage_id_of(aob_str)?) {
+        hook(aob_str, CallbackMode::Promote)?;
+      }
+    }
+    drop(hooks);
+    // Notify subscribers, error only means no active subscriber
+    let _ = self.remote_commit_tx.send(remote_commit);
+    Ok(())
+  }
+  /// Clean local repository, clear local changes
+  /// And performs remote pull
+  /// In dry run mode nothing is changed, only the report
+  /// of the would be lost local changes is returned
+  /// Recorded in the audit log unless dry run
+  pub fn proceed_clean(&self, dry_run: bool) -> Result<CleanReport, String> {
+    let res = self.clean_locals(dry_run);
+    if !dry_run {
+      let discarded_commits = res
+        .as_ref()
+        .map(|report| report.local_commits.len())
+        .unwrap_or_default();
+      self.audit(AuditAction::Clean { discarded_commits }, &res);
+    }
+    res
+  }
+  fn clean_locals(&self, dry_run: bool) -> Result<CleanReport, String> {
+    let report = {
+      // Lock in the same order as commit contexts,
+      // so no commit interleaves with the clean
+      let ctx = self.ctx();
+      let _commit_log = self.commit_log.lock().unwrap();
+      let repo_details = self.repo_details.lock().unwrap();
+      if !matches!(repo_details.mode, Mode::Remote { .. }) {
+        return Err(
+          "Cannot proceed clean operation, as the repository is not in remote mode"
+            .to_string(),
+        );
+      }
+      let mut report = CleanReport {
+        dry_run,
+        local_commits: CommitLog::load_locals(&ctx)?,
+        storages: vec![],
+      };
+      // Clear local changes on every storage object
+      for cleaner in self.storage_cleaners.lock().unwrap().iter() {
+        report.storages.push(cleaner(dry_run)?);
+      }
+      // Discard local commits and reset local commit pointer
+      if !dry_run {
+        CommitLog::clear_locals(&ctx)?;
+      }
+      report
+    };
+    if !dry_run {
+      // Pull remote changes
+      self.proceed_pull()?;
+      // Discarded local commits are still part of the projections
+      self.rebuild_projections()?;
+      self.reset_object_mirror()?;
+    }
+    Ok(report)
+  }
+  /// Start watcher for remote client to watch
+  /// remote updates
+  /// Watcher runs in a background thread and applies every incoming
+  /// remote commit through the storage hooks. Use subscribe_remote_commits
+  /// to get notified about the applied commits.
+  pub fn watch(&self) -> Result<(), String> {
+    let remote = self.repo_details.lock().unwrap().default_remote_name()?;
+    self.watch_from(&remote)
+  }
+  /// Start watcher on the given remote
+  pub fn watch_from(&self, remote: &str) -> Result<(), String> {
+    // Check remote exists
+    self.repo_details.lock().unwrap().remote(Some(remote))?;
+    // Reconnects after stream errors, and continues from the latest
+    // applied remote commit
+    let repo = self.handle();
+    let remote = remote.to_string();
+    let policy = RestartPolicy::Always {
+      delay: WATCH_RETRY_DELAY,
+    };
+    self
+      .worker
+      .spawn(&format!("sync_watch_{}", remote), policy, move || {
+        let (repo, remote) = (repo.handle(), remote.clone());
+        async move { repo.watch_remote(&remote).await }
+      })
+  }
+  // Subscribe to remote Watch stream and merge incoming commits
+  async fn watch_remote(&self, remote: &str) -> Result<(), String> {
+    // Remote details are read on every reconnect, as they might change
+    let remote_details = self
+      .repo_details
+      .lock()
+      .unwrap()
+      .remote(Some(remote))?
+      .clone();
+    let mut transport = self.connect_remote(&remote_details).await?;
+
+    self
+      .ensure_remote_public_key(remote, &mut transport)
+      .await?;
+
+    let after_commit_id = CommitIndex::remote_cursor(&self.ctx(), remote)
+      .map(|i| i.to_string())
+      .unwrap_or("".to_string());
+    let (codec, presence) = {
+      let ctx = self.ctx();
+      (ctx.action_codec, ctx.presence)
+    };
+
+    let mut events = transport
+      .watch(
+        after_commit_id,
+        self.subscribed_storages(),
+        self.client_id.to_string(),
+        codec,
+        presence,
+      )
+      .await?;
+
+    while let Some(event) = events.next().await {
+      let commit = match event? {
+        WatchEvent::Commit(commit) => commit,
+        WatchEvent::Presence(changes) => {
+          self.update_presence(changes);
+          continue;
+        }
+      };
+      info!(commit_id = %commit.id, "Applying watched remote commit");
+      self
+        .download_blobs(&mut transport, std::slice::from_ref(&commit))
+        .await?;
+      // Already applied commits, e.g. pulled after our own push
+      // are skipped. Out of sync, reconnect from the remote cursor.
+      let mut known_ids = None;
+      if let MergeResult::Diverged =
+        self.merge_remote_commit(remote, commit, &mut known_ids)?
+      {
+        return Err("Remote commit ancestor mismatch".to_string());
+      }
+    }
+
+    info!("Remote watch stream closed");
+    Ok(())
+  }
+  /// Subscribe to applied remote commits
+  /// Every remote commit is sent after its action objects are applied
+  /// to the local storages, so receivers can refresh their views.
+  pub fn subscribe_remote_commits(&self) -> broadcast::Receiver<Commit> {
+    self.remote_commit_tx.subscribe()
+  }
+  /// Announce the objects of the storage this device has open to the
+  /// default remote server, an empty list closes them
+  /// Announce again within PRESENCE_TTL to keep them open.
+  /// Returns the presence of the other devices in the storage.
+  pub fn announce_presence(
+    &self,
+    storage_id: &str,
+    object_ids: &[Uuid],
+  ) -> Result<Vec<Presence>, String> {
+    let presence = Presence {
+      device_id: self.device_id.clone(),
+      uid: self.ctx().uid.clone(),
+      storage_id: storage_id.to_string(),
+      object_ids: object_ids.to_vec(),
+      at: Utc::now(),
+    };
+    let others = self.with_default_remote(|mut transport| async move {
+      transport.announce(&presence).await
+    })?;
+    self.update_presence(others.clone());
+    Ok(others)
+  }
+  /// Other devices having the object open
+  /// Known from the latest announcement, or streamed by a watch with
+  /// presence, see Context::with_presence
+  pub fn presence(&self, storage_id: &str, object_id: Uuid) -> Vec<Presence> {
+    self
+      .current_presence(Some(storage_id))
+      .into_iter()
+      .filter(|p| p.has_open(object_id))
+      .collect()
+  }
+  /// Subscribe to presence changes of other devices
+  /// Closed or expired presences are sent with no object ids
+  pub fn subscribe_presence(&self) -> broadcast::Receiver<Presence> {
+    self.presence_tx.subscribe()
+  }
+  // Apply presence of other devices, and notify subscribers of the
+  // changed ones. Expired presences are closed along the way.
+  // Servers stream refreshed ones as well, so watchers keep them.
+  pub(crate) fn update_presence(&self, updates: Vec<Presence>) {
+    let is_server = self.is_server();
+    let changes = {
+      let mut presence = self.presence.lock().unwrap();
+      let mut changes: Vec<Presence> = updates
+        .into_iter()
+        .filter(|p| p.device_id != self.device_id)
+        .filter(|p| presence.update(p.clone()) || is_server)
+        .collect();
+      changes.extend(presence.prune(Utc::now()));
+      changes
+    };
+    for change in changes {
+      // No subscribers is fine
+      let _ = self.presence_tx.send(change);
+    }
+  }
+  // Presence not expired yet, in the storage if any
+  pub(crate) fn current_presence(
+    &self,
+    storage_id: Option<&str>,
+  ) -> Vec<Presence> {
+    self
+      .presence
+      .lock()
+      .unwrap()
+      .current(storage_id, Utc::now())
+  }
+  /// Repository id
+  pub fn id(&self) -> Uuid {
+    self.repo_details.lock().unwrap().id
+  }
+  /// Info of the default remote server
+  /// e.g. to check compatibility before pull and push
+  pub fn remote_info(&self) -> Result<RemoteInfo, String> {
+    let remote = self.repo_details.lock().unwrap().default_remote_name()?;
+    self.remote_info_from(&remote)
+  }
+  /// Info of the given remote server
+  pub fn remote_info_from(&self, remote: &str) -> Result<RemoteInfo, String> {
+    let remote_details = self
+      .repo_details
+      .lock()
+      .unwrap()
+      .remote(Some(remote))?
+      .clone();
+    let info = self.worker.block_on(async {
+      self.connect_remote(&remote_details).await?.info().await
+    })?;
+    let parse_id = |id: &str| {
+      Uuid::parse_str(id).map_err(|_| "Wrong id format in info".to_string())
+    };
+    Ok(RemoteInfo {
+      protocol_version: info.protocol_version,
+      repository_id: parse_id(&info.repository_id)?,
+      latest_remote_commit_id: match info.latest_remote_commit_id.is_empty() {
+        true => None,
+        false => Some(parse_id(&info.latest_remote_commit_id)?),
+      },
+      storage_count: info.storage_count as usize,
+      features: info.features,
+    })
+  }
+  /// Devices registered with the default remote server
+  /// Admin request, see ServeConfig::with_admin_uids
+  pub fn remote_devices(&self) -> Result<Vec<DeviceRecord>, String> {
+    self.with_default_remote(|mut transport| async move {
+      transport.list_devices().await
+    })
+  }
+  /// Reject later pushes of the device on the default remote server
+  /// Admin request, see Repository::revoke_device
+  pub fn remote_revoke_device(
+    &self,
+    device_id: &str,
+  ) -> Result<DeviceRecord, String> {
+    self.with_default_remote(|mut transport| async move {
+      transport.revoke_device(device_id).await
+    })
+  }
+  /// Make the next pull of the device from the default remote server
+  /// start from scratch
+  /// Admin request, see Repository::force_resync
+  pub fn remote_force_resync(
+    &self,
+    device_id: &str,
+  ) -> Result<DeviceRecord, String> {
+    self.with_default_remote(|mut transport| async move {
+      transport.force_resync(device_id).await
+    })
+  }
+  // Run request on a connection to the default remote
+  fn with_default_remote<T, Fut>(
+    &self,
+    request: impl FnOnce(Transport) -> Fut,
+  ) -> Result<T, String>
+  where
+    Fut: Future<Output = Result<T, String>>,
+  {
+    let remote_details = {
+      let repo_details = self.repo_details.lock().unwrap();
+      let remote = repo_details.default_remote_name()?;
+      repo_details.remote(Some(&remote))?.clone()
+    };
+    self.worker.block_on(async {
+      request(self.connect_remote(&remote_details).await?).await
+    })
+  }
+  /// Query objects of the given storage on the default remote server
+  /// Objects are returned as json, so thin clients do not need
+  /// to hold the storage locally
+  pub fn remote_query(
+    &self,
+    storage_id: &str,
+    query: &Query,
+  ) -> Result<Vec<QueryResult>, String> {
+    let remote = self.repo_details.lock().unwrap().default_remote_name()?;
+    self.remote_query_from(&remote, storage_id, query)
+  }
+  /// Query objects of the given storage on the given remote server
+  pub fn remote_query_from(
+    &self,
+    remote: &str,
+    storage_id: &str,
+    query: &Query,
+  ) -> Result<Vec<QueryResult>, String> {
+    let remote_details = self
+      .repo_details
+      .lock()
+      .unwrap()
+      .remote(Some(remote))?
+      .clone();
+    self.worker.block_on(async {
+      self
+        .connect_remote(&remote_details)
+        .await?
+        .query(storage_id, query)
+        .await
+    })
+  }
+  // Server side query of a registered storage
+  // None if the storage is not registered
+  pub(crate) fn query(
+    &self,
+    storage_id: &str,
+    query: &Query,
+  ) -> Result<Option<Vec<QueryResult>>, String> {
+    let queriers = self.storage_queriers.lock().unwrap();
+    match queriers.iter().find(|(id, _)| id == storage_id) {
+      Some((_, querier)) => querier(query).map(Some),
+      None => Ok(None),
+    }
+  }
+  // Server side repository info
+  pub(crate) fn info(&self) -> Result<InfoResponse, String> {
+    let (latest_remote_commit_id, storage_count) = {
+      let ctx = self.ctx();
+      (
+        CommitIndex::latest_remote_commit_id(&ctx),
+        stored_storage_ids(&ctx)?.len(),
+      )
+    };
+    let repo_details = self.repo_details.lock().unwrap();
+    let mut features: Vec<String> =
+      SERVER_FEATURES.iter().map(|f| f.to_string()).collect();
+    if matches!(repo_details.mode, Mode::Server { tls: Some(_), .. }) {
+      features.push("tls".to_string());
+    }
+    Ok(InfoResponse {
+      protocol_version: PROTOCOL_VERSION,
+      repository_id: repo_details.id.to_string(),
+      latest_remote_commit_id: latest_remote_commit_id
+        .map(|id| id.to_string())
+        .unwrap_or_default(),
+      storage_count: storage_count as u32,
+      features,
+    })
+  }
+  /// Client id of this repository instance
+  pub fn client_id(&self) -> Uuid {
+    self.client_id
+  }
+  // Server side subscription to pushed commits and their pusher ids
+  pub(crate) fn subscribe_pushed_commits(
+    &self,
+  ) -> broadcast::Receiver<(Commit, String)> {
+    self.pushed_commit_tx.subscribe()
+  }
+  pub(crate) fn publish_pushed_commit(
+    &self,
+    commit: Commit,
+    client_id: String,
+  ) {
+    // Error only means no active watcher
+    let _ = self.pushed_commit_tx.send((commit, client_id));
+  }
+  // Server side subscription to server shutdown
+  // Open or close the watch streams of a server
+  pub(crate) fn set_server_shutdown(&self, stopping: bool) {
+    self.server_shutdown_tx.send_replace(stopping);
+  }
+  // Check whether the repository is in server mode
+  pub(crate) fn is_server(&self) -> bool {
+    matches!(self.repo_details.lock().unwrap().mode, Mode::Server { .. })
+  }
+  pub(crate) fn subscribe_server_shutdown(
+    &self,
+  ) -> tokio::sync::watch::Receiver<bool> {
+    self.server_shutdown_tx.subscribe()
+  }
+  /// Revert commit
+  /// Creates a new local commit containing the inverse actions of the
+  /// given commit, so history is never rewritten. Actions are reverted by
+  /// ActionExt::inverse, or by restoring the object state before the action.
+  /// Returns the new commit id
+  pub fn revert_commit(&self, commit_id: Uuid) -> Result<Uuid, String> {
+    // Inverse actions are created under the commit locks,
+    // so the reverted objects cannot change meanwhile
+    let mut ctx = self.commit_ctx(&format!("Revert commit {}", commit_id));
+    let commit = CommitLog::load_remotes(&ctx)?
+      .into_iter()
+      .chain(CommitLog::load_locals(&ctx)?)
+      .find(|c| c.id == commit_id)
+      .ok_or(format!("Commit {} not found", commit_id))?;
+    for reverter in self.storage_reverters.lock().unwrap().iter() {
+      for aob in reverter(&ctx.temp_commit, &commit.serialized_actions)? {
+        ctx.temp_commit.serialized_actions.push(aob);
+      }
+    }
+    if ctx.temp_commit.serialized_actions.is_empty() {
+      return Err("Nothing to revert. Unknown storage.".to_string());
+    }
+    ctx.commit()?.into_result()
+  }
+  /// Snapshot repository
+  /// Packages every repository data (repo details, storage details,
+  /// storage objects, commit logs and commit index) into a single
+  /// versioned archive file
+  pub fn snapshot(&self, path: PathBuf) -> Result<(), String> {
+    let action = AuditAction::Snapshot {
+      path: path.display().to_string(),
+    };
+    let res = self.write_snapshot(path);
+    self.audit(action, &res);
+    res
+  }
+  fn write_snapshot(&self, path: PathBuf) -> Result<(), String> {
+    // Lock in the same order as commit contexts,
+    // so no commit can occur during snapshot
+    let ctx = self.ctx();
+    let _commit_log = self.commit_log.lock().unwrap();
+    let _repo_details = self.repo_details.lock().unwrap();
+    let mut entries = vec![];
+    for key in ctx.backend().scan(&ctx.db_root_path)? {
+      // Lock file belongs to the running repository, the device id
+      // to this terminal, a restored one gets a new one
+      if key == path_helper::repo_lock(&ctx)
+        || key == path_helper::device_id(&ctx)
+      {
+        continue;
+      }
+      let relative_path = key
+        .strip_prefix(&ctx.db_root_path)
+        .map_err(|_| format!("Key outside of repository: {:?}", key))?
+        .to_path_buf();
+      entries.push((relative_path, ctx.backend().get(&key)?));
+    }
+    let snapshot = Snapshot {
+      version: SNAPSHOT_VERSION,
+      dtime: Utc::now(),
+      entries,
+    };
+    let data = bincode::serialize(&snapshot).map_err(|e| e.to_string())?;
+    std::fs::write(&path, data)
+      .map_err(|e| format!("Error writing snapshot {:?}: {}", path, e))
+  }
+  /// Restore repository from snapshot
+  /// Repository must not exist under the context db root path.
+  /// Storages must be registered again on the restored repository.
+  /// Recorded in the audit log of the restored repository, failed
+  /// restores are not recorded.
+  pub fn restore(ctx: Context, path: PathBuf) -> Result<Self, String> {
+    let action = AuditAction::Restore {
+      path: path.display().to_string(),
+    };
+    let repo = Self::restore_snapshot(ctx, path)?;
+    let uid = repo.ctx().uid.to_string();
+    repo.record_audit_event(AuditEvent::new(&uid, action));
+    Ok(repo)
+  }
+  fn restore_snapshot(ctx: Context, path: PathBuf) -> Result<Self, String> {
+    let lock = acquire_lock(&ctx, None)?;
+    // Check if repository inited
+    if ctx.backend().exists(&path_helper::repo_details(&ctx)) {
+      return Err("Existing repository. Cannot restore snapshot".into());
+    }
+    let data = std::fs::read(&path)
+      .map_err(|e| format!("Error reading snapshot {:?}: {}", path, e))?;
+    let snapshot: Snapshot = bincode::deserialize(&data)
+      .map_err(|_| "Snapshot deser error".to_string())?;
+    if snapshot.version != SNAPSHOT_VERSION {
+      return Err(format!("Unsupported snapshot version {}", snapshot.version));
+    }
+    for (relative_path, data) in snapshot.entries {
+      ctx
+        .backend()
+        .put(&ctx.db_root_path.join(relative_path), &data)?;
+    }
+    Self::open(ctx, lock)
+  }
+  /// Migrate repository data to the given serialization format
+  /// Rewrites every storage, commit log and repository file.
+  /// Every storage on disk must be registered. Migration is not atomic,
+  /// take a snapshot first to be able to restore an interrupted one.
+  /// Repositories of interrupted migrations refuse to open.
+  pub fn migrate_format(&self, to: Format) -> Result<(), String> {
+    // Lock in the same order as commit contexts
+    let ctx = self.ctx();
+    let _commit_log = self.commit_log.lock().unwrap();
+    let repo_details = self.repo_details.lock().unwrap();
+    if ctx.format() == to {
+      return Ok(());
+    }
+    let migrators = self.storage_migrators.lock().unwrap();
+    for storage_id in stored_storage_ids(&ctx)? {
+      if !migrators.iter().any(|(id, _)| id == &storage_id) {
+        return Err(format!(
+          "Storage {} is not registered. Cannot migrate format",
+          storage_id
+        ));
+      }
+    }
+    // Marks the repository as partially migrated until the switch
+    let marker = path_helper::repo_format_migration(&ctx);
+    format_write(&ctx, marker.clone(), to)?;
+    let to_ctx = ctx.clone().with_format(to);
+    for (_, migrator) in migrators.iter() {
+      migrator(&ctx, &to_ctx)?;
+    }
+    CommitLog::migrate(&ctx, &to_ctx)?;
+    binary_migrate::<String>(&ctx, &to_ctx, path_helper::device_id(&ctx))?;
+    binary_migrate::<Vec<DeviceRecord>>(
+      &ctx,
+      &to_ctx,
+      path_helper::devices(&ctx),
+    )?;
+    binary_continuous_migrate::<AuditEvent>(
+      &ctx,
+      &to_ctx,
+      path_helper::audit_log(&ctx),
+    )?;
+    // Action objects of unknown storages, see UnknownStoragePolicy
+    for root in ["quarantine", "storage_raw"] {
+      for path in ctx.backend().scan(&ctx.db_root_path.join(root))? {
+        binary_migrate::<String>(&ctx, &to_ctx, path)?;
+      }
+    }
+    for feed in self.projections.lock().unwrap().iter() {
+      (feed.save)(&to_ctx)?;
+    }
+    repo_details.save(&to_ctx)?;
+    // Switch format of every context clone
+    format_write(&ctx, path_helper::repo_format(&ctx), to)?;
+    ctx.set_format(to);
+    ctx.backend().delete(&marker)
+  }
+  /// Check repository consistency, similar to fsck of file systems
+  /// Cross-checks storage members against the object files, re-links
+  /// valid orphan files and deletes invalid ones, then verifies action
+  /// chains and signatures of every object. A crash between writing an
+  /// object file and the storage details leaves such inconsistencies.
+  /// In dry run mode nothing is changed, only the report is returned
+  pub fn fsck(&self, dry_run: bool) -> Result<FsckReport, String> {
+    // Lock in the same order as commit contexts
+    let ctx = self.ctx();
+    let _commit_log = self.commit_log.lock().unwrap();
+    let mut report = FsckReport {
+      dry_run,
+      ..FsckReport::default()
+    };
+    for fsck in self.storage_fscks.lock().unwrap().iter() {
+      report.storages.push(fsck(dry_run)?);
+    }
+    report.unregistered_storages = stored_storage_ids(&ctx)?
+      .into_iter()
+      .filter(|id| !report.storages.iter().any(|s| &s.storage_id == id))
+      .collect();
+    Ok(report)
+  }
+  // Check invariants of every registered storage, see verify::verify_all
+  pub(crate) fn verify_storages(&self) -> Result<VerifyReport, String> {
+    // Lock in the same order as commit contexts
+    let _ctx = self.ctx();
+    let _commit_log = self.commit_log.lock().unwrap();
+    let keys = self.repo_details.lock().unwrap().trusted_keys();
+    let mut report = VerifyReport::default();
+    for verifier in self.storage_verifiers.lock().unwrap().iter() {
+      report.storages.push(verifier(&keys)?);
+    }
+    Ok(report)
+  }
+  /// Rewrite legacy (e.g. sha1) object signatures with the default
+  /// algorithm, in object files and commit logs alike.
+  /// In server mode remote action objects and commits are re-signed.
+  /// Clients can only rewrite their pending local actions, remote ones
+  /// keep their legacy signatures, which are still accepted.
+  /// Returns the number of rewritten action objects
+  pub fn rehash_all(&self) -> Result<usize, String> {
+    // Lock in the same order as commit contexts
+    let ctx = self.ctx();
+    let _commit_log = self.commit_log.lock().unwrap();
+    let repo_details = self.repo_details.lock().unwrap();
+    let signing_key = match repo_details.mode {
+      Mode::Server { .. } => Some(repo_details.signing_key()?),
+      _ => None,
+    };
+    let mut rehashed = HashMap::new();
+    for rehasher in self.storage_rehashers.lock().unwrap().iter() {
+      for uaob in rehasher(signing_key.as_ref())? {
+        rehashed.insert(uaob.id, uaob);
+      }
+    }
+    if rehashed.is_empty() {
+      return Ok(0);
+    }
+    CommitLog::rewrite_actions(&ctx, &rehashed, signing_key.as_ref())?;
+    Ok(rehashed.len())
+  }
+  /// Purge objects removed longer than the retention period of their
+  /// storage, see Storage::with_retention and retention
+  /// Objects under legal hold are kept. Purged action objects replace
+  /// the original ones in the commit logs, and blobs referred only by
+  /// them are deleted.
+  /// In server mode purged action objects and commits are re-signed,
+  /// so later clones get the purged history. Clients keep the server
+  /// signatures of their purged remote commits.
+  pub fn purge(&self) -> Result<PurgeReport, String> {
+    let res = self.purge_removed();
+    self.audit(AuditAction::Purge, &res);
+    res
+  }
+  fn purge_removed(&self) -> Result<PurgeReport, String> {
+    // Lock in the same order as commit contexts
+    let ctx = self.ctx();
+    let _commit_log = self.commit_log.lock().unwrap();
+    let repo_details = self.repo_details.lock().unwrap();
+    let signing_key = match repo_details.mode {
+      Mode::Server { .. } => Some(repo_details.signing_key()?),
+      _ => None,
+    };
+    let mut report = PurgeReport::default();
+    let mut purged = HashMap::new();
+    for purger in self.storage_purgers.lock().unwrap().iter() {
+      let (storage_report, uaobs) = purger(signing_key.as_ref())?;
+      purged.extend(uaobs.into_iter().map(|uaob| (uaob.id, uaob)));
+      report.storages.push(storage_report);
+    }
+    if purged.is_empty() {
+      return Ok(report);
+    }
+    let (rewritten, replaced) =
+      CommitLog::rewrite_actions(&ctx, &purged, signing_key.as_ref())?;
+    report.rewritten_commits = rewritten;
+    report.deleted_blobs = delete_orphan_blobs(&ctx, &replaced)?;
+    Ok(report)
+  }
+  /// Redact the payload of the given remote actions, e.g. an
+  /// accidentally committed password, see redaction
+  /// The actions are replaced by Redacted markers in commit logs and
+  /// object histories alike, and a signed redaction commit records
+  /// them, with comment reason. Replicas apply it on pull.
+  /// The current state of the objects is restored by the redaction
+  /// commit, so it must not contain the sensitive data anymore.
+  /// Server mode only. Returns the redaction commit id
+  pub fn redact(
+    &self,
+    action_ids: &[Uuid],
+    reason: &str,
+  ) -> Result<Uuid, String> {
+    let res = self.redact_actions(action_ids, reason);
+    let action = AuditAction::Redact {
+      action_ids: action_ids.to_vec(),
+      reason: reason.to_string(),
+    };
+    self.audit(action, &res);
+    res
+  }
+  fn redact_actions(
+    &self,
+    action_ids: &[Uuid],
+    reason: &str,
+  ) -> Result<Uuid, String> {
+    let mut commit_ctx = self.commit_ctx(reason);
+    if !matches!(commit_ctx.repo_details.mode, Mode::Server { .. }) {
+      return Err("Only server repository can redact actions".to_string());
+    }
+    let signing_key = commit_ctx.repo_details.signing_key()?;
+    let mut redacted = vec![];
+    for commit in CommitLog::load_remotes(&commit_ctx.ctx)? {
+      for aob_str in &commit.serialized_actions {
+        let uaob: UniversalActionObject = serde_json::from_str(aob_str)
+          .map_err(|_| {
+            "Error while deser aob into universal aob".to_string()
+          })?;
+        if action_ids.contains(&uaob.id) {
+          redacted.push(uaob);
+        }
+      }
+    }
+    if let Some(id) = action_ids
+      .iter()
+      .find(|id| !redacted.iter().any(|uaob| uaob.id == **id))
+    {
+      return Err(format!("Remote action {} not found", id));
+    }
+    for uaob in &mut redacted {
+      uaob.action = redacted_action(&uaob.action)?;
+      uaob.remote_signature = None;
+      uaob.remote_sign(&signing_key)?;
+    }
+    let objects: BTreeSet<(String, Uuid)> = redacted
+      .iter()
+      .map(|uaob| (uaob.storage_id.clone(), uaob.object_id))
+      .collect();
+    let commit = &mut commit_ctx.temp_commit;
+    commit.set_ancestor_id(
+      CommitIndex::latest_remote_commit_id(&commit_ctx.ctx).unwrap_or_default(),
+    );
+    commit.meta.insert(
+      REDACTION_META.to_string(),
+      serde_json::to_string(&redacted).map_err(|e| e.to_string())?,
+    );
+    {
+      let restorers = self.storage_restorers.lock().unwrap();
+      for (storage_id, object_id) in objects {
+        let restorer = restorers
+          .get(&storage_id)
+          .ok_or(format!("No storage {} registered", storage_id))?;
+        for aob_str in restorer(&commit_ctx.temp_commit, object_id)? {
+          let mut uaob: UniversalActionObject = serde_json::from_str(&aob_str)
+            .map_err(|_| {
+              "Error while deser aob into universal aob".to_string()
+            })?;
+          uaob.remote_sign(&signing_key)?;
+          commit_ctx.temp_commit.add_action_object(uaob);
+        }
+      }
+    }
+    commit_ctx.temp_commit.seq =
+      Some(CommitIndex::next_remote_seq(&commit_ctx.ctx)?);
+    commit_ctx.temp_commit.add_remote_signature(&signing_key)?;
+    let commit_id = commit_ctx.temp_commit.id;
+    commit_ctx.commit()?.into_result()?;
+    Ok(commit_id)
+  }
+  /// Compact remote commit log
+  /// Squashes remote commits older than horizon into a single baseline
+  /// commit, containing Create actions of the object states after them.
+  /// Baseline takes the id of the last squashed commit, so later commits
+  /// and clients up-to-date with it remain valid. Clients behind it must
+  /// clone again. Server mode only.
+  /// Returns the baseline commit id, None if nothing to compact
+  pub fn compact(
+    &self,
+    horizon: DateTime<Utc>,
+  ) -> Result<Option<Uuid>, String> {
+    let res = self.compact_remotes(horizon);
+    self.audit(AuditAction::Compact { horizon }, &res);
+    res
+  }
+  fn compact_remotes(
+    &self,
+    horizon: DateTime<Utc>,
+  ) -> Result<Option<Uuid>, String> {
+    // Lock in the same order as commit contexts
+    let ctx = self.ctx();
+    let _commit_log = self.commit_log.lock().unwrap();
+    let repo_details = self.repo_details.lock().unwrap();
+    if !matches!(repo_details.mode, Mode::Server { .. }) {
+      return Err("Only server repository can be compacted".to_string());
+    }
+    let mut remotes = CommitLog::load_remotes(&ctx)?;
+    let count = remotes.iter().take_while(|c| c.dtime < horizon).count();
+    if count == 0 {
+      return Ok(None);
+    }
+    let rest = remotes.split_off(count);
+    let squashed: HashSet<Uuid> = remotes.iter().map(|c| c.id).collect();
+    let last = remotes.last().unwrap();
+    let mut baseline = Commit {
+      id: last.id,
+      uid: ctx.uid.to_string(),
+      dtime: last.dtime,
+      comment: format!("Compacted {} commits", count),
+      ancestor_id: Uuid::default(),
+      serialized_actions: vec![],
+      remote_signature: None,
+      meta: BTreeMap::new(),
+      tags: vec![],
+      // Later commits continue the sequence of the squashed ones
+      seq: last.seq,
+    };
+    let signing_key = repo_details.signing_key()?;
+    for compactor in self.storage_compactors.lock().unwrap().iter() {
+      for aob_str in compactor(&baseline, &squashed)? {
+        let mut uaob: UniversalActionObject = serde_json::from_str(&aob_str)
+          .map_err(|_| {
+            "Error while deser aob into universal aob".to_string()
+          })?;
+        uaob.remote_sign(&signing_key)?;
+        baseline.add_action_object(uaob);
+      }
+    }
+    baseline.add_remote_signature(&signing_key)?;
+    let baseline_id = baseline.id;
+    CommitLog::replace_remotes(
+      &ctx,
+      std::iter::once(baseline).chain(rest).collect(),
+    )?;
+    Ok(Some(baseline_id))
+  }
+  /// Merge pushed commit to remote one
+  /// If authenticated_uid is given, commit and all of its
+  /// action objects must belong to it
+  /// Returns the applied & signed remote Commit if success
+  #[instrument(
+    skip_all,
+    fields(uid = authenticated_uid, commit_id = field::Empty)
+  )]
+  pub fn merge_pushed_commit(
+    &self,
+    commit_json_str: &str,
+    authenticated_uid: Option<&str>,
+  ) -> Result<Commit, String> {
+    let started = Instant::now();
+    self.record_trace_event(|| TraceEvent::Pushed {
+      at: Utc::now(),
+      uid: authenticated_uid.map(String::from),
+      commit_json: commit_json_str.to_string(),
+    });
+    let res = self.merge_pushed(commit_json_str, authenticated_uid);
+    match &res {
+      Ok(_) => {
+        self.metrics.push_accepted();
+        self.metrics.observe_merge(started.elapsed());
+      }
+      Err(e) => {
+        self.metrics.push_rejected();
+        if e.starts_with(POLICY_VIOLATION) {
+          self.record_access_denied(commit_json_str, authenticated_uid, e);
+        }
+      }
+    }
+    res
+  }
+  // Record pushed commit rejected by the access policy
+  fn record_access_denied(
+    &self,
+    commit_json_str: &str,
+    authenticated_uid: Option<&str>,
+    error: &str,
+  ) {
+    let commit: Commit = match serde_json::from_str(commit_json_str) {
+      Ok(commit) => commit,
+      Err(_) => return,
+    };
+    let uid = authenticated_uid.unwrap_or(&commit.uid);
+    let action = AuditAction::AccessDenied {
+      commit_id: commit.id,
+    };
+    self.record_audit_event(AuditEvent::new(uid, action).with_error(error));
+  }
+  // Rebase pushed commit concurrent to the remote commits after its
+  // ancestor on the latest remote commit, see MergeMode::Crdt
+  fn interleave_pushed(
+    &self,
+    ctx: &Context,
+    commit: &mut Commit,
+  ) -> Result<(), String> {
+    let concurrent = match commit.ancestor_id.is_nil() {
+      true => CommitLog::load_remotes(ctx)?,
+      false => CommitLog::load_remotes_after(ctx, commit.ancestor_id)?
+        .ok_or(format!("Unknown ancestor commit {}", commit.ancestor_id))?,
+    };
+    let mut concurrent_actions: HashMap<String, Vec<String>> = HashMap::new();
+    for aob_str in concurrent.iter().flat_map(|c| &c.serialized_actions) {
+      concurrent_actions
+        .entry(storage_id_of(aob_str)?)
+        .or_default()
+        .push(aob_str.to_string());
+    }
+    // Pushed action object indexes by storage
+    let mut pushed: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    for (index, aob_str) in commit.serialized_actions.iter().enumerate() {
+      pushed
+        .entry(storage_id_of(aob_str)?)
+        .or_default()
+        .push(index);
+    }
+    let interleavers = self.storage_interleavers.lock().unwrap();
+    for (storage_id, indexes) in pushed {
+      let interleaver = interleavers.get(&storage_id).ok_or(format!(
+        "Storage {} does not merge concurrent commits",
+        storage_id
+      ))?;
+      let aob_strs = indexes
+        .iter()
+        .map(|index| commit.serialized_actions[*index].clone())
+        .collect::<Vec<_>>();
+      let concurrent_aob_strs = concurrent_actions
+        .get(&storage_id)
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+      let interleaved = interleaver(&aob_strs, concurrent_aob_strs)?;
+      for (index, aob_str) in indexes.into_iter().zip(interleaved) {
+        commit.serialized_actions[index] = aob_str;
+      }
+    }
+    commit.ancestor_id = concurrent.last().map(|c| c.id).unwrap_or_default();
+    Ok(())
+  }
+  fn merge_pushed(
+    &self,
+    commit_json_str: &str,
+    authenticated_uid: Option<&str>,
+  ) -> Result<Commit, String> {
+    // Lock itself
+    let mut ctx = self.commit_ctx("");
+
+    // 1) Check Commit
+    // Deserialize commit object
+    let mut commit: Commit = serde_json::from_str(commit_json_str)
+      .map_err(|_| "Deser error during commit deser process".to_string())?;
+    Span::current().record("commit_id", field::display(commit.id));
+    // Check signature
+    if commit.remote_signature.is_some() {
+      return Err(
+        "Pushed commit has remote signature. Only local commits can be pushed!"
+          .to_string(),
+      );
+    }
+    // Revoked devices may not push, see Repository::revoke_device
+    if let Some(device_id) = commit.meta.get(DEVICE_ID_META) {
+      if DeviceRegistry::get(&ctx, device_id)?.is_some_and(|r| r.revoked) {
+        return Err(format!(
+          "{}: device {} is revoked",
+          POLICY_VIOLATION, device_id
+        ));
+      }
+    }
+    // Referred blobs must be uploaded before the commit
+    if let Some(blob) = commit
+      .blobs()
+      .into_iter()
+      .find(|blob| !blob::has_blob(&ctx, blob.id()))
+    {
+      return Err(format!(
+        "Pushed commit refers to missing blob {}",
+        blob.id()
+      ));
+    }
+
+    // Check ancestor
+    if let Some(latest_remote_commit_id) =
+      CommitIndex::latest_remote_commit_id(&ctx)
+    {
+      // Only if not first commit
+      if commit.ancestor_id != latest_remote_commit_id {
+        // Concurrent commit, merged only if every storage of it
+        // interleaves commuting actions
+        if let Err(e) = self.interleave_pushed(&ctx, &mut commit) {
+          debug!(error = %e, "Concurrent commit not interleaved");
+          // Return error if ancestor id is wrong
+          return Err(
+            "Commit ancestor id erro. Local repo not up-to-date. Pull required."
+              .to_string(),
+          );
+        }
+      }
+    }
+
+    // 2) Sign all action objects
+
+    // Deserialize action objects as universal aob
+    let mut action_objects: Vec<UniversalActionObject> = vec![];
+    for aob in &commit.serialized_actions {
+      action_objects.push(
+        serde_json::from_str(aob).map_err(|_| {
+          "Error while deser aob into universal aob".to_string()
+        })?,
+      );
+    }
+
+    // Check identity
+    if action_objects.iter().any(|aob| aob.uid != commit.uid) {
+      return Err("Action object uid does not match the commit uid".into());
+    }
+    // Only the server purges and redacts
+    if action_objects
+      .iter()
+      .any(|aob| aob.is_purged() || aob.is_redacted())
+      || commit.meta.contains_key(REDACTION_META)
+    {
+      return Err("Pushed commit contains purged or redacted actions".into());
+    }
+    // Only the server can stamp the verified uid
+    commit.meta.remove(VERIFIED_UID_META);
+    if let Some(uid) = authenticated_uid {
+      if commit.uid != uid {
+        return Err(format!(
+          "Pushed commit uid does not match the authenticated uid {}",
+          uid
+        ));
+      }
+      commit
+        .meta
+        .insert(VERIFIED_UID_META.to_string(), uid.to_string());
+    }
+
+    // Check storage schemas
+    self.check_storage_schemas(&action_objects)?;
+
+    // Check access policy
+    if let Some(policy) = self.access_policy.lock().unwrap().as_ref() {
+      let uid = authenticated_uid.unwrap_or(&commit.uid);
+      for aob in &action_objects {
+        policy
+          .authorize(uid, aob)
+          .map_err(|e| format!("{}: {}", POLICY_VIOLATION, e))?;
+      }
+    }
+
+    // Run pre merge hooks, any of them can reject the commit
+    for hook in self.pre_merge_hooks.lock().unwrap().iter() {
+      hook(&commit)?;
+    }
+
+    // Clear action objects
+    commit.serialized_actions = vec![];
+
+    let signing_key = ctx.repo_details.signing_key()?;
+
+    for mut uaob in action_objects {
+      // Sign action object to be a remote one
+      uaob.remote_sign(&signing_key)?;
+      // Add action object back again
+      commit.add_action_object(uaob);
+    }
+
+    // 3) ReCreate commit with sequence number, signature
+    //    and signed ActionObject
+    commit.seq = Some(CommitIndex::next_remote_seq(&ctx)?);
+    commit.add_remote_signature(&signing_key)?;
+
+    // 4) Check all action objects (Ancestor + Action + Signature)
+    //    and add commit as remote commit if all of them are valid
+    ctx.temp_commit = commit.clone();
+    ctx.commit()?.into_result()?;
+    // 5) Run post merge hooks after the repository got unlocked
+    for hook in self.post_merge_hooks.lock().unwrap().iter() {
+      hook(&commit);
+    }
+    // 6) Return remote commit
+    Ok(commit)
+  }
+  /// Register a projection
+  /// The projection is fed every action object the repository applies,
+  /// and its state is persisted under the given name after every commit.
+  /// Persisted state not up-to-date with the commit log is rebuilt.
+  pub fn add_projection<P: Projection>(
+    &self,
+    name: &str,
+  ) -> Result<ProjectionHandle<P>, String> {
+    // Lock in the same order as commit contexts
+    let ctx = self.ctx();
+    let _commit_log = self.commit_log.lock().unwrap();
+    let mut projections = self.projections.lock().unwrap();
+    if projections.iter().any(|feed| feed.name == name) {
+      return Err(format!("Projection {} is already registered", name));
+    }
+    let handle = ProjectionHandle::<P>::load(&ctx, name);
+    let latest = [
+      CommitIndex::latest_local_commit_id(&ctx),
+      CommitIndex::latest_remote_commit_id(&ctx),
+    ];
+    let up_to_date = match handle.commit_id() {
+      Some(commit_id) => latest.contains(&Some(commit_id)),
+      None => latest == [None, None],
+    };
+    let (apply, save, reset) = (handle.clone(), handle.clone(), handle.clone());
+    let feed = ProjectionFeed {
+      name: name.to_string(),
+      apply: Box::new(move |commit| apply.apply(commit)),
+      save: Box::new(move |ctx| save.save(ctx)),
+      reset: Box::new(move || reset.reset()),
+    };
+    if !up_to_date {
+      info!(projection = name, "Rebuilding projection");
+      feed.rebuild(&ctx)?;
+    }
+    projections.push(feed);
+    Ok(handle)
+  }
+  /// Rebuild every projection from the commit log
+  /// Remote commits are replayed first, then the local ones
+  pub fn rebuild_projections(&self) -> Result<(), String> {
+    // Lock in the same order as commit contexts
+    let ctx = self.ctx();
+    let _commit_log = self.commit_log.lock().unwrap();
+    for feed in self.projections.lock().unwrap().iter() {
+      feed.rebuild(&ctx)?;
+    }
+    Ok(())
+  }
+  /// Declare a reference between storages
+  /// Objects of the source storage (of type T) reference objects of
+  /// the target storage by the id the extractor returns.
+  /// Commits referencing missing or removed objects are rejected when
+  /// checked (commit() and server merge). on_delete decides what
+  /// removing a referenced object does.
+  pub fn add_reference<T: 'static>(
+    &self,
+    source_storage: &str,
+    extractor: impl Fn(&T) -> Option<Uuid> + Send + Sync + 'static,
+    target_storage: &str,
+    on_delete: OnDelete,
+  ) {
+    let extractor =
+      move |object: &dyn Any| object.downcast_ref::<T>().and_then(&extractor);
+    self.references.lock().unwrap().push(Reference {
+      source_storage: source_storage.to_string(),
+      target_storage: target_storage.to_string(),
+      extractor: Arc::new(extractor),
+      on_delete,
+    });
+  }
+  /// Register server hook validating pushed commits
+  /// Runs before the commit is signed and merged, while the repository
+  /// is locked, so it must not access the repository. Returned error
+  /// rejects the push and is sent back to the client.
+  pub fn on_pre_merge(
+    &self,
+    hook: impl Fn(&Commit) -> Result<(), String> + Send + 'static,
+  ) {
+    self.pre_merge_hooks.lock().unwrap().push(Box::new(hook));
+  }
+  /// Register hook validating local commits, reverts included
+  /// Runs on commit with the whole pending commit, before any action
+  /// object is checked or stored, while the repository is locked, so
+  /// it must not access the repository. Returned error vetoes the
+  /// commit, nothing is stored. See Commit::action_objects
+  pub fn on_pre_commit(
+    &self,
+    hook: impl Fn(&Commit) -> Result<(), String> + Send + 'static,
+  ) {
+    self.pre_commit_hooks.lock().unwrap().push(Box::new(hook));
+  }
+  /// Register hook notified about the progress of pulls and pushes
+  /// Called on every received, applied and pushed commit, and once a
+  /// phase is done, on the syncing thread, so it should return fast.
+  /// A GUI can render progress bars, or detect stalls by the time
+  /// elapsed since the last call.
+  pub fn on_sync_progress(
+    &self,
+    hook: impl Fn(&SyncProgress) + Send + 'static,
+  ) {
+    self
+      .sync_progress_hooks
+      .lock()
+      .unwrap()
+      .push(Box::new(hook));
+  }
+  /// Set mirror of the current object states
+  /// Registered storages are mirrored right away, storages registered
+  /// later once they get registered
+  pub fn set_object_mirror(
+    &self,
+    mirror: impl ObjectMirror,
+  ) -> Result<(), String> {
+    *self.object_mirror.lock().unwrap() = Some(Arc::new(mirror));
+    self.reset_object_mirror()
+  }
+  // Rebuild every storage of the object mirror
+  fn reset_object_mirror(&self) -> Result<(), String> {
+    // Lock in the same order as commit contexts
+    let _ctx = self.ctx();
+    let mirror = self.object_mirror.lock().unwrap().clone();
+    if let Some(mirror) = mirror {
+      for mirrorer in self.storage_mirrorers.lock().unwrap().iter() {
+        mirrorer(mirror.as_ref())?;
+      }
+    }
+    Ok(())
+  }
+  // Private method to register storage mirrorers
+  // Storage is mirrored right away if the object mirror is set
+  fn add_storage_mirrorer(
+    &self,
+    mirrorer: StorageMirrorer,
+  ) -> Result<(), String> {
+    if let Some(mirror) = self.object_mirror.lock().unwrap().as_ref() {
+      mirrorer(mirror.as_ref())?;
+    }
+    self.storage_mirrorers.lock().unwrap().push(mirrorer);
+    Ok(())
+  }
+  /// Set server access policy authorizing every pushed action object
+  /// Rejected pushes get a PERMISSION_DENIED status
+  pub fn set_access_policy(&self, policy: impl AccessPolicy) {
+    *self.access_policy.lock().unwrap() = Some(Arc::new(policy));
+  }
+  /// Register server hook called with every merged remote commit
+  /// e.g. to trigger notifications, indexing or webhooks
+  pub fn on_post_merge(&self, hook: impl Fn(&Commit) + Send + 'static) {
+    self.post_merge_hooks.lock().unwrap().push(Box::new(hook));
+  }
+  /// Start remote server
+  /// Without authentication
+  pub fn serve(self) -> Result<(), String> {
+    self.serve_with_config(ServeConfig::default(), std::future::pending())
+  }
+  /// Start remote server
+  /// Every request must be authenticated by the given provider, and
+  /// pushed commits must belong to the authenticated uid
+  pub fn serve_with_auth(
+    self,
+    auth_provider: impl AuthProvider,
+  ) -> Result<(), String> {
+    let config = ServeConfig::default().with_auth(auth_provider);
+    self.serve_with_config(config, std::future::pending())
+  }
+  /// Start remote server, stopping once signal completes
+  /// Without authentication
+  pub fn serve_with_shutdown(
+    self,
+    signal: impl Future<Output = ()>,
+  ) -> Result<(), String> {
+    self.serve_with_config(ServeConfig::default(), signal)
+  }
+  /// Sync api service to mount in an existing tonic server
+  /// Use a handle to keep using the repository, e.g.
+  /// `builder.add_service(repo.handle().into_service())`
+  pub fn into_service(self) -> ApiServer<Repository> {
+    ApiServer::new(self)
+  }
+  /// Sync api service authenticating every request by the given provider
+  /// Pushed commits must belong to the authenticated uid.
+  pub fn into_service_with_auth(
+    self,
+    auth_provider: impl AuthProvider,
+  ) -> InterceptedService<ApiServer<Repository>, ServerAuth> {
+    let audit = self.audit_sink();
+    ApiServer::with_interceptor(
+      self,
+      ServerAuth::new(Some(Arc::new(auth_provider)), None).with_audit(audit),
+    )
+  }
+  /// Start remote server with the given config,
+  /// stopping once signal completes
+  /// On shutdown new connections are refused, watch streams are closed,
+  /// and in-flight requests (e.g. merges) complete before it returns.
+  pub fn serve_with_config(
+    self,
+    config: ServeConfig,
+    signal: impl Future<Output = ()>,
+  ) -> Result<(), String> {
+    let (server_addr, tls, metrics_addr) =
+      match &self.repo_details.lock().unwrap().mode {
+        Mode::Server {
+          server_addr,
+          tls,
+          metrics_addr,
+        } => (server_addr.to_string(), tls.clone(), metrics_addr.clone()),
+        _ => {
+          panic!("Cannot start server, as the repository is not in server mode")
+        }
+      };
+    let server_addr = server_addr
+      .parse()
+      .map_err(|e| format!("Invalid server address: {}", e))?;
+    let mut server = Server::builder();
+    if let Some(tls) = tls {
+      server = server
+        .tls_config(tls.config()?)
+        .map_err(|e| format!("TLS config error: {}", e))?;
+    }
+    let shutdown_tx = self.server_shutdown_tx.clone();
+    shutdown_tx.send_replace(false);
+    let grpc = async {
+      server
+        // Health checks are not authenticated, e.g. for load balancers
+        .add_service(HealthServer::new(HealthService))
+        .add_service(ApiServer::with_interceptor(
+          self.handle(),
+          ServerAuth::new(
+            config.auth_provider,
+            config.limits.map(|limits| Arc::new(Limiter::new(limits))),
+          )
+          .with_audit(self.audit_sink())
+          .with_admin_uids(config.admin_uids),
+        ))
+        .serve_with_shutdown(server_addr, async {
+          signal.await;
+          info!("Shutting down server");
+          // Close watch streams, so their connections can end
+          shutdown_tx.send_replace(true);
+        })
+        .await
+        .map_err(|e| format!("Server error: {}", e))
+    };
+    let metrics = async {
+      match &metrics_addr {
+        Some(metrics_addr) => self.serve_metrics(metrics_addr).await,
+        None => Ok(()),
+      }
+    };
+    let serve = async { futures::try_join!(grpc, metrics).map(|_| ()) };
+    let res = config.runtime.block_on(serve)?;
+    // Wait for merges still holding the repository,
+    // then write the changes buffered by a write-behind context
+    let ctx = self.ctx();
+    let _commit_log = self.commit_log.lock().unwrap();
+    ctx.backend().flush()?;
+    res
+  }
+  // Serve metrics until the server shuts down
+  #[cfg(feature = "metrics")]
+  async fn serve_metrics(&self, addr: &str) -> Result<(), String> {
+    let addr = addr
+      .parse()
+      .map_err(|e| format!("Invalid metrics address: {}", e))?;
+    let repo = Arc::new(self.handle());
+    let mut shutdown = self.subscribe_server_shutdown();
+    crate::metrics::serve(addr, move || repo.render_metrics(), async move {
+      let _ = shutdown.wait_for(|stopping| *stopping).await;
+    })
+    .await
+  }
+  #[cfg(not(feature = "metrics"))]
+  async fn serve_metrics(&self, _addr: &str) -> Result<(), String> {
+    Err("Metrics listener requires the metrics feature".to_string())
+  }
+  /// Repository metrics
+  pub fn metrics(&self) -> &Arc<Metrics> {
+    &self.metrics
+  }
+  /// Render metrics in the Prometheus text format
+  pub fn render_metrics(&self) -> String {
+    let object_counts: Vec<(String, usize)> = self
+      .storage_counters
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|counter| counter())
+      .collect();
+    self.metrics.render(&object_counts)
+  }
+  /// Set handling of action objects whose storage is not registered
+  pub fn set_unknown_storage_policy(&self, policy: UnknownStoragePolicy) {
+    *self.unknown_storage_policy.lock().unwrap() = policy;
+  }
+  /// Registered storage ids with their object counts
+  pub fn list_storages(&self) -> Vec<(String, usize)> {
+    let mut storages: Vec<(String, usize)> = self
+      .storage_counters
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|counter| counter())
+      .collect();
+    storages.sort();
+    storages
+  }
+  /// Quarantined action objects of unknown storages
+  pub fn quarantined(&self) -> Result<Vec<UniversalActionObject>, String> {
+    let ctx = self.ctx();
+    let root = ctx.db_root_path.join("quarantine");
+    let mut res = vec![];
+    for path in ctx.backend().scan(&root)? {
+      let aob_str: String = binary_read(&ctx, path)?;
+      res.push(serde_json::from_str(&aob_str).map_err(|e| e.to_string())?);
+    }
+    Ok(res)
+  }
+  /// Remove quarantined action objects of the given storage
+  /// Returns the number of removed action objects
+  pub fn clear_quarantined(&self, storage_id: &str) -> Result<usize, String> {
+    let ctx = self.ctx();
+    let paths = ctx
+      .backend()
+      .scan(&path_helper::quarantine_path(&ctx, storage_id))?;
+    for path in &paths {
+      binary_remove(&ctx, path.clone())?;
+    }
+    Ok(paths.len())
+  }
+  // Apply action objects stored before their storage got registered
+  // Each one is removed once applied, so a failing hook resumes
+  // with the ones not applied yet
+  fn apply_raw_actions(&self, storage_id: &str) -> Result<(), String> {
+    let ctx = self.ctx();
+    let paths = ctx
+      .backend()
+      .scan(&path_helper::raw_actions_path(&ctx, storage_id))?;
+    if paths.is_empty() {
+      return Ok(());
+    }
+    let mut raw_actions = vec![];
+    for path in paths {
+      let aob_str: String = binary_read(&ctx, path.clone())?;
+      let uaob: UniversalActionObject =
+        serde_json::from_str(&aob_str).map_err(|e| e.to_string())?;
+      raw_actions.push((uaob, aob_str, path));
+    }
+    // Apply them in the order they were created
+    raw_actions.sort_by_key(|(uaob, _, _)| (uaob.clock, uaob.dtime, uaob.id));
+    let hooks = self.storage_hooks.lock().unwrap();
+    let hook = hooks
+      .get(storage_id)
+      .ok_or(format!("No storage {} registered", storage_id))?;
+    for (_, aob_str, path) in &raw_actions {
+      hook(aob_str, CallbackMode::Apply)?;
+      binary_remove(&ctx, path.clone())?;
+    }
+    drop(hooks);
+    for flusher in self.storage_flushers.lock().unwrap().iter() {
+      flusher()?;
+    }
+    info!(
+      storage_id,
+      actions = raw_actions.len(),
+      "Applied raw action objects"
+    );
+    Ok(())
+  }
+  /// Registered storage by its id
+  /// Errors if it is not registered, or its types are not T and A
+  pub fn storage<T, A>(&self, storage_id: &str) -> Result<Storage<T, A>, String>
+  where
+    T: ObjectExt + 'static,
+    A: ActionExt<ObjectType = T> + 'static,
+  {
+    self
+      .storages
+      .lock()
+      .unwrap()
+      .get(storage_id)
+      .ok_or(format!("No storage {} registered", storage_id))?
+      .downcast_ref::<Storage<T, A>>()
+      .cloned()
+      .ok_or(format!("Storage {} has different types", storage_id))
+  }
+  // Private method to register typed storage handles
+  // Errors if storage id is already registered
+  fn add_storage_handle(
+    &self,
+    storage_id: String,
+    storage: Box<dyn Any + Send>,
+  ) -> Result<(), String> {
+    let mut storages = self.storages.lock().unwrap();
+    if storages.contains_key(&storage_id) {
+      return Err(format!("Storage {} is already registered", storage_id));
+    }
+    storages.insert(storage_id, storage);
+    Ok(())
+  }
+  // Private method to register
+  // storage hooks
+  // Storage update process will occur via these hooks (callbacks)
+  fn add_storage_hook(&self, storage_id: String, hook: StorageHook) {
+    self.storage_hooks.lock().unwrap().insert(storage_id, hook);
+  }
+  // Private method to register storage cleaners
+  // Clean process will discard local changes via these callbacks
+  fn add_storage_cleaner(&self, cleaner: StorageCleaner) -> Result<(), String> {
+    self.storage_cleaners.lock().unwrap().push(cleaner);
+    Ok(())
+  }
+  // Private method to register storage reverters
+  // Revert process will create inverse actions via these callbacks
+  fn add_storage_reverter(
+    &self,
+    reverter: StorageReverter,
+  ) -> Result<(), String> {
+    self.storage_reverters.lock().unwrap().push(reverter);
+    Ok(())
+  }
+  // Private method to register storage rebasers
+  // Push process will rebase local commits via these callbacks
+  fn add_storage_rebaser(&self, storage_id: String, rebaser: StorageRebaser) {
+    self
+      .storage_rebasers
+      .lock()
+      .unwrap()
+      .insert(storage_id, rebaser);
+  }
+  // Private method to register storage interleavers
+  // Server merges concurrent commits via these callbacks
+  fn add_storage_restorer(
+    &self,
+    storage_id: String,
+    restorer: StorageRestorer,
+  ) {
+    self
+      .storage_restorers
+      .lock()
+      .unwrap()
+      .insert(storage_id, restorer);
+  }
+  // Private method to register delta bases
+  fn add_storage_baseline(
+    &self,
+    storage_id: String,
+    baseline: StorageBaseline,
+  ) {
+    self
+      .storage_baselines
+      .lock()
+      .unwrap()
+      .insert(storage_id, baseline);
+  }
+  fn add_storage_interleaver(
+    &self,
+    storage_id: String,
+    interleaver: StorageInterleaver,
+  ) {
+    self
+      .storage_interleavers
+      .lock()
+      .unwrap()
+      .insert(storage_id, interleaver);
+  }
+  // Private method to register storage fscks
+  fn add_storage_fsck(&self, fsck: StorageFsck) {
+    self.storage_fscks.lock().unwrap().push(fsck);
+  }
+  // Private method to register storage verifiers
+  fn add_storage_verifier(&self, verifier: StorageVerifier) {
+    self.storage_verifiers.lock().unwrap().push(verifier);
+  }
+  // Private method to register storage flushers
+  fn add_storage_flusher(&self, flusher: StorageFlusher) {
+    self.storage_flushers.lock().unwrap().push(flusher);
+  }
+  // Private method to register storage reloaders
+  fn add_storage_reloader(&self, reloader: StorageReloader) {
+    self.storage_reloaders.lock().unwrap().push(reloader);
+  }
+  // Private method to register storage checkpointers
+  fn add_storage_checkpointer(&self, checkpointer: StorageCheckpointer) {
+    self
+      .storage_checkpointers
+      .lock()
+      .unwrap()
+      .push(checkpointer);
+  }
+  // Private method to register storage rehashers
+  fn add_storage_rehasher(&self, rehasher: StorageRehasher) {
+    self.storage_rehashers.lock().unwrap().push(rehasher);
+  }
+  // Private method to register storage purgers
+  fn add_storage_purger(&self, purger: StoragePurger) {
+    self.storage_purgers.lock().unwrap().push(purger);
+  }
+  // Private method to register storage referencers
+  // References between storages are enforced via these callbacks
+  fn add_storage_referencer(
+    &self,
+    storage_id: String,
+    referencer: StorageReferencer,
+  ) {
+    self
+      .storage_referencers
+      .lock()
+      .unwrap()
+      .insert(storage_id, referencer);
+  }
+  // Private method to register storage checkers
+  // Commits are checked via these callbacks before applying them
+  fn add_storage_checker(&self, checker: StorageChecker) -> Result<(), String> {
+    self.storage_checkers.lock().unwrap().push(checker);
+    Ok(())
+  }
+  // Private method to register storage compactors
+  // Compaction builds the baseline commit via these callbacks
+  fn add_storage_compactor(
+    &self,
+    compactor: StorageCompactor,
+  ) -> Result<(), String> {
+    self.storage_compactors.lock().unwrap().push(compactor);
+    Ok(())
+  }
+  // Private method to register storage reporters
+  // Status is collected via these callbacks
+  fn add_storage_reporter(
+    &self,
+    reporter: StorageReporter,
+  ) -> Result<(), String> {
+    self.storage_reporters.lock().unwrap().push(reporter);
+    Ok(())
+  }
+  // Private method to register storage meters
+  // Disk usage is collected via these callbacks
+  fn add_storage_meter(&self, meter: StorageMeter) {
+    self.storage_meters.lock().unwrap().push(meter);
+  }
+  // Private method to register storage counters
+  // Object counts of the metrics are collected via these callbacks
+  fn add_storage_counter(&self, counter: StorageCounter) {
+    self.storage_counters.lock().unwrap().push(counter);
+  }
+  // Private method to register storage queriers
+  // Queries of the remote api are answered via these callbacks
+  fn add_storage_querier(
+    &self,
+    storage_id: String,
+    querier: StorageQuerier,
+  ) -> Result<(), String> {
+    self
+      .storage_queriers
+      .lock()
+      .unwrap()
+      .push((storage_id, querier));
+    Ok(())
+  }
+  // Private method to register storage migrators
+  // Format migration rewrites storage data via these callbacks
+  fn add_storage_migrator(
+    &self,
+    storage_id: String,
+    migrator: StorageMigrator,
+  ) -> Result<(), String> {
+    self
+      .storage_migrators
+      .lock()
+      .unwrap()
+      .push((storage_id, migrator));
+    Ok(())
+  }
+  // Private method to register storage schemas
+  // Pushed action objects are checked against their hashes
+  fn add_storage_schema(&self, storage_id: String, schema: StorageSchema) {
+    self
+      .storage_schemas
+      .lock()
+      .unwrap()
+      .insert(storage_id, schema);
+  }
+  // Delta encode pulled commits, in the order sent
+  pub(crate) fn delta_encode_commits(
+    &self,
+    commits: Vec<Commit>,
+  ) -> Vec<Commit> {
+    let baselines = self.storage_baselines.lock().unwrap();
+    let baseline = |storage_id: &str, object_id, action_id| match baselines
+      .get(storage_id)
+    {
+      Some(baseline) => baseline(object_id, action_id),
+      None => Ok(None),
+    };
+    let mut encoder = DeltaEncoder::default();
+    commits
+      .into_iter()
+      .map(|mut commit| {
+        commit.serialized_actions =
+          encoder.encode(&commit.serialized_actions, &baseline);
+        commit
+      })
+      .collect()
+  }
+  // Restore the delta encoded action objects of a pulled commit
+  fn delta_decode_commit(&self, mut commit: Commit) -> Result<Commit, String> {
+    if !commit
+      .serialized_actions
+      .iter()
+      .any(|aob| delta::is_delta(aob))
+    {
+      return Ok(commit);
+    }
+    let baselines = self.storage_baselines.lock().unwrap();
+    let baseline = |storage_id: &str, object_id, action_id| match baselines
+      .get(storage_id)
+    {
+      Some(baseline) => baseline(object_id, action_id),
+      None => Ok(None),
+    };
+    commit.serialized_actions =
+      delta::decode(std::mem::take(&mut commit.serialized_actions), &baseline)?;
+    Ok(commit)
+  }
+  /// JSON Schemas of the registered storages by storage id
+  /// See schema_export::export_schemas
+  pub fn storage_schemas(&self) -> BTreeMap<String, StorageSchema> {
+    let schemas = self.storage_schemas.lock().unwrap();
+    schemas
+      .iter()
+      .map(|(k, v)| (k.clone(), v.clone()))
+      .collect()
+  }
+  // Check pushed action objects were created with the same
+  // storage schema as the registered one
+  // Action objects without schema hash are from older clients
+  fn check_storage_schemas(
+    &self,
+    action_objects: &[UniversalActionObject],
+  ) -> Result<(), String> {
+    let schemas = self.storage_schemas.lock().unwrap();
+    for aob in action_objects {
+      let (hash, expected) =
+        match (&aob.schema_hash, schemas.get(&aob.storage_id)) {
+          (Some(hash), Some(expected)) => (hash, &expected.hash),
+          _ => continue,
+        };
+      if hash != expected {
+        return Err(format!(
+          "Schema mismatch in storage {}. Action object {} was created \
+           with schema {}, server schema is {}. Client and server storage \
+           types differ.",
+          aob.storage_id, aob.id, hash, expected
+        ));
+      }
+    }
+    Ok(())
+  }
+  /// Create a new repository handle sharing the same state
+  pub fn handle(&self) -> Self {
+    Self {
+      ctx: self.ctx.clone(),
+      commit_log: self.commit_log.clone(),
+      repo_details: self.repo_details.clone(),
+      storages: self.storages.clone(),
+      storage_hooks: self.storage_hooks.clone(),
+      storage_cleaners: self.storage_cleaners.clone(),
+      storage_reverters: self.storage_reverters.clone(),
+      storage_rebasers: self.storage_rebasers.clone(),
+      storage_interleavers: self.storage_interleavers.clone(),
+      storage_checkers: self.storage_checkers.clone(),
+      storage_compactors: self.storage_compactors.clone(),
+      storage_restorers: self.storage_restorers.clone(),
+      storage_baselines: self.storage_baselines.clone(),
+      storage_migrators: self.storage_migrators.clone(),
+      storage_schemas: self.storage_schemas.clone(),
+      storage_reporters: self.storage_reporters.clone(),
+      storage_meters: self.storage_meters.clone(),
+      storage_counters: self.storage_counters.clone(),
+      metrics: self.metrics.clone(),
+      storage_fscks: self.storage_fscks.clone(),
+      storage_verifiers: self.storage_verifiers.clone(),
+      storage_rehashers: self.storage_rehashers.clone(),
+      storage_purgers: self.storage_purgers.clone(),
+      storage_flushers: self.storage_flushers.clone(),
+      storage_reloaders: self.storage_reloaders.clone(),
+      storage_checkpointers: self.storage_checkpointers.clone(),
+      storage_queriers: self.storage_queriers.clone(),
+      references: self.references.clone(),
+      storage_referencers: self.storage_referencers.clone(),
+      projections: self.projections.clone(),
+      pre_merge_hooks: self.pre_merge_hooks.clone(),
+      pre_commit_hooks: self.pre_commit_hooks.clone(),
+      sync_progress_hooks: self.sync_progress_hooks.clone(),
+      access_policy: self.access_policy.clone(),
+      object_mirror: self.object_mirror.clone(),
+      trace_recorder: self.trace_recorder.clone(),
+      audit_log: self.audit_log.clone(),
+      worker: self.worker.clone(),
+      devices: self.devices.clone(),
+      registered_remotes: self.registered_remotes.clone(),
+      presence: self.presence.clone(),
+      presence_tx: self.presence_tx.clone(),
+      storage_mirrorers: self.storage_mirrorers.clone(),
+      post_merge_hooks: self.post_merge_hooks.clone(),
+      remote_commit_tx: self.remote_commit_tx.clone(),
+      pushed_commit_tx: self.pushed_commit_tx.clone(),
+      server_shutdown_tx: self.server_shutdown_tx.clone(),
+      client_id: self.client_id,
+      device_id: self.device_id.clone(),
+      resolved_conflicts: self.resolved_conflicts.clone(),
+      unknown_storage_policy: self.unknown_storage_policy.clone(),
+      _lock: self._lock.clone(),
+    }
+  }
+  pub fn ctx<'a>(&'a self) -> ContextGuard {
+    let read_guard = self.ctx.read().unwrap();
+    ContextGuard { read_guard }
+  }
+  /// Commit builder, e.g.
+  /// `repo.commit().comment("..").tag("..").run(|ctx| { .. })`
+  pub fn commit(&self) -> CommitBuilder<'_> {
+    CommitBuilder {
+      repo: self,
+      comment: String::new(),
+      meta: CommitMeta::default(),
+    }
+  }
+  pub fn commit_ctx<'a>(
+    &'a self,
+    commit_comment: &str,
+  ) -> CommitContextGuard<'a> {
+    CommitContextGuard::new(self, commit_comment, CommitMeta::default())
+  }
+  /// Commit context acting on behalf of the given principal
+  /// The commit and its action objects claim the principal uid
+  /// instead of the context uid, e.g. for apps serving many users
+  pub fn commit_ctx_as<'a>(
+    &'a self,
+    principal: &Principal,
+    commit_comment: &str,
+  ) -> CommitContextGuard<'a> {
+    let mut ctx =
+      CommitContextGuard::new(self, commit_comment, CommitMeta::default());
+    ctx.temp_commit.uid = principal.uid().to_string();
+    ctx
+  }
+  /// Commit context whose commit carries the given metadata
+  pub fn commit_ctx_with_meta<'a>(
+    &'a self,
+    commit_comment: &str,
+    commit_meta: CommitMeta,
+  ) -> CommitContextGuard<'a> {
+    CommitContextGuard::new(self, commit_comment, commit_meta)
+  }
+  fn merge_commit_ctx<'a>(&'a self, commit: Commit) -> CommitContextGuard<'a> {
+    CommitContextGuard::new_merge(self, commit)
+  }
+  // Store and apply a traced commit as it is, see trace::replay
+  pub(crate) fn replay_commit(
+    &self,
+    commit: Commit,
+  ) -> Result<CommitReport, String> {
+    self.merge_commit_ctx(commit).commit()
+  }
+  // Promote a traced pushed commit, see trace::replay
+  pub(crate) fn replay_promotion(&self, commit: Commit) -> Result<(), String> {
+    self.promote_commit(None, commit)
+  }
+  /// Record received and applied commits to the trace file
+  /// Replaces the current recording if any, see trace::replay
+  pub fn record_trace(&self, path: &Path) -> Result<(), String> {
+    let recorder = TraceRecorder::create(path)?;
+    *self.trace_recorder.lock().unwrap() = Some(Arc::new(recorder));
+    Ok(())
+  }
+  /// Stop recording the trace file
+  pub fn stop_trace(&self) {
+    *self.trace_recorder.lock().unwrap() = None;
+  }
+  // Event is only built if recording
+  // Notify sync progress hooks
+  // Storages of the current commit are only read if there are hooks
+  fn report_sync_progress(
+    &self,
+    progress: &SyncProgress,
+    current: Option<&Commit>,
+  ) {
+    let hooks = self.sync_progress_hooks.lock().unwrap();
+    if hooks.is_empty() {
+      return;
+    }
+    let mut progress = progress.clone();
+    if let Some(commit) = current {
+      let storage_ids: BTreeSet<String> = commit
+        .serialized_actions
+        .iter()
+        .filter_map(|aob| storage_id_of(aob).ok())
+        .collect();
+      progress.storage_ids = storage_ids.into_iter().collect();
+    }
+    for hook in hooks.iter() {
+      hook(&progress);
+    }
+  }
+  /// Background tasks of the repository, e.g. watchers, see worker
+  pub fn worker_tasks(&self) -> Vec<TaskStatus> {
+    self.worker.tasks()
+  }
+  /// Audit log events matching the filter, in the order recorded
+  /// see audit
+  pub fn audit_log(
+    &self,
+    filter: AuditFilter,
+  ) -> Result<Vec<AuditEvent>, String> {
+    let ctx = self.ctx();
+    let _audit_log = self.audit_log.lock().unwrap();
+    AuditLog::load(&ctx, &filter)
+  }
+  // Record operation of the repository uid with its result
+  // Must not be called holding a context guard
+  fn audit<T>(&self, action: AuditAction, res: &Result<T, String>) {
+    let uid = self.ctx().uid.to_string();
+    self.record_audit_event(AuditEvent::new(&uid, action).with_result(res));
+  }
+  // Must not be called holding a context guard
+  pub(crate) fn record_audit_event(&self, event: AuditEvent) {
+    let ctx = self.ctx();
+    let _audit_log = self.audit_log.lock().unwrap();
+    AuditLog::append(&ctx, &event);
+  }
+  // Records rejected requests of the server auth interceptor
+  fn audit_sink(&self) -> AuditSink {
+    let repo = self.handle();
+    Arc::new(move |event| repo.record_audit_event(event))
+  }
+  /// Stable id of this device, see device
+  pub fn device_id(&self) -> &str {
+    &self.device_id
+  }
+  /// Devices registered with this server, in the order first seen
+  pub fn devices(&self) -> Result<Vec<DeviceRecord>, String> {
+    let ctx = self.ctx();
+    let _devices = self.devices.lock().unwrap();
+    DeviceRegistry::load(&ctx)
+  }
+  // Insert or update the record of a registering client device
+  pub(crate) fn register_device(
+    &self,
+    device_id: &str,
+    uid: &str,
+    app_version: Option<String>,
+  ) -> Result<DeviceRecord, String> {
+    let ctx = self.ctx();
+    let _devices = self.devices.lock().unwrap();
+    DeviceRegistry::register(&ctx, device_id, uid, app_version)
+  }
+  // Registered client device, None if unknown
+  pub(crate) fn device(
+    &self,
+    device_id: &str,
+  ) -> Result<Option<DeviceRecord>, String> {
+    let ctx = self.ctx();
+    let _devices = self.devices.lock().unwrap();
+    DeviceRegistry::get(&ctx, device_id)
+  }
+  /// Reject later pushes of the registered device, see device
+  /// Recorded in the audit log
+  pub fn revoke_device(&self, device_id: &str) -> Result<DeviceRecord, String> {
+    let uid = self.ctx().uid.to_string();
+    self.revoke_device_as(&uid, device_id)
+  }
+  // Revoke device on behalf of admin uid
+  pub(crate) fn revoke_device_as(
+    &self,
+    uid: &str,
+    device_id: &str,
+  ) -> Result<DeviceRecord, String> {
+    let res = self.set_device_flag(device_id, |record| record.revoked = true);
+    let action = AuditAction::RevokeDevice {
+      device_id: device_id.to_string(),
+    };
+    self.record_audit_event(AuditEvent::new(uid, action).with_result(&res));
+    res
+  }
+  /// Make the next pull of the registered device start from scratch
+  /// Its pulls after a cursor are answered with RESYNC_REQUIRED until
+  /// it pulls the whole remote history. Recorded in the audit log
+  pub fn force_resync(&self, device_id: &str) -> Result<DeviceRecord, String> {
+    let uid = self.ctx().uid.to_string();
+    self.force_resync_as(&uid, device_id)
+  }
+  // Force resync of device on behalf of admin uid
+  pub(crate) fn force_resync_as(
+    &self,
+    uid: &str,
+    device_id: &str,
+  ) -> Result<DeviceRecord, String> {
+    let res =
+      self.set_device_flag(device_id, |record| record.resync_required = true);
+    let action = AuditAction::ForceResync {
+      device_id: device_id.to_string(),
+    };
+    self.record_audit_event(AuditEvent::new(uid, action).with_result(&res));
+    res
+  }
+  fn set_device_flag(
+    &self,
+    device_id: &str,
+    f: impl FnOnce(&mut DeviceRecord),
+  ) -> Result<DeviceRecord, String> {
+    let ctx = self.ctx();
+    let _devices = self.devices.lock().unwrap();
+    DeviceRegistry::update(&ctx, device_id, f)?
+      .ok_or_else(|| format!("Unknown device {}", device_id))
+  }
+  // Record merged push of a registered client device
+  pub(crate) fn record_device_push(&self, device_id: &str) {
+    self.record_device_sync(device_id, |_| ());
+  }
+  // Record pull of a registered client device up to cursor
+  // A pull from scratch fulfills a forced resync
+  pub(crate) fn record_device_pull(
+    &self,
+    device_id: &str,
+    from_scratch: bool,
+    cursor: Option<Uuid>,
+  ) {
+    self.record_device_sync(device_id, |record| {
+      if cursor.is_some() {
+        record.cursor = cursor;
+      }
+      if from_scratch {
+        record.resync_required = false;
+      }
+    });
+  }
+  // Unknown devices are ignored
+  // Failing to record never fails the sync
+  fn record_device_sync(
+    &self,
+    device_id: &str,
+    f: impl FnOnce(&mut DeviceRecord),
+  ) {
+    let ctx = self.ctx();
+    let _devices = self.devices.lock().unwrap();
+    let res = DeviceRegistry::update(&ctx, device_id, |record| {
+      record.last_sync = Some(Utc::now());
+      f(record);
+    });
+    if let Err(e) = res {
+      warn!(device_id, error = %e, "Device sync not recorded");
+    }
+  }
+  // Register this device with remote once per session
+  // Failing registration is logged, syncing goes on
+  async fn ensure_device_registered(
+    &self,
+    remote: &str,
+    transport: &mut Transport,
+  ) {
+    if self.registered_remotes.lock().unwrap().contains(remote) {
+      return;
+    }
+    let app_version = self.ctx().app_version.clone();
+    match transport.register(&self.device_id, app_version).await {
+      Ok(()) => {
+        self
+          .registered_remotes
+          .lock()
+          .unwrap()
+          .insert(remote.to_string());
+      }
+      Err(e) => warn!(remote, error = %e, "Device registration failed"),
+    }
+  }
+  fn record_trace_event(&self, event: impl FnOnce() -> TraceEvent) {
+    let recorder = self.trace_recorder.lock().unwrap().clone();
+    if let Some(recorder) = recorder {
+      recorder.record(&event());
+    }
+  }
+  pub fn local_commits(&self) -> Result<Vec<Commit>, String> {
+    CommitLog::load_locals(&self.ctx())
+  }
+  pub fn remote_commits(&self) -> Result<Vec<Commit>, String> {
+    CommitLog::load_remotes(&self.ctx())
+  }
+  /// Every commit, remote ones first then local ones
+  /// Logs are read lazily, e.g. to walk the whole history
+  pub fn iter_commits(
+    &self,
+  ) -> Result<impl Iterator<Item = Result<Commit, String>>, String> {
+    let ctx = self.ctx();
+    let remotes = CommitLog::iter(&ctx, path_helper::commit_remote_log(&ctx))?;
+    let locals = CommitLog::iter(&ctx, path_helper::commit_local_log(&ctx))?;
+    Ok(remotes.chain(locals))
+  }
+  /// Commits matching the filter, remote ones first then local ones
+  /// Logs are scanned lazily, stopping once the page is full
+  pub fn commits(&self, filter: CommitFilter) -> Result<Vec<Commit>, String> {
+    let ctx = self.ctx();
+    let remotes = CommitLog::iter(&ctx, path_helper::commit_remote_log(&ctx))?;
+    let locals = CommitLog::iter(&ctx, path_helper::commit_local_log(&ctx))?;
+    let mut res = vec![];
+    let mut skipped = 0;
+    for commit in remotes.chain(locals) {
+      if filter.limit == Some(res.len()) {
+        break;
+      }
+      let commit = commit?;
+      if !filter.matches(&commit) {
+        continue;
+      }
+      match skipped < filter.offset {
+        true => skipped += 1,
+        false => res.push(commit),
+      }
+    }
+    Ok(res)
+  }
+  /// Remote commits after the given one
+  /// None if the commit is not in the remote log, e.g. after compaction
+  pub fn remote_commits_after(
+    &self,
+    after_id: Uuid,
+  ) -> Result<Option<Vec<Commit>>, String> {
+    CommitLog::load_remotes_after(&self.ctx(), after_id)
+  }
+  /// Recover commit logs after a crash
+  /// Truncates both logs at their first truncated or corrupted record,
+  /// reporting how many records were salvaged
+  pub fn recover_commit_logs(&self) -> Result<CommitLogRecovery, String> {
+    let ctx = self.ctx();
+    let _commit_log = self.commit_log.lock().unwrap();
+    CommitLog::recover(&ctx)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+  struct User {
+    name: String,
+    age: i32,
+  }
 
-    unimplemented!()
+  impl ObjectExt for User {}
+
+  #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+  enum UserAction {
+    SetAge(i32),
+    IncrementAge(i32),
   }
 
-  /// Pull remote repository
-  pub fn proceed_pull(&self) -> Result<(), String> {
-    let remote_addr = match &self.repo_details.lock().unwrap().mode {
-      Mode::Remote { remote_url } => remote_url.to_string(),
-      _ => {
-        panic!("Cannot proceed pull operation, as the repository is not in remote mode")
+  impl ActionExt for UserAction {
+    type ObjectType = User;
+    fn apply_patch(
+      &self,
+      object: &User,
+      _dtime: DateTime<Utc>,
+      _uid: &str,
+    ) -> Result<User, String> {
+      let mut object = object.clone();
+      match self {
+        UserAction::SetAge(age) => object.age = *age,
+        UserAction::IncrementAge(delta) => object.age += delta,
       }
-    };
+      Ok(object)
+    }
+    // Every action sets the age
+    fn conflicts_with(&self, _remote: &Self) -> bool {
+      true
+    }
+    fn is_additive(&self) -> bool {
+      matches!(self, UserAction::IncrementAge(_))
+    }
+    fn display(&self) -> String {
+      format!("{:?}", self)
+    }
+  }
 
-    let runtime = tokio::runtime::Builder::new_current_thread()
-      .enable_all()
-      .worker_threads(1)
-      .thread_name("sync_server")
-      .build()
-      .unwrap();
+  fn users(repo: &Repository) -> Result<Storage<User, UserAction>, String> {
+    Storage::load_or_init(repo, "users".into())?.register(repo)
+  }
 
-    let ctx = self.ctx();
+  fn create_user(
+    repo: &Repository,
+    storage: &Storage<User, UserAction>,
+    age: i32,
+  ) -> Result<Uuid, String> {
+    let mut ctx = repo.commit_ctx("Create user");
+    storage.create_object(
+      User {
+        name: "anna".into(),
+        age,
+      },
+      &mut ctx,
+    );
+    ctx.commit()?.into_result()
+  }
 
-    // Get last local remote commit id
-    let after_commit_id = CommitIndex::latest_remote_commit_id(&ctx)
-      .map(|i| i.to_string())
-      .unwrap_or("".to_string());
+  // Memory backend failing the next write of the given key
+  #[derive(Clone, Default)]
+  struct FailingBackend {
+    inner: MemoryBackend,
+    fail_write: Arc<Mutex<Option<PathBuf>>>,
+  }
 
-    runtime.block_on(async {
-      let mut remote_client = ApiClient::connect(remote_addr)
-        .await
-        .expect("Could not connect to UPL service");
+  impl FailingBackend {
+    fn fail_next_write(&self, key: PathBuf) {
+      *self.fail_write.lock().unwrap() = Some(key);
+    }
+    fn check_write(&self, key: &Path) -> Result<(), String> {
+      let mut fail_write = self.fail_write.lock().unwrap();
+      if fail_write.as_deref() == Some(key) {
+        *fail_write = None;
+        return Err(format!("Failed to write {:?}", key));
+      }
+      Ok(())
+    }
+  }
 
-      let mut res = remote_client
-        .pull(PullRequest { after_commit_id })
-        .await
-        .unwrap()
-        .into_inner();
+  impl Backend for FailingBackend {
+    fn get(&self, key: &Path) -> Result<Vec<u8>, String> {
+      self.inner.get(key)
+    }
+    fn put(&self, key: &Path, data: &[u8]) -> Result<(), String> {
+      self.check_write(key)?;
+      self.inner.put(key, data)
+    }
+    fn append(&self, key: &Path, data: &[u8]) -> Result<(), String> {
+      self.check_write(key)?;
+      self.inner.append(key, data)
+    }
+    fn scan(&self, prefix: &Path) -> Result<Vec<PathBuf>, String> {
+      self.inner.scan(prefix)
+    }
+    fn delete(&self, key: &Path) -> Result<(), String> {
+      self.inner.delete(key)
+    }
+    fn exists(&self, key: &Path) -> bool {
+      self.inner.exists(key)
+    }
+    fn is_persistent(&self) -> bool {
+      false
+    }
+  }
 
-      let mut commits = vec![];
+  fn age_of(
+    repo: &Repository,
+    storage: &Storage<User, UserAction>,
+    id: Uuid,
+  ) -> i32 {
+    storage
+      .get_object_by_id(&repo.ctx().clone(), id)
+      .unwrap()
+      .age
+  }
 
-      while let Some(commit) = res.message().await.unwrap() {
-        commits.push(commit);
-      }
+  fn user_ids(
+    repo: &Repository,
+    storage: &Storage<User, UserAction>,
+  ) -> Vec<Uuid> {
+    let mut ids = storage
+      .get_all(&repo.ctx().clone())
+      .unwrap()
+      .iter()
+      .map(|so| so.id)
+      .collect::<Vec<_>>();
+    ids.sort();
+    ids
+  }
 
-      for commit_obj in commits {
-        let commit: Commit = serde_json::from_str(&commit_obj.obj_json_string)
-          .expect("Commit deser error");
-        let ctx = self.merge_commit_ctx(commit);
-        drop(ctx)
-      }
-    });
+  #[test]
+  fn test_rollback_failed_apply() {
+    let backend = FailingBackend::default();
+    let ctx = Context::init(PathBuf::from("/"), "anna".into())
+      .with_backend(backend.clone());
+    let repo = Repository::init(ctx, Mode::local()).unwrap();
+    let storage = users(&repo).unwrap();
+    create_user(&repo, &storage, 30).unwrap();
+    create_user(&repo, &storage, 40).unwrap();
+    let ids = user_ids(&repo, &storage);
+    let db = repo.ctx().clone();
+    let ages = ids
+      .iter()
+      .map(|id| age_of(&repo, &storage, *id))
+      .collect::<Vec<_>>();
+    let locals = CommitLog::load_locals(&db).unwrap();
+    // Second object fails to write, after the first one got applied
+    backend
+      .fail_next_write(path_helper::storage_object_path(&db, "users", ids[1]));
+    let mut ctx = repo.commit_ctx("Set ages");
+    for id in &ids {
+      let so = storage.get_object_by_id(&db, *id).unwrap();
+      so.patch(UserAction::SetAge(50), &mut ctx).unwrap();
+    }
+    let report = ctx.commit().unwrap();
+    assert!(!report.is_ok());
+    for (id, age) in ids.iter().zip(ages) {
+      assert_eq!(age_of(&repo, &storage, *id), age);
+    }
+    let after = CommitLog::load_locals(&db).unwrap();
+    assert_eq!(
+      after.iter().map(|c| c.id).collect::<Vec<_>>(),
+      locals.iter().map(|c| c.id).collect::<Vec<_>>()
+    );
+    assert!(!repo
+      .ctx()
+      .backend()
+      .exists(&path_helper::commit_intent(&db)));
+    // Repository keeps working after the rollback
+    let mut ctx = repo.commit_ctx("Set age");
+    let so = storage.get_object_by_id(&db, ids[0]).unwrap();
+    so.patch(UserAction::SetAge(60), &mut ctx).unwrap();
+    ctx.commit().unwrap().into_result().unwrap();
+    assert_eq!(age_of(&repo, &storage, ids[0]), 60);
+  }
 
-    Ok(())
+  #[test]
+  fn test_commit_across_storages_all_or_nothing() {
+    let backend = FailingBackend::default();
+    let ctx = Context::init(PathBuf::from("/"), "anna".into())
+      .with_backend(backend.clone());
+    let repo = Repository::init(ctx, Mode::local()).unwrap();
+    let storage = users(&repo).unwrap();
+    let admins =
+      Storage::<User, UserAction>::load_or_init(&repo, "admins".into())
+        .unwrap()
+        .register(&repo)
+        .unwrap();
+    create_user(&repo, &storage, 30).unwrap();
+    create_user(&repo, &admins, 40).unwrap();
+    let user_id = user_ids(&repo, &storage)[0];
+    let admin_id = user_ids(&repo, &admins)[0];
+    let db = repo.ctx().clone();
+    let locals = CommitLog::load_locals(&db).unwrap().len();
+    let set_ages = |age: i32| {
+      let mut ctx = repo.commit_ctx("Set ages");
+      let user = storage.get_object_by_id(&db, user_id).unwrap();
+      user.patch(UserAction::SetAge(age), &mut ctx).unwrap();
+      let admin = admins.get_object_by_id(&db, admin_id).unwrap();
+      admin.patch(UserAction::SetAge(age), &mut ctx).unwrap();
+      ctx.commit()
+    };
+    // Failing store leaves nothing behind
+    backend.fail_next_write(path_helper::commit_local_log(&db));
+    assert!(set_ages(50).is_err());
+    // Failing apply in the second storage rolls back the first one
+    backend.fail_next_write(path_helper::storage_object_path(
+      &db, "admins", admin_id,
+    ));
+    assert!(!set_ages(50).unwrap().is_ok());
+    assert_eq!(age_of(&repo, &storage, user_id), 30);
+    assert_eq!(age_of(&repo, &admins, admin_id), 40);
+    assert_eq!(CommitLog::load_locals(&db).unwrap().len(), locals);
+    assert!(!db.backend().exists(&path_helper::commit_intent(&db)));
+    set_ages(50).unwrap().into_result().unwrap();
+    assert_eq!(age_of(&repo, &storage, user_id), 50);
+    assert_eq!(age_of(&repo, &admins, admin_id), 50);
   }
-  /// Push repository local commits to remote
-  pub fn proceed_push(&self) -> Result<(), String> {
-    // Before push operation
-    // Proceed pull
-    self.proceed_pull()?;
 
-    let remote_addr = match &self.repo_details.lock().unwrap().mode {
-      Mode::Remote { remote_url } => remote_url.to_string(),
-      _ => {
-        panic!("Cannot proceed push operation, as the repository is not in remote mode")
-      }
+  #[test]
+  fn test_open_rolls_back_interrupted_commit() {
+    let repo =
+      Repository::init(Context::in_memory("anna".into()), Mode::local())
+        .unwrap();
+    let storage = users(&repo).unwrap();
+    create_user(&repo, &storage, 30).unwrap();
+    let id = user_ids(&repo, &storage)[0];
+    let ctx = repo.ctx().clone();
+    let object_path = path_helper::storage_object_path(&ctx, "users", id);
+    let details_path = path_helper::storage_details_path(&ctx, "users");
+    let mut files = vec![];
+    for path in [&object_path, &details_path] {
+      let before = ctx.backend().get(path).unwrap();
+      let relative = path.strip_prefix(&ctx.db_root_path).unwrap();
+      files.push((relative.to_path_buf(), Some(before)));
+    }
+    let mut commit = repo.commit_ctx("Set age");
+    let so = storage.get_object_by_id(&ctx, id).unwrap();
+    so.patch(UserAction::SetAge(50), &mut commit).unwrap();
+    let commit_id = commit.commit().unwrap().into_result().unwrap();
+    // Process died before the intent got cleared
+    let intent = CommitIntent {
+      commit_id,
+      is_remote: false,
+      files,
     };
+    binary_write(&ctx, path_helper::commit_intent(&ctx), intent).unwrap();
+    drop((storage, repo));
+    let repo = Repository::load(ctx.clone()).unwrap();
+    let storage = users(&repo).unwrap();
+    assert_eq!(age_of(&repo, &storage, id), 30);
+    let locals = CommitLog::load_locals(&ctx).unwrap();
+    assert!(locals.iter().all(|c| c.id != commit_id));
+    assert!(!ctx.backend().exists(&path_helper::commit_intent(&ctx)));
+  }
 
-    let runtime = tokio::runtime::Builder::new_current_thread()
-      .enable_all()
-      .worker_threads(1)
-      .thread_name("sync_server")
-      .build()
-      .unwrap();
+  fn user_action(
+    object_id: Uuid,
+    action: ActionKind<User, UserAction>,
+    clock: Hlc,
+    dtime: DateTime<Utc>,
+  ) -> ActionObject<User, UserAction> {
+    ActionObject {
+      id: Uuid::new_v4(),
+      storage_id: "users".into(),
+      object_id,
+      uid: "anna".into(),
+      dtime,
+      commit_id: None,
+      parent_action_id: None,
+      action,
+      object_signature: String::new(),
+      schema_hash: None,
+      clock: Some(clock),
+      remote_signature: None,
+    }
+  }
 
-    runtime.block_on(async {
-      let mut remote_client = ApiClient::connect(remote_addr)
-        .await
-        .expect("Could not connect to UPL service");
+  #[test]
+  fn test_rebuild_orders_by_clock() {
+    let clock = HybridClock::default();
+    let now = Utc::now();
+    let user = User {
+      name: "anna".into(),
+      age: 30,
+    };
+    let mut create =
+      user_action(Uuid::new_v4(), ActionKind::Create(user), clock.tick(), now);
+    create.remote_signature = Some(String::new());
+    let mut object = StorageObject::new_from_aob(create.clone(), 0).unwrap();
+    // Later action by its clock, from a client whose clock is behind
+    let first = user_action(
+      object.id,
+      ActionKind::Patch(UserAction::SetAge(40)),
+      clock.tick(),
+      now + chrono::Duration::hours(1),
+    );
+    let second = user_action(
+      object.id,
+      ActionKind::Patch(UserAction::SetAge(50)),
+      clock.tick(),
+      now - chrono::Duration::hours(1),
+    );
+    object.local_actions = vec![second.clone(), first.clone()];
+    object
+      .rebuild_local_objects(None, create.clock, &TakeLocal)
+      .unwrap();
+    assert_eq!(object.local_object.age, 50);
+    assert_eq!(
+      object
+        .local_actions
+        .iter()
+        .map(|a| a.id)
+        .collect::<Vec<_>>(),
+      vec![first.id, second.id]
+    );
+    assert_eq!(object.local_actions[1].parent_action_id, Some(first.id));
+  }
 
-      let local_commits = self
-        .local_commits()
-        .unwrap()
-        .into_iter()
-        .map(|c| CommitObj {
-          obj_json_string: serde_json::to_string(&c).unwrap(),
-        })
-        .collect::<Vec<CommitObj>>();
+  fn increment_age(
+    repo: &Repository,
+    storage: &Storage<User, UserAction>,
+    id: Uuid,
+  ) {
+    let db = repo.ctx().clone();
+    let mut ctx = repo.commit_ctx("Increment age");
+    let so = storage.get_object_by_id(&db, id).unwrap();
+    so.patch(UserAction::IncrementAge(1), &mut ctx).unwrap();
+    ctx.commit().unwrap().into_result().unwrap();
+  }
 
-      let mut commits = vec![];
+  #[test]
+  fn test_concurrent_increments_converge() {
+    for merge_mode in [MergeMode::Rebase, MergeMode::Crdt] {
+      // Colliding local actions are dropped, unless additive
+      let server = crate::testing::TestServer::start(move |repo| {
+        Storage::<User, UserAction>::load_or_init(repo, "users".into())?
+          .with_merge_mode(merge_mode)
+          .with_conflict_resolver(TakeRemote)
+          .register(repo)
+      })
+      .unwrap();
+      let anna = server.client("anna").unwrap();
+      let bob = server.client("bob").unwrap();
+      create_user(&anna.repo, &anna.storages, 30).unwrap();
+      anna.repo.proceed_push().unwrap();
+      bob.repo.proceed_pull().unwrap();
+      let id = user_ids(&bob.repo, &bob.storages)[0];
+      increment_age(&anna.repo, &anna.storages, id);
+      increment_age(&bob.repo, &bob.storages, id);
+      anna.repo.proceed_push().unwrap();
+      match merge_mode {
+        // Rebased on the remote increment while pulling before push
+        MergeMode::Rebase => bob.repo.proceed_push().unwrap(),
+        // Pushed concurrently, the server interleaves it
+        MergeMode::Crdt => bob.repo.push_local_commits(DEFAULT_REMOTE).unwrap(),
+      }
+      anna.repo.proceed_pull().unwrap();
+      assert_eq!(age_of(&anna.repo, &anna.storages, id), 32);
+      assert_eq!(age_of(&bob.repo, &bob.storages, id), 32);
+      assert_eq!(age_of(&server.repo, &server.storages, id), 32);
+      assert!(bob.repo.local_commits().unwrap().is_empty());
+    }
+  }
 
-      for commit in local_commits {
-        info!("Sending commit obj");
-        let mut commit = remote_client.push(commit).await.unwrap().into_inner();
-        info!("Commit received back");
-        commits.push(commit);
+  #[test]
+  fn test_clean_dry_run_and_clean() {
+    let server = crate::testing::TestServer::start(users).unwrap();
+    let anna = server.client("anna").unwrap();
+    create_user(&anna.repo, &anna.storages, 30).unwrap();
+    anna.repo.proceed_push().unwrap();
+    let pushed = user_ids(&anna.repo, &anna.storages)[0];
+    let db = anna.repo.ctx().clone();
+    let mut ctx = anna.repo.commit_ctx("Set age");
+    let so = anna.storages.get_object_by_id(&db, pushed).unwrap();
+    so.patch(UserAction::SetAge(40), &mut ctx).unwrap();
+    ctx.commit().unwrap().into_result().unwrap();
+    create_user(&anna.repo, &anna.storages, 50).unwrap();
+    let local = user_ids(&anna.repo, &anna.storages)
+      .into_iter()
+      .find(|id| *id != pushed)
+      .unwrap();
+    for dry_run in [true, false] {
+      let report = anna.repo.proceed_clean(dry_run).unwrap();
+      assert_eq!(report.local_commits.len(), 2);
+      assert_eq!(report.storages[0].removed_objects, vec![local]);
+      assert_eq!(report.storages[0].cleared_objects, vec![pushed]);
+      assert_eq!(report.storages[0].discarded_actions, 2);
+      if dry_run {
+        // Nothing changed
+        assert_eq!(anna.repo.local_commits().unwrap().len(), 2);
+        assert_eq!(age_of(&anna.repo, &anna.storages, pushed), 40);
+        assert_eq!(age_of(&anna.repo, &anna.storages, local), 50);
       }
+    }
+    assert!(anna.repo.local_commits().unwrap().is_empty());
+    assert_eq!(user_ids(&anna.repo, &anna.storages), vec![pushed]);
+    assert_eq!(age_of(&anna.repo, &anna.storages, pushed), 30);
+  }
 
-      info!("Pushed {} items", commits.len());
-    });
+  #[test]
+  fn test_revert_commit() {
+    let repo =
+      Repository::init(Context::in_memory("anna".into()), Mode::local())
+        .unwrap();
+    let storage = users(&repo).unwrap();
+    create_user(&repo, &storage, 30).unwrap();
+    let id = user_ids(&repo, &storage)[0];
+    let db = repo.ctx().clone();
+    let mut ctx = repo.commit_ctx("Set age");
+    let so = storage.get_object_by_id(&db, id).unwrap();
+    so.patch(UserAction::SetAge(40), &mut ctx).unwrap();
+    let commit_id = ctx.commit().unwrap().into_result().unwrap();
+    let revert_id = repo.revert_commit(commit_id).unwrap();
+    assert_eq!(age_of(&repo, &storage, id), 30);
+    let revert = CommitLog::load_locals(&db)
+      .unwrap()
+      .into_iter()
+      .find(|c| c.id == revert_id)
+      .unwrap();
+    assert!(revert.meta.contains_key(DEVICE_ID_META));
+  }
 
-    // After push operation
-    // Proceed pull to update local storages
-    self.proceed_pull()?;
+  #[test]
+  fn test_revert_create() {
+    let repo =
+      Repository::init(Context::in_memory("anna".into()), Mode::local())
+        .unwrap();
+    let storage = users(&repo).unwrap();
+    let commit_id = create_user(&repo, &storage, 30).unwrap();
+    let id = user_ids(&repo, &storage)[0];
+    repo.revert_commit(commit_id).unwrap();
+    let db = repo.ctx().clone();
+    assert!(storage.get_object_by_id(&db, id).unwrap().is_removed());
+    assert!(user_ids(&repo, &storage).is_empty());
+  }
 
-    Ok(())
+  #[test]
+  fn test_pre_commit_hook_vetoes_revert() {
+    let repo =
+      Repository::init(Context::in_memory("anna".into()), Mode::local())
+        .unwrap();
+    let storage = users(&repo).unwrap();
+    create_user(&repo, &storage, 30).unwrap();
+    let id = user_ids(&repo, &storage)[0];
+    let db = repo.ctx().clone();
+    let mut ctx = repo.commit_ctx("Set age");
+    let so = storage.get_object_by_id(&db, id).unwrap();
+    so.patch(UserAction::SetAge(40), &mut ctx).unwrap();
+    let commit_id = ctx.commit().unwrap().into_result().unwrap();
+    let vetoed = Arc::new(Mutex::new(vec![]));
+    let hook_vetoed = vetoed.clone();
+    repo.on_pre_commit(move |commit| {
+      hook_vetoed.lock().unwrap().push(commit.id);
+      Err("Vetoed".to_string())
+    });
+    let locals = CommitLog::load_locals(&db).unwrap().len();
+    let mut ctx = repo.commit_ctx("Set age");
+    let so = storage.get_object_by_id(&db, id).unwrap();
+    so.patch(UserAction::SetAge(50), &mut ctx).unwrap();
+    assert_eq!(ctx.commit().unwrap_err(), "Vetoed");
+    assert_eq!(repo.revert_commit(commit_id).unwrap_err(), "Vetoed");
+    assert_eq!(vetoed.lock().unwrap().len(), 2);
+    assert_eq!(age_of(&repo, &storage, id), 40);
+    assert_eq!(CommitLog::load_locals(&db).unwrap().len(), locals);
   }
-  /// Clean local repository, clear local changes
-  /// And performs remote pull
-  pub fn proceed_clean(&self) -> Result<(), String> {
-    unimplemented!()
+
+  #[test]
+  fn test_failed_clone_is_removed() {
+    let path = std::env::temp_dir()
+      .join(format!("storage_test_clone_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    let ctx = Context::init(path.clone(), "anna".into());
+    // Nothing listens on port 1
+    let url = "http://127.0.0.1:1";
+    let res = Repository::clone(ctx.clone(), url, |_| {
+      Err::<(), _>("Storage error".to_string())
+    });
+    assert_eq!(res.err().unwrap(), "Storage error");
+    // Only the lock file is left, as it outlives its repository
+    let lock = vec![path_helper::repo_lock(&ctx)];
+    assert_eq!(ctx.backend().scan(&path).unwrap(), lock);
+    // Retry is not refused as an existing repository
+    let res = Repository::clone(ctx.clone(), url, |_| Ok(()));
+    assert!(res
+      .err()
+      .unwrap()
+      .starts_with("Could not connect to remote"));
+    assert_eq!(ctx.backend().scan(&path).unwrap(), lock);
+    std::fs::remove_dir_all(&path).unwrap();
   }
-  /// Start watcher for remote client to watch
-  /// remote updates
-  pub fn watch(&self) -> Result<(), String> {
-    match &self.repo_details.lock().unwrap().mode {
-      Mode::Remote { .. } => {}
-      _ => {
-        panic!(
-          "Cannot start remote watch, as the repository is not in remote mode"
-        )
-      }
+
+  #[test]
+  fn test_failed_pull_resumes_from_cursor() {
+    let server = crate::testing::TestServer::start(users).unwrap();
+    let backend = FailingBackend::default();
+    let ctx = Context::init(PathBuf::from("/"), "bob".into())
+      .with_backend(backend.clone())
+      .with_pull_batch_size(2);
+    let bob = server.client_with_ctx(ctx).unwrap();
+    let anna = server.client("anna").unwrap();
+    let mut commit_ids = vec![];
+    let mut object_ids = vec![];
+    for age in 0..4 {
+      let before = user_ids(&anna.repo, &anna.storages);
+      commit_ids.push(create_user(&anna.repo, &anna.storages, age).unwrap());
+      let after = user_ids(&anna.repo, &anna.storages);
+      object_ids.extend(after.into_iter().filter(|id| !before.contains(id)));
     }
-    unimplemented!()
+    anna.repo.proceed_push().unwrap();
+    // First commit of the second batch fails to apply
+    let db = bob.repo.ctx().clone();
+    backend.fail_next_write(path_helper::storage_object_path(
+      &db,
+      "users",
+      object_ids[2],
+    ));
+    assert!(bob.repo.proceed_pull().is_err());
+    assert_eq!(user_ids(&bob.repo, &bob.storages).len(), 2);
+    assert_eq!(
+      CommitIndex::remote_cursor(&db, DEFAULT_REMOTE),
+      Some(commit_ids[1])
+    );
+    let summary = bob.repo.proceed_pull().unwrap();
+    assert_eq!(summary.commits_applied, 2);
+    assert_eq!(summary.commits_skipped, 0);
+    assert_eq!(user_ids(&bob.repo, &bob.storages).len(), 4);
+    assert_eq!(
+      CommitIndex::remote_cursor(&db, DEFAULT_REMOTE),
+      Some(commit_ids[3])
+    );
   }
-  /// Merge pushed commit to remote one
-  /// Returns the applied & signed remote Commit if success
-  pub fn merge_pushed_commit(
-    &self,
-    commit_json_str: &str,
-  ) -> Result<Commit, String> {
-    // Lock itself
-    let mut ctx = self.commit_ctx("");
 
-    // 1) Check Commit
-    // Deserialize commit object
-    let mut commit: Commit = serde_json::from_str(commit_json_str)
-      .map_err(|_| "Deser error during commit deser process".to_string())?;
-    // Check signature
-    if commit.remote_signature.is_some() {
-      return Err(
-        "Pushed commit has remote signature. Only local commits can be pushed!"
-          .to_string(),
-      );
+  #[test]
+  fn test_raw_actions_applied_once() {
+    let server = crate::testing::TestServer::start(users).unwrap();
+    let anna = server.client("anna").unwrap();
+    // Bob has no users storage yet
+    let backend = FailingBackend::default();
+    let ctx = Context::init(PathBuf::from("/"), "bob".into())
+      .with_backend(backend.clone());
+    let (bob, _) = Repository::clone(ctx, server.url(), |repo| {
+      repo.set_unknown_storage_policy(UnknownStoragePolicy::StoreRaw);
+      Ok::<_, String>(())
+    })
+    .unwrap();
+    let mut ctx = anna.repo.commit_ctx("Create users");
+    for age in [30, 40] {
+      let user = User {
+        name: "anna".into(),
+        age,
+      };
+      anna.storages.create_object(user, &mut ctx);
     }
+    ctx.commit().unwrap().into_result().unwrap();
+    let action_ids = CommitLog::load_locals(&anna.repo.ctx().clone()).unwrap()
+      [0]
+      .serialized_actions
+      .iter()
+      .map(|aob_str| {
+        serde_json::from_str::<UniversalActionObject>(aob_str)
+          .unwrap()
+          .id
+      })
+      .collect::<Vec<_>>();
+    anna.repo.proceed_push().unwrap();
+    // Second raw action fails to store, the first one is rolled back
+    let db = bob.ctx().clone();
+    let raw_path = path_helper::raw_actions_path(&db, "users");
+    backend
+      .fail_next_write(raw_path.join(action_ids[1].as_simple().to_string()));
+    assert!(bob.proceed_pull().is_err());
+    assert!(db.backend().scan(&raw_path).unwrap().is_empty());
+    bob.proceed_pull().unwrap();
+    assert_eq!(db.backend().scan(&raw_path).unwrap().len(), 2);
+    let storage = users(&bob).unwrap();
+    let ages = user_ids(&bob, &storage)
+      .into_iter()
+      .map(|id| age_of(&bob, &storage, id))
+      .collect::<BTreeSet<_>>();
+    assert_eq!(ages, BTreeSet::from([30, 40]));
+    assert!(db.backend().scan(&raw_path).unwrap().is_empty());
+  }
 
-    // Check ancestor
-    if let Some(latest_remote_commit_id) =
-      CommitIndex::latest_remote_commit_id(&ctx)
-    {
-      // Only if not first commit
-      if commit.ancestor_id != latest_remote_commit_id {
-        // Return error if ancestor id is wrong
-        return Err(
-          "Commit ancestor id erro. Local repo not up-to-date. Pull required."
-            .to_string(),
-        );
-      }
+  #[test]
+  fn test_rollback_restores_object_log() {
+    let server = crate::testing::TestServer::start(users).unwrap();
+    let anna = server.client("anna").unwrap();
+    let backend = FailingBackend::default();
+    let ctx = Context::init(PathBuf::from("/"), "bob".into())
+      .with_backend(backend.clone());
+    let bob = server.client_with_ctx(ctx).unwrap();
+    create_user(&anna.repo, &anna.storages, 30).unwrap();
+    anna.repo.proceed_push().unwrap();
+    bob.repo.proceed_pull().unwrap();
+    let id = user_ids(&bob.repo, &bob.storages)[0];
+    increment_age(&anna.repo, &anna.storages, id);
+    anna.repo.proceed_push().unwrap();
+    // Object fails to write after its action log got appended
+    let db = bob.repo.ctx().clone();
+    let log_path = path_helper::storage_object_log_path(&db, "users", id);
+    let log = db.backend().get(&log_path).unwrap();
+    backend.fail_next_write(path_helper::storage_object_path(&db, "users", id));
+    assert!(bob.repo.proceed_pull().is_err());
+    assert_eq!(db.backend().get(&log_path).unwrap(), log);
+    // Pulled again, the action is logged once
+    bob.repo.proceed_pull().unwrap();
+    assert_eq!(age_of(&bob.repo, &bob.storages, id), 31);
+    let logged =
+      binary_continuous_read::<StoredAction, StoredAction>(&db, log_path)
+        .unwrap();
+    assert_eq!(logged.len(), 2);
+  }
+
+  #[test]
+  fn test_purge_removed_objects() {
+    let server = crate::testing::TestServer::start(|repo| {
+      // Removed objects are due at once
+      let retention =
+        Retention::new().with_purge_removed_after(chrono::Duration::zero());
+      Storage::<User, UserAction>::load_or_init(repo, "users".into())?
+        .with_retention(retention)
+        .register(repo)
+    })
+    .unwrap();
+    let anna = server.client("anna").unwrap();
+    create_user(&anna.repo, &anna.storages, 30).unwrap();
+    create_user(&anna.repo, &anna.storages, 40).unwrap();
+    create_user(&anna.repo, &anna.storages, 50).unwrap();
+    let ids = user_ids(&anna.repo, &anna.storages);
+    let db = anna.repo.ctx().clone();
+    let mut ctx = anna.repo.commit_ctx("Remove users");
+    for id in &ids[..2] {
+      let so = anna.storages.get_object_by_id(&db, *id).unwrap();
+      so.remove(&mut ctx).unwrap();
     }
+    ctx.commit().unwrap().into_result().unwrap();
+    anna.repo.proceed_push().unwrap();
+    // Second removed object is kept by legal hold
+    let server_db = server.repo.ctx().clone();
+    server
+      .storages
+      .set_object_legal_hold(&server_db, ids[1], true)
+      .unwrap();
+    let report = server.repo.purge().unwrap();
+    assert_eq!(report.storages[0].purged_objects, vec![ids[0]]);
+    assert_eq!(report.storages[0].held_objects, vec![ids[1]]);
+    assert_eq!(report.storages[0].purged_actions, 2);
+    assert_eq!(report.rewritten_commits, 2);
+    let tombstones = server.storages.tombstones(&server_db).unwrap();
+    assert_eq!(tombstones.len(), 1);
+    assert_eq!(tombstones[0].object_id, ids[0]);
+    assert!(server
+      .storages
+      .get_object_by_id(&server_db, ids[0])
+      .is_err());
+    assert!(server
+      .storages
+      .get_object_by_id(&server_db, ids[1])
+      .unwrap()
+      .is_removed());
+    // Action chains stay intact
+    assert!(crate::verify::verify_all(&server.repo).unwrap().is_clean());
+    assert!(server.repo.fsck(true).unwrap().is_clean());
+    // Purged history is accepted by new clones
+    let bob = server.client("bob").unwrap();
+    assert_eq!(user_ids(&bob.repo, &bob.storages), vec![ids[2]]);
+    let purged = bob
+      .repo
+      .remote_commits()
+      .unwrap()
+      .iter()
+      .flat_map(|commit| commit.serialized_actions.clone())
+      .map(|aob_str| {
+        serde_json::from_str::<UniversalActionObject>(&aob_str).unwrap()
+      })
+      .filter(|uaob| uaob.object_id == ids[0])
+      .collect::<Vec<_>>();
+    assert_eq!(purged.len(), 2);
+    assert!(purged.iter().all(|uaob| uaob.is_purged()));
+    // Purge is idempotent
+    let report = server.repo.purge().unwrap();
+    assert!(report.storages[0].purged_objects.is_empty());
+  }
 
-    // 2) Sign all action objects
+  #[test]
+  fn test_migrate_format_round_trip() {
+    let backend = MemoryBackend::default();
+    let open_ctx = || {
+      Context::init(PathBuf::from("/"), "anna".into())
+        .with_backend(backend.clone())
+    };
+    let repo = Repository::init(open_ctx(), Mode::local()).unwrap();
+    let storage = users(&repo).unwrap();
+    create_user(&repo, &storage, 30).unwrap();
+    create_user(&repo, &storage, 40).unwrap();
+    let ids = user_ids(&repo, &storage);
+    let ages = |repo: &Repository, storage: &Storage<User, UserAction>| {
+      ids
+        .iter()
+        .map(|id| age_of(repo, storage, *id))
+        .collect::<Vec<_>>()
+    };
+    let before = ages(&repo, &storage);
+    let db = repo.ctx().clone();
+    let locals = CommitLog::load_locals(&db).unwrap();
+    storage.set_object_legal_hold(&db, ids[0], true).unwrap();
+    repo.migrate_format(Format::Json).unwrap();
+    drop((storage, repo));
+    let repo = Repository::load(open_ctx()).unwrap();
+    assert_eq!(repo.ctx().format(), Format::Json);
+    let storage = users(&repo).unwrap();
+    assert_eq!(user_ids(&repo, &storage), ids);
+    assert_eq!(ages(&repo, &storage), before);
+    let db = repo.ctx().clone();
+    let holds = storage.legal_holds(&db).unwrap();
+    assert_eq!(holds.objects, BTreeSet::from([ids[0]]));
+    let migrated = CommitLog::load_locals(&db).unwrap();
+    assert_eq!(
+      migrated.iter().map(|c| c.id).collect::<Vec<_>>(),
+      locals.iter().map(|c| c.id).collect::<Vec<_>>()
+    );
+    // Migrated repository keeps working
+    create_user(&repo, &storage, 50).unwrap();
+    assert_eq!(user_ids(&repo, &storage).len(), 3);
+  }
 
-    // Deserialize action objects as universal aob
-    let mut action_objects: Vec<UniversalActionObject> = vec![];
-    for aob in &commit.serialized_actions {
-      action_objects.push(
-        serde_json::from_str(aob).map_err(|_| {
-          "Error while deser aob into universal aob".to_string()
-        })?,
+  #[test]
+  fn test_interrupted_migration_detected() {
+    let backend = FailingBackend::default();
+    let open_ctx = || {
+      Context::init(PathBuf::from("/"), "anna".into())
+        .with_backend(backend.clone())
+    };
+    let repo = Repository::init(open_ctx(), Mode::local()).unwrap();
+    let storage = users(&repo).unwrap();
+    create_user(&repo, &storage, 30).unwrap();
+    // Storages got migrated, repository details did not
+    let db = repo.ctx().clone();
+    backend.fail_next_write(path_helper::repo_details(&db));
+    assert!(repo.migrate_format(Format::Json).is_err());
+    drop((storage, repo));
+    let err = Repository::load(open_ctx()).err().unwrap();
+    assert!(err.contains("Format migration to Json was interrupted"));
+  }
+
+  #[test]
+  fn test_snapshot_restore() {
+    let server = crate::testing::TestServer::start(users).unwrap();
+    let anna = server.client("anna").unwrap();
+    create_user(&anna.repo, &anna.storages, 30).unwrap();
+    anna.repo.proceed_push().unwrap();
+    create_user(&anna.repo, &anna.storages, 40).unwrap();
+    let path = std::env::temp_dir()
+      .join(format!("storage_test_snapshot_{}", std::process::id()));
+    anna.repo.snapshot(path.clone()).unwrap();
+    let restored =
+      Repository::restore(Context::in_memory("anna".into()), path.clone());
+    std::fs::remove_file(&path).unwrap();
+    let restored = restored.unwrap();
+    let storage = users(&restored).unwrap();
+    let ids = user_ids(&anna.repo, &anna.storages);
+    assert_eq!(user_ids(&restored, &storage), ids);
+    for id in ids {
+      assert_eq!(
+        age_of(&restored, &storage, id),
+        age_of(&anna.repo, &anna.storages, id)
       );
     }
+    let commit_ids =
+      |commits: Vec<Commit>| commits.iter().map(|c| c.id).collect::<Vec<_>>();
+    assert_eq!(
+      commit_ids(restored.remote_commits().unwrap()),
+      commit_ids(anna.repo.remote_commits().unwrap())
+    );
+    assert_eq!(
+      commit_ids(restored.local_commits().unwrap()),
+      commit_ids(anna.repo.local_commits().unwrap())
+    );
+    // Restored repository keeps syncing
+    restored.proceed_push().unwrap();
+    assert_eq!(server.repo.remote_commits().unwrap().len(), 2);
+  }
 
-    // Clear action objects
-    commit.serialized_actions = vec![];
+  #[test]
+  fn test_restore_over_existing_repository() {
+    let repo =
+      Repository::init(Context::in_memory("anna".into()), Mode::local())
+        .unwrap();
+    let storage = users(&repo).unwrap();
+    create_user(&repo, &storage, 30).unwrap();
+    let path = std::env::temp_dir()
+      .join(format!("storage_test_restore_{}", std::process::id()));
+    repo.snapshot(path.clone()).unwrap();
+    create_user(&repo, &storage, 40).unwrap();
+    let res = Repository::restore(repo.ctx().clone(), path.clone());
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+      res.err().unwrap(),
+      "Existing repository. Cannot restore snapshot"
+    );
+    // Existing repository is left untouched
+    assert_eq!(user_ids(&repo, &storage).len(), 2);
+    assert_eq!(repo.local_commits().unwrap().len(), 2);
+  }
 
-    for mut uaob in action_objects {
-      // Sign action object to be a remote one
-      uaob.remote_sign()?;
-      // Add action object back again
-      commit.add_action_object(uaob);
-    }
+  // User of schema version 0, age was called years
+  #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+  struct UserV0 {
+    name: String,
+    years: i32,
+  }
 
-    // 3) Check all action objects (Ancestor + Action + Signature)
-    let hooks = &ctx.storage_hooks;
-    for aob_str in &commit.serialized_actions {
-      let mut found_hook = false;
-      for hook in hooks.deref() {
-        let res = hook(aob_str, CallbackMode::Check);
-        if let Some(res) = res {
-          match res {
-            Ok(()) => {
-              found_hook = true;
-              break;
-            }
-            Err(e) => {
-              return Err(e);
-            }
-          }
-        }
-      }
-      if !found_hook {
-        return Err("Unknown storage.".to_string());
+  impl ObjectExt for UserV0 {}
+
+  #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+  enum UserV0Action {
+    SetYears(i32),
+  }
+
+  impl ActionExt for UserV0Action {
+    type ObjectType = UserV0;
+    fn apply_patch(
+      &self,
+      object: &UserV0,
+      _dtime: DateTime<Utc>,
+      _uid: &str,
+    ) -> Result<UserV0, String> {
+      let mut object = object.clone();
+      match self {
+        UserV0Action::SetYears(years) => object.years = *years,
       }
+      Ok(object)
     }
+    fn display(&self) -> String {
+      format!("{:?}", self)
+    }
+  }
 
-    // 4) ReCreate commit with signature and signed ActionObject
-    commit.add_remote_signature()?;
+  // Renames years to age
+  struct UserMigrator;
 
-    // 5) Add commit as remote commit
-    //    merge_commit_ctx will create a merge commit context with the
-    //    prepared commit, and it will auto merge into remote as merge_commit_ctx drops
-    ctx.temp_commit = commit.clone();
-    // 6) Return remote commit
-    Ok(commit)
-  }
-  /// Start remote server
-  pub fn serve(self) -> Result<(), String> {
-    let server_addr = match &self.repo_details.lock().unwrap().mode {
-      Mode::Server { server_addr } => server_addr.to_string(),
-      _ => {
-        panic!("Cannot start server, as the repository is not in server mode")
+  impl Migrator for UserMigrator {
+    fn schema_version(&self) -> u32 {
+      1
+    }
+    fn migrate(
+      &self,
+      from_version: u32,
+      mut payload: Value,
+    ) -> Result<Value, String> {
+      match from_version {
+        0 => {
+          let fields = payload.as_object_mut().ok_or("Not an object")?;
+          let years = fields.remove("years").ok_or("No years")?;
+          fields.insert("age".into(), years);
+          Ok(payload)
+        }
+        _ => Err(format!("Unknown version {}", from_version)),
       }
+    }
+  }
+
+  #[test]
+  fn test_schema_migration() {
+    let backend = MemoryBackend::default();
+    let open_ctx = || {
+      Context::init(PathBuf::from("/"), "anna".into())
+        .with_backend(backend.clone())
+    };
+    let repo = Repository::init(open_ctx(), Mode::local()).unwrap();
+    let storage =
+      Storage::<UserV0, UserV0Action>::load_or_init(&repo, "users".into())
+        .unwrap()
+        .register(&repo)
+        .unwrap();
+    let mut ctx = repo.commit_ctx("Create user");
+    let user = UserV0 {
+      name: "anna".into(),
+      years: 30,
     };
-    let runtime = tokio::runtime::Builder::new_current_thread()
-      .enable_all()
-      .worker_threads(1)
-      .thread_name("sync_server")
-      .build()
+    storage.create_object(user, &mut ctx);
+    ctx.commit().unwrap().into_result().unwrap();
+    drop((storage, repo));
+    // Object stored at version 0 is migrated on read
+    let repo = Repository::load(open_ctx()).unwrap();
+    let storage = Storage::<User, UserAction>::load_or_init_with_migrator(
+      &repo,
+      "users".into(),
+      UserMigrator,
+    )
+    .unwrap()
+    .register(&repo)
+    .unwrap();
+    assert_eq!(storage.schema_version(), 1);
+    let id = user_ids(&repo, &storage)[0];
+    assert_eq!(age_of(&repo, &storage, id), 30);
+    increment_age(&repo, &storage, id);
+    assert_eq!(age_of(&repo, &storage, id), 31);
+    drop((storage, repo));
+    // Version 1 is newer than the one of a storage without migrator
+    let repo = Repository::load(open_ctx()).unwrap();
+    let err = Storage::<User, UserAction>::load_or_init(&repo, "users".into())
+      .err()
       .unwrap();
-    runtime.block_on(async {
-      Server::builder()
-        .add_service(ApiServer::new(self))
-        .serve(server_addr.parse().unwrap())
-        .await
-        .expect("Error starting server");
-    });
-    Ok(())
-  }
-  // Private method to register
-  // storage hooks
-  // Storage update process will occur via these hooks (callbacks)
-  fn add_storage_hook(
-    &self,
-    hook: Box<dyn Fn(&str, CallbackMode) -> Option<Result<(), String>> + Send>,
-  ) -> Result<(), String> {
-    self.storage_hooks.lock().unwrap().push(hook);
-    Ok(())
-  }
-  pub fn ctx<'a>(&'a self) -> ContextGuard {
-    let mutex_guard = (&self.ctx).lock().unwrap();
-    ContextGuard { mutex_guard }
-  }
-  pub fn commit_ctx<'a>(
-    &'a self,
-    commit_comment: &str,
-  ) -> CommitContextGuard<'a> {
-    CommitContextGuard::new(self, commit_comment)
-  }
-  fn merge_commit_ctx<'a>(&'a self, commit: Commit) -> CommitContextGuard<'a> {
-    CommitContextGuard::new_merge(self, commit)
-  }
-  pub fn local_commits(&self) -> Result<Vec<Commit>, String> {
-    CommitLog::load_locals(&self.ctx())
+    assert_eq!(err, "Storage users schema version 1 is newer than 0");
   }
-  pub fn remote_commits(&self) -> Result<Vec<Commit>, String> {
-    CommitLog::load_remotes(&self.ctx())
+
+  #[test]
+  fn test_open_legacy_repo_details() {
+    let ctx = Context::in_memory("server".into());
+    let mode = Mode::server("127.0.0.1:0".into());
+    drop(Repository::init(ctx.clone(), mode.clone()).unwrap());
+    // Rewrite details as older versions wrote them, without signing key
+    let legacy = LegacyRepoDetails {
+      mode: LegacyMode::Server {
+        server_addr: "127.0.0.1:0".into(),
+      },
+    };
+    let path = path_helper::repo_details(&ctx);
+    ctx
+      .backend()
+      .put(&path, &bincode::serialize(&legacy).unwrap())
+      .unwrap();
+    let repo = Repository::load(ctx.clone()).unwrap();
+    let signing_key = {
+      let details = repo.repo_details.lock().unwrap();
+      assert_eq!(details.mode, mode);
+      details.signing_key().unwrap().to_bytes()
+    };
+    drop(repo);
+    // Upgraded details are saved, so the key is kept
+    let stored = binary_read::<StoredRepoDetails<RepoDetails>>(&ctx, path);
+    let StoredRepoDetails::V1(details) = stored.unwrap();
+    assert_eq!(details.signing_key, Some(signing_key));
   }
-  pub fn remote_commits_after(
-    &self,
-    after_id: Uuid,
-  ) -> Result<Vec<Commit>, String> {
-    CommitLog::load_remotes_after(&self.ctx(), after_id)
+
+  #[test]
+  fn test_open_legacy_commit_log() {
+    let repo =
+      Repository::init(Context::in_memory("anna".into()), Mode::local())
+        .unwrap();
+    let storage = users(&repo).unwrap();
+    create_user(&repo, &storage, 30).unwrap();
+    let ctx = repo.ctx().clone();
+    let commits = CommitLog::load_locals(&ctx).unwrap();
+    // Rewrite the local log unframed, as older versions wrote it
+    let mut legacy = vec![];
+    for commit in &commits {
+      let base = StoredCommitBase {
+        id: commit.id,
+        uid: commit.uid.clone(),
+        dtime: commit.dtime,
+        comment: commit.comment.clone(),
+        ancestor_id: commit.ancestor_id,
+        serialized_actions: commit.serialized_actions.clone(),
+        remote_signature: None,
+      };
+      bincode::serialize_into(&mut legacy, &base).unwrap();
+    }
+    let path = path_helper::commit_local_log(&ctx);
+    ctx.backend().put(&path, &legacy).unwrap();
+    drop((storage, repo));
+    let repo = Repository::load(ctx.clone()).unwrap();
+    let loaded = CommitLog::load_locals(&repo.ctx()).unwrap();
+    assert_eq!(
+      loaded.iter().map(|c| c.id).collect::<Vec<_>>(),
+      commits.iter().map(|c| c.id).collect::<Vec<_>>()
+    );
+    assert!(!binary_continuous_is_legacy(&ctx, &path).unwrap());
   }
 }
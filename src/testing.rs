@@ -0,0 +1,140 @@
+//! In-process client/server pair for integration tests
+//! The server is served on an ephemeral local port from its own thread,
+//! clients are cloned from it in remote mode. Every repository is kept
+//! in memory, so tests need no temp directories, e.g.
+//! `let alice = TestServer::start(register)?.client("alice")?`
+
+use std::{
+  net::{SocketAddr, TcpListener, TcpStream},
+  thread::JoinHandle,
+  time::{Duration, Instant},
+};
+
+use tokio::sync::oneshot;
+
+use crate::{
+  server::ServeConfig,
+  sync::{Context, Mode, Repository},
+};
+
+// Longest wait for the server to accept connections
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+const START_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+type Register<S> = Box<dyn Fn(&Repository) -> Result<S, String>>;
+
+/// Server repository served in the background
+/// Stopped when dropped.
+pub struct TestServer<S> {
+  pub repo: Repository,
+  /// Storages registered on the server repository
+  pub storages: S,
+  url: String,
+  register: Register<S>,
+  shutdown_tx: Option<oneshot::Sender<()>>,
+  thread: Option<JoinHandle<Result<(), String>>>,
+}
+
+/// Client repository in remote mode, connected to a TestServer
+pub struct TestClient<S> {
+  pub repo: Repository,
+  /// Storages registered on the client repository
+  pub storages: S,
+}
+
+impl<S> TestServer<S> {
+  /// Start server without authentication
+  /// register is called on the server and on every client repository
+  pub fn start(
+    register: impl Fn(&Repository) -> Result<S, String> + 'static,
+  ) -> Result<Self, String> {
+    Self::start_with_config(register, ServeConfig::default())
+  }
+  /// Start server with the given config, e.g. with auth or limits
+  pub fn start_with_config(
+    register: impl Fn(&Repository) -> Result<S, String> + 'static,
+    config: ServeConfig,
+  ) -> Result<Self, String> {
+    let addr = free_addr()?;
+    let repo = Repository::init(
+      Context::in_memory("server".to_string()),
+      Mode::server(addr.to_string()),
+    )?;
+    let storages = register(&repo)?;
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let server = repo.handle();
+    let thread = std::thread::spawn(move || {
+      server.serve_with_config(config, async {
+        let _ = shutdown_rx.await;
+      })
+    });
+    let mut res = Self {
+      repo,
+      storages,
+      url: format!("http://{}", addr),
+      register: Box::new(register),
+      shutdown_tx: Some(shutdown_tx),
+      thread: Some(thread),
+    };
+    res.wait_started(addr)?;
+    Ok(res)
+  }
+  /// Url clients connect to
+  pub fn url(&self) -> &str {
+    &self.url
+  }
+  /// Clone a new client repository with the given uid
+  pub fn client(&self, uid: &str) -> Result<TestClient<S>, String> {
+    self.client_with_ctx(Context::in_memory(uid.to_string()))
+  }
+  /// Clone a new client repository with the given context,
+  /// e.g. with an auth token
+  pub fn client_with_ctx(&self, ctx: Context) -> Result<TestClient<S>, String> {
+    let (repo, storages) =
+      Repository::clone(ctx, &self.url, |repo| (self.register)(repo))?;
+    Ok(TestClient { repo, storages })
+  }
+  // Wait until the server accepts connections,
+  // or return its error if it stopped
+  fn wait_started(&mut self, addr: SocketAddr) -> Result<(), String> {
+    let deadline = Instant::now() + START_TIMEOUT;
+    while TcpStream::connect(addr).is_err() {
+      if self.thread.as_ref().is_some_and(|t| t.is_finished()) {
+        self.stop()?;
+        return Err(format!("Test server stopped on {}", addr));
+      }
+      if Instant::now() > deadline {
+        return Err(format!("Test server not started on {}", addr));
+      }
+      std::thread::sleep(START_RETRY_DELAY);
+    }
+    Ok(())
+  }
+  /// Stop server and wait for it to shut down
+  pub fn stop(&mut self) -> Result<(), String> {
+    if let Some(shutdown_tx) = self.shutdown_tx.take() {
+      let _ = shutdown_tx.send(());
+    }
+    match self.thread.take() {
+      Some(thread) => thread
+        .join()
+        .map_err(|_| "Test server panicked".to_string())?,
+      None => Ok(()),
+    }
+  }
+}
+
+impl<S> Drop for TestServer<S> {
+  fn drop(&mut self) {
+    if let Err(e) = self.stop() {
+      warn!(error = %e, "Test server stopped with error");
+    }
+  }
+}
+
+// Local address with a port free at the time of the call
+fn free_addr() -> Result<SocketAddr, String> {
+  TcpListener::bind("127.0.0.1:0")
+    .and_then(|listener| listener.local_addr())
+    .map_err(|e| format!("No free local port: {}", e))
+}
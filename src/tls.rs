@@ -0,0 +1,111 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use tonic::transport::{
+  Certificate, ClientTlsConfig, Identity, ServerTlsConfig,
+};
+
+/// PEM encoded certificate chain and private key
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct TlsIdentity {
+  pub cert_path: PathBuf,
+  pub key_path: PathBuf,
+}
+
+impl TlsIdentity {
+  pub fn new(cert_path: PathBuf, key_path: PathBuf) -> Self {
+    Self {
+      cert_path,
+      key_path,
+    }
+  }
+  fn load(&self) -> Result<Identity, String> {
+    Ok(Identity::from_pem(
+      read_pem(&self.cert_path)?,
+      read_pem(&self.key_path)?,
+    ))
+  }
+}
+
+/// Server TLS configuration
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ServerTls {
+  // Server certificate and private key
+  pub identity: TlsIdentity,
+  // CA certificate to verify client certificates with
+  // Clients must present a valid certificate (mTLS) if set
+  pub client_ca_path: Option<PathBuf>,
+}
+
+impl ServerTls {
+  pub fn new(identity: TlsIdentity) -> Self {
+    Self {
+      identity,
+      client_ca_path: None,
+    }
+  }
+  /// Require client certificates signed by the given CA
+  pub fn with_client_ca(mut self, client_ca_path: PathBuf) -> Self {
+    self.client_ca_path = Some(client_ca_path);
+    self
+  }
+  pub(crate) fn config(&self) -> Result<ServerTlsConfig, String> {
+    let mut config = ServerTlsConfig::new().identity(self.identity.load()?);
+    if let Some(client_ca_path) = &self.client_ca_path {
+      config =
+        config.client_ca_root(Certificate::from_pem(read_pem(client_ca_path)?));
+    }
+    Ok(config)
+  }
+}
+
+/// Client TLS configuration
+/// Server certificate is verified against the system roots,
+/// or against the given CA certificate
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Default)]
+pub struct ClientTls {
+  // Trusted CA certificate, e.g. for self signed server certificates
+  pub ca_cert_path: Option<PathBuf>,
+  // Domain name to verify the server certificate against,
+  // if it differs from the remote url host
+  pub domain_name: Option<String>,
+  // Client certificate and private key for mTLS
+  pub identity: Option<TlsIdentity>,
+}
+
+impl ClientTls {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  pub fn with_ca_cert(mut self, ca_cert_path: PathBuf) -> Self {
+    self.ca_cert_path = Some(ca_cert_path);
+    self
+  }
+  pub fn with_domain_name(mut self, domain_name: &str) -> Self {
+    self.domain_name = Some(domain_name.to_string());
+    self
+  }
+  pub fn with_identity(mut self, identity: TlsIdentity) -> Self {
+    self.identity = Some(identity);
+    self
+  }
+  pub(crate) fn config(&self) -> Result<ClientTlsConfig, String> {
+    let mut config = ClientTlsConfig::new();
+    if let Some(ca_cert_path) = &self.ca_cert_path {
+      config =
+        config.ca_certificate(Certificate::from_pem(read_pem(ca_cert_path)?));
+    }
+    if let Some(domain_name) = &self.domain_name {
+      config = config.domain_name(domain_name);
+    }
+    if let Some(identity) = &self.identity {
+      config = config.identity(identity.load()?);
+    }
+    Ok(config)
+  }
+}
+
+fn read_pem(path: &PathBuf) -> Result<Vec<u8>, String> {
+  std::fs::read(path)
+    .map_err(|e| format!("Error reading PEM file {:?}: {}", path, e))
+}
@@ -0,0 +1,199 @@
+//! Record and replay of repository merges, e.g. to reproduce
+//! intermittent merge bugs offline
+//! A recording repository appends every received commit (pushed to
+//! the server, pulled from a remote or accepted by it) and every
+//! applied commit with its action results to a trace file, one json
+//! event per line. Replay feeds the applied and accepted commits in
+//! order into a fresh repository, through the same store, apply and
+//! promote paths, and reports every action whose result differs from
+//! the recorded one.
+
+use std::{
+  fs::{File, OpenOptions},
+  io::{BufRead, BufReader, Write},
+  path::Path,
+  sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::sync::{Commit, CommitReport, Repository};
+
+/// Trace file event
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum TraceEvent {
+  /// Commit pushed to the server, as received
+  Pushed {
+    at: DateTime<Utc>,
+    // Authenticated uid of the pusher
+    uid: Option<String>,
+    commit_json: String,
+  },
+  /// Commit pulled or watched from a remote
+  Pulled {
+    at: DateTime<Utc>,
+    remote: String,
+    commit: Commit,
+  },
+  /// Pushed local commit accepted and signed by a remote
+  Promoted {
+    at: DateTime<Utc>,
+    remote: String,
+    commit: Commit,
+  },
+  /// Commit stored and applied by the repository
+  Applied {
+    at: DateTime<Utc>,
+    commit: Commit,
+    actions: Vec<TracedAction>,
+    errors: Vec<String>,
+  },
+}
+
+/// Recorded apply result of an action object
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TracedAction {
+  pub action_id: Uuid,
+  pub storage_id: String,
+  pub object_id: Uuid,
+  pub error: Option<String>,
+}
+
+impl TraceEvent {
+  pub(crate) fn applied(commit: &Commit, report: &CommitReport) -> Self {
+    TraceEvent::Applied {
+      at: Utc::now(),
+      commit: commit.clone(),
+      actions: traced_actions(report),
+      errors: report.errors.clone(),
+    }
+  }
+}
+
+fn traced_actions(report: &CommitReport) -> Vec<TracedAction> {
+  report
+    .actions
+    .iter()
+    .map(|a| TracedAction {
+      action_id: a.action_id,
+      storage_id: a.storage_id.clone(),
+      object_id: a.object_id,
+      error: a.result.clone().err(),
+    })
+    .collect()
+}
+
+/// Trace file writer
+/// Every event is flushed once written, so the trace survives a crash
+pub struct TraceRecorder {
+  file: Mutex<File>,
+}
+
+impl TraceRecorder {
+  /// Create trace file, appending to it if exists
+  pub fn create(path: &Path) -> Result<Self, String> {
+    let file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(path)
+      .map_err(|e| format!("Error opening trace file {:?}: {}", path, e))?;
+    Ok(Self {
+      file: Mutex::new(file),
+    })
+  }
+  // Failing to record never fails the traced operation
+  pub(crate) fn record(&self, event: &TraceEvent) {
+    let res = serde_json::to_string(event)
+      .map_err(|e| e.to_string())
+      .and_then(|line| {
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", line)
+          .and_then(|_| file.flush())
+          .map_err(|e| e.to_string())
+      });
+    if let Err(e) = res {
+      warn!(error = %e, "Trace event not recorded");
+    }
+  }
+}
+
+/// Result of a replay
+#[derive(Default, Debug)]
+pub struct ReplayReport {
+  // Received commit events, not replayed themselves
+  pub received: usize,
+  // Replayed commits
+  pub commits: usize,
+  // Replayed promotions of pushed local commits
+  pub promoted: usize,
+  // Commits whose action results differ from the recorded ones
+  pub mismatches: Vec<(Uuid, String)>,
+}
+
+impl ReplayReport {
+  /// True if every commit reproduced its recorded results
+  pub fn is_clean(&self) -> bool {
+    self.mismatches.is_empty()
+  }
+}
+
+/// Replay trace file into a fresh repository
+/// Its storages must be registered as in the recording one.
+/// Commits are stored, applied and promoted as they are, so neither
+/// signatures nor ancestors are checked again. Stops at the first
+/// commit that could not be stored or promoted.
+pub fn replay(repo: &Repository, path: &Path) -> Result<ReplayReport, String> {
+  let file = File::open(path)
+    .map_err(|e| format!("Error opening trace file {:?}: {}", path, e))?;
+  let mut report = ReplayReport::default();
+  for (index, line) in BufReader::new(file).lines().enumerate() {
+    let line = line.map_err(|e| e.to_string())?;
+    if line.trim().is_empty() {
+      continue;
+    }
+    let event: TraceEvent = serde_json::from_str(&line)
+      .map_err(|e| format!("Invalid event on line {}: {}", index + 1, e))?;
+    let (commit, actions, errors) = match event {
+      TraceEvent::Pushed { .. } | TraceEvent::Pulled { .. } => {
+        report.received += 1;
+        continue;
+      }
+      TraceEvent::Promoted { commit, .. } => {
+        repo.replay_promotion(commit)?;
+        report.promoted += 1;
+        continue;
+      }
+      TraceEvent::Applied {
+        commit,
+        actions,
+        errors,
+        ..
+      } => (commit, actions, errors),
+    };
+    let commit_id = commit.id();
+    let replayed = repo.replay_commit(commit)?;
+    report.commits += 1;
+    let replayed_actions = traced_actions(&replayed);
+    if replayed_actions != actions {
+      report.mismatches.push((
+        commit_id,
+        format!(
+          "Action results differ, recorded {:?}, replayed {:?}",
+          actions, replayed_actions
+        ),
+      ));
+    } else if replayed.errors != errors {
+      report.mismatches.push((
+        commit_id,
+        format!(
+          "Errors differ, recorded {:?}, replayed {:?}",
+          errors, replayed.errors
+        ),
+      ));
+    }
+  }
+  Ok(report)
+}
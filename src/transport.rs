@@ -0,0 +1,880 @@
+//! Client side transports of the sync protocol
+//! Selected by the remote url scheme:
+//! - http://, https:// gRPC
+//! - http+gateway:// HTTP/JSON gateway (http-gateway feature),
+//!   for networks blocking HTTP/2, e.g. behind some proxies.
+//!   It is unencrypted, so auth tokens are sent to loopback addresses only.
+//!
+//! Every transport carries the same Commit JSON payloads.
+
+use std::pin::Pin;
+
+use futures_util::{Stream, StreamExt};
+use tonic::{
+  service::interceptor::InterceptedService, transport::Channel, Code, Status,
+};
+
+use crate::{
+  auth::ClientAuth,
+  blob::BlobAssembler,
+  codec::ActionCodec,
+  device::DeviceRecord,
+  presence::Presence,
+  query::{Query, QueryResult},
+  server::{
+    commit_chunks,
+    sync_api::{
+      api_client::ApiClient, BlobChunk, BlobList, CommitObj, DeviceRequest,
+      InfoRequest, InfoResponse, ListDevicesRequest, PresenceRequest,
+      PublicKeyRequest, PullRequest, QueryRequest, RegisterRequest,
+      WatchRequest,
+    },
+    ChunkAssembler, PROTOCOL_VERSION, RESYNC_REQUIRED,
+  },
+  sync::Commit,
+  tls::ClientTls,
+};
+
+// Remote gRPC client with auth interceptor
+type RemoteClient = ApiClient<InterceptedService<Channel, ClientAuth>>;
+
+// Remote commits and presence changes streamed by watch
+pub(crate) type WatchStream =
+  Pin<Box<dyn Stream<Item = Result<WatchEvent, String>> + Send>>;
+
+pub(crate) enum WatchEvent {
+  Commit(Commit),
+  Presence(Vec<Presence>),
+}
+
+/// Url scheme prefix of the HTTP/JSON gateway transport
+pub const GATEWAY_SCHEME: &str = "http+gateway://";
+
+// Pull request settings
+pub(crate) struct PullOptions<'a> {
+  // At most limit commits, all if 0
+  pub(crate) limit: u32,
+  // Delta encoded action objects are accepted over gRPC only,
+  // as are action objects encoded by codec
+  pub(crate) accept_delta: bool,
+  pub(crate) codec: ActionCodec,
+  // Pulling device, see device
+  pub(crate) device_id: &'a str,
+}
+
+// Connection to a remote server
+pub(crate) enum Transport {
+  Grpc(RemoteClient),
+  #[cfg(feature = "http-gateway")]
+  Http(http::HttpTransport),
+}
+
+impl Transport {
+  // Connect to remote
+  // Uses TLS if configured, and every request carries
+  // the auth token if any
+  pub(crate) async fn connect(
+    url: &str,
+    tls: Option<&ClientTls>,
+    auth_token: Option<String>,
+    repo: Option<String>,
+  ) -> Result<Self, String> {
+    if let Some(_address) = url.strip_prefix(GATEWAY_SCHEME) {
+      #[cfg(feature = "http-gateway")]
+      return match (tls, repo) {
+        (Some(_), _) => {
+          Err("TLS is not supported by the gateway transport".into())
+        }
+        (_, Some(_)) => Err(
+          "Repository hubs are not supported by the gateway transport".into(),
+        ),
+        (None, None) => {
+          Ok(Self::Http(http::HttpTransport::new(_address, auth_token)?))
+        }
+      };
+      #[cfg(not(feature = "http-gateway"))]
+      return Err("Gateway transport requires the http-gateway feature".into());
+    }
+    let mut endpoint = Channel::from_shared(url.to_string())
+      .map_err(|e| format!("Invalid remote url: {}", e))?;
+    if let Some(tls) = tls {
+      endpoint = endpoint
+        .tls_config(tls.config()?)
+        .map_err(|e| format!("TLS config error: {}", e))?;
+    }
+    let channel = endpoint
+      .connect()
+      .await
+      .map_err(|e| format!("Could not connect to remote: {}", e))?;
+    let auth = ClientAuth::new(auth_token, repo);
+    Ok(Self::Grpc(ApiClient::with_interceptor(channel, auth)))
+  }
+
+  // Hex encoded public key of the remote
+  pub(crate) async fn public_key(&mut self) -> Result<String, String> {
+    match self {
+      Self::Grpc(client) => Ok(
+        client
+          .public_key(PublicKeyRequest {})
+          .await
+          .map_err(|e| format!("Public key request error: {}", e))?
+          .into_inner()
+          .public_key,
+      ),
+      #[cfg(feature = "http-gateway")]
+      Self::Http(client) => client.public_key().await,
+    }
+  }
+
+  // Remote commits after the cursor, all if it is empty
+  // on_received is called with each commit and its size in bytes
+  pub(crate) async fn pull(
+    &mut self,
+    after_commit_id: String,
+    storage_ids: Vec<String>,
+    options: PullOptions<'_>,
+    mut on_received: impl FnMut(&Commit, usize),
+  ) -> Result<Vec<Commit>, String> {
+    let PullOptions {
+      limit,
+      accept_delta,
+      codec,
+      device_id,
+    } = options;
+    match self {
+      Self::Grpc(client) => {
+        let mut res = client
+          .pull(PullRequest {
+            after_commit_id,
+            storage_ids,
+            protocol_version: PROTOCOL_VERSION,
+            limit,
+            accept_delta,
+            action_codec: codec.name().to_string(),
+            device_id: device_id.to_string(),
+          })
+          .await
+          .map_err(|e| remote_request_error("Pull", e))?
+          .into_inner();
+        let mut commits = vec![];
+        while let Some(commit_obj) = res
+          .message()
+          .await
+          .map_err(|e| format!("Pull stream error: {}", e))?
+        {
+          let commit = decode_commit_obj(&commit_obj)?;
+          let size =
+            commit_obj.obj_json_string.len() + commit_obj.encoded_actions.len();
+          on_received(&commit, size);
+          commits.push(commit);
+        }
+        Ok(commits)
+      }
+      #[cfg(feature = "http-gateway")]
+      Self::Http(client) => {
+        let commits = client
+          .pull(&after_commit_id, &storage_ids, limit, device_id)
+          .await?;
+        for commit in &commits {
+          let size = serde_json::to_string(commit).map_or(0, |s| s.len());
+          on_received(commit, size);
+        }
+        Ok(commits)
+      }
+    }
+  }
+
+  // Push local commit, returns it merged and signed by the remote
+  // Over gRPC commits larger than max_message_size are sent in chunks
+  pub(crate) async fn push(
+    &mut self,
+    commit: Commit,
+    client_id: &str,
+    max_message_size: usize,
+  ) -> Result<Commit, String> {
+    let client = match self {
+      Self::Grpc(client) => client,
+      #[cfg(feature = "http-gateway")]
+      Self::Http(client) => return client.push(&commit, client_id).await,
+    };
+    let obj_json_string =
+      serde_json::to_string(&commit).map_err(|e| e.to_string())?;
+    if obj_json_string.len() < max_message_size {
+      let commit_obj = CommitObj {
+        obj_json_string,
+        client_id: client_id.to_string(),
+        protocol_version: PROTOCOL_VERSION,
+        action_codec: String::new(),
+        encoded_actions: vec![],
+        presence: vec![],
+      };
+      debug!(commit_id = %commit.id(), "Sending commit");
+      let res = client
+        .push(commit_obj)
+        .await
+        .map_err(|e| format!("Push error: {}", e.message()))?
+        .into_inner();
+      debug!("Commit received back");
+      return decode_commit_obj(&res);
+    }
+    let chunks =
+      commit_chunks(commit, max_message_size, client_id, PROTOCOL_VERSION)?;
+    debug!(chunks = chunks.len(), "Sending commit in chunks");
+    let mut res = client
+      .push_chunked(tokio_stream::iter(chunks))
+      .await
+      .map_err(|e| format!("Push error: {}", e.message()))?
+      .into_inner();
+    let mut assembler = ChunkAssembler::new(max_message_size);
+    loop {
+      let chunk = res
+        .message()
+        .await
+        .map_err(|e| format!("Push error: {}", e.message()))?
+        .ok_or("Push error: incomplete chunked commit")?;
+      check_protocol_version(chunk.protocol_version)?;
+      if let Some(commit) = assembler.push(chunk)? {
+        return Ok(commit);
+      }
+    }
+  }
+
+  // Stream remote commits after the cursor, then the new ones
+  // Commits pushed by client_id are not streamed back
+  // Action objects encoded by codec, and presence changes if asked
+  // for, are streamed over gRPC only
+  pub(crate) async fn watch(
+    &mut self,
+    after_commit_id: String,
+    storage_ids: Vec<String>,
+    client_id: String,
+    codec: ActionCodec,
+    presence: bool,
+  ) -> Result<WatchStream, String> {
+    match self {
+      Self::Grpc(client) => {
+        let stream = client
+          .watch(WatchRequest {
+            after_commit_id,
+            storage_ids,
+            client_id,
+            protocol_version: PROTOCOL_VERSION,
+            action_codec: codec.name().to_string(),
+            presence,
+          })
+          .await
+          .map_err(|e| remote_request_error("Watch", e))?
+          .into_inner();
+        Ok(Box::pin(stream.map(|commit_obj| {
+          commit_obj
+            .map_err(|e| format!("Watch stream error: {}", e))
+            .and_then(decode_watch_event)
+        })))
+      }
+      #[cfg(feature = "http-gateway")]
+      Self::Http(client) => {
+        let stream = client
+          .watch(&after_commit_id, &storage_ids, &client_id)
+          .await?;
+        Ok(Box::pin(stream.map(|res| res.map(WatchEvent::Commit))))
+      }
+    }
+  }
+
+  // Announce the objects of the storage the device has open
+  // Returns the presence of the other devices in the storage
+  pub(crate) async fn announce(
+    &mut self,
+    presence: &Presence,
+  ) -> Result<Vec<Presence>, String> {
+    let client = match self {
+      Self::Grpc(client) => client,
+      #[cfg(feature = "http-gateway")]
+      Self::Http(_) => {
+        return Err("Presence is not supported by the gateway transport".into())
+      }
+    };
+    let request = PresenceRequest {
+      device_id: presence.device_id.clone(),
+      uid: presence.uid.clone(),
+      storage_id: presence.storage_id.clone(),
+      object_ids: presence
+        .object_ids
+        .iter()
+        .map(|id| id.to_string())
+        .collect(),
+    };
+    client
+      .announce(request)
+      .await
+      .map_err(|e| format!("Announce error: {}", e.message()))?
+      .into_inner()
+      .presence
+      .into_iter()
+      .map(Presence::try_from)
+      .collect()
+  }
+
+  // Remote server info
+  pub(crate) async fn info(&mut self) -> Result<InfoResponse, String> {
+    match self {
+      Self::Grpc(client) => Ok(
+        client
+          .info(InfoRequest {})
+          .await
+          .map_err(|e| format!("Info request error: {}", e))?
+          .into_inner(),
+      ),
+      #[cfg(feature = "http-gateway")]
+      Self::Http(client) => client.info().await,
+    }
+  }
+
+  // Register device with the remote
+  // Servers not tracking devices are not asked
+  pub(crate) async fn register(
+    &mut self,
+    device_id: &str,
+    app_version: Option<String>,
+  ) -> Result<(), String> {
+    match self {
+      Self::Grpc(client) => {
+        let res = client
+          .register(RegisterRequest {
+            device_id: device_id.to_string(),
+            app_version: app_version.unwrap_or_default(),
+            protocol_version: PROTOCOL_VERSION,
+          })
+          .await;
+        match res {
+          Ok(_) => Ok(()),
+          Err(e) if e.code() == Code::Unimplemented => Ok(()),
+          Err(e) => Err(format!("Register request error: {}", e)),
+        }
+      }
+      #[cfg(feature = "http-gateway")]
+      Self::Http(client) => client.register(device_id, app_version).await,
+    }
+  }
+
+  // Query objects of a remote storage
+  pub(crate) async fn query(
+    &mut self,
+    storage_id: &str,
+    query: &Query,
+  ) -> Result<Vec<QueryResult>, String> {
+    match self {
+      Self::Grpc(client) => {
+        let request = QueryRequest {
+          storage_id: storage_id.to_string(),
+          query_json: serde_json::to_string(query)
+            .map_err(|e| e.to_string())?,
+        };
+        let response = client
+          .query(request)
+          .await
+          .map_err(|e| format!("Query request error: {}", e))?;
+        response
+          .into_inner()
+          .objects
+          .into_iter()
+          .map(QueryResult::try_from)
+          .collect()
+      }
+      #[cfg(feature = "http-gateway")]
+      Self::Http(client) => client.query(storage_id, query).await,
+    }
+  }
+}
+
+impl Transport {
+  // Ids of the given blobs the remote does not store yet
+  pub(crate) async fn missing_blobs(
+    &mut self,
+    blob_ids: Vec<String>,
+  ) -> Result<Vec<String>, String> {
+    Ok(
+      self
+        .grpc_for_blobs()?
+        .missing_blobs(BlobList { blob_ids })
+        .await
+        .map_err(|e| format!("Missing blobs request error: {}", e))?
+        .into_inner()
+        .blob_ids,
+    )
+  }
+
+  // Upload blob chunks
+  pub(crate) async fn push_blobs(
+    &mut self,
+    chunks: Vec<BlobChunk>,
+  ) -> Result<(), String> {
+    self
+      .grpc_for_blobs()?
+      .push_blobs(tokio_stream::iter(chunks))
+      .await
+      .map_err(|e| format!("Blob push error: {}", e.message()))?;
+    Ok(())
+  }
+
+  // Download the given blobs, returns their ids and contents
+  pub(crate) async fn pull_blobs(
+    &mut self,
+    blob_ids: Vec<String>,
+  ) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let mut res = self
+      .grpc_for_blobs()?
+      .pull_blobs(BlobList { blob_ids })
+      .await
+      .map_err(|e| format!("Blob pull error: {}", e.message()))?
+      .into_inner();
+    let mut assembler = BlobAssembler::default();
+    while let Some(chunk) = res
+      .message()
+      .await
+      .map_err(|e| format!("Blob pull stream error: {}", e))?
+    {
+      assembler.push(chunk);
+    }
+    Ok(assembler.finish())
+  }
+
+  // Devices registered with the remote, admin request
+  pub(crate) async fn list_devices(
+    &mut self,
+  ) -> Result<Vec<DeviceRecord>, String> {
+    self
+      .grpc_for_admin()?
+      .list_devices(ListDevicesRequest {})
+      .await
+      .map_err(|e| format!("List devices error: {}", e.message()))?
+      .into_inner()
+      .devices
+      .into_iter()
+      .map(DeviceRecord::try_from)
+      .collect()
+  }
+
+  // Reject later pushes of the device, admin request
+  pub(crate) async fn revoke_device(
+    &mut self,
+    device_id: &str,
+  ) -> Result<DeviceRecord, String> {
+    let request = DeviceRequest {
+      device_id: device_id.to_string(),
+    };
+    self
+      .grpc_for_admin()?
+      .revoke_device(request)
+      .await
+      .map_err(|e| format!("Revoke device error: {}", e.message()))?
+      .into_inner()
+      .try_into()
+  }
+
+  // Make the next pull of the device start from scratch, admin request
+  pub(crate) async fn force_resync(
+    &mut self,
+    device_id: &str,
+  ) -> Result<DeviceRecord, String> {
+    let request = DeviceRequest {
+      device_id: device_id.to_string(),
+    };
+    self
+      .grpc_for_admin()?
+      .force_resync(request)
+      .await
+      .map_err(|e| format!("Force resync error: {}", e.message()))?
+      .into_inner()
+      .try_into()
+  }
+
+  // Admin requests are sent over gRPC only
+  fn grpc_for_admin(&mut self) -> Result<&mut RemoteClient, String> {
+    match self {
+      Self::Grpc(client) => Ok(client),
+      #[cfg(feature = "http-gateway")]
+      Self::Http(_) => {
+        Err("Admin requests are not supported by the gateway transport".into())
+      }
+    }
+  }
+
+  // Blobs are transferred over gRPC only
+  fn grpc_for_blobs(&mut self) -> Result<&mut RemoteClient, String> {
+    match self {
+      Self::Grpc(client) => Ok(client),
+      #[cfg(feature = "http-gateway")]
+      Self::Http(_) => {
+        Err("Blobs are not supported by the gateway transport".into())
+      }
+    }
+  }
+}
+
+// Error of a pull or watch request
+// Unknown cursor means local remote log diverged from the server
+fn remote_request_error(request: &str, status: Status) -> String {
+  match status.code() {
+    Code::OutOfRange if status.message().starts_with(RESYNC_REQUIRED) => {
+      format!("{}. Clone the repository again", status.message())
+    }
+    _ => format!("{} request error: {}", request, status),
+  }
+}
+
+// Servers speaking a newer protocol than negotiated are refused
+fn check_protocol_version(protocol_version: u32) -> Result<(), String> {
+  match protocol_version > PROTOCOL_VERSION {
+    true => Err(format!(
+      "Server protocol version {} is newer than ours ({}). Please upgrade",
+      protocol_version, PROTOCOL_VERSION
+    )),
+    false => Ok(()),
+  }
+}
+
+// Commit or presence changes streamed by watch
+fn decode_watch_event(commit_obj: CommitObj) -> Result<WatchEvent, String> {
+  if commit_obj.presence.is_empty() {
+    return decode_commit_obj(&commit_obj).map(WatchEvent::Commit);
+  }
+  commit_obj
+    .presence
+    .into_iter()
+    .map(Presence::try_from)
+    .collect::<Result<_, _>>()
+    .map(WatchEvent::Presence)
+}
+
+// Decode commit received from the server
+// with its action objects encoded by codec, if any
+fn decode_commit_obj(commit_obj: &CommitObj) -> Result<Commit, String> {
+  check_protocol_version(commit_obj.protocol_version)?;
+  let mut commit: Commit = serde_json::from_str(&commit_obj.obj_json_string)
+    .map_err(|_| "Commit deser error".to_string())?;
+  if !commit_obj.action_codec.is_empty() {
+    let codec = ActionCodec::from_name(&commit_obj.action_codec)?;
+    commit
+      .extend_serialized_actions(codec.decode(&commit_obj.encoded_actions)?);
+  }
+  Ok(commit)
+}
+
+#[cfg(feature = "http-gateway")]
+mod http {
+  use std::{net::IpAddr, pin::Pin};
+
+  use futures_util::{Stream, StreamExt};
+  use hyper::{
+    body::{to_bytes, HttpBody},
+    client::HttpConnector,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Body, Client, Method, Request, StatusCode,
+  };
+  use serde::de::DeserializeOwned;
+
+  use crate::{
+    auth::BEARER,
+    gateway::{
+      ErrorBody, InfoBody, PublicKeyBody, PushBody, QueryBody, RegisterBody,
+      RegisteredBody,
+    },
+    query::{Query, QueryResult},
+    server::sync_api::InfoResponse,
+    sync::Commit,
+  };
+
+  // Remote commits streamed by the gateway watch
+  pub(crate) type CommitStream =
+    Pin<Box<dyn Stream<Item = Result<Commit, String>> + Send>>;
+
+  // HTTP/1.1 client of the gateway
+  pub(crate) struct HttpTransport {
+    client: Client<HttpConnector>,
+    base_url: String,
+    auth_token: Option<String>,
+  }
+
+  impl HttpTransport {
+    // Errors if the auth token would leave the host unencrypted
+    pub(crate) fn new(
+      address: &str,
+      auth_token: Option<String>,
+    ) -> Result<Self, String> {
+      if auth_token.is_some() && !is_loopback(address) {
+        return Err(
+          "Auth tokens are sent over the gateway transport to loopback \
+           addresses only, e.g. to a local TLS terminating proxy"
+            .into(),
+        );
+      }
+      Ok(Self {
+        client: Client::new(),
+        base_url: format!("http://{}", address.trim_end_matches('/')),
+        auth_token,
+      })
+    }
+
+    // Send request, errors with the gateway error message
+    async fn send(
+      &self,
+      method: Method,
+      path_and_query: &str,
+      body: Option<String>,
+    ) -> Result<Body, String> {
+      let mut request = Request::builder()
+        .method(method)
+        .uri(format!("{}{}", self.base_url, path_and_query));
+      if let Some(token) = &self.auth_token {
+        request = request.header(AUTHORIZATION, format!("{}{}", BEARER, token));
+      }
+      let request = match body {
+        Some(body) => request
+          .header(CONTENT_TYPE, "application/json")
+          .body(Body::from(body)),
+        None => request.body(Body::empty()),
+      }
+      .map_err(|e| format!("Invalid gateway request: {}", e))?;
+      let response = self
+        .client
+        .request(request)
+        .await
+        .map_err(|e| format!("Could not connect to remote: {}", e))?;
+      let status = response.status();
+      if status.is_success() {
+        return Ok(response.into_body());
+      }
+      let body = to_bytes(response.into_body())
+        .await
+        .map_err(|e| e.to_string())?;
+      let message = serde_json::from_slice::<ErrorBody>(&body)
+        .map(|body| body.error)
+        .unwrap_or_else(|_| status.to_string());
+      match status {
+        // Unknown cursor
+        StatusCode::GONE => {
+          Err(format!("{}. Clone the repository again", message))
+        }
+        _ => Err(format!("Gateway error: {}", message)),
+      }
+    }
+
+    async fn get_json<T: DeserializeOwned>(
+      &self,
+      path_and_query: &str,
+    ) -> Result<T, String> {
+      let body = self.send(Method::GET, path_and_query, None).await?;
+      read_json(body).await
+    }
+
+    pub(crate) async fn public_key(&self) -> Result<String, String> {
+      let body: PublicKeyBody = self.get_json("/public_key").await?;
+      Ok(body.public_key)
+    }
+
+    pub(crate) async fn pull(
+      &self,
+      after_commit_id: &str,
+      storage_ids: &[String],
+      limit: u32,
+      device_id: &str,
+    ) -> Result<Vec<Commit>, String> {
+      let query = cursor_query(after_commit_id, storage_ids, "");
+      self
+        .get_json(&format!(
+          "/pull?{}&limit={}&device_id={}",
+          query, limit, device_id
+        ))
+        .await
+    }
+
+    pub(crate) async fn push(
+      &self,
+      commit: &Commit,
+      client_id: &str,
+    ) -> Result<Commit, String> {
+      let body = PushBody {
+        commit: serde_json::to_value(commit).map_err(|e| e.to_string())?,
+        client_id: client_id.to_string(),
+      };
+      let body = serde_json::to_string(&body).map_err(|e| e.to_string())?;
+      let res = self.send(Method::POST, "/push", Some(body)).await?;
+      read_json(res).await
+    }
+
+    // Server sent events of the gateway watch endpoint
+    pub(crate) async fn watch(
+      &self,
+      after_commit_id: &str,
+      storage_ids: &[String],
+      client_id: &str,
+    ) -> Result<CommitStream, String> {
+      let query = cursor_query(after_commit_id, storage_ids, client_id);
+      let body = self
+        .send(Method::GET, &format!("/watch?{}", query), None)
+        .await?;
+      Ok(commit_stream(body))
+    }
+
+    pub(crate) async fn info(&self) -> Result<InfoResponse, String> {
+      let info: InfoBody = self.get_json("/info").await?;
+      Ok(info.into())
+    }
+
+    pub(crate) async fn register(
+      &self,
+      device_id: &str,
+      app_version: Option<String>,
+    ) -> Result<(), String> {
+      let body = RegisterBody {
+        device_id: device_id.to_string(),
+        app_version,
+      };
+      let body = serde_json::to_string(&body).map_err(|e| e.to_string())?;
+      let res = self.send(Method::POST, "/register", Some(body)).await?;
+      read_json::<RegisteredBody>(res).await?;
+      Ok(())
+    }
+
+    pub(crate) async fn query(
+      &self,
+      storage_id: &str,
+      query: &Query,
+    ) -> Result<Vec<QueryResult>, String> {
+      let body = QueryBody {
+        storage_id: storage_id.to_string(),
+        query: query.clone(),
+      };
+      let body = serde_json::to_string(&body).map_err(|e| e.to_string())?;
+      let res = self.send(Method::POST, "/query", Some(body)).await?;
+      read_json(res).await
+    }
+  }
+
+  async fn read_json<T: DeserializeOwned>(body: Body) -> Result<T, String> {
+    let body = to_bytes(body).await.map_err(|e| e.to_string())?;
+    serde_json::from_slice(&body)
+      .map_err(|e| format!("Gateway response deser error: {}", e))
+  }
+
+  // Whether the host of address is a loopback one
+  fn is_loopback(address: &str) -> bool {
+    let authority = address.split('/').next().unwrap_or_default();
+    let host = match authority.strip_prefix('[') {
+      // IPv6 address
+      Some(rest) => rest.split(']').next().unwrap_or_default(),
+      None => authority.split(':').next().unwrap_or_default(),
+    };
+    host == "localhost"
+      || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+  }
+
+  // Commits of the server sent events body
+  // Chunks might split multi byte characters,
+  // so only complete events are decoded
+  fn commit_stream(body: Body) -> CommitStream {
+    let stream = futures_util::stream::unfold(
+      (body, Vec::new()),
+      |(mut body, mut buffer)| async move {
+        loop {
+          // Events are separated by an empty line
+          if let Some(end) = buffer.windows(2).position(|w| w == b"\n\n") {
+            let event: Vec<u8> = buffer.drain(..end + 2).collect();
+            let res = match String::from_utf8(event) {
+              Ok(event) => parse_event(&event),
+              Err(_) => Some(Err("Watch event is not UTF-8".to_string())),
+            };
+            match res {
+              Some(res) => return Some((res, (body, buffer))),
+              // Keep alive comment
+              None => continue,
+            }
+          }
+          match body.data().await? {
+            Ok(chunk) => buffer.extend_from_slice(&chunk),
+            Err(e) => {
+              let res = Err(format!("Watch stream error: {}", e));
+              return Some((res, (body, buffer)));
+            }
+          }
+        }
+      },
+    );
+    Box::pin(stream.fuse())
+  }
+
+  fn cursor_query(
+    after_commit_id: &str,
+    storage_ids: &[String],
+    client_id: &str,
+  ) -> String {
+    form_urlencoded::Serializer::new(String::new())
+      .append_pair("after_commit_id", after_commit_id)
+      .append_pair("storage_ids", &storage_ids.join(","))
+      .append_pair("client_id", client_id)
+      .finish()
+  }
+
+  // Commit of a "commit" event, error of an "error" event
+  // None for events without data
+  fn parse_event(event: &str) -> Option<Result<Commit, String>> {
+    let mut name = "message";
+    let mut data = String::new();
+    for line in event.lines() {
+      if let Some(value) = line.strip_prefix("event:") {
+        name = value.trim_start();
+      } else if let Some(value) = line.strip_prefix("data:") {
+        data.push_str(value.strip_prefix(' ').unwrap_or(value));
+      }
+    }
+    if data.is_empty() {
+      return None;
+    }
+    Some(match name {
+      "commit" => serde_json::from_str(&data)
+        .map_err(|_| "Commit deser error".to_string()),
+      _ => Err(format!("Watch stream error: {}", data)),
+    })
+  }
+
+  #[cfg(test)]
+  mod tests {
+    use hyper::body::Bytes;
+
+    use super::*;
+
+    #[test]
+    fn test_event_split_across_chunks() {
+      let rt = tokio::runtime::Runtime::new().unwrap();
+      rt.block_on(async {
+        let (mut sender, body) = Body::channel();
+        let event = ": keep alive\n\nevent: error\ndata: Szépség\n\n";
+        // Split inside the two byte é
+        let split = event.find('é').unwrap() + 1;
+        let chunks = [&event.as_bytes()[..split], &event.as_bytes()[split..]];
+        let chunks = chunks.map(Bytes::copy_from_slice);
+        tokio::spawn(async move {
+          for chunk in chunks {
+            sender.send_data(chunk).await.unwrap();
+          }
+        });
+        let mut stream = commit_stream(body);
+        let res = stream.next().await.unwrap();
+        assert_eq!(res.err().unwrap(), "Watch stream error: Szépség");
+        assert!(stream.next().await.is_none());
+      });
+    }
+
+    #[test]
+    fn test_token_sent_to_loopback_only() {
+      let token = || Some("token".to_string());
+      for address in ["127.0.0.1:8080", "localhost:8080/sync", "[::1]:8080"] {
+        assert!(HttpTransport::new(address, token()).is_ok(), "{}", address);
+      }
+      assert!(HttpTransport::new("example.com:8080", token()).is_err());
+      assert!(HttpTransport::new("10.0.0.1", token()).is_err());
+      assert!(HttpTransport::new("example.com:8080", None).is_ok());
+    }
+  }
+}
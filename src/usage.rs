@@ -0,0 +1,95 @@
+//! Disk usage reporting and soft storage quotas
+//! Usage is measured via the repository backend, so it is reported
+//! the same way for every backend, e.g. to bill or limit tenants of
+//! hosted deployments.
+
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::backend::Backend;
+
+/// Error prefix of commits rejected by a storage quota
+pub const QUOTA_EXCEEDED: &str = "Quota exceeded";
+
+/// Soft storage quota, unlimited by default
+/// Checked with the commits updating the storage, see
+/// Storage::with_quota. Commits only removing objects are accepted.
+#[derive(Debug, Clone, Default)]
+pub struct Quota {
+  pub(crate) max_objects: Option<usize>,
+  pub(crate) max_bytes: Option<u64>,
+}
+
+impl Quota {
+  pub fn new() -> Self {
+    Self::default()
+  }
+  /// Most objects of the storage, removed ones are not counted
+  pub fn with_max_objects(mut self, max_objects: usize) -> Self {
+    self.max_objects = Some(max_objects);
+    self
+  }
+  /// Most bytes used by the storage, see StorageUsage::total_bytes
+  /// Soft limit: measured once, then estimated by adding the size of
+  /// the checked commits until usage is measured again.
+  pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+    self.max_bytes = Some(max_bytes);
+    self
+  }
+}
+
+/// Disk usage of a storage
+#[derive(Default, Debug, Clone, Serialize)]
+pub struct StorageUsage {
+  pub storage_id: String,
+  // Not removed objects
+  pub objects: usize,
+  // Stored object states, removed ones included
+  pub object_bytes: u64,
+  // Per object action logs
+  pub log_bytes: u64,
+  // Blobs referred by the objects, each counted once
+  pub blob_bytes: u64,
+}
+
+impl StorageUsage {
+  pub fn total_bytes(&self) -> u64 {
+    self.object_bytes + self.log_bytes + self.blob_bytes
+  }
+}
+
+/// Disk usage of the repository
+#[derive(Default, Debug, Clone, Serialize)]
+pub struct DiskUsage {
+  pub storages: Vec<StorageUsage>,
+  // Local and remote commit logs
+  pub commit_log_bytes: u64,
+  // Every stored blob, referred or not
+  pub blob_bytes: u64,
+  // Everything under the repository root
+  pub total_bytes: u64,
+}
+
+// Size of every value under prefix
+pub(crate) fn prefix_size(
+  backend: &dyn Backend,
+  prefix: &Path,
+) -> Result<u64, String> {
+  let mut res = 0;
+  for key in backend.scan(prefix)? {
+    res += backend.size(&key)?;
+  }
+  Ok(res)
+}
+
+// Size of the value of key, 0 if it does not exist
+pub(crate) fn key_size(
+  backend: &dyn Backend,
+  key: &Path,
+) -> Result<u64, String> {
+  match backend.exists(key) {
+    true => backend.size(key),
+    false => Ok(0),
+  }
+}
@@ -0,0 +1,61 @@
+//! Invariant checks of storage object action chains
+//! Unlike fsck, nothing is repaired, so they can be run in CI
+//! e.g. against generated action sequences.
+
+use std::fmt::Debug;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::sync::{ActionExt, ObjectExt, Repository, StorageObject};
+
+/// Invariant violations of a storage found by verify_all
+#[derive(Default, Debug)]
+pub struct StorageVerifyReport {
+  pub storage_id: String,
+  // Number of checked objects
+  pub objects: usize,
+  // Objects violating an invariant, with the first violation
+  pub violations: Vec<(Uuid, String)>,
+}
+
+/// Result of verify_all
+#[derive(Default, Debug)]
+pub struct VerifyReport {
+  pub storages: Vec<StorageVerifyReport>,
+}
+
+impl VerifyReport {
+  /// True if no invariant violated
+  pub fn is_clean(&self) -> bool {
+    self.storages.iter().all(|s| s.violations.is_empty())
+  }
+}
+
+/// Check the invariants of a storage object
+/// Action chain is linked by parent action ids and starts with create,
+/// every object signature matches the replayed object, remote actions
+/// precede local ones and only they are remote signed, and replaying
+/// the actions reproduces the remote and local objects exactly.
+/// Returns the first violation
+pub fn check_storage_object<T, A>(
+  storage_object: &StorageObject<T, A>,
+) -> Result<(), String>
+where
+  T: ObjectExt + Serialize + for<'de> Deserialize<'de> + JsonSchema,
+  A: ActionExt<ObjectType = T>
+    + Serialize
+    + for<'de> Deserialize<'de>
+    + Debug
+    + JsonSchema,
+{
+  storage_object.check_invariants(&[])
+}
+
+/// Check every object of every registered storage
+/// Remote signatures are verified too, against the pinned public keys
+/// of the remotes, or against the repository key in server mode.
+pub fn verify_all(repo: &Repository) -> Result<VerifyReport, String> {
+  repo.verify_storages()
+}
@@ -0,0 +1,252 @@
+//! Supervised runtime of background sync work
+//! Every handle of a repository shares one multi-threaded tokio runtime,
+//! started on first use. Watchers, the write-behind flusher and the
+//! history checkpointer run on it as named tasks, and push, pull and
+//! other remote calls block on it instead of building runtimes of
+//! their own. Failing or panicking tasks are restarted by their
+//! RestartPolicy, see Repository::worker_tasks.
+//!
+//! With the tokio-console feature, built with
+//! RUSTFLAGS="--cfg tokio_unstable", tasks are spawned with their
+//! names, so tokio-console shows them once the application installs
+//! console_subscriber.
+
+use std::{
+  collections::BTreeMap,
+  future::Future,
+  sync::{Arc, Mutex, OnceLock},
+  time::Duration,
+};
+
+use serde::Serialize;
+use tokio::{runtime::Runtime, task::JoinHandle};
+use tracing::Instrument;
+
+/// Restart policy of a supervised task
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+  /// Run once, whatever the outcome
+  Never,
+  /// Restart after delay if it failed or panicked
+  OnFailure { delay: Duration },
+  /// Restart after delay whenever it ends, e.g. reconnecting watchers
+  Always { delay: Duration },
+}
+
+impl RestartPolicy {
+  fn restart_delay(&self, failed: bool) -> Option<Duration> {
+    match self {
+      RestartPolicy::Never => None,
+      RestartPolicy::OnFailure { delay } => failed.then_some(*delay),
+      RestartPolicy::Always { delay } => Some(*delay),
+    }
+  }
+}
+
+/// State of a supervised task
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+  Running,
+  // Waiting for the restart delay
+  Restarting,
+  // Ended and not restarted
+  Finished,
+}
+
+/// Supervised task, see Repository::worker_tasks
+#[derive(Serialize, Debug, Clone)]
+pub struct TaskStatus {
+  pub name: String,
+  pub state: TaskState,
+  pub restarts: usize,
+  // Error or panic message of the latest failed run
+  pub last_error: Option<String>,
+}
+
+/// Runtime of the background work of a repository
+/// Shut down in the background once every repository handle is
+/// dropped, cancelling its tasks.
+#[derive(Default)]
+pub struct Worker {
+  runtime: OnceLock<Runtime>,
+  tasks: Arc<Mutex<BTreeMap<String, TaskStatus>>>,
+}
+
+impl Worker {
+  pub(crate) fn new() -> Self {
+    Self::default()
+  }
+  fn runtime(&self) -> &Runtime {
+    self.runtime.get_or_init(|| {
+      tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .thread_name("sync_worker")
+        .build()
+        .expect("Error starting sync worker runtime")
+    })
+  }
+  /// Run future to completion on the worker runtime
+  /// Must not be called from async code, as any block_on
+  pub(crate) fn block_on<F: Future>(&self, future: F) -> F::Output {
+    self.runtime().block_on(future)
+  }
+  /// Spawn supervised task, run by task and restarted by policy
+  /// Errors if a task of the same name is still running
+  pub(crate) fn spawn<F, Fut>(
+    &self,
+    name: &str,
+    policy: RestartPolicy,
+    task: F,
+  ) -> Result<(), String>
+  where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = Result<(), String>> + Send + 'static,
+  {
+    {
+      let mut tasks = self.tasks.lock().unwrap();
+      if tasks
+        .get(name)
+        .is_some_and(|status| status.state != TaskState::Finished)
+      {
+        return Err(format!("Task {} is already running", name));
+      }
+      tasks.insert(
+        name.to_string(),
+        TaskStatus {
+          name: name.to_string(),
+          state: TaskState::Running,
+          restarts: 0,
+          last_error: None,
+        },
+      );
+    }
+    let tasks = self.tasks.clone();
+    let name = name.to_string();
+    let _guard = self.runtime().enter();
+    spawn_named(&format!("{}_supervisor", name), async move {
+      loop {
+        let res = match spawn_named(&name, task()).await {
+          Ok(res) => res,
+          Err(e) if e.is_panic() => Err(panic_message(e.into_panic())),
+          // Cancelled by the runtime shutdown
+          Err(_) => return,
+        };
+        if let Err(e) = &res {
+          error!(task = %name, error = %e, "Worker task failed");
+        }
+        let delay = policy.restart_delay(res.is_err());
+        set_state(&tasks, &name, |status| {
+          status.last_error = res.err().or(status.last_error.take());
+          status.state = match delay {
+            Some(_) => TaskState::Restarting,
+            None => TaskState::Finished,
+          };
+        });
+        match delay {
+          Some(delay) => tokio::time::sleep(delay).await,
+          None => return,
+        }
+        debug!(task = %name, "Restarting worker task");
+        set_state(&tasks, &name, |status| {
+          status.restarts += 1;
+          status.state = TaskState::Running;
+        });
+      }
+    });
+    Ok(())
+  }
+  /// Supervised tasks by name, finished ones included
+  pub fn tasks(&self) -> Vec<TaskStatus> {
+    self.tasks.lock().unwrap().values().cloned().collect()
+  }
+}
+
+impl Drop for Worker {
+  fn drop(&mut self) {
+    // Blocking shutdown would panic if dropped in async code
+    if let Some(runtime) = self.runtime.take() {
+      runtime.shutdown_background();
+    }
+  }
+}
+
+fn set_state(
+  tasks: &Mutex<BTreeMap<String, TaskStatus>>,
+  name: &str,
+  f: impl FnOnce(&mut TaskStatus),
+) {
+  if let Some(status) = tasks.lock().unwrap().get_mut(name) {
+    f(status);
+  }
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+  let message = payload
+    .downcast_ref::<&str>()
+    .map(|s| s.to_string())
+    .or_else(|| payload.downcast_ref::<String>().cloned())
+    .unwrap_or_default();
+  format!("Panicked: {}", message)
+}
+
+// Task named for tokio-console, if enabled
+#[cfg(all(tokio_unstable, feature = "tokio-console"))]
+fn spawn_named<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+  F: Future + Send + 'static,
+  F::Output: Send + 'static,
+{
+  tokio::task::Builder::new()
+    .name(name)
+    .spawn(future.instrument(info_span!("sync_task", task = name)))
+    .expect("Error spawning worker task")
+}
+
+// Task named by its tracing span
+#[cfg(not(all(tokio_unstable, feature = "tokio-console")))]
+fn spawn_named<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+  F: Future + Send + 'static,
+  F::Output: Send + 'static,
+{
+  tokio::spawn(future.instrument(info_span!("sync_task", task = name)))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::sync::atomic::{AtomicUsize, Ordering};
+
+  use super::*;
+
+  #[test]
+  fn test_restart_on_panic() {
+    let worker = Worker::new();
+    let runs = Arc::new(AtomicUsize::new(0));
+    let counter = runs.clone();
+    let delay = Duration::from_millis(1);
+    worker
+      .spawn("flaky", RestartPolicy::OnFailure { delay }, move || {
+        let run = counter.fetch_add(1, Ordering::SeqCst);
+        async move {
+          match run {
+            0 => panic!("first run"),
+            1 => Err("second run".to_string()),
+            _ => Ok(()),
+          }
+        }
+      })
+      .unwrap();
+    let started = std::time::Instant::now();
+    while worker.tasks()[0].state != TaskState::Finished {
+      assert!(started.elapsed() < Duration::from_secs(5));
+      std::thread::sleep(delay);
+    }
+    let status = &worker.tasks()[0];
+    assert_eq!(runs.load(Ordering::SeqCst), 3);
+    assert_eq!(status.restarts, 2);
+    assert_eq!(status.last_error.as_deref(), Some("second run"));
+    assert!(worker
+      .spawn("flaky", RestartPolicy::Never, || async { Ok(()) })
+      .is_ok());
+  }
+}
@@ -0,0 +1,171 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+  parse_macro_input, spanned::Spanned, Attribute, Data, DeriveInput, Error,
+  Fields, Ident, Path,
+};
+
+/// Derive ActionExt for setter style action enums
+///
+/// Every variant must be a single field tuple variant, setting
+/// the object field given by its `#[action(field = ...)]` attribute.
+/// Target object type is given by the enum `#[action(object = ...)]`
+/// attribute.
+///
+/// ```ignore
+/// #[derive(Action, Serialize, Deserialize, Clone, Debug)]
+/// #[action(object = User)]
+/// enum UserAction {
+///   #[action(field = name)]
+///   SetName(String),
+///   #[action(field = age)]
+///   SetAge(i32),
+/// }
+/// ```
+///
+/// Generated apply_patch sets the field, display shows the new value,
+/// conflicts_with reports actions setting the same field,
+/// commutes_with actions setting different fields, and inverse
+/// sets the field back to its previous value.
+#[proc_macro_derive(Action, attributes(action))]
+pub fn derive_action(input: TokenStream) -> TokenStream {
+  let input = parse_macro_input!(input as DeriveInput);
+  expand(input)
+    .unwrap_or_else(Error::into_compile_error)
+    .into()
+}
+
+// Setter variant
+struct Setter {
+  variant: Ident,
+  field: Ident,
+}
+
+fn expand(input: DeriveInput) -> Result<TokenStream2, Error> {
+  let name = &input.ident;
+  let object = parse_object(&input.attrs, input.span())?;
+  let data = match &input.data {
+    Data::Enum(data) => data,
+    _ => {
+      return Err(Error::new(
+        input.span(),
+        "Action can only be derived for enums",
+      ))
+    }
+  };
+  let mut setters = vec![];
+  for variant in &data.variants {
+    match &variant.fields {
+      Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {}
+      _ => {
+        return Err(Error::new(
+          variant.span(),
+          "Action variant must have exactly one unnamed field",
+        ))
+      }
+    }
+    setters.push(Setter {
+      variant: variant.ident.clone(),
+      field: parse_field(&variant.attrs, variant.span())?,
+    });
+  }
+
+  let apply_arms = setters.iter().map(|Setter { variant, field }| {
+    quote! { Self::#variant(value) => object.#field = value.clone(), }
+  });
+  let display_arms = setters.iter().map(|Setter { variant, field }| {
+    let field = field.to_string();
+    quote! { Self::#variant(value) => format!("{} = {:?}", #field, value), }
+  });
+  let conflict_arms = setters.iter().map(|Setter { variant, .. }| {
+    quote! { (Self::#variant(_), Self::#variant(_)) => true, }
+  });
+  let field_arms = setters.iter().map(|Setter { variant, field }| {
+    let field = field.to_string();
+    quote! { Self::#variant(_) => #field, }
+  });
+  let inverse_arms = setters.iter().map(|Setter { variant, field }| {
+    quote! { Self::#variant(_) => Self::#variant(before.#field.clone()), }
+  });
+
+  Ok(quote! {
+    impl ::storage::sync::ActionExt for #name {
+      type ObjectType = #object;
+      fn apply_patch(
+        &self,
+        object: &Self::ObjectType,
+        _dtime: ::storage::__private::DateTime<::storage::__private::Utc>,
+        _uid: &str,
+      ) -> Result<Self::ObjectType, String> {
+        let mut object = object.clone();
+        match self {
+          #(#apply_arms)*
+        }
+        Ok(object)
+      }
+      fn display(&self) -> String {
+        match self {
+          #(#display_arms)*
+        }
+      }
+      fn conflicts_with(&self, remote: &Self) -> bool {
+        #[allow(unreachable_patterns)]
+        match (self, remote) {
+          #(#conflict_arms)*
+          _ => false,
+        }
+      }
+      fn commutes_with(&self, other: &Self) -> bool {
+        let field = |action: &Self| match action {
+          #(#field_arms)*
+        };
+        field(self) != field(other)
+      }
+      fn inverse(&self, before: &Self::ObjectType) -> Option<Self> {
+        Some(match self {
+          #(#inverse_arms)*
+        })
+      }
+    }
+  })
+}
+
+// Parse enum level #[action(object = Type)]
+fn parse_object(
+  attrs: &[Attribute],
+  span: proc_macro2::Span,
+) -> Result<Path, Error> {
+  let mut object = None;
+  for attr in attrs.iter().filter(|a| a.path().is_ident("action")) {
+    attr.parse_nested_meta(|meta| {
+      if meta.path.is_ident("object") {
+        object = Some(meta.value()?.parse::<Path>()?);
+        return Ok(());
+      }
+      Err(meta.error("Unknown action attribute, expected `object`"))
+    })?;
+  }
+  object.ok_or(Error::new(
+    span,
+    "Missing #[action(object = ...)] attribute",
+  ))
+}
+
+// Parse variant level #[action(field = name)]
+fn parse_field(
+  attrs: &[Attribute],
+  span: proc_macro2::Span,
+) -> Result<Ident, Error> {
+  let mut field = None;
+  for attr in attrs.iter().filter(|a| a.path().is_ident("action")) {
+    attr.parse_nested_meta(|meta| {
+      if meta.path.is_ident("field") {
+        field = Some(meta.value()?.parse::<Ident>()?);
+        return Ok(());
+      }
+      Err(meta.error("Unknown action attribute, expected `field`"))
+    })?;
+  }
+  field.ok_or(Error::new(span, "Missing #[action(field = ...)] attribute"))
+}